@@ -1,5 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use docs_rs::storage::{compress, decompress, CompressionAlgorithm};
+use std::io::{Cursor, Write};
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 pub fn regex_capture_matches(c: &mut Criterion) {
     // this isn't a great benchmark because it only tests on one file
@@ -35,5 +37,54 @@ pub fn regex_capture_matches(c: &mut Criterion) {
         });
 }
 
-criterion_group!(compression, regex_capture_matches);
+pub fn archive_entries(c: &mut Criterion) {
+    // Serving a page from a remote archive means decompressing a single zip entry, so compare
+    // the entries exactly as `zip` writes them for every algorithm we support in archives.
+    let html = std::fs::read_to_string("benches/struct.CaptureMatches.html").unwrap();
+    let html_slice = html.as_bytes();
+
+    let mut group = c.benchmark_group("regex html archive entry");
+    group.throughput(Throughput::Bytes(html_slice.len() as u64));
+
+    for (method, alg) in [
+        (CompressionMethod::Zstd, CompressionAlgorithm::Zstd),
+        (CompressionMethod::Bzip2, CompressionAlgorithm::Bzip2),
+    ] {
+        group.bench_function(format!("compress {}", alg), |b| {
+            b.iter(|| {
+                let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+                zip.start_file(
+                    "file.html",
+                    FileOptions::default().compression_method(method),
+                )
+                .unwrap();
+                zip.write_all(black_box(html_slice)).unwrap();
+                zip.finish().unwrap()
+            });
+        });
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(
+            "file.html",
+            FileOptions::default().compression_method(method),
+        )
+        .unwrap();
+        zip.write_all(html_slice).unwrap();
+        let archive = zip.finish().unwrap().into_inner();
+
+        let mut reader = ZipArchive::new(Cursor::new(&archive)).unwrap();
+        let entry = reader.by_index(0).unwrap();
+        let range =
+            entry.data_start() as usize..(entry.data_start() + entry.compressed_size()) as usize;
+        let entry_data = &archive[range];
+
+        group.bench_function(format!("decompress {}", alg), |b| {
+            b.iter(|| decompress(black_box(entry_data), alg, 5 * 1024 * 1024));
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(compression, regex_capture_matches, archive_entries);
 criterion_main!(compression);
@@ -95,6 +95,7 @@ pub enum MetadataError {
 /// targets = [ "x86_64-apple-darwin", "x86_64-pc-windows-msvc" ]
 /// rustc-args = [ "--example-rustc-arg" ]
 /// rustdoc-args = [ "--example-rustdoc-arg" ]
+/// doctests = true
 /// ```
 ///
 /// You can define one or more fields in your `Cargo.toml`.
@@ -138,6 +139,12 @@ pub struct Metadata {
     /// These cannot be a subcommand, they may only be options.
     #[serde(default)]
     cargo_args: Vec<String>,
+
+    /// Whether to run the doctests of the crate after documenting it.
+    ///
+    /// Doctests are only run if the docs.rs instance is configured to run them.
+    #[serde(default)]
+    pub doctests: bool,
 }
 
 /// The targets that should be built for a crate.
@@ -251,9 +258,27 @@ impl Metadata {
     /// For example, the links may point somewhere different than they would on docs.rs.
     /// However, rustdoc will see exactly the same code as it would on docs.rs, even counting `cfg`s.
     pub fn cargo_args(&self, additional_args: &[String], rustdoc_args: &[String]) -> Vec<String> {
-        let mut cargo_args: Vec<String> =
-            vec!["rustdoc".into(), "--lib".into(), "-Zrustdoc-map".into()];
+        self.args_for(
+            vec!["rustdoc".into(), "--lib".into(), "-Zrustdoc-map".into()],
+            additional_args,
+            rustdoc_args,
+        )
+    }
 
+    /// Return the arguments that should be passed to `cargo` to run the doctests of the crate,
+    /// with the same features and flags as its documentation.
+    ///
+    /// You can pass `additional_args` to cargo, as with [`Metadata::cargo_args`].
+    pub fn doctest_args(&self, additional_args: &[String]) -> Vec<String> {
+        self.args_for(vec!["test".into(), "--doc".into()], additional_args, &[])
+    }
+
+    fn args_for(
+        &self,
+        mut cargo_args: Vec<String>,
+        additional_args: &[String],
+        rustdoc_args: &[String],
+    ) -> Vec<String> {
         if let Some(features) = &self.features {
             cargo_args.push("--features".into());
             cargo_args.push(features.join(" "));
@@ -383,9 +408,11 @@ mod test_parsing {
             rustc-args = [ "--example-rustc-arg" ]
             rustdoc-args = [ "--example-rustdoc-arg" ]
             cargo-args = [ "-Zbuild-std" ]
+            doctests = true
         "#;
 
         let metadata = Metadata::from_str(manifest).unwrap();
+        assert!(metadata.doctests);
 
         assert!(metadata.features.is_some());
         assert!(metadata.all_features);
@@ -792,4 +819,27 @@ mod test_calculations {
         ];
         assert_eq!(metadata.cargo_args(&[], &[]), expected_args);
     }
+
+    #[test]
+    fn test_doctest_args() {
+        let metadata = Metadata {
+            features: Some(vec!["feature1".into()]),
+            no_default_features: true,
+            rustdoc_args: vec!["--cfg".into(), "docsrs".into()],
+            ..Metadata::default()
+        };
+        let expected_args = vec![
+            String::from("test"),
+            "--doc".into(),
+            "--features".into(),
+            "feature1".into(),
+            "--no-default-features".into(),
+            "-Z".into(),
+            "unstable-options".into(),
+            "--config".into(),
+            r#"build.rustdocflags=["--cfg", "docsrs"]"#.into(),
+            "-j1".into(),
+        ];
+        assert_eq!(metadata.doctest_args(&["-j1".into()]), expected_args);
+    }
 }
@@ -4,11 +4,22 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Context as _, Error, Result};
-use docs_rs::db::{self, add_path_into_database, Pool, PoolClient};
+use chrono::{DateTime, NaiveDate, Utc};
+use docs_rs::db::{
+    self, add_path_into_database, docker_images, sandbox_overrides, Pool, PoolClient,
+};
 use docs_rs::repositories::RepositoryStatsUpdater;
-use docs_rs::utils::{remove_crate_priority, set_crate_priority};
+use docs_rs::storage::{
+    gc_storage, migrate_storage, verify_storage, GcStorageOptions, MigrateStorageOptions,
+    StorageKind, VerifyStorageFilter,
+};
+use docs_rs::utils::{
+    cancel_campaign, create_campaign, list_campaigns, remove_crate_priority, set_crate_priority,
+    CampaignSelection,
+};
 use docs_rs::{
-    BuildQueue, Config, Context, Index, Metrics, PackageKind, RustwideBuilder, Server, Storage,
+    BuildQueue, Config, Context, DryRun, Index, Metrics, PackageKind, QueueFilter, RustwideBuilder,
+    Server, Storage,
 };
 use once_cell::sync::OnceCell;
 use sentry_log::SentryLogger;
@@ -104,6 +115,17 @@ enum CommandLine {
             possible_values(Toggle::VARIANTS)
         )]
         registry_watcher: Toggle,
+
+        /// Number of crates to build at the same time
+        #[structopt(long = "build-workers", default_value = "1")]
+        build_workers: usize,
+    },
+
+    /// Starts a daemon that only builds the crates in the queue
+    StartBuildServer {
+        /// Number of crates to build at the same time
+        #[structopt(long = "workers", default_value = "1")]
+        workers: usize,
     },
 
     /// Database operations
@@ -129,8 +151,18 @@ impl CommandLine {
                 // Blocks indefinitely
                 let _ = Server::start(Some(&socket_addr), &ctx)?;
             }
-            Self::Daemon { registry_watcher } => {
-                docs_rs::utils::start_daemon(&ctx, registry_watcher == Toggle::Enabled)?;
+            Self::Daemon {
+                registry_watcher,
+                build_workers,
+            } => {
+                docs_rs::utils::start_daemon(
+                    &ctx,
+                    registry_watcher == Toggle::Enabled,
+                    build_workers,
+                )?;
+            }
+            Self::StartBuildServer { workers } => {
+                docs_rs::utils::start_build_server(&ctx, workers)?;
             }
             Self::Database { subcommand } => subcommand.handle_args(ctx)?,
             Self::Queue { subcommand } => subcommand.handle_args(ctx)?,
@@ -158,6 +190,73 @@ enum QueueSubcommand {
             default_value = "5"
         )]
         build_priority: i32,
+        /// Don't build the crate before this time, e.g. 2022-01-01T12:00:00Z
+        #[structopt(long = "not-before")]
+        not_before: Option<DateTime<Utc>>,
+    },
+
+    /// List the queued crates in the order they're built, followed by the failed ones
+    List {
+        /// Only list the crates matching this pattern, see
+        /// https://www.postgresql.org/docs/current/functions-matching.html for its syntax
+        #[structopt(long = "name-pattern")]
+        name_pattern: Option<String>,
+        /// Only list the crates which failed too often to be built again
+        #[structopt(long = "failed", conflicts_with = "pending")]
+        failed: bool,
+        /// Only list the crates which are still built
+        #[structopt(long = "pending")]
+        pending: bool,
+        /// Only list the crates with at least this priority
+        #[structopt(long = "min-priority", allow_hyphen_values = true)]
+        min_priority: Option<i32>,
+        /// Only list the crates with at most this priority
+        #[structopt(long = "max-priority", allow_hyphen_values = true)]
+        max_priority: Option<i32>,
+        /// Print a JSON object per crate
+        #[structopt(long = "json")]
+        json: bool,
+    },
+
+    /// Remove a crate from the queue, all of its versions if no version is given
+    Remove {
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+        #[structopt(name = "CRATE_VERSION")]
+        crate_version: Option<String>,
+    },
+
+    /// Build the crates which failed too often to be built again
+    RetryFailed {
+        /// Only retry the crates matching this pattern, see
+        /// https://www.postgresql.org/docs/current/functions-matching.html for its syntax
+        #[structopt(long = "name-pattern")]
+        name_pattern: Option<String>,
+    },
+
+    /// Change the priority of a queued crate
+    SetPriority {
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+        #[structopt(name = "CRATE_VERSION")]
+        crate_version: String,
+        /// The new priority (new crate builds get priority 0)
+        #[structopt(name = "PRIORITY", allow_hyphen_values = true)]
+        priority: i32,
+    },
+
+    /// Show where a crate is in the queue and when it's built
+    Show {
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+        #[structopt(name = "CRATE_VERSION")]
+        crate_version: String,
+    },
+
+    /// Inspect and release the crates which aren't built because their build panicked
+    Quarantine {
+        #[structopt(subcommand)]
+        subcommand: QuarantineSubcommand,
     },
 
     /// Interactions with build queue priorities
@@ -165,6 +264,12 @@ enum QueueSubcommand {
         #[structopt(subcommand)]
         subcommand: PrioritySubcommand,
     },
+
+    /// Rebuild old releases at a low priority
+    Campaign {
+        #[structopt(subcommand)]
+        subcommand: CampaignSubcommand,
+    },
 }
 
 impl QueueSubcommand {
@@ -174,14 +279,284 @@ impl QueueSubcommand {
                 crate_name,
                 crate_version,
                 build_priority,
-            } => ctx.build_queue()?.add_crate(
+                not_before,
+            } => ctx.build_queue()?.add_crate_not_before(
                 &crate_name,
                 &crate_version,
                 build_priority,
                 ctx.config()?.registry_url.as_deref(),
+                not_before,
             )?,
 
+            Self::List {
+                name_pattern,
+                failed,
+                pending,
+                min_priority,
+                max_priority,
+                json,
+            } => {
+                let build_queue = ctx.build_queue()?;
+                let filter = QueueFilter {
+                    name_pattern,
+                    failed: if failed {
+                        Some(true)
+                    } else if pending {
+                        Some(false)
+                    } else {
+                        None
+                    },
+                    min_priority,
+                    max_priority,
+                };
+                for krate in build_queue.list(&filter)? {
+                    if json {
+                        println!("{}", serde_json::to_string(&krate)?);
+                        continue;
+                    }
+                    let mut line = format!(
+                        "{} {} (priority {})",
+                        krate.name, krate.version, krate.priority
+                    );
+                    if krate.attempt >= build_queue.max_attempts() {
+                        write!(line, ", failed")?;
+                    } else if krate.attempt > 0 {
+                        write!(line, ", failed {} time(s)", krate.attempt)?;
+                    }
+                    if let Some(next_attempt_at) = krate.next_attempt_at {
+                        write!(line, ", not before {}", next_attempt_at)?;
+                    }
+                    println!("{}", line);
+                }
+            }
+
+            Self::Remove {
+                crate_name,
+                crate_version,
+            } => {
+                let removed = ctx
+                    .build_queue()?
+                    .remove_crate(&crate_name, crate_version.as_deref())?;
+                if removed == 0 {
+                    return Err(anyhow!("{} is not queued", crate_name));
+                }
+                println!(
+                    "Removed {} version(s) of {} from the queue",
+                    removed, crate_name
+                );
+            }
+
+            Self::RetryFailed { name_pattern } => {
+                let retried = ctx.build_queue()?.retry_failed(name_pattern.as_deref())?;
+                println!("Retrying {} failed crate(s)", retried);
+            }
+
+            Self::SetPriority {
+                crate_name,
+                crate_version,
+                priority,
+            } => {
+                if !ctx
+                    .build_queue()?
+                    .set_priority(&crate_name, &crate_version, priority)?
+                {
+                    return Err(anyhow!("{} {} is not queued", crate_name, crate_version));
+                }
+            }
+
+            Self::Show {
+                crate_name,
+                crate_version,
+            } => {
+                let position = ctx
+                    .build_queue()?
+                    .position(&crate_name, &crate_version)?
+                    .ok_or_else(|| anyhow!("{} {} is not queued", crate_name, crate_version))?;
+                let krate = &position.krate;
+                println!("{} {}", krate.name, krate.version);
+                println!("priority: {}", krate.priority);
+                println!("failed attempts: {}", krate.attempt);
+                if let Some(next_attempt_at) = krate.next_attempt_at {
+                    println!("not built before: {}", next_attempt_at);
+                }
+                match position.position {
+                    _ if position.building => println!("status: building"),
+                    _ if position.quarantined => {
+                        println!("status: quarantined because its build panicked")
+                    }
+                    Some(position) => println!("status: {} crate(s) are built before it", position),
+                    None => println!("status: failed too often to be built again"),
+                }
+                if let Some(estimated_start) = position.estimated_start {
+                    println!("estimated start: {}", estimated_start);
+                }
+            }
+
+            Self::Quarantine { subcommand } => subcommand.handle_args(ctx)?,
+
             Self::DefaultPriority { subcommand } => subcommand.handle_args(ctx)?,
+
+            Self::Campaign { subcommand } => subcommand.handle_args(ctx)?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+enum QuarantineSubcommand {
+    /// List the quarantined crates, the most recently quarantined first
+    List {
+        /// Also print the backtraces of the panics
+        #[structopt(long = "backtrace")]
+        backtrace: bool,
+        /// Print a JSON object per crate, including the backtrace
+        #[structopt(long = "json", conflicts_with = "backtrace")]
+        json: bool,
+    },
+
+    /// Build the quarantined crates again
+    Release {
+        /// Only release the crates matching this pattern, see
+        /// https://www.postgresql.org/docs/current/functions-matching.html for its syntax
+        #[structopt(long = "name-pattern")]
+        name_pattern: Option<String>,
+    },
+}
+
+impl QuarantineSubcommand {
+    fn handle_args(self, ctx: BinContext) -> Result<()> {
+        match self {
+            Self::List { backtrace, json } => {
+                for quarantined in ctx.build_queue()?.quarantined_crates()? {
+                    if json {
+                        println!("{}", serde_json::to_string(&quarantined)?);
+                        continue;
+                    }
+                    println!(
+                        "{} {} (quarantined at {}): {}",
+                        quarantined.krate.name,
+                        quarantined.krate.version,
+                        quarantined.quarantined_at,
+                        quarantined.reason
+                    );
+                    if backtrace {
+                        if let Some(backtrace) = quarantined.backtrace {
+                            println!("{}", backtrace);
+                        }
+                    }
+                }
+            }
+
+            Self::Release { name_pattern } => {
+                let released = ctx
+                    .build_queue()?
+                    .release_quarantined(name_pattern.as_deref())?;
+                println!("Released {} quarantined crate(s)", released);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+enum CampaignSubcommand {
+    /// Create a campaign rebuilding the releases matching all of the given conditions.
+    ///
+    /// Only the latest version of every library is rebuilt, unless `--all-versions` is passed.
+    Create {
+        /// Name of the campaign
+        #[structopt(name = "NAME")]
+        name: String,
+        /// Rebuild releases documented with a rustc older than this date (YYYY-MM-DD)
+        #[structopt(long = "built-before")]
+        built_before: Option<NaiveDate>,
+        /// Rebuild releases whose repository has at least this many stars
+        #[structopt(long = "min-stars")]
+        min_stars: Option<i32>,
+        /// Rebuild releases with at least this many downloads
+        #[structopt(long = "min-downloads")]
+        min_downloads: Option<i32>,
+        /// Rebuild the crates matching this pattern, see
+        /// https://www.postgresql.org/docs/current/functions-matching.html for its syntax
+        #[structopt(long = "name-pattern")]
+        name_pattern: Option<String>,
+        /// Rebuild every version instead of only the latest one
+        #[structopt(long = "all-versions")]
+        all_versions: bool,
+        /// Build priority of the releases (new crate builds get priority 0)
+        #[structopt(short = "p", long = "priority", default_value = "20")]
+        priority: i32,
+        /// How many releases of the campaign can be in the queue at once
+        #[structopt(long = "max-queued", default_value = "100")]
+        max_queued: i32,
+    },
+
+    /// List the campaigns and their progress
+    List,
+
+    /// Stop adding the releases of a campaign to the queue
+    Cancel {
+        /// Id of the campaign
+        #[structopt(name = "ID")]
+        id: i32,
+    },
+}
+
+impl CampaignSubcommand {
+    fn handle_args(self, ctx: BinContext) -> Result<()> {
+        match self {
+            Self::Create {
+                name,
+                built_before,
+                min_stars,
+                min_downloads,
+                name_pattern,
+                all_versions,
+                priority,
+                max_queued,
+            } => {
+                let selection = CampaignSelection {
+                    built_before,
+                    min_stars,
+                    min_downloads,
+                    name_pattern,
+                    all_versions,
+                };
+                let (id, selected) =
+                    create_campaign(&mut *ctx.conn()?, &name, &selection, priority, max_queued)?
+                        .ok_or_else(|| anyhow!("a campaign named `{}` already exists", name))?;
+                println!("Created campaign {} rebuilding {} releases", id, selected);
+            }
+
+            Self::List => {
+                for campaign in list_campaigns(&mut *ctx.conn()?, &*ctx.build_queue()?)? {
+                    let status = if campaign.cancelled {
+                        "cancelled".to_string()
+                    } else if let Some(finished_at) = campaign.finished_at {
+                        format!("finished at {}", finished_at)
+                    } else {
+                        "active".to_string()
+                    };
+                    println!(
+                        "{}: {} ({}), {} of {} releases done, {} queued, {} pending",
+                        campaign.id,
+                        campaign.name,
+                        status,
+                        campaign.done,
+                        campaign.total,
+                        campaign.queued,
+                        campaign.pending,
+                    );
+                }
+            }
+
+            Self::Cancel { id } => {
+                if cancel_campaign(&mut *ctx.conn()?, id)? {
+                    println!("Cancelled campaign {}", id);
+                } else {
+                    println!("Campaign {} did not exist or was not active", id);
+                }
+            }
         }
         Ok(())
     }
@@ -268,6 +643,13 @@ enum BuildSubcommand {
         /// Build a crate at a specific path
         #[structopt(short = "l", long = "local", conflicts_with_all(&["CRATE_NAME", "CRATE_VERSION"]))]
         local: Option<PathBuf>,
+
+        /// Print the limits and the cargo invocations of every target without building anything.
+        ///
+        /// This needs the toolchain and, unless the crate is local, the registry index to be
+        /// installed already. Only the manifest of the crate is downloaded.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
     },
 
     /// update the currently installed rustup toolchain
@@ -308,9 +690,32 @@ impl BuildSubcommand {
                 crate_name,
                 crate_version,
                 local,
+                dry_run,
             } => {
-                let mut builder = rustwide_builder()?;
+                // dry runs don't need the rustwide workspace, so the builder isn't initialized
+                if dry_run {
+                    let dry_run = if let Some(path) = local {
+                        DryRun::for_local(&ctx, &path, skip_if_exists)?
+                    } else {
+                        DryRun::new(
+                            &ctx,
+                            &crate_name
+                                .with_context(|| anyhow!("must specify name if not local"))?,
+                            &crate_version
+                                .with_context(|| anyhow!("must specify version if not local"))?,
+                            ctx.config()?
+                                .registry_url
+                                .as_deref()
+                                .map(PackageKind::Registry)
+                                .unwrap_or(PackageKind::CratesIo),
+                            skip_if_exists,
+                        )?
+                    };
+                    print!("{}", dry_run);
+                    return Ok(());
+                }
 
+                let mut builder = rustwide_builder()?;
                 if let Some(path) = local {
                     builder
                         .build_local_package(&path)
@@ -405,6 +810,90 @@ enum DatabaseSubcommand {
         command: BlacklistSubcommand,
     },
 
+    /// Override the sandbox limits of crates
+    Limits {
+        #[structopt(subcommand)]
+        command: LimitsSubcommand,
+    },
+
+    /// Manage the docker images crates can be built in instead of the default one
+    DockerImages {
+        #[structopt(subcommand)]
+        command: DockerImagesSubcommand,
+    },
+
+    /// Copies all files from one storage backend to another one, continuing where a previous
+    /// interrupted run stopped
+    MigrateStorage {
+        /// The backend to copy the files from (database, s3 or filesystem)
+        #[structopt(long)]
+        from: StorageKind,
+
+        /// The backend to copy the files to (database, s3 or filesystem)
+        #[structopt(long)]
+        to: StorageKind,
+
+        /// How many files to copy at once
+        #[structopt(long, default_value = "100")]
+        batch_size: usize,
+
+        /// The maximum number of bytes to copy at once, bigger files are copied on their own
+        #[structopt(long, default_value = "268435456")]
+        batch_max_bytes: u64,
+
+        /// Delete the files from the source once their copy is verified
+        #[structopt(long)]
+        delete_source: bool,
+
+        /// Start from the beginning, ignoring the progress of previous runs
+        #[structopt(long)]
+        restart: bool,
+    },
+
+    /// Checks that the archives of the releases exist and can be read, and prints every broken
+    /// release as a line of JSON
+    VerifyStorage {
+        /// Only check the releases of this crate
+        #[structopt(long = "crate", name = "CRATE")]
+        crate_name: Option<String>,
+
+        /// Only check releases published on or after this date (YYYY-MM-DD)
+        #[structopt(long)]
+        since: Option<NaiveDate>,
+
+        /// Only check releases published before this date (YYYY-MM-DD)
+        #[structopt(long)]
+        until: Option<NaiveDate>,
+
+        /// How many files to decompress from every archive
+        #[structopt(long, default_value = "3")]
+        samples: usize,
+
+        /// Add the broken releases to the build queue
+        #[structopt(long)]
+        rebuild: bool,
+
+        /// Priority of the queued rebuilds
+        #[structopt(long, default_value = "5", requires = "rebuild")]
+        rebuild_priority: i32,
+    },
+
+    /// Finds the objects in the storage that don't belong to any release or build, and prints
+    /// every one of them as a line of JSON
+    GcStorage {
+        /// Only consider objects older than this many hours
+        #[structopt(long, default_value = "168")]
+        grace_period_hours: i64,
+
+        /// Delete the orphaned objects instead of only printing them
+        #[structopt(long)]
+        delete: bool,
+
+        /// How many objects to list at once
+        #[structopt(long, default_value = "1000")]
+        batch_size: usize,
+    },
+
     /// Compares the database with the index and resolves inconsistencies
     #[cfg(feature = "consistency_check")]
     Synchronize {
@@ -461,6 +950,107 @@ impl DatabaseSubcommand {
             .context("failed to delete the crate")?,
             Self::Blacklist { command } => command.handle_args(ctx)?,
 
+            Self::Limits { command } => command.handle_args(ctx)?,
+
+            Self::DockerImages { command } => command.handle_args(ctx)?,
+
+            Self::MigrateStorage {
+                from,
+                to,
+                batch_size,
+                batch_max_bytes,
+                delete_source,
+                restart,
+            } => {
+                let progress = migrate_storage(
+                    ctx.pool()?,
+                    ctx.metrics()?,
+                    ctx.config()?,
+                    from,
+                    to,
+                    &MigrateStorageOptions {
+                        batch_size,
+                        batch_max_bytes,
+                        delete_source,
+                        restart,
+                    },
+                )
+                .context("failed to migrate the storage")?;
+                println!(
+                    "copied {} files ({} bytes) from {} to {}",
+                    progress.files, progress.bytes, from, to
+                );
+            }
+
+            Self::VerifyStorage {
+                crate_name,
+                since,
+                until,
+                samples,
+                rebuild,
+                rebuild_priority,
+            } => {
+                let start_of_day =
+                    |date: NaiveDate| DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc);
+                let filter = VerifyStorageFilter {
+                    crate_name,
+                    since: since.map(start_of_day),
+                    until: until.map(start_of_day),
+                };
+
+                let build_queue = ctx.build_queue()?;
+                let config = ctx.config()?;
+                let mut broken = 0;
+                let checked = verify_storage(
+                    &mut *ctx.conn()?,
+                    &*ctx.storage()?,
+                    &filter,
+                    samples,
+                    |report| {
+                        println!("{}", serde_json::to_string(&report)?);
+                        broken += 1;
+                        if rebuild {
+                            build_queue.add_crate(
+                                &report.name,
+                                &report.version,
+                                rebuild_priority,
+                                config.registry_url.as_deref(),
+                            )?;
+                        }
+                        Ok(())
+                    },
+                )?;
+                eprintln!("checked {} releases, {} are broken", checked, broken);
+            }
+
+            Self::GcStorage {
+                grace_period_hours,
+                delete,
+                batch_size,
+            } => {
+                let summary = gc_storage(
+                    &mut *ctx.conn()?,
+                    &*ctx.storage()?,
+                    &*ctx.metrics()?,
+                    &GcStorageOptions {
+                        grace_period: chrono::Duration::hours(grace_period_hours),
+                        delete,
+                        batch_size,
+                    },
+                    |orphan| {
+                        println!("{}", serde_json::to_string(orphan)?);
+                        Ok(())
+                    },
+                )?;
+                eprintln!(
+                    "checked {} objects, {} {} orphaned objects ({} bytes)",
+                    summary.checked,
+                    if delete { "deleted" } else { "found" },
+                    summary.orphans,
+                    summary.bytes
+                );
+            }
+
             #[cfg(feature = "consistency_check")]
             Self::Synchronize { dry_run } => {
                 docs_rs::utils::consistency::run_check(&mut *ctx.conn()?, &*ctx.index()?, dry_run)?;
@@ -511,6 +1101,204 @@ impl BlacklistSubcommand {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+enum LimitsSubcommand {
+    /// Show the overridden limits of a crate
+    Get {
+        /// Crate name
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+    },
+
+    /// List the crates with overridden limits
+    List,
+
+    /// Override limits of a crate, the limits which aren't passed stay as they are
+    Set {
+        /// Crate name
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+
+        /// Available memory in bytes
+        #[structopt(long)]
+        memory: Option<usize>,
+
+        /// Maximum number of targets to build
+        #[structopt(long)]
+        targets: Option<usize>,
+
+        /// Timeout of a build in seconds
+        #[structopt(long)]
+        timeout: Option<u64>,
+
+        /// Whether the build can access the network (true or false)
+        #[structopt(long)]
+        networking: Option<bool>,
+
+        /// Maximum size of a build log in bytes
+        #[structopt(long)]
+        max_log_size: Option<usize>,
+    },
+
+    /// Remove the overridden limits of a crate
+    Remove {
+        /// Crate name
+        #[structopt(name = "CRATE_NAME")]
+        crate_name: String,
+    },
+}
+
+impl LimitsSubcommand {
+    fn handle_args(self, ctx: BinContext) -> Result<()> {
+        let conn = &mut *ctx.conn()?;
+        match self {
+            Self::Get { crate_name } => {
+                let overrides = sandbox_overrides::get(conn, &crate_name)
+                    .context("failed to get the overridden limits")?;
+                match overrides {
+                    Some(overrides) => println!("{}", format_overrides(&overrides)),
+                    None => println!("{} has no overridden limits", crate_name),
+                }
+            }
+
+            Self::List => {
+                for (name, overrides) in
+                    sandbox_overrides::list(conn).context("failed to list the overridden limits")?
+                {
+                    println!("{}: {}", name, format_overrides(&overrides));
+                }
+            }
+
+            Self::Set {
+                crate_name,
+                memory,
+                targets,
+                timeout,
+                networking,
+                max_log_size,
+            } => {
+                let overrides = sandbox_overrides::Overrides {
+                    memory,
+                    targets,
+                    timeout: timeout.map(std::time::Duration::from_secs),
+                    networking,
+                    max_log_size,
+                };
+                if overrides == sandbox_overrides::Overrides::default() {
+                    return Err(anyhow!("no limits to override were passed"));
+                }
+                sandbox_overrides::set(conn, &crate_name, &overrides)
+                    .context("failed to override the limits")?;
+            }
+
+            Self::Remove { crate_name } => sandbox_overrides::remove(conn, &crate_name)
+                .context("failed to remove the overridden limits")?,
+        }
+        Ok(())
+    }
+}
+
+/// Formats the overridden limits as `name=value` pairs, skipping the default ones.
+fn format_overrides(overrides: &sandbox_overrides::Overrides) -> String {
+    let mut limits = Vec::new();
+    if let Some(memory) = overrides.memory {
+        limits.push(format!("memory={}", memory));
+    }
+    if let Some(targets) = overrides.targets {
+        limits.push(format!("targets={}", targets));
+    }
+    if let Some(timeout) = overrides.timeout {
+        limits.push(format!("timeout={}", timeout.as_secs()));
+    }
+    if let Some(networking) = overrides.networking {
+        limits.push(format!("networking={}", networking));
+    }
+    if let Some(max_log_size) = overrides.max_log_size {
+        limits.push(format!("max-log-size={}", max_log_size));
+    }
+    limits.join(" ")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
+enum DockerImagesSubcommand {
+    /// List the allowed images and the crates built in them
+    List,
+
+    /// Allow crates to be built in an image
+    Add {
+        /// Image name, like `ghcr.io/rust-lang/crates-build-env/linux`
+        #[structopt(name = "IMAGE")]
+        image: String,
+
+        /// What the image provides compared to the default one
+        #[structopt(long)]
+        description: Option<String>,
+    },
+
+    /// Remove an image from the allowed ones, the crates built in it use the default image again
+    Remove {
+        /// Image name
+        #[structopt(name = "IMAGE")]
+        image: String,
+    },
+
+    /// Build the crates matching a pattern in an allowed image
+    Assign {
+        /// Crate name, or a regular expression matching the whole names of crates
+        #[structopt(name = "CRATE_PATTERN")]
+        crate_pattern: String,
+
+        /// Image name
+        #[structopt(name = "IMAGE")]
+        image: String,
+    },
+
+    /// Build the crates matching a pattern in the default image again
+    Unassign {
+        /// The crate pattern passed to `assign`
+        #[structopt(name = "CRATE_PATTERN")]
+        crate_pattern: String,
+    },
+}
+
+impl DockerImagesSubcommand {
+    fn handle_args(self, ctx: BinContext) -> Result<()> {
+        let conn = &mut *ctx.conn()?;
+        match self {
+            Self::List => {
+                for image in docker_images::list(conn).context("failed to list the images")? {
+                    match image.description {
+                        Some(description) => println!("{} ({})", image.image, description),
+                        None => println!("{}", image.image),
+                    }
+                    for crate_pattern in image.crate_patterns {
+                        println!("    {}", crate_pattern);
+                    }
+                }
+            }
+
+            Self::Add { image, description } => {
+                docker_images::add(conn, &image, description.as_deref())
+                    .context("failed to allow the image")?
+            }
+
+            Self::Remove { image } => {
+                docker_images::remove(conn, &image).context("failed to remove the image")?
+            }
+
+            Self::Assign {
+                crate_pattern,
+                image,
+            } => docker_images::assign(conn, &crate_pattern, &image)
+                .context("failed to assign the image")?,
+
+            Self::Unassign { crate_pattern } => docker_images::unassign(conn, &crate_pattern)
+                .context("failed to unassign the image")?,
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
 enum DeleteSubcommand {
     /// Delete a whole crate
@@ -2,25 +2,60 @@ use crate::db::{delete_crate, Pool};
 use crate::docbuilder::PackageKind;
 use crate::error::Result;
 use crate::storage::Storage;
-use crate::utils::{get_crate_priority, report_error};
+use crate::utils::{catch_panic, create_toolchain_campaign, get_crate_priority, report_error};
 use crate::{Config, Index, Metrics, RustwideBuilder};
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 
 use crates_index_diff::Change;
-use log::{debug, info};
+use log::{debug, error, info};
+use postgres::Client;
 
 use std::fs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Debug, Clone, Eq, PartialEq, serde::Serialize)]
-pub(crate) struct QueuedCrate {
+pub struct QueuedCrate {
     #[serde(skip)]
     id: i32,
-    pub(crate) name: String,
-    pub(crate) version: String,
-    pub(crate) priority: i32,
-    pub(crate) registry: Option<String>,
+    pub name: String,
+    pub version: String,
+    pub priority: i32,
+    pub registry: Option<String>,
+    /// how often building the crate failed
+    pub attempt: i32,
+    /// the crate isn't built before this time
+    pub next_attempt_at: Option<DateTime<Utc>>,
+}
+
+impl QueuedCrate {
+    fn from_row(row: postgres::Row) -> Self {
+        QueuedCrate {
+            id: row.get("id"),
+            name: row.get("name"),
+            version: row.get("version"),
+            priority: row.get("priority"),
+            registry: row.get("registry"),
+            attempt: row.get("attempt"),
+            next_attempt_at: row.get("next_attempt_at"),
+        }
+    }
+}
+
+/// A queued crate which isn't built anymore because its build panicked.
+#[derive(Debug, Clone, Eq, PartialEq, serde::Serialize)]
+pub struct QuarantinedCrate {
+    #[serde(flatten)]
+    pub krate: QueuedCrate,
+    pub quarantined_at: DateTime<Utc>,
+    /// the message of the panic
+    pub reason: String,
+    pub backtrace: Option<String>,
 }
 
 #[derive(Debug)]
@@ -30,6 +65,10 @@ pub struct BuildQueue {
     pub(crate) db: Pool,
     metrics: Arc<Metrics>,
     max_attempts: i32,
+    /// identifies the builders of this process in the leases of the queue
+    instance: String,
+    /// how many builds of this process panicked in a row
+    consecutive_panics: AtomicU32,
 }
 
 impl BuildQueue {
@@ -39,12 +78,24 @@ impl BuildQueue {
         config: Arc<Config>,
         storage: Arc<Storage>,
     ) -> Self {
+        let mut random = [0; 4];
+        getrandom::getrandom(&mut random).expect("failed to generate an instance id");
+
         BuildQueue {
             max_attempts: config.build_attempts.into(),
+            instance: format!(
+                "{}-{}",
+                std::process::id(),
+                random
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            ),
             config,
             db,
             metrics,
             storage,
+            consecutive_panics: AtomicU32::new(0),
         }
     }
 
@@ -54,23 +105,46 @@ impl BuildQueue {
         version: &str,
         priority: i32,
         registry: Option<&str>,
+    ) -> Result<()> {
+        self.add_crate_not_before(name, version, priority, registry, None)
+    }
+
+    /// Adds a crate to the queue which isn't built before `not_before`, or as soon as possible
+    /// if it's `None`.
+    ///
+    /// If the crate is queued already, its time of the next attempt is only changed when
+    /// `not_before` is given, and it stays quarantined until it's released with
+    /// [`BuildQueue::release_quarantined`].
+    pub fn add_crate_not_before(
+        &self,
+        name: &str,
+        version: &str,
+        priority: i32,
+        registry: Option<&str>,
+        not_before: Option<DateTime<Utc>>,
     ) -> Result<()> {
         self.db.get()?.execute(
-            "INSERT INTO queue (name, version, priority, registry) 
-             VALUES ($1, $2, $3, $4)
+            "INSERT INTO queue (name, version, priority, registry, next_attempt_at)
+             VALUES ($1, $2, $3, $4, $5)
              ON CONFLICT (name, version) DO UPDATE
                 SET priority = EXCLUDED.priority,
                     registry = EXCLUDED.registry,
+                    next_attempt_at = COALESCE(EXCLUDED.next_attempt_at, queue.next_attempt_at),
                     attempt = 0
             ;",
-            &[&name, &version, &priority, &registry],
+            &[&name, &version, &priority, &registry, &not_before],
         )?;
         Ok(())
     }
 
+    /// How often a crate is built before it's given up on.
+    pub fn max_attempts(&self) -> i32 {
+        self.max_attempts
+    }
+
     pub(crate) fn pending_count(&self) -> Result<usize> {
         let res = self.db.get()?.query(
-            "SELECT COUNT(*) FROM queue WHERE attempt < $1;",
+            "SELECT COUNT(*) FROM queue WHERE attempt < $1 AND quarantined_at IS NULL;",
             &[&self.max_attempts],
         )?;
         Ok(res[0].get::<_, i64>(0) as usize)
@@ -78,7 +152,9 @@ impl BuildQueue {
 
     pub(crate) fn prioritized_count(&self) -> Result<usize> {
         let res = self.db.get()?.query(
-            "SELECT COUNT(*) FROM queue WHERE attempt < $1 AND priority <= 0;",
+            "SELECT COUNT(*)
+             FROM queue
+             WHERE attempt < $1 AND priority <= 0 AND quarantined_at IS NULL;",
             &[&self.max_attempts],
         )?;
         Ok(res[0].get::<_, i64>(0) as usize)
@@ -86,66 +162,132 @@ impl BuildQueue {
 
     pub(crate) fn failed_count(&self) -> Result<usize> {
         let res = self.db.get()?.query(
-            "SELECT COUNT(*) FROM queue WHERE attempt >= $1;",
+            "SELECT COUNT(*) FROM queue WHERE attempt >= $1 AND quarantined_at IS NULL;",
             &[&self.max_attempts],
         )?;
         Ok(res[0].get::<_, i64>(0) as usize)
     }
 
+    pub(crate) fn quarantined_count(&self) -> Result<usize> {
+        let res = self.db.get()?.query(
+            "SELECT COUNT(*) FROM queue WHERE quarantined_at IS NOT NULL;",
+            &[],
+        )?;
+        Ok(res[0].get::<_, i64>(0) as usize)
+    }
+
     pub(crate) fn queued_crates(&self) -> Result<Vec<QueuedCrate>> {
         let query = self.db.get()?.query(
-            "SELECT id, name, version, priority, registry
+            "SELECT id, name, version, priority, registry, attempt, next_attempt_at
              FROM queue
-             WHERE attempt < $1
+             WHERE attempt < $1 AND quarantined_at IS NULL
              ORDER BY priority ASC, attempt ASC, id ASC",
             &[&self.max_attempts],
         )?;
 
-        Ok(query
-            .into_iter()
-            .map(|row| QueuedCrate {
-                id: row.get("id"),
-                name: row.get("name"),
-                version: row.get("version"),
-                priority: row.get("priority"),
-                registry: row.get("registry"),
-            })
-            .collect())
+        Ok(query.into_iter().map(QueuedCrate::from_row).collect())
     }
 
+    /// Claims the next crate of the queue for the current thread and builds it with `f`.
+    ///
+    /// Crates claimed by other builders are skipped. The claim is a lease which is renewed while
+    /// `f` is running, a crate whose lease wasn't renewed for `build_lease_timeout` seconds
+    /// belongs to a builder that died, and is claimed again.
+    ///
+    /// If `f` panics the crate is quarantined, so it isn't built again until an admin released
+    /// it. The queue is locked once `build_max_consecutive_panics` builds panicked in a row.
     pub(crate) fn process_next_crate(
         &self,
         f: impl FnOnce(&QueuedCrate) -> Result<()>,
     ) -> Result<()> {
         let mut conn = self.db.get()?;
 
-        let queued = self.queued_crates()?;
-        let to_process = match queued.get(0) {
+        let worker = self.worker_id();
+        let to_process = match self.claim_next_crate(&mut conn, &worker)? {
             Some(krate) => krate,
             None => return Ok(()),
         };
 
-        let res = f(to_process).with_context(|| {
-            format!(
-                "Failed to build package {}-{} from queue",
-                to_process.name, to_process.version
-            )
-        });
+        let res = {
+            let _lease = LeaseRenewal::start(
+                self.db.clone(),
+                to_process.id,
+                worker.clone(),
+                Duration::from_secs(self.config.build_lease_timeout) / 4,
+            )?;
+            catch_panic(|| f(&to_process))
+        };
         self.metrics.total_builds.inc();
+
+        let res = match res {
+            Ok(res) => {
+                self.consecutive_panics.store(0, Ordering::SeqCst);
+                res.with_context(|| {
+                    format!(
+                        "Failed to build package {}-{} from queue",
+                        to_process.name, to_process.version
+                    )
+                })
+            }
+            Err(panic) => {
+                conn.execute(
+                    "UPDATE queue
+                     SET quarantined_at = NOW(),
+                         quarantine_reason = $3,
+                         quarantine_backtrace = $4,
+                         claimed_by = NULL,
+                         claimed_at = NULL
+                     WHERE id = $1 AND claimed_by = $2;",
+                    &[&to_process.id, &worker, &panic.message, &panic.backtrace],
+                )?;
+                self.metrics.quarantined_builds.inc();
+                error!(
+                    "building {}-{} panicked, quarantined it: {}",
+                    to_process.name, to_process.version, panic.message
+                );
+
+                let panics = self.consecutive_panics.fetch_add(1, Ordering::SeqCst) + 1;
+                if panics >= self.config.build_max_consecutive_panics {
+                    error!("{} builds panicked in a row, locking the queue", panics);
+                    self.lock()?;
+                }
+                return Ok(());
+            }
+        };
         match res {
             Ok(()) => {
-                conn.execute("DELETE FROM queue WHERE id = $1;", &[&to_process.id])?;
+                conn.execute(
+                    "DELETE FROM queue WHERE id = $1 AND claimed_by = $2;",
+                    &[&to_process.id, &worker],
+                )?;
             }
             Err(e) => {
-                // Increase attempt count
-                let rows = conn.query(
-                    "UPDATE queue SET attempt = attempt + 1 WHERE id = $1 RETURNING attempt;",
-                    &[&to_process.id],
+                // Increase attempt count and release the crate for the next attempt, which is
+                // delayed exponentially so transient problems don't use up all attempts
+                let row = conn.query_opt(
+                    "UPDATE queue
+                     SET attempt = attempt + 1,
+                         claimed_by = NULL,
+                         claimed_at = NULL,
+                         next_attempt_at = NOW() + make_interval(
+                            secs => LEAST($3 * POWER(2, attempt), $4)
+                         )
+                     WHERE id = $1 AND claimed_by = $2
+                     RETURNING attempt;",
+                    &[
+                        &to_process.id,
+                        &worker,
+                        &(self.config.build_retry_delay as f64),
+                        &(self.config.build_max_retry_delay as f64),
+                    ],
                 )?;
-                let attempt: i32 = rows[0].get(0);
 
-                if attempt >= self.max_attempts {
-                    self.metrics.failed_builds.inc();
+                // if the lease was taken over by another builder, that one counts the attempt
+                if let Some(row) = row {
+                    let attempt: i32 = row.get(0);
+                    if attempt >= self.max_attempts {
+                        self.metrics.failed_builds.inc();
+                    }
                 }
 
                 report_error(&e);
@@ -154,6 +296,89 @@ impl BuildQueue {
 
         Ok(())
     }
+
+    fn claim_next_crate(&self, conn: &mut Client, worker: &str) -> Result<Option<QueuedCrate>> {
+        let row = conn.query_opt(
+            "UPDATE queue
+             SET claimed_by = $2, claimed_at = NOW()
+             WHERE id = (
+                SELECT id
+                FROM queue
+                WHERE attempt < $1
+                    AND quarantined_at IS NULL
+                    AND (claimed_at IS NULL OR claimed_at < NOW() - make_interval(secs => $3))
+                    AND (next_attempt_at IS NULL OR next_attempt_at <= NOW())
+                ORDER BY priority ASC, attempt ASC, id ASC
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+             )
+             RETURNING id, name, version, priority, registry, attempt, next_attempt_at",
+            &[
+                &self.max_attempts,
+                &worker,
+                &(self.config.build_lease_timeout as f64),
+            ],
+        )?;
+
+        Ok(row.map(QueuedCrate::from_row))
+    }
+
+    /// The random id of this process, telling it apart from others processing the same queue.
+    pub(crate) fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    /// The name leases of the current thread are stored with.
+    fn worker_id(&self) -> String {
+        format!(
+            "{}/{}",
+            self.instance,
+            thread::current().name().unwrap_or("unnamed")
+        )
+    }
+}
+
+/// Renews the lease on a crate of the queue from a background thread until it's dropped.
+struct LeaseRenewal {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LeaseRenewal {
+    fn start(db: Pool, id: i32, worker: String, interval: Duration) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name(format!("lease renewal of {}", worker))
+            .spawn(move || {
+                let renew = || -> Result<()> {
+                    db.get()?.execute(
+                        "UPDATE queue SET claimed_at = NOW() WHERE id = $1 AND claimed_by = $2",
+                        &[&id, &worker],
+                    )?;
+                    Ok(())
+                };
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(err) = renew() {
+                        report_error(&err.context("failed to renew the lease of a queued crate"));
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for LeaseRenewal {
+    fn drop(&mut self) {
+        // disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
 
 /// Locking functions.
@@ -188,6 +413,206 @@ impl BuildQueue {
     }
 }
 
+/// Which crates of the queue are listed, every set condition has to match.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueueFilter {
+    /// only the crates whose name matches this pattern, see
+    /// https://www.postgresql.org/docs/current/functions-matching.html for its syntax
+    pub name_pattern: Option<String>,
+    /// only the crates which failed too often to be built again (`true`), or only the ones which
+    /// are still built (`false`)
+    pub failed: Option<bool>,
+    pub min_priority: Option<i32>,
+    pub max_priority: Option<i32>,
+}
+
+/// Where a crate is in the queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueuePosition {
+    pub krate: QueuedCrate,
+    /// whether a builder is building the crate right now
+    pub building: bool,
+    /// whether the crate isn't built because its build panicked
+    pub quarantined: bool,
+    /// how many crates which aren't being built yet are built before this one, `None` if it
+    /// failed too often to be built again
+    pub position: Option<usize>,
+    /// when the crate is built, estimated from the builds of the last hour
+    pub estimated_start: Option<DateTime<Utc>>,
+}
+
+/// Management methods.
+impl BuildQueue {
+    /// Lists the queued crates matching `filter`, in the order they're built, followed by the
+    /// ones which failed too often. Quarantined crates are listed by
+    /// [`BuildQueue::quarantined_crates`] instead.
+    pub fn list(&self, filter: &QueueFilter) -> Result<Vec<QueuedCrate>> {
+        let query = self.db.get()?.query(
+            "SELECT id, name, version, priority, registry, attempt, next_attempt_at
+             FROM queue
+             WHERE quarantined_at IS NULL
+                AND ($2::TEXT IS NULL OR name ~ $2)
+                AND ($3::BOOL IS NULL OR (attempt >= $1) = $3)
+                AND ($4::INT IS NULL OR priority >= $4)
+                AND ($5::INT IS NULL OR priority <= $5)
+             ORDER BY attempt >= $1 ASC, priority ASC, attempt ASC, id ASC",
+            &[
+                &self.max_attempts,
+                &filter.name_pattern,
+                &filter.failed,
+                &filter.min_priority,
+                &filter.max_priority,
+            ],
+        )?;
+
+        Ok(query.into_iter().map(QueuedCrate::from_row).collect())
+    }
+
+    /// Removes a version of a crate, or all of its versions if `version` is `None`, from the
+    /// queue. Returns how many versions were removed.
+    pub fn remove_crate(&self, name: &str, version: Option<&str>) -> Result<u64> {
+        Ok(self.db.get()?.execute(
+            "DELETE FROM queue WHERE name = $1 AND ($2::TEXT IS NULL OR version = $2)",
+            &[&name, &version],
+        )?)
+    }
+
+    /// Resets the attempts of the crates which failed too often to be built again, so they're
+    /// built again right away. Only the crates matching `name_pattern` are reset if it's given.
+    ///
+    /// Returns how many crates were reset.
+    pub fn retry_failed(&self, name_pattern: Option<&str>) -> Result<u64> {
+        Ok(self.db.get()?.execute(
+            "UPDATE queue
+             SET attempt = 0, next_attempt_at = NULL
+             WHERE attempt >= $1 AND ($2::TEXT IS NULL OR name ~ $2)",
+            &[&self.max_attempts, &name_pattern],
+        )?)
+    }
+
+    /// Lists the crates which aren't built anymore because their build panicked, the most
+    /// recently quarantined first.
+    pub fn quarantined_crates(&self) -> Result<Vec<QuarantinedCrate>> {
+        let query = self.db.get()?.query(
+            "SELECT id, name, version, priority, registry, attempt, next_attempt_at,
+                quarantined_at, quarantine_reason, quarantine_backtrace
+             FROM queue
+             WHERE quarantined_at IS NOT NULL
+             ORDER BY quarantined_at DESC, id DESC",
+            &[],
+        )?;
+
+        Ok(query
+            .into_iter()
+            .map(|row| QuarantinedCrate {
+                quarantined_at: row.get("quarantined_at"),
+                reason: row.get("quarantine_reason"),
+                backtrace: row.get("quarantine_backtrace"),
+                krate: QueuedCrate::from_row(row),
+            })
+            .collect())
+    }
+
+    /// Releases the quarantined crates, so they're built again right away. Only the crates
+    /// matching `name_pattern` are released if it's given.
+    ///
+    /// Returns how many crates were released.
+    pub fn release_quarantined(&self, name_pattern: Option<&str>) -> Result<u64> {
+        Ok(self.db.get()?.execute(
+            "UPDATE queue
+             SET quarantined_at = NULL,
+                 quarantine_reason = NULL,
+                 quarantine_backtrace = NULL,
+                 next_attempt_at = NULL
+             WHERE quarantined_at IS NOT NULL AND ($1::TEXT IS NULL OR name ~ $1)",
+            &[&name_pattern],
+        )?)
+    }
+
+    /// Changes the priority of a queued crate. Returns whether the crate is in the queue.
+    pub fn set_priority(&self, name: &str, version: &str, priority: i32) -> Result<bool> {
+        Ok(self.db.get()?.execute(
+            "UPDATE queue SET priority = $3 WHERE name = $1 AND version = $2",
+            &[&name, &version, &priority],
+        )? > 0)
+    }
+
+    /// Returns where a crate is in the queue, if it's queued.
+    pub fn position(&self, name: &str, version: &str) -> Result<Option<QueuePosition>> {
+        let mut conn = self.db.get()?;
+        let row = match conn.query_opt(
+            "SELECT
+                id, name, version, priority, registry, attempt, next_attempt_at,
+                claimed_at IS NOT NULL
+                    AND claimed_at >= NOW() - make_interval(secs => $3) AS building,
+                quarantined_at IS NOT NULL AS quarantined
+             FROM queue
+             WHERE name = $1 AND version = $2",
+            &[&name, &version, &(self.config.build_lease_timeout as f64)],
+        )? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let building: bool = row.get("building");
+        let quarantined: bool = row.get("quarantined");
+        let krate = QueuedCrate::from_row(row);
+
+        if building || quarantined || krate.attempt >= self.max_attempts {
+            return Ok(Some(QueuePosition {
+                position: if building { Some(0) } else { None },
+                estimated_start: None,
+                building,
+                quarantined,
+                krate,
+            }));
+        }
+
+        let position = conn
+            .query_one(
+                "SELECT COUNT(*)
+                 FROM queue
+                 WHERE attempt < $1
+                    AND quarantined_at IS NULL
+                    AND (priority, attempt, id) < ($2, $3, $4)
+                    AND (claimed_at IS NULL OR claimed_at < NOW() - make_interval(secs => $5))",
+                &[
+                    &self.max_attempts,
+                    &krate.priority,
+                    &krate.attempt,
+                    &krate.id,
+                    &(self.config.build_lease_timeout as f64),
+                ],
+            )?
+            .get::<_, i64>(0) as usize;
+        let builds_last_hour = conn
+            .query_one(
+                "SELECT COUNT(*) FROM builds WHERE build_time > NOW() - INTERVAL '1 hour'",
+                &[],
+            )?
+            .get::<_, i64>(0);
+
+        // without recent builds there's nothing to estimate the duration of a build from
+        let estimated_start = if builds_last_hour > 0 {
+            let wait = chrono::Duration::seconds(position as i64 * 3600 / builds_last_hour);
+            let start = Utc::now() + wait;
+            Some(match krate.next_attempt_at {
+                Some(at) if at > start => at,
+                _ => start,
+            })
+        } else {
+            None
+        };
+
+        Ok(Some(QueuePosition {
+            krate,
+            building,
+            quarantined,
+            position: Some(position),
+            estimated_start,
+        }))
+    }
+}
+
 /// Index methods.
 impl BuildQueue {
     /// Updates registry index repository and adds new crates into build queue.
@@ -304,6 +729,15 @@ impl BuildQueue {
                         self.lock()?;
                         return Err(err);
                     }
+
+                    if self.config.rebuild_campaign_on_toolchain_change {
+                        if let Err(err) =
+                            create_toolchain_campaign(&mut *self.db.get()?, builder.rustc_version())
+                                .context("creating the rebuild campaign failed")
+                        {
+                            report_error(&err);
+                        }
+                    }
                 }
                 Ok(false) => {}
             }
@@ -380,6 +814,7 @@ mod tests {
         crate::test::wrapper(|env| {
             env.override_config(|config| {
                 config.build_attempts = MAX_ATTEMPTS;
+                config.build_retry_delay = 0;
             });
 
             let queue = env.build_queue();
@@ -452,6 +887,97 @@ mod tests {
         })
     }
 
+    /// Runs `process_next_crate` from another thread, like another builder would, and returns
+    /// the name of the crate it processed.
+    fn process_in_other_builder(queue: &Arc<BuildQueue>) -> Option<String> {
+        let queue = queue.clone();
+        thread::Builder::new()
+            .name("other builder".into())
+            .spawn(move || {
+                let mut processed = None;
+                queue
+                    .process_next_crate(|krate| {
+                        processed = Some(krate.name.clone());
+                        Ok(())
+                    })
+                    .unwrap();
+                processed
+            })
+            .unwrap()
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_claimed_crates_are_skipped() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 0, None)?;
+
+            queue.process_next_crate(|krate| {
+                assert_eq!("foo", krate.name);
+                assert_eq!(process_in_other_builder(&queue).as_deref(), Some("bar"));
+                assert_eq!(process_in_other_builder(&queue), None);
+                Ok(())
+            })?;
+            assert_eq!(queue.pending_count()?, 0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_stale_leases_are_claimed_again() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_lease_timeout = 60;
+            });
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 0, None)?;
+
+            let mut conn = env.db().conn();
+            conn.execute(
+                "UPDATE queue
+                 SET claimed_by = 'dead builder', claimed_at = NOW() - INTERVAL '1 hour'
+                 WHERE name = 'foo'",
+                &[],
+            )?;
+            conn.execute(
+                "UPDATE queue SET claimed_by = 'busy builder', claimed_at = NOW() WHERE name = 'bar'",
+                &[],
+            )?;
+
+            assert_eq!(process_in_other_builder(&queue).as_deref(), Some("foo"));
+            assert_eq!(process_in_other_builder(&queue), None);
+            assert_eq!(queue.pending_count()?, 1);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_lease_is_renewed_while_building() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_lease_timeout = 1;
+            });
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+
+            queue.process_next_crate(|_| {
+                thread::sleep(Duration::from_millis(1500));
+                // the lease would have expired by now without being renewed
+                assert_eq!(process_in_other_builder(&queue), None);
+                Ok(())
+            })?;
+            assert_eq!(queue.pending_count()?, 0);
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_pending_count() {
         crate::test::wrapper(|env| {
@@ -502,6 +1028,7 @@ mod tests {
         crate::test::wrapper(|env| {
             env.override_config(|config| {
                 config.build_attempts = MAX_ATTEMPTS;
+                config.build_retry_delay = 0;
             });
             let queue = env.build_queue();
 
@@ -529,6 +1056,373 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_failed_crates_are_retried_with_backoff() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_attempts = 5;
+                config.build_retry_delay = 60;
+                config.build_max_retry_delay = 100;
+            });
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 10, None)?;
+
+            let process = || -> Result<Option<String>> {
+                let mut processed = None;
+                queue.process_next_crate(|krate| {
+                    processed = Some(krate.name.clone());
+                    anyhow::bail!("simulate a failure");
+                })?;
+                Ok(processed)
+            };
+            let delay = || -> Result<i64> {
+                let next_attempt_at = queue
+                    .queued_crates()?
+                    .into_iter()
+                    .find(|krate| krate.name == "foo")
+                    .unwrap()
+                    .next_attempt_at
+                    .unwrap();
+                Ok((next_attempt_at - Utc::now()).num_seconds())
+            };
+            let retry_now = || -> Result<()> {
+                env.db().conn().execute(
+                    "UPDATE queue SET next_attempt_at = NOW() WHERE name = 'foo'",
+                    &[],
+                )?;
+                Ok(())
+            };
+
+            // foo is retried after bar, even though it has a higher priority
+            assert_eq!(process()?.as_deref(), Some("foo"));
+            assert!((55..=60).contains(&delay()?));
+            assert_eq!(process()?.as_deref(), Some("bar"));
+            assert_eq!(process()?, None);
+
+            // the delay doubles with every attempt, up to the maximum
+            retry_now()?;
+            assert_eq!(process()?.as_deref(), Some("foo"));
+            assert!((95..=100).contains(&delay()?));
+
+            // adding the crate again resets its attempts, but keeps the time of the next one
+            let next_attempt_at = queue.queued_crates()?.remove(0).next_attempt_at;
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            let foo = queue.queued_crates()?.remove(0);
+            assert_eq!((foo.attempt, foo.next_attempt_at), (0, next_attempt_at));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_add_crate_not_before() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            let later = Utc::now() + chrono::Duration::hours(1);
+            queue.add_crate_not_before("foo", "1.0.0", 0, None, Some(later))?;
+
+            let mut called = false;
+            queue.process_next_crate(|_| {
+                called = true;
+                Ok(())
+            })?;
+            assert!(!called, "the crate was built too early");
+
+            let queued = queue.queued_crates()?;
+            assert_eq!(queued.len(), 1);
+            assert_eq!(
+                queued[0].next_attempt_at.map(|at| at.timestamp()),
+                Some(later.timestamp())
+            );
+            assert_eq!(queue.pending_count()?, 1);
+
+            // adding it again without a time keeps the one it had
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            assert_eq!(
+                queue.queued_crates()?[0]
+                    .next_attempt_at
+                    .map(|at| at.timestamp()),
+                Some(later.timestamp())
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_add_quarantined_crate_again() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.process_next_crate(|_| panic!("the builder is broken"))?;
+            assert_eq!(queue.quarantined_count()?, 1);
+
+            queue.add_crate("foo", "1.0.0", -10, None)?;
+            assert_eq!(queue.quarantined_count()?, 1);
+            let quarantined = queue.quarantined_crates()?;
+            assert_eq!(quarantined[0].reason, "the builder is broken");
+            assert!(quarantined[0].backtrace.is_some());
+            queue.process_next_crate(|_| panic!("no crate should be built"))?;
+
+            assert_eq!(queue.release_quarantined(None)?, 1);
+            assert_eq!(queue.pending_count()?, 1);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_list() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_attempts = 1;
+                config.build_retry_delay = 0;
+            });
+            let queue = env.build_queue();
+            queue.add_crate("failed", "1.0.0", -20, None)?;
+            queue.process_next_crate(|_| anyhow::bail!("this failed"))?;
+            queue.add_crate("foo", "1.0.0", 10, None)?;
+            queue.add_crate("foo-bar", "0.1.0", 0, None)?;
+            queue.add_crate("baz", "2.0.0", -10, None)?;
+
+            let list = |filter: QueueFilter| -> Result<Vec<String>> {
+                Ok(queue
+                    .list(&filter)?
+                    .into_iter()
+                    .map(|krate| format!("{}-{}", krate.name, krate.version))
+                    .collect())
+            };
+
+            assert_eq!(
+                list(QueueFilter::default())?,
+                vec!["baz-2.0.0", "foo-bar-0.1.0", "foo-1.0.0", "failed-1.0.0"]
+            );
+            assert_eq!(
+                list(QueueFilter {
+                    name_pattern: Some("^foo".into()),
+                    ..Default::default()
+                })?,
+                vec!["foo-bar-0.1.0", "foo-1.0.0"]
+            );
+            assert_eq!(
+                list(QueueFilter {
+                    failed: Some(true),
+                    ..Default::default()
+                })?,
+                vec!["failed-1.0.0"]
+            );
+            assert_eq!(
+                list(QueueFilter {
+                    failed: Some(false),
+                    min_priority: Some(0),
+                    max_priority: Some(5),
+                    ..Default::default()
+                })?,
+                vec!["foo-bar-0.1.0"]
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_remove_crate() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("foo", "2.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 0, None)?;
+
+            assert_eq!(queue.remove_crate("foo", Some("1.0.0"))?, 1);
+            assert_eq!(queue.remove_crate("foo", Some("1.0.0"))?, 0);
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            assert_eq!(queue.remove_crate("foo", None)?, 2);
+
+            let queued = queue.queued_crates()?;
+            assert_eq!(queued.len(), 1);
+            assert_eq!(queued[0].name, "bar");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_retry_failed() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_attempts = 1;
+                config.build_retry_delay = 0;
+            });
+            let queue = env.build_queue();
+            for name in &["foo", "foo-bar", "baz"] {
+                queue.add_crate(name, "1.0.0", 0, None)?;
+                queue.process_next_crate(|_| anyhow::bail!("this failed"))?;
+            }
+            queue.add_crate("pending", "1.0.0", 0, None)?;
+            assert_eq!(queue.failed_count()?, 3);
+
+            assert_eq!(queue.retry_failed(Some("^foo"))?, 2);
+            assert_eq!(queue.failed_count()?, 1);
+            assert_eq!(queue.pending_count()?, 3);
+
+            assert_eq!(queue.retry_failed(None)?, 1);
+            assert_eq!(queue.failed_count()?, 0);
+            assert!(queue
+                .queued_crates()?
+                .iter()
+                .all(|krate| krate.attempt == 0 && krate.next_attempt_at.is_none()));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_panicking_crates_are_quarantined() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 0, None)?;
+
+            queue.process_next_crate(|krate| {
+                assert_eq!("foo", krate.name);
+                panic!("the builder is broken");
+            })?;
+            assert!(!queue.is_locked());
+            assert_eq!(queue.pending_count()?, 1);
+            assert_eq!(queue.failed_count()?, 0);
+            assert_eq!(queue.quarantined_count()?, 1);
+
+            let quarantined = queue.quarantined_crates()?;
+            assert_eq!(quarantined.len(), 1);
+            assert_eq!(quarantined[0].krate.name, "foo");
+            assert_eq!(quarantined[0].reason, "the builder is broken");
+            assert!(quarantined[0].backtrace.is_some());
+            let position = queue.position("foo", "1.0.0")?.unwrap();
+            assert!(position.quarantined);
+            assert_eq!(position.position, None);
+            assert!(queue
+                .list(&QueueFilter::default())?
+                .iter()
+                .all(|krate| krate.name == "bar"));
+
+            // the next crate is built, and the quarantined one is skipped
+            queue.process_next_crate(|krate| {
+                assert_eq!("bar", krate.name);
+                Ok(())
+            })?;
+            queue.process_next_crate(|_| panic!("no crate should be built"))?;
+
+            assert_eq!(queue.release_quarantined(Some("^bar"))?, 0);
+            assert_eq!(queue.release_quarantined(None)?, 1);
+            assert_eq!(queue.quarantined_count()?, 0);
+            let mut built = false;
+            queue.process_next_crate(|krate| {
+                assert_eq!("foo", krate.name);
+                built = true;
+                Ok(())
+            })?;
+            assert!(built);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_queue_is_locked_after_consecutive_panics() {
+        crate::test::wrapper(|env| {
+            // the lock file is stored in the prefix, which is shared with the other tests
+            let prefix = tempfile::tempdir()?;
+            env.override_config(|config| {
+                config.build_max_consecutive_panics = 2;
+                config.prefix = prefix.path().to_path_buf();
+            });
+            let queue = env.build_queue();
+            for name in &["a", "b", "c", "d"] {
+                queue.add_crate(name, "1.0.0", 0, None)?;
+            }
+
+            // a build which didn't panic resets the count
+            queue.process_next_crate(|_| panic!("panic"))?;
+            queue.process_next_crate(|_| anyhow::bail!("this failed"))?;
+            queue.process_next_crate(|_| panic!("panic"))?;
+            assert!(!queue.is_locked());
+
+            queue.process_next_crate(|_| panic!("panic"))?;
+            assert!(queue.is_locked());
+            assert_eq!(queue.quarantined_count()?, 3);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_set_priority() {
+        crate::test::wrapper(|env| {
+            let queue = env.build_queue();
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 5, None)?;
+
+            assert!(queue.set_priority("bar", "1.0.0", -5)?);
+            assert!(!queue.set_priority("bar", "2.0.0", -5)?);
+            assert_eq!(
+                queue
+                    .queued_crates()?
+                    .iter()
+                    .map(|krate| (krate.name.as_str(), krate.priority))
+                    .collect::<Vec<_>>(),
+                vec![("bar", -5), ("foo", 0)]
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_position() {
+        crate::test::wrapper(|env| {
+            env.override_config(|config| {
+                config.build_attempts = 1;
+                config.build_retry_delay = 0;
+            });
+            let queue = env.build_queue();
+            queue.add_crate("failed", "1.0.0", -10, None)?;
+            queue.process_next_crate(|_| anyhow::bail!("this failed"))?;
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "1.0.0", 5, None)?;
+            queue.add_crate("baz", "1.0.0", 10, None)?;
+
+            assert_eq!(queue.position("missing", "1.0.0")?, None);
+
+            let failed = queue.position("failed", "1.0.0")?.unwrap();
+            assert_eq!(failed.position, None);
+            assert!(!failed.building);
+
+            // without builds in the last hour the start can't be estimated
+            let baz = queue.position("baz", "1.0.0")?.unwrap();
+            assert_eq!(baz.position, Some(2));
+            assert_eq!(baz.estimated_start, None);
+
+            // 4 builds per hour take 15 minutes each
+            for version in &["0.1.0", "0.2.0", "0.3.0", "0.4.0"] {
+                env.fake_release().name("other").version(version).create()?;
+            }
+            let baz = queue.position("baz", "1.0.0")?.unwrap();
+            let wait = baz.estimated_start.unwrap() - Utc::now();
+            assert!((29..=30).contains(&wait.num_minutes()));
+
+            queue.process_next_crate(|_| {
+                let foo = queue.position("foo", "1.0.0")?.unwrap();
+                assert!(foo.building);
+                assert_eq!(foo.position, Some(0));
+                assert_eq!(queue.position("bar", "1.0.0")?.unwrap().position, Some(0));
+                Ok(())
+            })?;
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_queued_crates() {
         crate::test::wrapper(|env| {
@@ -1,4 +1,4 @@
-use crate::storage::StorageKind;
+use crate::storage::{CompressionAlgorithm, StorageKind};
 use anyhow::{anyhow, bail, Context, Result};
 use std::env::VarError;
 use std::error::Error;
@@ -19,6 +19,13 @@ pub struct Config {
     // Storage params
     pub(crate) storage_backend: StorageKind,
 
+    // compression algorithm for new files in the storage, outside of archives (`Zstd`, `Gzip`,
+    // `Brotli` or `Bzip2`)
+    pub(crate) storage_compression: CompressionAlgorithm,
+
+    // Filesystem storage params
+    pub(crate) local_storage_path: PathBuf,
+
     // S3 params
     pub(crate) s3_bucket: String,
     pub(crate) s3_region: String,
@@ -36,10 +43,19 @@ pub struct Config {
     // Max size of the files served by the docs.rs frontend
     pub(crate) max_file_size: usize,
     pub(crate) max_file_size_html: usize,
+    // Max size of the offline documentation and source bundles built for downloads
+    pub(crate) max_download_bundle_size: usize,
+    // Max number of download bundles built at the same time by one web server
+    pub(crate) max_concurrent_bundle_builds: usize,
     // The most memory that can be used to parse an HTML file
     pub(crate) max_parse_memory: usize,
     // Time between 'git gc --auto' calls in seconds
     pub(crate) registry_gc_interval: u64,
+    // Time between deleting the orphaned objects in the storage in seconds, the daemon doesn't
+    // delete them if it's not set
+    pub(crate) gc_storage_interval: Option<u64>,
+    // Minimum age of the orphaned objects in the storage before they are deleted, in seconds
+    pub(crate) gc_storage_grace_period: u64,
 
     // random crate search generates a number of random IDs to
     // efficiently find a random crate with > 100 GH stars.
@@ -53,12 +69,34 @@ pub struct Config {
     // where do we want to store the locally cached index files
     // for the remote archives?
     pub(crate) local_archive_cache_path: PathBuf,
+    // maximum size of the locally cached index files in bytes, the least recently
+    // used ones are removed when it's exceeded.
+    pub(crate) local_archive_cache_max_size: u64,
+    // store the files of new archives deduplicated by their contents, instead of inside the zip
+    // archive.
+    pub(crate) archive_deduplication: bool,
 
     // Content Security Policy
     pub(crate) csp_report_only: bool,
 
     // Build params
     pub(crate) build_attempts: u16,
+    // seconds after which a crate claimed by a builder that stopped renewing its lease can be
+    // claimed by another builder
+    pub(crate) build_lease_timeout: u64,
+    // seconds before a crate which failed to build is retried, doubled after every failed attempt
+    pub(crate) build_retry_delay: u64,
+    // maximum number of seconds before a crate which failed to build is retried
+    pub(crate) build_max_retry_delay: u64,
+    // the queue is locked after this many builds in a row panicked, a single panicking build
+    // only quarantines its crate
+    pub(crate) build_max_consecutive_panics: u32,
+    // the builders pause while there are less than this many bytes of free disk space in the
+    // rustwide workspace or the temporary directory
+    pub(crate) build_min_free_disk_space: u64,
+    // create a rebuild campaign for the releases documented with an older rustc when the
+    // toolchain is updated
+    pub(crate) rebuild_campaign_on_toolchain_change: bool,
     pub(crate) rustwide_workspace: PathBuf,
     pub(crate) inside_docker: bool,
     pub(crate) docker_image: Option<String>,
@@ -66,6 +104,8 @@ pub struct Config {
     pub(crate) build_cpu_limit: Option<u32>,
     pub(crate) include_default_targets: bool,
     pub(crate) disable_memory_limit: bool,
+    // run the doctests of the crates which opted in with `doctests = true` in their metadata
+    pub(crate) run_doctests: bool,
 }
 
 impl Config {
@@ -94,6 +134,18 @@ impl Config {
 
         Ok(Self {
             build_attempts: env("DOCSRS_BUILD_ATTEMPTS", 5)?,
+            build_lease_timeout: env("DOCSRS_BUILD_LEASE_TIMEOUT", 5 * 60)?,
+            build_retry_delay: env("DOCSRS_BUILD_RETRY_DELAY", 60)?,
+            build_max_retry_delay: env("DOCSRS_BUILD_MAX_RETRY_DELAY", 60 * 60)?,
+            build_max_consecutive_panics: env("DOCSRS_BUILD_MAX_CONSECUTIVE_PANICS", 3)?,
+            build_min_free_disk_space: env(
+                "DOCSRS_BUILD_MIN_FREE_DISK_SPACE",
+                5 * 1024 * 1024 * 1024,
+            )?,
+            rebuild_campaign_on_toolchain_change: env(
+                "DOCSRS_REBUILD_CAMPAIGN_ON_TOOLCHAIN_CHANGE",
+                false,
+            )?,
 
             registry_index_path: env("REGISTRY_INDEX_PATH", prefix.join("crates.io-index"))?,
             registry_url: maybe_env("REGISTRY_URL")?,
@@ -105,6 +157,10 @@ impl Config {
 
             storage_backend: env("DOCSRS_STORAGE_BACKEND", StorageKind::Database)?,
 
+            storage_compression: env("DOCSRS_STORAGE_COMPRESSION", CompressionAlgorithm::Zstd)?,
+
+            local_storage_path: env("DOCSRS_LOCAL_STORAGE_PATH", prefix.join("storage"))?,
+
             s3_bucket: env("DOCSRS_S3_BUCKET", "rust-docs-rs".to_string())?,
             s3_region: env("S3_REGION", "us-west-1".to_string())?,
             s3_endpoint: maybe_env("S3_ENDPOINT")?,
@@ -121,10 +177,14 @@ impl Config {
 
             max_file_size: env("DOCSRS_MAX_FILE_SIZE", 50 * 1024 * 1024)?,
             max_file_size_html: env("DOCSRS_MAX_FILE_SIZE_HTML", 50 * 1024 * 1024)?,
+            max_download_bundle_size: env("DOCSRS_MAX_DOWNLOAD_BUNDLE_SIZE", 500 * 1024 * 1024)?,
+            max_concurrent_bundle_builds: env("DOCSRS_MAX_CONCURRENT_BUNDLE_BUILDS", 2)?,
             // LOL HTML only uses as much memory as the size of the start tag!
             // https://github.com/rust-lang/docs.rs/pull/930#issuecomment-667729380
             max_parse_memory: env("DOCSRS_MAX_PARSE_MEMORY", 5 * 1024 * 1024)?,
             registry_gc_interval: env("DOCSRS_REGISTRY_GC_INTERVAL", 60 * 60)?,
+            gc_storage_interval: maybe_env("DOCSRS_GC_STORAGE_INTERVAL")?,
+            gc_storage_grace_period: env("DOCSRS_GC_STORAGE_GRACE_PERIOD", 7 * 24 * 60 * 60)?,
 
             random_crate_search_view_size: env("DOCSRS_RANDOM_CRATE_SEARCH_VIEW_SIZE", 500)?,
 
@@ -134,6 +194,11 @@ impl Config {
                 "DOCSRS_ARCHIVE_INDEX_CACHE_PATH",
                 prefix.join("archive_cache"),
             )?,
+            local_archive_cache_max_size: env(
+                "DOCSRS_ARCHIVE_INDEX_CACHE_MAX_SIZE",
+                10 * 1024 * 1024 * 1024,
+            )?,
+            archive_deduplication: env("DOCSRS_ARCHIVE_DEDUPLICATION", false)?,
 
             rustwide_workspace: env("DOCSRS_RUSTWIDE_WORKSPACE", PathBuf::from(".workspace"))?,
             inside_docker: env("DOCSRS_DOCKER", false)?,
@@ -143,6 +208,7 @@ impl Config {
             build_cpu_limit: maybe_env("DOCSRS_BUILD_CPU_LIMIT")?,
             include_default_targets: env("DOCSRS_INCLUDE_DEFAULT_TARGETS", true)?,
             disable_memory_limit: env("DOCSRS_DISABLE_MEMORY_LIMIT", false)?,
+            run_doctests: env("DOCSRS_RUN_DOCTESTS", false)?,
         })
     }
 }
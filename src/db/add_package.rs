@@ -1,6 +1,9 @@
 use crate::{
     db::types::Feature,
-    docbuilder::{BuildResult, DocCoverage},
+    docbuilder::{
+        BuildReproducibility, BuildResult, DocCoverage, DoctestResult, FileCoverage,
+        TargetBuildResult, UndocumentedItem, MAX_UNDOCUMENTED_ITEMS,
+    },
     error::Result,
     index::api::{CrateData, CrateOwner, ReleaseData},
     storage::CompressionAlgorithm,
@@ -101,7 +104,13 @@ pub(crate) fn add_package_into_database(
             &registry_data.yanked,
             &res.successful,
             &has_docs,
-            &false, // TODO: Add test status somehow
+            &matches!(
+                res.doctests,
+                Some(DoctestResult {
+                    successful: true,
+                    ..
+                })
+            ),
             &metadata_pkg.license,
             &metadata_pkg.repository,
             &metadata_pkg.homepage,
@@ -178,6 +187,81 @@ pub(crate) fn add_doc_coverage(
     Ok(rows[0].get(0))
 }
 
+/// Replaces the documentation coverage of each file and the undocumented items of a release.
+///
+/// Only the first [`MAX_UNDOCUMENTED_ITEMS`] undocumented items are stored, next to how many
+/// there are.
+pub(crate) fn add_doc_coverage_details(
+    conn: &mut Client,
+    release_id: i32,
+    files: &[FileCoverage],
+    undocumented_items: &[UndocumentedItem],
+) -> Result<()> {
+    let mut transaction = conn.transaction()?;
+    transaction.execute(
+        "DELETE FROM doc_coverage_files WHERE release_id = $1",
+        &[&release_id],
+    )?;
+    transaction.execute(
+        "DELETE FROM undocumented_items WHERE release_id = $1",
+        &[&release_id],
+    )?;
+
+    let column = |f: fn(&FileCoverage) -> i32| files.iter().map(f).collect::<Vec<_>>();
+    transaction.execute(
+        "INSERT INTO doc_coverage_files (
+            release_id, file, total_items, documented_items,
+            total_items_needing_examples, items_with_examples
+         )
+         SELECT $1, * FROM UNNEST($2::TEXT[], $3::INT[], $4::INT[], $5::INT[], $6::INT[])",
+        &[
+            &release_id,
+            &files.iter().map(|file| &file.file).collect::<Vec<_>>(),
+            &column(|file| file.coverage.total_items),
+            &column(|file| file.coverage.documented_items),
+            &column(|file| file.coverage.total_items_needing_examples),
+            &column(|file| file.coverage.items_with_examples),
+        ],
+    )?;
+
+    transaction.execute(
+        "INSERT INTO doc_coverage (release_id, undocumented_items)
+         VALUES ($1, $2)
+         ON CONFLICT (release_id) DO UPDATE
+            SET undocumented_items = $2",
+        &[&release_id, &(undocumented_items.len() as i32)],
+    )?;
+    let items = &undocumented_items[..undocumented_items.len().min(MAX_UNDOCUMENTED_ITEMS)];
+    transaction.execute(
+        "INSERT INTO undocumented_items (release_id, path, kind, file, line)
+         SELECT $1, * FROM UNNEST($2::TEXT[], $3::TEXT[], $4::TEXT[], $5::INT[])",
+        &[
+            &release_id,
+            &items.iter().map(|item| &item.path).collect::<Vec<_>>(),
+            &items.iter().map(|item| &item.kind).collect::<Vec<_>>(),
+            &items.iter().map(|item| &item.file).collect::<Vec<_>>(),
+            &items.iter().map(|item| item.line).collect::<Vec<_>>(),
+        ],
+    )?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Sets whether the rustdoc JSON output of a release is stored, and its format version
+pub(crate) fn set_rustdoc_json(
+    conn: &mut Client,
+    release_id: i32,
+    format_version: Option<i32>,
+) -> Result<()> {
+    conn.execute(
+        "UPDATE releases
+         SET rustdoc_json = $2, rustdoc_json_format_version = $3
+         WHERE id = $1",
+        &[&release_id, &format_version.is_some(), &format_version],
+    )?;
+    Ok(())
+}
+
 /// Adds a build into database
 pub(crate) fn add_build_into_database(
     conn: &mut Client,
@@ -186,19 +270,72 @@ pub(crate) fn add_build_into_database(
 ) -> Result<i32> {
     debug!("Adding build into database");
     let rows = conn.query(
-        "INSERT INTO builds (rid, rustc_version, docsrs_version, build_status)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id",
+        "INSERT INTO builds (
+            rid, rustc_version, docsrs_version, build_status, failure_reason,
+            doctests_successful, doctests_passed, doctests_failed, doctests_ignored
+         )
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+         RETURNING id",
         &[
             &release_id,
             &res.rustc_version,
             &res.docsrs_version,
             &res.successful,
+            &res.failure_reason.map(|reason| reason.to_string()),
+            &res.doctests.map(|doctests| doctests.successful),
+            &res.doctests.map(|doctests| doctests.passed),
+            &res.doctests.map(|doctests| doctests.failed),
+            &res.doctests.map(|doctests| doctests.ignored),
         ],
     )?;
     Ok(rows[0].get(0))
 }
 
+/// Adds the results of building the documentation for each target of a build.
+pub(crate) fn add_build_targets_into_database(
+    conn: &mut Client,
+    build_id: i32,
+    targets: &[TargetBuildResult],
+) -> Result<()> {
+    for target in targets {
+        conn.execute(
+            "INSERT INTO build_targets (build_id, target, successful, failure_reason, cargo_args)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &build_id,
+                &target.target,
+                &target.successful,
+                &target.failure_reason.map(|reason| reason.to_string()),
+                &target.cargo_args,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds what's needed to run a build again.
+pub(crate) fn add_build_reproducibility_into_database(
+    conn: &mut Client,
+    build_id: i32,
+    reproducibility: &BuildReproducibility,
+) -> Result<()> {
+    conn.execute(
+        "INSERT INTO build_reproducibility (
+            build_id, docker_image, environment, rustdoc_flags, cargo_lock, lockfile_regenerated
+         )
+         VALUES ($1, $2, $3, $4, $5, $6)",
+        &[
+            &build_id,
+            &reproducibility.docker_image,
+            &serde_json::to_value(&reproducibility.environment)?,
+            &reproducibility.rustdoc_flags,
+            &reproducibility.cargo_lock,
+            &reproducibility.lockfile_regenerated,
+        ],
+    )?;
+    Ok(())
+}
+
 fn initialize_package_in_database(conn: &mut Client, pkg: &MetadataPackage) -> Result<i32> {
     let mut rows = conn.query("SELECT id FROM crates WHERE name = $1", &[&pkg.name])?;
     // insert crate into database if it is not exists
@@ -1,5 +1,5 @@
 use crate::error::Result;
-use crate::storage::{rustdoc_archive_path, source_archive_path, Storage};
+use crate::storage::{rustdoc_archive_path, rustdoc_json_path, source_archive_path, Storage};
 use crate::{Config, Context};
 use anyhow::Context as _;
 use postgres::Client;
@@ -7,8 +7,8 @@ use std::fs;
 
 /// List of directories in docs.rs's underlying storage (either the database or S3) containing a
 /// subdirectory named after the crate. Those subdirectories will be deleted.
-static LIBRARY_STORAGE_PATHS_TO_DELETE: &[&str] = &["rustdoc", "sources"];
-static BINARY_STORAGE_PATHS_TO_DELETE: &[&str] = &["sources"];
+static LIBRARY_STORAGE_PATHS_TO_DELETE: &[&str] = &["rustdoc", "sources", "downloads"];
+static BINARY_STORAGE_PATHS_TO_DELETE: &[&str] = &["sources", "downloads"];
 
 #[derive(Debug, thiserror::Error)]
 enum CrateDeletionError {
@@ -35,6 +35,7 @@ pub fn delete_crate(
         // delete the whole rustdoc/source folder for this crate.
         // it will include existing archives.
         let remote_folder = format!("{}/{}/", prefix, name);
+        storage.release_deduplicated_files_with_prefix(&remote_folder)?;
         storage.delete_prefix(&remote_folder)?;
 
         // remove existing local archive index files.
@@ -66,6 +67,8 @@ pub fn delete_version(ctx: &dyn Context, name: &str, version: &str) -> Result<()
     for prefix in paths {
         storage.delete_prefix(&format!("{}/{}/{}/", prefix, name, version))?;
     }
+    // the rustdoc JSON is stored next to the rustdoc folder of the version, not inside it
+    storage.delete_prefix(&rustdoc_json_path(name, version))?;
 
     let local_archive_cache = &ctx.config()?.local_archive_cache_path;
     let mut paths = vec![source_archive_path(name, version)];
@@ -74,7 +77,9 @@ pub fn delete_version(ctx: &dyn Context, name: &str, version: &str) -> Result<()
     }
 
     for archive_filename in paths {
-        // delete remove archive and remote index
+        // delete remove archive and remote index, and the deduplicated files only this archive
+        // was using
+        storage.release_deduplicated_files(&archive_filename)?;
         storage.delete_prefix(&archive_filename)?;
 
         // delete eventually existing local indexes
@@ -407,4 +412,67 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn test_delete_version_rustdoc_json() {
+        wrapper(|env| {
+            for version in ["1.0.0", "2.0.0"] {
+                env.fake_release()
+                    .name("a")
+                    .version(version)
+                    .rustdoc_json(1, b"{}")
+                    .create()?;
+                assert!(env.storage().exists(&rustdoc_json_path("a", version))?);
+            }
+
+            delete_version(env, "a", "1.0.0")?;
+            assert!(!env.storage().exists(&rustdoc_json_path("a", "1.0.0"))?);
+            assert!(env.storage().exists(&rustdoc_json_path("a", "2.0.0"))?);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_delete_deduplicated_files() {
+        wrapper(|env| {
+            env.override_config(|config| config.archive_deduplication = true);
+            fn referenced_blobs(conn: &mut Client) -> Result<i64> {
+                Ok(conn
+                    .query_one(
+                        "SELECT COALESCE(SUM(refcount), 0)::BIGINT FROM content_blobs",
+                        &[],
+                    )?
+                    .get(0))
+            }
+
+            for version in ["1.0.0", "2.0.0"] {
+                env.fake_release()
+                    .name("a")
+                    .version(version)
+                    .archive_storage(true)
+                    .create()?;
+            }
+            let db = env.db();
+            let references = referenced_blobs(&mut db.conn())?;
+            assert!(references > 0);
+
+            // the files shared with 2.0.0 are kept
+            delete_version(env, "a", "1.0.0")?;
+            assert_eq!(referenced_blobs(&mut db.conn())?, references / 2);
+            assert!(env
+                .storage()
+                .rustdoc_file_exists("a", "2.0.0", "a/index.html", true)?);
+            assert_success("/a/2.0.0/a/", env.frontend())?;
+
+            delete_crate(&mut db.conn(), &env.storage(), &env.config(), "a")?;
+            assert_eq!(referenced_blobs(&mut db.conn())?, 0);
+            assert!(db
+                .conn()
+                .query("SELECT path FROM files WHERE path LIKE 'blobs/%'", &[])?
+                .is_empty());
+
+            Ok(())
+        })
+    }
 }
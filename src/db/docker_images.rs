@@ -0,0 +1,213 @@
+//! The allow-list of docker images crates can be built in instead of the default one, and which
+//! crates use them.
+
+use crate::error::Result;
+use postgres::Client;
+
+#[derive(Debug, thiserror::Error)]
+enum DockerImagesError {
+    #[error("image {0} is already allowed")]
+    ImageAlreadyAllowed(String),
+
+    #[error("image {0} is not allowed, add it first")]
+    ImageNotAllowed(String),
+
+    #[error("no image is assigned to {0}")]
+    PatternNotAssigned(String),
+}
+
+/// An allowed docker image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DockerImage {
+    /// the name of the image, like `ghcr.io/rust-lang/crates-build-env/linux`
+    pub image: String,
+    pub description: Option<String>,
+    /// the crate patterns built in this image, sorted ascending
+    pub crate_patterns: Vec<String>,
+}
+
+/// Returns whether the image is on the allow-list.
+pub fn is_allowed(conn: &mut Client, image: &str) -> Result<bool> {
+    Ok(conn
+        .query_opt("SELECT 1 FROM docker_images WHERE image = $1;", &[&image])?
+        .is_some())
+}
+
+/// Returns the allowed images with the crate patterns assigned to them, sorted by name.
+pub fn list(conn: &mut Client) -> Result<Vec<DockerImage>> {
+    Ok(conn
+        .query(
+            "SELECT docker_images.image,
+                docker_images.description,
+                ARRAY_REMOVE(
+                    ARRAY_AGG(crate_docker_images.crate_pattern ORDER BY crate_pattern),
+                    NULL
+                ) AS crate_patterns
+             FROM docker_images
+             LEFT JOIN crate_docker_images ON crate_docker_images.image = docker_images.image
+             GROUP BY docker_images.image
+             ORDER BY docker_images.image;",
+            &[],
+        )?
+        .into_iter()
+        .map(|row| DockerImage {
+            image: row.get("image"),
+            description: row.get("description"),
+            crate_patterns: row.get("crate_patterns"),
+        })
+        .collect())
+}
+
+/// Adds an image to the allow-list.
+pub fn add(conn: &mut Client, image: &str, description: Option<&str>) -> Result<()> {
+    if is_allowed(conn, image)? {
+        return Err(DockerImagesError::ImageAlreadyAllowed(image.into()).into());
+    }
+
+    conn.execute(
+        "INSERT INTO docker_images (image, description) VALUES ($1, $2);",
+        &[&image, &description],
+    )?;
+    Ok(())
+}
+
+/// Removes an image from the allow-list, the crates assigned to it are built in the default
+/// image again.
+pub fn remove(conn: &mut Client, image: &str) -> Result<()> {
+    if conn.execute("DELETE FROM docker_images WHERE image = $1;", &[&image])? == 0 {
+        return Err(DockerImagesError::ImageNotAllowed(image.into()).into());
+    }
+    Ok(())
+}
+
+/// Builds the crates whose whole name matches `crate_pattern` in an allowed image, replacing the
+/// image previously assigned to the pattern. The pattern is a regular expression, see
+/// https://www.postgresql.org/docs/current/functions-matching.html for its syntax, so the name
+/// of a single crate can be used as it is.
+pub fn assign(conn: &mut Client, crate_pattern: &str, image: &str) -> Result<()> {
+    if !is_allowed(conn, image)? {
+        return Err(DockerImagesError::ImageNotAllowed(image.into()).into());
+    }
+    // fail early for invalid patterns instead of when building a crate
+    conn.execute("SELECT '' ~ $1;", &[&crate_pattern])?;
+
+    conn.execute(
+        "INSERT INTO crate_docker_images (crate_pattern, image)
+         VALUES ($1, $2)
+         ON CONFLICT (crate_pattern) DO UPDATE SET image = $2;",
+        &[&crate_pattern, &image],
+    )?;
+    Ok(())
+}
+
+/// Removes the image assigned to a crate pattern.
+pub fn unassign(conn: &mut Client, crate_pattern: &str) -> Result<()> {
+    if conn.execute(
+        "DELETE FROM crate_docker_images WHERE crate_pattern = $1;",
+        &[&crate_pattern],
+    )? == 0
+    {
+        return Err(DockerImagesError::PatternNotAssigned(crate_pattern.into()).into());
+    }
+    Ok(())
+}
+
+/// Returns the image a crate is built in, or `None` if it's built in the default image.
+///
+/// When multiple patterns match, the crate's own name wins, followed by the longest pattern.
+pub fn image_for_crate(conn: &mut Client, name: &str) -> Result<Option<String>> {
+    Ok(conn
+        .query_opt(
+            "SELECT image
+             FROM crate_docker_images
+             WHERE $1 ~ ('^(?:' || crate_pattern || ')$')
+             ORDER BY crate_pattern = $1 DESC, LENGTH(crate_pattern) DESC, crate_pattern
+             LIMIT 1;",
+            &[&name],
+        )?
+        .map(|row| row.get("image")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allow_list() {
+        crate::test::wrapper(|env| {
+            let mut conn = env.db().conn();
+            assert!(list(&mut conn)?.is_empty());
+
+            add(&mut conn, "docsrs/gtk", Some("GTK 3 and 4"))?;
+            add(&mut conn, "docsrs/cuda", None)?;
+            assert!(add(&mut conn, "docsrs/gtk", None).is_err());
+            assert!(assign(&mut conn, "foo", "docsrs/unknown").is_err());
+            assert!(assign(&mut conn, "(foo", "docsrs/gtk").is_err());
+
+            assign(&mut conn, "gtk4?(-.*)?", "docsrs/gtk")?;
+            assign(&mut conn, "gdk", "docsrs/gtk")?;
+            assert_eq!(
+                list(&mut conn)?,
+                vec![
+                    DockerImage {
+                        image: "docsrs/cuda".into(),
+                        description: None,
+                        crate_patterns: Vec::new(),
+                    },
+                    DockerImage {
+                        image: "docsrs/gtk".into(),
+                        description: Some("GTK 3 and 4".into()),
+                        crate_patterns: vec!["gdk".into(), "gtk4?(-.*)?".into()],
+                    },
+                ]
+            );
+
+            // removing an image removes its assignments
+            remove(&mut conn, "docsrs/gtk")?;
+            assert!(remove(&mut conn, "docsrs/gtk").is_err());
+            assert_eq!(image_for_crate(&mut conn, "gdk")?, None);
+            assert!(unassign(&mut conn, "gdk").is_err());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn image_of_crates() {
+        crate::test::wrapper(|env| {
+            let mut conn = env.db().conn();
+            add(&mut conn, "docsrs/gtk", None)?;
+            add(&mut conn, "docsrs/gtk-sys", None)?;
+
+            assign(&mut conn, "gtk(-.*)?", "docsrs/gtk")?;
+            assign(&mut conn, "gtk-sys", "docsrs/gtk-sys")?;
+
+            assert_eq!(
+                image_for_crate(&mut conn, "gtk")?,
+                Some("docsrs/gtk".into())
+            );
+            assert_eq!(
+                image_for_crate(&mut conn, "gtk-rs")?,
+                Some("docsrs/gtk".into())
+            );
+            assert_eq!(
+                image_for_crate(&mut conn, "gtk-sys")?,
+                Some("docsrs/gtk-sys".into())
+            );
+            // the whole name has to match
+            assert_eq!(image_for_crate(&mut conn, "libgtk")?, None);
+
+            // assigning a pattern again replaces its image
+            assign(&mut conn, "gtk-sys", "docsrs/gtk")?;
+            assert_eq!(
+                image_for_crate(&mut conn, "gtk-sys")?,
+                Some("docsrs/gtk".into())
+            );
+            unassign(&mut conn, "gtk-sys")?;
+            unassign(&mut conn, "gtk(-.*)?")?;
+            assert_eq!(image_for_crate(&mut conn, "gtk")?, None);
+
+            Ok(())
+        });
+    }
+}
@@ -1,11 +1,11 @@
 //! Simple module to store files in database.
 //!
-//! docs.rs supports two ways of storing files: in a postgres database and in an S3 bucket.
-//! It does not support storing files on disk because of the sheer number of files:
-//! doing so would quickly run into file descriptor limits when running the web server.
+//! docs.rs supports three ways of storing files: in a postgres database, in an S3 bucket and in a
+//! local directory.
 //!
 //! It's recommended that you use the S3 bucket in production to avoid running out of disk space.
-//! However, postgres is still available for testing and backwards compatibility.
+//! However, postgres is still available for testing and backwards compatibility, and the local
+//! directory is meant for small self-hosted instances and local development.
 
 use crate::error::Result;
 use crate::storage::{CompressionAlgorithm, CompressionAlgorithms, Storage};
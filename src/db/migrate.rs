@@ -837,7 +837,269 @@ pub fn migrate(version: Option<Version>, conn: &mut Client) -> crate::error::Res
                     )
                     .map(|_| ())
             }
-        )
+        ),
+        sql_migration!(
+            context,
+            33,
+            "add reference counts for deduplicated archive files",
+            "
+                CREATE TABLE content_blobs (
+                    hash BYTEA PRIMARY KEY,
+                    size BIGINT NOT NULL,
+                    refcount INT NOT NULL
+                );
+                CREATE TABLE archive_blobs (
+                    archive VARCHAR(4096) NOT NULL,
+                    hash BYTEA NOT NULL REFERENCES content_blobs(hash),
+                    PRIMARY KEY (archive, hash)
+                );
+            ",
+            "
+                DROP TABLE archive_blobs;
+                DROP TABLE content_blobs;
+            ",
+        ),
+        sql_migration!(
+            context,
+            34,
+            "add checkpoints for storage migrations",
+            "
+                CREATE TABLE storage_migrations (
+                    source VARCHAR(32) NOT NULL,
+                    destination VARCHAR(32) NOT NULL,
+                    last_path VARCHAR(4096),
+                    files BIGINT NOT NULL DEFAULT 0,
+                    bytes BIGINT NOT NULL DEFAULT 0,
+                    started_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    finished_at TIMESTAMPTZ,
+                    PRIMARY KEY (source, destination)
+                );
+            ",
+            "DROP TABLE storage_migrations;",
+        ),
+        sql_migration!(
+            context,
+            35,
+            "add leases to the build queue so multiple builders can process it",
+            "
+                ALTER TABLE queue
+                    ADD COLUMN claimed_by TEXT,
+                    ADD COLUMN claimed_at TIMESTAMPTZ;
+            ",
+            "
+                ALTER TABLE queue
+                    DROP COLUMN claimed_by,
+                    DROP COLUMN claimed_at;
+            ",
+        ),
+        sql_migration!(
+            context,
+            36,
+            "add the reason of failed builds",
+            "ALTER TABLE builds ADD COLUMN failure_reason TEXT;",
+            "ALTER TABLE builds DROP COLUMN failure_reason;",
+        ),
+        sql_migration!(
+            context,
+            37,
+            "add the results of the builds of every target",
+            "
+                ALTER TABLE builds ADD PRIMARY KEY (id);
+                CREATE TABLE build_targets (
+                    build_id INT NOT NULL REFERENCES builds(id) ON DELETE CASCADE,
+                    target TEXT NOT NULL,
+                    successful BOOL NOT NULL,
+                    failure_reason TEXT,
+                    PRIMARY KEY (build_id, target)
+                );
+                CREATE INDEX builds_rid_idx ON builds (rid);
+            ",
+            "
+                DROP INDEX builds_rid_idx;
+                DROP TABLE build_targets;
+                ALTER TABLE builds DROP CONSTRAINT builds_pkey;
+            ",
+        ),
+        sql_migration!(
+            context,
+            38,
+            "add rebuild campaigns",
+            "
+                CREATE TABLE rebuild_campaigns (
+                    id SERIAL PRIMARY KEY,
+                    name TEXT NOT NULL UNIQUE,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    built_before DATE,
+                    min_stars INT,
+                    min_downloads INT,
+                    name_pattern TEXT,
+                    all_versions BOOL NOT NULL,
+                    priority INT NOT NULL,
+                    max_queued INT NOT NULL,
+                    cancelled BOOL NOT NULL DEFAULT FALSE,
+                    finished_at TIMESTAMPTZ
+                );
+                CREATE TABLE rebuild_campaign_releases (
+                    campaign_id INT NOT NULL REFERENCES rebuild_campaigns(id) ON DELETE CASCADE,
+                    release_id INT NOT NULL REFERENCES releases(id) ON DELETE CASCADE,
+                    queued_at TIMESTAMPTZ,
+                    PRIMARY KEY (campaign_id, release_id)
+                );
+            ",
+            "
+                DROP TABLE rebuild_campaign_releases;
+                DROP TABLE rebuild_campaigns;
+            ",
+        ),
+        sql_migration!(
+            context,
+            39,
+            "add whether the rustdoc JSON output of releases is stored",
+            "
+                ALTER TABLE releases
+                    ADD COLUMN rustdoc_json BOOL NOT NULL DEFAULT FALSE,
+                    ADD COLUMN rustdoc_json_format_version INT;
+            ",
+            "
+                ALTER TABLE releases
+                    DROP COLUMN rustdoc_json,
+                    DROP COLUMN rustdoc_json_format_version;
+            ",
+        ),
+        sql_migration!(
+            context,
+            40,
+            "add the time queued crates are built after",
+            "ALTER TABLE queue ADD COLUMN next_attempt_at TIMESTAMPTZ;",
+            "ALTER TABLE queue DROP COLUMN next_attempt_at;",
+        ),
+        sql_migration!(
+            context,
+            41,
+            "allow overriding the networking and build log size limits",
+            "
+                ALTER TABLE sandbox_overrides
+                    ADD COLUMN networking BOOL,
+                    ADD COLUMN max_log_size_bytes BIGINT;
+            ",
+            "
+                ALTER TABLE sandbox_overrides
+                    DROP COLUMN networking,
+                    DROP COLUMN max_log_size_bytes;
+            ",
+        ),
+        sql_migration!(
+            context,
+            42,
+            "record what's needed to reproduce builds",
+            "
+                ALTER TABLE build_targets ADD COLUMN cargo_args TEXT[];
+                CREATE TABLE build_reproducibility (
+                    build_id INT PRIMARY KEY REFERENCES builds(id) ON DELETE CASCADE,
+                    docker_image TEXT NOT NULL,
+                    environment JSONB NOT NULL,
+                    rustdoc_flags TEXT[] NOT NULL,
+                    cargo_lock TEXT,
+                    lockfile_regenerated BOOL NOT NULL
+                );
+            ",
+            "
+                DROP TABLE build_reproducibility;
+                ALTER TABLE build_targets DROP COLUMN cargo_args;
+            ",
+        ),
+        sql_migration!(
+            context,
+            43,
+            "add the results of the doctests of builds",
+            "
+                ALTER TABLE builds
+                    ADD COLUMN doctests_successful BOOL,
+                    ADD COLUMN doctests_passed INT,
+                    ADD COLUMN doctests_failed INT,
+                    ADD COLUMN doctests_ignored INT;
+            ",
+            "
+                ALTER TABLE builds
+                    DROP COLUMN doctests_successful,
+                    DROP COLUMN doctests_passed,
+                    DROP COLUMN doctests_failed,
+                    DROP COLUMN doctests_ignored;
+            ",
+        ),
+        sql_migration!(
+            context,
+            44,
+            "store the documentation coverage of each file and the undocumented items",
+            "
+                CREATE TABLE doc_coverage_files (
+                    release_id INT NOT NULL REFERENCES releases(id) ON DELETE CASCADE,
+                    file TEXT NOT NULL,
+                    total_items INT NOT NULL,
+                    documented_items INT NOT NULL,
+                    total_items_needing_examples INT NOT NULL,
+                    items_with_examples INT NOT NULL,
+                    PRIMARY KEY (release_id, file)
+                );
+                CREATE TABLE undocumented_items (
+                    release_id INT NOT NULL REFERENCES releases(id) ON DELETE CASCADE,
+                    path TEXT NOT NULL,
+                    kind TEXT NOT NULL,
+                    file TEXT,
+                    line INT
+                );
+                CREATE INDEX undocumented_items_release_id_idx ON undocumented_items (release_id);
+            ",
+            "
+                DROP TABLE undocumented_items;
+                DROP TABLE doc_coverage_files;
+            ",
+        ),
+        sql_migration!(
+            context,
+            45,
+            "add the allow-list of docker images crates can be built in",
+            "
+                CREATE TABLE docker_images (
+                    image TEXT PRIMARY KEY,
+                    description TEXT,
+                    added_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );
+                CREATE TABLE crate_docker_images (
+                    crate_pattern TEXT PRIMARY KEY,
+                    image TEXT NOT NULL REFERENCES docker_images(image) ON DELETE CASCADE
+                );
+            ",
+            "
+                DROP TABLE crate_docker_images;
+                DROP TABLE docker_images;
+            ",
+        ),
+        sql_migration!(
+            context,
+            46,
+            "quarantine queued crates whose build panicked",
+            "
+                ALTER TABLE queue
+                    ADD COLUMN quarantined_at TIMESTAMPTZ,
+                    ADD COLUMN quarantine_reason TEXT,
+                    ADD COLUMN quarantine_backtrace TEXT;
+            ",
+            "
+                ALTER TABLE queue
+                    DROP COLUMN quarantined_at,
+                    DROP COLUMN quarantine_reason,
+                    DROP COLUMN quarantine_backtrace;
+            ",
+        ),
+        sql_migration!(
+            context,
+            47,
+            "store how many items of a release are undocumented",
+            "ALTER TABLE doc_coverage ADD COLUMN undocumented_items INT;",
+            "ALTER TABLE doc_coverage DROP COLUMN undocumented_items;",
+        ),
     ];
 
     for migration in migrations {
@@ -2,7 +2,9 @@
 
 pub use self::add_package::update_crate_data_in_database;
 pub(crate) use self::add_package::{
-    add_build_into_database, add_doc_coverage, add_package_into_database,
+    add_build_into_database, add_build_reproducibility_into_database,
+    add_build_targets_into_database, add_doc_coverage, add_doc_coverage_details,
+    add_package_into_database, set_rustdoc_json,
 };
 pub use self::delete::{delete_crate, delete_version};
 pub use self::file::{add_path_into_database, add_path_into_remote_archive};
@@ -12,7 +14,9 @@ pub use self::pool::{Pool, PoolClient, PoolError};
 mod add_package;
 pub mod blacklist;
 mod delete;
+pub mod docker_images;
 pub(crate) mod file;
 mod migrate;
 mod pool;
+pub mod sandbox_overrides;
 pub(crate) mod types;
@@ -0,0 +1,170 @@
+//! The sandbox limits overridden for single crates.
+
+use crate::error::Result;
+use postgres::{Client, Row};
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+enum SandboxOverridesError {
+    #[error("crate {0} has no overridden limits")]
+    CrateNotOverridden(String),
+}
+
+/// The overridden limits of a crate, the default limits are used for the `None` fields.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    /// available memory, in bytes
+    pub memory: Option<usize>,
+    pub targets: Option<usize>,
+    pub timeout: Option<Duration>,
+    pub networking: Option<bool>,
+    /// maximum size of a build log, in bytes
+    pub max_log_size: Option<usize>,
+}
+
+impl Overrides {
+    fn from_row(row: &Row) -> Self {
+        Self {
+            memory: row
+                .get::<_, Option<i64>>("max_memory_bytes")
+                .map(|memory| memory as usize),
+            targets: row
+                .get::<_, Option<i32>>("max_targets")
+                .map(|targets| targets as usize),
+            timeout: row
+                .get::<_, Option<i32>>("timeout_seconds")
+                .map(|timeout| Duration::from_secs(timeout as u64)),
+            networking: row.get("networking"),
+            max_log_size: row
+                .get::<_, Option<i64>>("max_log_size_bytes")
+                .map(|size| size as usize),
+        }
+    }
+}
+
+/// Returns the overridden limits of a crate.
+pub fn get(conn: &mut Client, name: &str) -> Result<Option<Overrides>> {
+    Ok(conn
+        .query_opt(
+            "SELECT * FROM sandbox_overrides WHERE crate_name = $1;",
+            &[&name],
+        )?
+        .map(|row| Overrides::from_row(&row)))
+}
+
+/// Returns the crates with overridden limits, sorted by name.
+pub fn list(conn: &mut Client) -> Result<Vec<(String, Overrides)>> {
+    Ok(conn
+        .query("SELECT * FROM sandbox_overrides ORDER BY crate_name;", &[])?
+        .into_iter()
+        .map(|row| (row.get("crate_name"), Overrides::from_row(&row)))
+        .collect())
+}
+
+/// Overrides the limits of a crate, keeping the previously overridden limits which are `None`
+/// in `overrides`.
+pub fn set(conn: &mut Client, name: &str, overrides: &Overrides) -> Result<()> {
+    conn.execute(
+        "INSERT INTO sandbox_overrides (
+            crate_name, max_memory_bytes, max_targets, timeout_seconds, networking,
+            max_log_size_bytes
+         )
+         VALUES ($1, $2, $3, $4, $5, $6)
+         ON CONFLICT (crate_name) DO UPDATE
+            SET max_memory_bytes = COALESCE($2, sandbox_overrides.max_memory_bytes),
+                max_targets = COALESCE($3, sandbox_overrides.max_targets),
+                timeout_seconds = COALESCE($4, sandbox_overrides.timeout_seconds),
+                networking = COALESCE($5, sandbox_overrides.networking),
+                max_log_size_bytes = COALESCE($6, sandbox_overrides.max_log_size_bytes);",
+        &[
+            &name,
+            &overrides.memory.map(|memory| memory as i64),
+            &overrides.targets.map(|targets| targets as i32),
+            &overrides.timeout.map(|timeout| timeout.as_secs() as i32),
+            &overrides.networking,
+            &overrides.max_log_size.map(|size| size as i64),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Removes the overridden limits of a crate, so it's built with the default limits again.
+pub fn remove(conn: &mut Client, name: &str) -> Result<()> {
+    if conn.execute(
+        "DELETE FROM sandbox_overrides WHERE crate_name = $1;",
+        &[&name],
+    )? == 0
+    {
+        return Err(SandboxOverridesError::CrateNotOverridden(name.into()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_and_remove() {
+        crate::test::wrapper(|env| {
+            let mut conn = env.db().conn();
+            assert_eq!(get(&mut conn, "foo")?, None);
+
+            set(
+                &mut conn,
+                "foo",
+                &Overrides {
+                    memory: Some(1024),
+                    networking: Some(true),
+                    ..Default::default()
+                },
+            )?;
+            // only the given limits are changed
+            set(
+                &mut conn,
+                "foo",
+                &Overrides {
+                    timeout: Some(Duration::from_secs(60)),
+                    max_log_size: Some(2048),
+                    ..Default::default()
+                },
+            )?;
+            set(
+                &mut conn,
+                "bar",
+                &Overrides {
+                    targets: Some(1),
+                    ..Default::default()
+                },
+            )?;
+
+            let foo = Overrides {
+                memory: Some(1024),
+                targets: None,
+                timeout: Some(Duration::from_secs(60)),
+                networking: Some(true),
+                max_log_size: Some(2048),
+            };
+            assert_eq!(get(&mut conn, "foo")?, Some(foo.clone()));
+            assert_eq!(
+                list(&mut conn)?,
+                vec![
+                    (
+                        "bar".into(),
+                        Overrides {
+                            targets: Some(1),
+                            ..Default::default()
+                        }
+                    ),
+                    ("foo".into(), foo),
+                ]
+            );
+
+            remove(&mut conn, "foo")?;
+            assert_eq!(get(&mut conn, "foo")?, None);
+            assert!(remove(&mut conn, "foo").is_err());
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,179 @@
+use crate::docbuilder::Limits;
+use rustwide::cmd::CommandError;
+use serde::Serialize;
+
+/// Why a build failed, stored in the `failure_reason` column of the `builds` table.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, strum::Display, strum::EnumString, strum::EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum BuildFailure {
+    /// The sandbox hit its memory limit and was killed.
+    OutOfMemory,
+    /// The build took longer than the timeout of the crate.
+    Timeout,
+    /// rustc or rustdoc crashed.
+    RustdocIce,
+    /// The build tried to access the network, which is disabled in the sandbox.
+    NeedsNetwork,
+    /// A library or header of the system the crate links to isn't installed in the build image.
+    MissingSystemLibrary,
+    /// Cargo couldn't resolve the dependencies of the crate.
+    DependencyResolution,
+    /// The manifest of the crate couldn't be parsed.
+    InvalidManifest,
+    /// A build script of the crate or of a dependency failed.
+    BuildScript,
+    /// The crate or one of its dependencies doesn't compile.
+    CompileError,
+    /// None of the above.
+    Unknown,
+}
+
+/// Patterns in the build log, checked in this order after the failures detected from the exit
+/// status of the build.
+const LOG_PATTERNS: &[(BuildFailure, &[&str])] = &[
+    (
+        BuildFailure::OutOfMemory,
+        &["memory allocation of", "(signal: 9, SIGKILL: kill)"],
+    ),
+    (
+        BuildFailure::RustdocIce,
+        &[
+            "error: internal compiler error",
+            "the compiler unexpectedly panicked",
+            "thread 'rustc' panicked",
+            "thread 'rustdoc' panicked",
+        ],
+    ),
+    (
+        BuildFailure::MissingSystemLibrary,
+        &[
+            "was not found in the pkg-config search path",
+            "Could not run `\"pkg-config\"",
+            "Could not find system library",
+            "unable to find library -l",
+            "cannot find -l",
+            ".h: No such file or directory",
+        ],
+    ),
+    (
+        BuildFailure::DependencyResolution,
+        &[
+            "failed to select a version for",
+            "no matching package named",
+            "failed to resolve patches",
+            "cyclic package dependency",
+            "failed to parse lock file",
+            "needs to be updated but --locked was passed",
+        ],
+    ),
+    (
+        BuildFailure::InvalidManifest,
+        &["failed to parse manifest", "failed to read `"],
+    ),
+    (
+        BuildFailure::BuildScript,
+        &["failed to run custom build command for"],
+    ),
+    (
+        BuildFailure::CompileError,
+        &[
+            "error[E",
+            "error: could not compile",
+            "error: could not document",
+        ],
+    ),
+];
+
+/// Patterns in the build log that show the build tried to access the network.
+const NETWORK_PATTERNS: &[&str] = &[
+    "Could not resolve host",
+    "Temporary failure in name resolution",
+    "failed to lookup address information",
+    "Network is unreachable",
+    "dns error",
+];
+
+impl BuildFailure {
+    /// Classifies a failed build from the error it returned, the limits it ran with and its log.
+    pub(crate) fn classify(error: Option<&CommandError>, limits: &Limits, log: &str) -> Self {
+        match error {
+            Some(CommandError::SandboxOOM) => return BuildFailure::OutOfMemory,
+            Some(CommandError::Timeout(_)) | Some(CommandError::NoOutputFor(_)) => {
+                return BuildFailure::Timeout
+            }
+            _ => {}
+        }
+
+        // a network error is the root cause of the other errors if networking is disabled
+        if !limits.networking() && NETWORK_PATTERNS.iter().any(|p| log.contains(p)) {
+            return BuildFailure::NeedsNetwork;
+        }
+
+        LOG_PATTERNS
+            .iter()
+            .find(|(_, patterns)| patterns.iter().any(|p| log.contains(p)))
+            .map(|(failure, _)| *failure)
+            .unwrap_or(BuildFailure::Unknown)
+    }
+
+    /// A description of the failure for the web pages.
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            BuildFailure::OutOfMemory => "Out of memory",
+            BuildFailure::Timeout => "Timeout",
+            BuildFailure::RustdocIce => "Compiler crash",
+            BuildFailure::NeedsNetwork => "Needs network access",
+            BuildFailure::MissingSystemLibrary => "Missing system library",
+            BuildFailure::DependencyResolution => "Dependency resolution failed",
+            BuildFailure::InvalidManifest => "Invalid manifest",
+            BuildFailure::BuildScript => "Build script failed",
+            BuildFailure::CompileError => "Compile error",
+            BuildFailure::Unknown => "Unknown",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(
+        Some(CommandError::SandboxOOM), "error: could not compile `foo`"
+        => BuildFailure::OutOfMemory; "oom kill")]
+    #[test_case(Some(CommandError::Timeout(900)), "" => BuildFailure::Timeout; "timeout")]
+    #[test_case(
+        None, "error: internal compiler error: unexpected panic\nerror: could not document `foo`"
+        => BuildFailure::RustdocIce; "ice")]
+    #[test_case(
+        None, "Package openssl was not found in the pkg-config search path.\nerror: failed to run custom build command for `openssl-sys v0.9.72`"
+        => BuildFailure::MissingSystemLibrary; "missing library")]
+    #[test_case(
+        None, "error: failed to select a version for `foo`."
+        => BuildFailure::DependencyResolution; "dependency resolution")]
+    #[test_case(
+        None, "error: failed to run custom build command for `foo v0.1.0`"
+        => BuildFailure::BuildScript; "build script")]
+    #[test_case(
+        None, "error[E0425]: cannot find value `x` in this scope\nerror: could not compile `foo`"
+        => BuildFailure::CompileError; "compile error")]
+    #[test_case(
+        None, "warning: spurious network error: Could not resolve host: github.com\nerror: failed to run custom build command for `foo v0.1.0`"
+        => BuildFailure::NeedsNetwork; "network")]
+    #[test_case(None, "something else" => BuildFailure::Unknown; "unknown")]
+    fn classify(error: Option<CommandError>, log: &str) -> BuildFailure {
+        BuildFailure::classify(error.as_ref(), &Limits::default(), log)
+    }
+
+    #[test]
+    fn roundtrip() {
+        use strum::IntoEnumIterator;
+        for failure in BuildFailure::iter() {
+            assert_eq!(failure.to_string().parse::<BuildFailure>(), Ok(failure));
+        }
+        assert_eq!(BuildFailure::OutOfMemory.to_string(), "out_of_memory");
+    }
+}
@@ -0,0 +1,392 @@
+use crate::error::Result;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct DocCoverage {
+    /// The total items that could be documented in the current crate, used to calculate
+    /// documentation coverage.
+    pub(crate) total_items: i32,
+    /// The items of the crate that are documented, used to calculate documentation coverage.
+    pub(crate) documented_items: i32,
+    /// The total items that could have code examples in the current crate, used to calculate
+    /// documentation coverage.
+    pub(crate) total_items_needing_examples: i32,
+    /// The items of the crate that have a code example, used to calculate documentation coverage.
+    pub(crate) items_with_examples: i32,
+}
+
+impl DocCoverage {
+    /// Sums up the coverage of all the files of a crate, returning `None` if there was nothing
+    /// to document.
+    pub(crate) fn total(files: &[FileCoverage]) -> Option<Self> {
+        let mut total = DocCoverage::default();
+        for file in files {
+            total.total_items += file.coverage.total_items;
+            total.documented_items += file.coverage.documented_items;
+            total.total_items_needing_examples += file.coverage.total_items_needing_examples;
+            total.items_with_examples += file.coverage.items_with_examples;
+        }
+
+        if total.total_items == 0 && total.documented_items == 0 {
+            None
+        } else {
+            Some(total)
+        }
+    }
+}
+
+/// The documentation coverage of a single source file, as reported by `rustdoc --show-coverage`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct FileCoverage {
+    pub(crate) file: String,
+    #[serde(flatten)]
+    pub(crate) coverage: DocCoverage,
+}
+
+/// A public item of a crate without documentation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct UndocumentedItem {
+    /// the path of the item, like `krate::module::Struct::method`
+    pub(crate) path: String,
+    /// the kind of the item in the rustdoc JSON output, like `struct` or `function`
+    pub(crate) kind: String,
+    pub(crate) file: Option<String>,
+    pub(crate) line: Option<i32>,
+}
+
+/// Kinds of items which are never documented themselves.
+const IGNORED_KINDS: &[&str] = &["impl", "import", "use", "extern_crate"];
+
+/// The most undocumented items stored and shown for a release, as crates with generated code can
+/// have hundreds of thousands of them.
+pub(crate) const MAX_UNDOCUMENTED_ITEMS: usize = 1000;
+
+/// The parts of the rustdoc JSON output used to list the undocumented items, everything else is
+/// skipped while parsing it.
+#[derive(Debug, Deserialize)]
+struct RustdocJson {
+    #[serde(default)]
+    index: HashMap<String, Item>,
+    #[serde(default)]
+    paths: HashMap<String, ItemSummary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Item {
+    crate_id: Option<u64>,
+    name: Option<String>,
+    /// a string, or an object for restricted visibilities in newer format versions
+    #[serde(default)]
+    visibility: Value,
+    docs: Option<String>,
+    span: Option<Span>,
+    /// the kind of the item in older format versions
+    kind: Option<String>,
+    #[serde(default)]
+    inner: Inner,
+}
+
+#[derive(Debug, Deserialize)]
+struct Span {
+    filename: String,
+    begin: (i64, i64),
+}
+
+#[derive(Debug, Deserialize)]
+struct ItemSummary {
+    crate_id: Option<u64>,
+    path: Option<Vec<String>>,
+}
+
+/// What's used of the `inner` field of an item, whose layout changes with every format version.
+#[derive(Debug, Default)]
+struct Inner {
+    /// the single key of `inner`, which is the kind of the item in newer format versions
+    kind: Option<String>,
+    /// the IDs of the fields, variants and items of the item
+    children: Vec<String>,
+    /// the IDs of the implementations of the item
+    impls: Vec<String>,
+    /// whether the item is the implementation of a trait
+    implements_trait: bool,
+}
+
+impl<'de> Deserialize<'de> for Inner {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        // only the `inner` of one item is kept in memory at a time
+        let inner = Value::deserialize(deserializer)?;
+        let mut children = Vec::new();
+        collect_ids(&inner, &["fields", "variants", "items"], &mut children);
+        let mut impls = Vec::new();
+        collect_ids(&inner, &["impls"], &mut impls);
+        Ok(Inner {
+            kind: match inner.as_object() {
+                Some(object) if object.len() == 1 => object.keys().next().cloned(),
+                _ => None,
+            },
+            children,
+            impls,
+            implements_trait: !matches!(find_key(&inner, "trait"), Some(Value::Null) | None),
+        })
+    }
+}
+
+impl Item {
+    /// The kind of the item, from either the `kind` field of older format versions or the single
+    /// key of `inner` in newer ones.
+    fn kind(&self) -> Option<&str> {
+        self.kind.as_deref().or(self.inner.kind.as_deref())
+    }
+
+    fn is_public(&self) -> bool {
+        self.visibility.as_str() == Some("public")
+    }
+}
+
+/// Lists the public items of the local crate without documentation in the rustdoc JSON output.
+///
+/// The items of public traits are included, even though their visibility is `default` instead of
+/// `public` like the one of enum variants. The items of trait implementations are not, as they're
+/// documented on the trait.
+///
+/// Only the parts of the format which didn't change across the format versions are used, so
+/// this works for both the old (`kind` + `inner`) and the new (`inner: { kind: ... }`) layouts.
+pub(crate) fn undocumented_items(rustdoc_json: &[u8]) -> Result<Vec<UndocumentedItem>> {
+    let RustdocJson { index, paths } = serde_json::from_slice(rustdoc_json)?;
+
+    let paths: HashMap<&str, String> = paths
+        .iter()
+        .filter(|(_, summary)| summary.crate_id == Some(0))
+        .filter_map(|(id, summary)| Some((id.as_str(), summary.path.as_ref()?.join("::"))))
+        .collect();
+
+    // fields, variants and methods don't have an entry in `paths`, their path is built from the
+    // one of the item they belong to
+    let mut parents: HashMap<&str, &str> = HashMap::new();
+    let mut public_trait_items = HashSet::new();
+    for (id, item) in &index {
+        let parent = match paths.get(id.as_str()) {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let mut children: Vec<&str> = item.inner.children.iter().map(String::as_str).collect();
+        for impl_id in &item.inner.impls {
+            // the items of trait implementations are documented on the trait
+            match index.get(impl_id) {
+                Some(implementation) if !implementation.inner.implements_trait => {
+                    children.extend(implementation.inner.children.iter().map(String::as_str))
+                }
+                _ => {}
+            }
+        }
+
+        if item.kind() == Some("trait") && item.is_public() {
+            public_trait_items.extend(children.iter().copied());
+        }
+        for child in children {
+            parents.entry(child).or_insert(parent);
+        }
+    }
+
+    let mut items = Vec::new();
+    for (id, item) in &index {
+        if item.crate_id != Some(0) {
+            continue;
+        }
+        let kind = match item.kind() {
+            Some(kind) if !IGNORED_KINDS.contains(&kind) => kind,
+            _ => continue,
+        };
+        if !item.is_public() && kind != "variant" && !public_trait_items.contains(id.as_str()) {
+            continue;
+        }
+        if matches!(&item.docs, Some(docs) if !docs.trim().is_empty()) {
+            continue;
+        }
+
+        let path = match (paths.get(id.as_str()), parents.get(id.as_str()), &item.name) {
+            (Some(path), _, _) => path.clone(),
+            (None, Some(parent), Some(name)) => format!("{}::{}", parent, name),
+            (None, None, Some(name)) => name.clone(),
+            (None, _, None) => continue,
+        };
+
+        items.push(UndocumentedItem {
+            path,
+            kind: kind.to_string(),
+            file: item.span.as_ref().map(|span| span.filename.clone()),
+            line: item.span.as_ref().map(|span| span.begin.0 as i32),
+        });
+    }
+
+    items.sort_by(|a, b| (&a.file, a.line, &a.path).cmp(&(&b.file, b.line, &b.path)));
+    Ok(items)
+}
+
+/// Collects the IDs in the arrays with one of the given `keys` nested anywhere in `value`.
+fn collect_ids(value: &Value, keys: &[&str], ids: &mut Vec<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                match value.as_array() {
+                    Some(array) if keys.contains(&key.as_str()) => {
+                        ids.extend(array.iter().filter_map(id_key));
+                    }
+                    _ => collect_ids(value, keys, ids),
+                }
+            }
+        }
+        Value::Array(array) => {
+            for value in array {
+                collect_ids(value, keys, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Finds the first value with the given `key` nested anywhere in `value`.
+fn find_key<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(object) => object
+            .get(key)
+            .or_else(|| object.values().find_map(|value| find_key(value, key))),
+        _ => None,
+    }
+}
+
+/// IDs are strings in older format versions and integers in newer ones, while the keys of the
+/// index are always strings.
+fn id_key(id: &Value) -> Option<String> {
+    match id {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn item(path: &str, kind: &str, file: &str, line: i32) -> UndocumentedItem {
+        UndocumentedItem {
+            path: path.into(),
+            kind: kind.into(),
+            file: Some(file.into()),
+            line: Some(line),
+        }
+    }
+
+    #[test]
+    fn total_coverage() {
+        let file = |file: &str, total_items, documented_items| FileCoverage {
+            file: file.into(),
+            coverage: DocCoverage {
+                total_items,
+                documented_items,
+                total_items_needing_examples: 1,
+                items_with_examples: 0,
+            },
+        };
+
+        assert_eq!(DocCoverage::total(&[]), None);
+        assert_eq!(
+            DocCoverage::total(&[file("src/lib.rs", 3, 2), file("src/foo.rs", 5, 1)]),
+            Some(DocCoverage {
+                total_items: 8,
+                documented_items: 3,
+                total_items_needing_examples: 2,
+                items_with_examples: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn undocumented_items_new_format() {
+        let span = |line| json!({ "filename": "src/lib.rs", "begin": [line, 0], "end": [line, 1] });
+        let json = json!({
+            "format_version": 30,
+            "index": {
+                "0": { "crate_id": 0, "name": "foo", "visibility": "public", "docs": "The crate",
+                       "span": span(1), "inner": { "module": { "items": [1, 2, 5, 11] } } },
+                "1": { "crate_id": 0, "name": "Bar", "visibility": "public", "docs": null,
+                       "span": span(3),
+                       "inner": { "struct": { "kind": { "plain": { "fields": [3, 4] } }, "impls": [6, 8] } } },
+                "2": { "crate_id": 0, "name": "private", "visibility": "crate", "docs": null,
+                       "span": span(10), "inner": { "function": {} } },
+                "3": { "crate_id": 0, "name": "field", "visibility": "public", "docs": "  ",
+                       "span": span(4), "inner": { "struct_field": {} } },
+                "4": { "crate_id": 0, "name": "documented", "visibility": "public", "docs": "Docs",
+                       "span": span(5), "inner": { "struct_field": {} } },
+                "5": { "crate_id": 0, "name": null, "visibility": "public", "docs": null,
+                       "span": span(12), "inner": { "use": { "source": "std::fmt" } } },
+                "6": { "crate_id": 0, "name": null, "visibility": "default", "docs": null,
+                       "span": span(15), "inner": { "impl": { "trait": null, "items": [7] } } },
+                "7": { "crate_id": 0, "name": "method", "visibility": "public", "docs": null,
+                       "span": span(16), "inner": { "function": {} } },
+                "8": { "crate_id": 0, "name": null, "visibility": "default", "docs": null,
+                       "span": span(20), "inner": { "impl": { "trait": { "name": "Clone" }, "items": [9] } } },
+                "9": { "crate_id": 0, "name": "clone", "visibility": "default", "docs": null,
+                       "span": span(21), "inner": { "function": {} } },
+                "10": { "crate_id": 1, "name": "Debug", "visibility": "public", "docs": null,
+                        "span": null, "inner": { "trait": {} } },
+                "11": { "crate_id": 0, "name": "Trait", "visibility": "public", "docs": "Docs",
+                        "span": span(30), "inner": { "trait": { "items": [12], "implementations": [] } } },
+                "12": { "crate_id": 0, "name": "required", "visibility": "default", "docs": null,
+                        "span": span(31), "inner": { "function": {} } }
+            },
+            "paths": {
+                "0": { "crate_id": 0, "path": ["foo"], "kind": "module" },
+                "1": { "crate_id": 0, "path": ["foo", "Bar"], "kind": "struct" },
+                "10": { "crate_id": 1, "path": ["core", "fmt", "Debug"], "kind": "trait" },
+                "11": { "crate_id": 0, "path": ["foo", "Trait"], "kind": "trait" }
+            }
+        });
+
+        assert_eq!(
+            undocumented_items(json.to_string().as_bytes()).unwrap(),
+            vec![
+                item("foo::Bar", "struct", "src/lib.rs", 3),
+                item("foo::Bar::field", "struct_field", "src/lib.rs", 4),
+                item("foo::Bar::method", "function", "src/lib.rs", 16),
+                item("foo::Trait::required", "function", "src/lib.rs", 31),
+            ]
+        );
+    }
+
+    #[test]
+    fn undocumented_items_old_format() {
+        let json = json!({
+            "format_version": 15,
+            "index": {
+                "0:0": { "crate_id": 0, "name": "foo", "visibility": "public", "docs": null,
+                         "span": { "filename": "src/lib.rs", "begin": [1, 0], "end": [1, 0] },
+                         "kind": "module", "inner": { "items": ["0:1"] } },
+                "0:1": { "crate_id": 0, "name": "Baz", "visibility": "public", "docs": null,
+                         "span": { "filename": "src/baz.rs", "begin": [2, 0], "end": [4, 1] },
+                         "kind": "enum", "inner": { "variants": ["0:2"], "impls": [] } },
+                "0:2": { "crate_id": 0, "name": "A", "visibility": "default", "docs": null,
+                         "span": { "filename": "src/baz.rs", "begin": [3, 4], "end": [3, 5] },
+                         "kind": "variant", "inner": {} }
+            },
+            "paths": {
+                "0:0": { "crate_id": 0, "path": ["foo"], "kind": "module" },
+                "0:1": { "crate_id": 0, "path": ["foo", "Baz"], "kind": "enum" }
+            }
+        });
+
+        assert_eq!(
+            undocumented_items(json.to_string().as_bytes()).unwrap(),
+            vec![
+                item("foo::Baz", "enum", "src/baz.rs", 2),
+                item("foo::Baz::A", "variant", "src/baz.rs", 3),
+                item("foo", "module", "src/lib.rs", 1),
+            ]
+        );
+    }
+}
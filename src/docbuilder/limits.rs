@@ -1,3 +1,4 @@
+use crate::db::sandbox_overrides;
 use crate::error::Result;
 use postgres::Client;
 use serde::Serialize;
@@ -28,24 +29,24 @@ impl Limits {
     pub(crate) fn for_crate(conn: &mut Client, name: &str) -> Result<Self> {
         let mut limits = Self::default();
 
-        let res = conn.query(
-            "SELECT * FROM sandbox_overrides WHERE crate_name = $1;",
-            &[&name],
-        )?;
-        if !res.is_empty() {
-            let row = &res[0];
-            if let Some(memory) = row.get::<_, Option<i64>>("max_memory_bytes") {
-                limits.memory = memory as usize;
+        if let Some(overrides) = sandbox_overrides::get(conn, name)? {
+            if let Some(memory) = overrides.memory {
+                limits.memory = memory;
             }
-            let timeout = row.get::<_, Option<i32>>("timeout_seconds");
-            if let Some(timeout) = timeout {
-                limits.timeout = Duration::from_secs(timeout as u64);
+            if let Some(timeout) = overrides.timeout {
+                limits.timeout = timeout;
             }
-            if let Some(targets) = row.get::<_, Option<i32>>("max_targets") {
-                limits.targets = targets as usize;
-            } else if timeout.is_some() {
+            if let Some(targets) = overrides.targets {
+                limits.targets = targets;
+            } else if overrides.timeout.is_some() {
                 limits.targets = 1;
             }
+            if let Some(networking) = overrides.networking {
+                limits.networking = networking;
+            }
+            if let Some(max_log_size) = overrides.max_log_size {
+                limits.max_log_size = max_log_size;
+            }
         }
 
         Ok(limits)
@@ -119,6 +120,30 @@ mod test {
         });
     }
 
+    #[test]
+    fn networking_and_log_size() {
+        wrapper(|env| {
+            let db = env.db();
+            let krate = "needs-network";
+            db.conn().query(
+                "INSERT INTO sandbox_overrides (crate_name, networking, max_log_size_bytes)
+                 VALUES ($1, TRUE, 1024 * 1024);",
+                &[&krate],
+            )?;
+            let limits = Limits::for_crate(&mut db.conn(), krate)?;
+            assert_eq!(
+                limits,
+                Limits {
+                    networking: true,
+                    max_log_size: 1024 * 1024,
+                    ..Limits::default()
+                }
+            );
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn targets_default_to_one_with_timeout() {
         wrapper(|env| {
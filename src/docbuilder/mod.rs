@@ -1,7 +1,15 @@
+mod build_failure;
+mod coverage;
 mod crates;
 mod limits;
 mod rustwide_builder;
 
+pub(crate) use self::build_failure::BuildFailure;
+pub(crate) use self::coverage::{
+    DocCoverage, FileCoverage, UndocumentedItem, MAX_UNDOCUMENTED_ITEMS,
+};
 pub(crate) use self::limits::Limits;
-pub(crate) use self::rustwide_builder::{BuildResult, DocCoverage};
-pub use self::rustwide_builder::{PackageKind, RustwideBuilder};
+pub(crate) use self::rustwide_builder::{
+    doctest_log_path, BuildReproducibility, BuildResult, DoctestResult, TargetBuildResult,
+};
+pub use self::rustwide_builder::{DryRun, PackageKind, RustwideBuilder};
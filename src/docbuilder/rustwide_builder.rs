@@ -1,15 +1,23 @@
 use crate::db::file::add_path_into_database;
 use crate::db::{
-    add_build_into_database, add_doc_coverage, add_package_into_database,
-    add_path_into_remote_archive, update_crate_data_in_database, Pool,
+    add_build_into_database, add_build_reproducibility_into_database,
+    add_build_targets_into_database, add_doc_coverage, add_doc_coverage_details,
+    add_package_into_database, add_path_into_remote_archive, set_rustdoc_json,
+    update_crate_data_in_database, Pool,
+};
+use crate::db::{blacklist::is_blacklisted, docker_images};
+use crate::docbuilder::{
+    coverage::undocumented_items, crates::crates_from_path, BuildFailure, DocCoverage,
+    FileCoverage, Limits,
 };
-use crate::docbuilder::{crates::crates_from_path, Limits};
 use crate::error::Result;
 use crate::index::api::ReleaseData;
 use crate::repositories::RepositoryStatsUpdater;
-use crate::storage::{rustdoc_archive_path, source_archive_path};
-use crate::utils::{copy_dir_all, parse_rustc_version, queue_builder, CargoMetadata};
-use crate::{db::blacklist::is_blacklisted, utils::MetadataPackage};
+use crate::storage::{
+    download_bundles_prefix, rustdoc_archive_path, rustdoc_json_path, source_archive_path,
+};
+use crate::utils::MetadataPackage;
+use crate::utils::{copy_dir_all, parse_rustc_version, queue_builder, shell_quote, CargoMetadata};
 use crate::{Config, Context, Index, Metrics, Storage};
 use anyhow::{anyhow, bail, Error};
 use docsrs_metadata::{Metadata, DEFAULT_TARGETS, HOST_TARGET};
@@ -20,14 +28,18 @@ use rustwide::cmd::{Command, CommandError, SandboxBuilder, SandboxImage};
 use rustwide::logging::{self, LogStorage};
 use rustwide::toolchain::ToolchainError;
 use rustwide::{AlternativeRegistry, Build, Crate, Toolchain, Workspace, WorkspaceBuilder};
+use serde::Serialize;
 use serde_json::Value;
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 const USER_AGENT: &str = "docs.rs builder (https://github.com/rust-lang/docs.rs)";
 const DUMMY_CRATE_NAME: &str = "empty-library";
 const DUMMY_CRATE_VERSION: &str = "1.0.0";
+/// The sandbox image rustwide uses when no image is configured.
+const DEFAULT_DOCKER_IMAGE: &str = "ghcr.io/rust-lang/crates-build-env/linux";
 
 pub enum PackageKind<'a> {
     Local(&'a Path),
@@ -37,6 +49,10 @@ pub enum PackageKind<'a> {
 
 pub struct RustwideBuilder {
     workspace: Workspace,
+    workspace_path: PathBuf,
+    /// workspaces sharing the directory of `workspace`, whose sandboxes use the docker image
+    /// assigned to some crates instead of the default one
+    image_workspaces: HashMap<String, Workspace>,
     toolchain: Toolchain,
     config: Arc<Config>,
     db: Pool,
@@ -46,21 +62,28 @@ pub struct RustwideBuilder {
     rustc_version: String,
     repository_stats_updater: Arc<RepositoryStatsUpdater>,
     skip_build_if_exists: bool,
+    /// the prefix of the temporary directories of the builds
+    tempdir_prefix: String,
 }
 
 impl RustwideBuilder {
     pub fn init(context: &dyn Context) -> Result<Self> {
         let config = context.config()?;
+        Self::init_with_workspace(context, &config.rustwide_workspace)
+    }
 
-        let mut builder = WorkspaceBuilder::new(&config.rustwide_workspace, USER_AGENT)
+    /// Initializes a builder using the rustwide workspace at `workspace_path` instead of the
+    /// configured one, so multiple builders can run at the same time.
+    pub(crate) fn init_with_workspace(
+        context: &dyn Context,
+        workspace_path: &Path,
+    ) -> Result<Self> {
+        let config = context.config()?;
+
+        let mut builder = WorkspaceBuilder::new(workspace_path, USER_AGENT)
             .running_inside_docker(config.inside_docker);
         if let Some(custom_image) = &config.docker_image {
-            let image = match SandboxImage::local(custom_image) {
-                Ok(i) => i,
-                Err(CommandError::SandboxImageMissing(_)) => SandboxImage::remote(custom_image)?,
-                Err(err) => return Err(err.into()),
-            };
-            builder = builder.sandbox_image(image);
+            builder = builder.sandbox_image(load_sandbox_image(custom_image)?);
         }
         if cfg!(test) {
             builder = builder.fast_init(true);
@@ -75,6 +98,8 @@ impl RustwideBuilder {
 
         Ok(RustwideBuilder {
             workspace,
+            workspace_path: workspace_path.to_path_buf(),
+            image_workspaces: HashMap::new(),
             toolchain,
             config,
             db: context.pool()?,
@@ -84,13 +109,62 @@ impl RustwideBuilder {
             rustc_version: String::new(),
             repository_stats_updater: context.repository_stats_updater()?,
             skip_build_if_exists: false,
+            tempdir_prefix: queue_builder::TEMPDIR_PREFIX.into(),
         })
     }
 
+    /// The output of `rustc --version` of the installed toolchain.
+    pub(crate) fn rustc_version(&self) -> &str {
+        &self.rustc_version
+    }
+
+    /// The directory of the default rustwide workspace.
+    pub(crate) fn workspace_path(&self) -> &Path {
+        &self.workspace_path
+    }
+
     pub fn set_skip_build_if_exists(&mut self, should: bool) {
         self.skip_build_if_exists = should;
     }
 
+    /// The prefix of the temporary directories created by the builds.
+    pub(crate) fn tempdir_prefix(&self) -> &str {
+        &self.tempdir_prefix
+    }
+
+    /// Uses `prefix` for the temporary directories of the builds, so they can be told apart from
+    /// the ones of other builders running at the same time.
+    pub(crate) fn set_tempdir_prefix(&mut self, prefix: String) {
+        self.tempdir_prefix = prefix;
+    }
+
+    /// Initializes a workspace whose sandboxes run in the given docker image, unless it was
+    /// already initialized.
+    ///
+    /// rustwide configures the image of the sandboxes on the workspace, so the new workspace
+    /// uses the same directory as the default one and only differs in its image.
+    fn init_image_workspace(&mut self, image: &str) -> Result<()> {
+        if !self.image_workspaces.contains_key(image) {
+            info!("initializing a workspace for the docker image {}", image);
+            let workspace = WorkspaceBuilder::new(&self.workspace_path, USER_AGENT)
+                .running_inside_docker(self.config.inside_docker)
+                .sandbox_image(load_sandbox_image(image)?)
+                .fast_init(true)
+                .init()
+                .map_err(FailureError::compat)?;
+            self.image_workspaces.insert(image.to_string(), workspace);
+        }
+        Ok(())
+    }
+
+    /// Returns the workspace initialized for `image` by [`RustwideBuilder::init_image_workspace`],
+    /// or the default workspace if `image` is `None`.
+    fn workspace_for_image(&self, image: Option<&str>) -> &Workspace {
+        image
+            .and_then(|image| self.image_workspaces.get(image))
+            .unwrap_or(&self.workspace)
+    }
+
     fn prepare_sandbox(&self, limits: &Limits) -> SandboxBuilder {
         SandboxBuilder::new()
             .cpu_limit(self.config.build_cpu_limit.map(|limit| limit as f32))
@@ -312,7 +386,16 @@ impl RustwideBuilder {
             }
         }
 
-        let mut build_dir = self.workspace.build_dir(&format!("{}-{}", name, version));
+        let custom_image = docker_images::image_for_crate(&mut conn, name)?;
+        let docker_image = custom_image
+            .clone()
+            .unwrap_or_else(|| default_docker_image(&self.config));
+        if let Some(image) = &custom_image {
+            self.init_image_workspace(image)?;
+        }
+        let workspace = self.workspace_for_image(custom_image.as_deref());
+
+        let mut build_dir = workspace.build_dir(&format!("{}-{}", name, version));
         build_dir.purge().map_err(FailureError::compat)?;
 
         let krate = match kind {
@@ -322,10 +405,10 @@ impl RustwideBuilder {
                 Crate::registry(AlternativeRegistry::new(registry), name, version)
             }
         };
-        krate.fetch(&self.workspace).map_err(FailureError::compat)?;
+        krate.fetch(workspace).map_err(FailureError::compat)?;
 
         let local_storage = tempfile::Builder::new()
-            .prefix(queue_builder::TEMPDIR_PREFIX)
+            .prefix(&self.tempdir_prefix)
             .tempdir()?;
 
         let successful = build_dir
@@ -348,9 +431,11 @@ impl RustwideBuilder {
 
                     // If the build fails with the lockfile given, try using only the dependencies listed in Cargo.toml.
                     let cargo_lock = build.host_source_dir().join("Cargo.lock");
+                    let mut lockfile_regenerated = false;
                     if !res.result.successful && cargo_lock.exists() {
                         info!("removing lockfile and reattempting build");
-                        std::fs::remove_file(cargo_lock)?;
+                        lockfile_regenerated = true;
+                        std::fs::remove_file(&cargo_lock)?;
                         Command::new(&self.workspace, self.toolchain.cargo())
                             .cd(build.host_source_dir())
                             .args(&["generate-lockfile", "-Zno-index-update"])
@@ -380,6 +465,40 @@ impl RustwideBuilder {
                         }
                     }
 
+                    // doctests can't be run when cross-compiling
+                    let mut doctest_log = None;
+                    if has_docs
+                        && self.config.run_doctests
+                        && metadata.doctests
+                        && default_target == HOST_TARGET
+                    {
+                        debug!("running the doctests of {} {}", name, version);
+                        let (doctests, log) = self.run_doctests(build, &metadata, &limits);
+                        res.result.doctests = Some(doctests);
+                        doctest_log = Some(log);
+                    }
+
+                    // everything besides the cargo arguments of the targets needed to run the
+                    // build again, the lockfile is the one the build ended up using
+                    let reproducibility = BuildReproducibility {
+                        docker_image: docker_image.clone(),
+                        environment: res.invocation.environment.clone(),
+                        rustdoc_flags: res.invocation.rustdoc_flags.clone(),
+                        cargo_lock: std::fs::read_to_string(&cargo_lock).ok(),
+                        lockfile_regenerated,
+                    };
+
+                    // the results and logs of every target, starting with the default one
+                    let mut target_builds = vec![(
+                        TargetBuildResult {
+                            target: res.target.clone(),
+                            successful: res.result.successful,
+                            failure_reason: res.result.failure_reason,
+                            cargo_args: res.invocation.args.clone(),
+                        },
+                        res.build_log,
+                    )];
+
                     let mut algs = HashSet::new();
                     if has_docs {
                         debug!("adding documentation for the default target to the database");
@@ -396,14 +515,14 @@ impl RustwideBuilder {
                         // Limit the number of targets so that no one can try to build all 200000 possible targets
                         for target in other_targets.into_iter().take(limits.targets()) {
                             debug!("building package {} {} for {}", name, version, target);
-                            self.build_target(
+                            target_builds.push(self.build_target(
                                 target,
                                 build,
                                 &limits,
                                 local_storage.path(),
                                 &mut successful_targets,
                                 &metadata,
-                            )?;
+                            )?);
                         }
                         let (_, new_alg) = add_path_into_remote_archive(
                             &self.storage,
@@ -425,6 +544,11 @@ impl RustwideBuilder {
                         files_list
                     };
 
+                    // the offline bundles are built again from the new archives when they're
+                    // downloaded the next time
+                    self.storage
+                        .delete_prefix(&download_bundles_prefix(name, version))?;
+
                     let has_examples = build.host_source_dir().join("examples").is_dir();
                     if res.result.successful {
                         self.metrics.successful_builds.inc();
@@ -461,13 +585,49 @@ impl RustwideBuilder {
                         true,
                     )?;
 
-                    if let Some(doc_coverage) = res.doc_coverage {
+                    if let Some(doc_coverage) = DocCoverage::total(&res.file_coverage) {
                         add_doc_coverage(&mut conn, release_id, doc_coverage)?;
                     }
+                    let undocumented = match &res.rustdoc_json {
+                        Some(json) => undocumented_items(&json.content).unwrap_or_else(|err| {
+                            info!("error when trying to list the undocumented items: {}", err);
+                            Vec::new()
+                        }),
+                        None => Vec::new(),
+                    };
+                    add_doc_coverage_details(
+                        &mut conn,
+                        release_id,
+                        &res.file_coverage,
+                        &undocumented,
+                    )?;
+
+                    let json_path = rustdoc_json_path(name, version);
+                    match res.rustdoc_json.take() {
+                        Some(json) if has_docs => {
+                            self.storage.store_one(json_path, json.content)?;
+                            set_rustdoc_json(&mut conn, release_id, Some(json.format_version))?;
+                        }
+                        _ => {
+                            self.storage.delete_prefix(&json_path)?;
+                            set_rustdoc_json(&mut conn, release_id, None)?;
+                        }
+                    }
 
                     let build_id = add_build_into_database(&mut conn, release_id, &res.result)?;
-                    let build_log_path = format!("build-logs/{}/{}.txt", build_id, default_target);
-                    self.storage.store_one(build_log_path, res.build_log)?;
+                    let (target_results, build_logs): (Vec<_>, Vec<_>) =
+                        target_builds.into_iter().unzip();
+                    add_build_targets_into_database(&mut conn, build_id, &target_results)?;
+                    add_build_reproducibility_into_database(&mut conn, build_id, &reproducibility)?;
+                    for (target, build_log) in target_results.iter().zip(build_logs) {
+                        let build_log_path =
+                            format!("build-logs/{}/{}.txt", build_id, target.target);
+                        self.storage.store_one(build_log_path, build_log)?;
+                    }
+                    if let Some(doctest_log) = doctest_log {
+                        self.storage
+                            .store_one(doctest_log_path(build_id), doctest_log)?;
+                    }
 
                     // Some crates.io crate data is mutable, so we proactively update it during a release
                     match self.index.api().get_crate_data(name) {
@@ -496,7 +656,7 @@ impl RustwideBuilder {
 
         build_dir.purge().map_err(FailureError::compat)?;
         krate
-            .purge_from_cache(&self.workspace)
+            .purge_from_cache(workspace)
             .map_err(FailureError::compat)?;
         local_storage.close()?;
         Ok(successful)
@@ -510,8 +670,9 @@ impl RustwideBuilder {
         local_storage: &Path,
         successful_targets: &mut Vec<String>,
         metadata: &Metadata,
-    ) -> Result<()> {
+    ) -> Result<(TargetBuildResult, String)> {
         let target_res = self.execute_build(target, false, build, limits, metadata, false)?;
+        let mut successful = false;
         if target_res.result.successful {
             // Cargo is not giving any error and not generating documentation of some crates
             // when we use a target compile options. Check documentation exists before
@@ -520,9 +681,54 @@ impl RustwideBuilder {
                 debug!("adding documentation for target {} to the database", target,);
                 self.copy_docs(&build.host_target_dir(), local_storage, target, false)?;
                 successful_targets.push(target.to_string());
+                successful = true;
             }
         }
-        Ok(())
+        Ok((
+            TargetBuildResult {
+                target: target_res.target,
+                successful,
+                failure_reason: target_res.result.failure_reason,
+                cargo_args: target_res.invocation.args,
+            },
+            target_res.build_log,
+        ))
+    }
+
+    /// Runs the doctests of the crate for the host target in the sandbox of the build, returning
+    /// their results and log.
+    fn run_doctests(
+        &self,
+        build: &Build,
+        metadata: &Metadata,
+        limits: &Limits,
+    ) -> (DoctestResult, String) {
+        let mut cargo_args = Vec::new();
+        if let Some(cpu_limit) = self.config.build_cpu_limit {
+            cargo_args.push(format!("-j{}", cpu_limit));
+        }
+
+        let mut storage = LogStorage::new(LevelFilter::Info);
+        storage.set_max_size(limits.max_log_size());
+
+        // the summaries are counted while running, they can be cut off from a long log
+        let mut doctests = DoctestResult::default();
+        let result = logging::capture(&storage, || {
+            let mut command = build
+                .cargo()
+                .timeout(Some(limits.timeout()))
+                .no_output_timeout(None);
+            for (key, val) in metadata.environment_variables() {
+                command = command.env(key, val);
+            }
+            command
+                .args(&metadata.doctest_args(&cargo_args))
+                .process_lines(&mut |line, _| doctests.add_summary(line))
+                .run()
+        });
+        doctests.successful = result.is_ok();
+
+        (doctests, storage.to_string())
     }
 
     fn get_coverage(
@@ -531,7 +737,7 @@ impl RustwideBuilder {
         build: &Build,
         metadata: &Metadata,
         limits: &Limits,
-    ) -> Result<Option<DocCoverage>> {
+    ) -> Result<Vec<FileCoverage>> {
         let rustdoc_flags = vec![
             "--output-format".to_string(),
             "json".to_string(),
@@ -539,45 +745,95 @@ impl RustwideBuilder {
         ];
 
         #[derive(serde::Deserialize)]
-        struct FileCoverage {
+        struct RawFileCoverage {
             total: i32,
             with_docs: i32,
             total_examples: i32,
             with_examples: i32,
         }
 
-        let mut coverage = DocCoverage {
-            total_items: 0,
-            documented_items: 0,
-            total_items_needing_examples: 0,
-            items_with_examples: 0,
-        };
+        let mut files = Vec::new();
 
-        self.prepare_command(build, target, metadata, limits, rustdoc_flags)?
+        let invocation = cargo_invocation(&self.config, target, metadata, rustdoc_flags);
+        self.prepare_command(build, target, limits, &invocation)?
             .process_lines(&mut |line, _| {
                 if line.starts_with('{') && line.ends_with('}') {
-                    let parsed = match serde_json::from_str::<HashMap<String, FileCoverage>>(line) {
-                        Ok(parsed) => parsed,
-                        Err(_) => return,
-                    };
-                    for file in parsed.values() {
-                        coverage.total_items += file.total;
-                        coverage.documented_items += file.with_docs;
-                        coverage.total_items_needing_examples += file.total_examples;
-                        coverage.items_with_examples += file.with_examples;
+                    let parsed =
+                        match serde_json::from_str::<HashMap<String, RawFileCoverage>>(line) {
+                            Ok(parsed) => parsed,
+                            Err(_) => return,
+                        };
+                    for (file, coverage) in parsed {
+                        files.push(FileCoverage {
+                            file,
+                            coverage: DocCoverage {
+                                total_items: coverage.total,
+                                documented_items: coverage.with_docs,
+                                total_items_needing_examples: coverage.total_examples,
+                                items_with_examples: coverage.with_examples,
+                            },
+                        });
                     }
                 }
             })
             .log_output(false)
             .run()?;
 
-        Ok(
-            if coverage.total_items == 0 && coverage.documented_items == 0 {
-                None
-            } else {
-                Some(coverage)
-            },
-        )
+        files.sort_by(|a, b| a.file.cmp(&b.file));
+        Ok(files)
+    }
+
+    fn get_rustdoc_json(
+        &self,
+        target: &str,
+        build: &Build,
+        metadata: &Metadata,
+        limits: &Limits,
+        cargo_metadata: &CargoMetadata,
+    ) -> Result<Option<RustdocJson>> {
+        let name = match cargo_metadata.root().library_name() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let rustdoc_flags = vec!["--output-format".to_string(), "json".to_string()];
+
+        let invocation = cargo_invocation(&self.config, target, metadata, rustdoc_flags);
+        self.prepare_command(build, target, limits, &invocation)?
+            .log_output(false)
+            .run()?;
+
+        // proc-macros are documented in `target/doc`, see `execute_build`
+        let doc_dir = if metadata.proc_macro {
+            build.host_target_dir().join("doc")
+        } else {
+            build.host_target_dir().join(target).join("doc")
+        };
+        let path = doc_dir.join(format!("{}.json", name));
+        let content = if path.is_file() {
+            Some(std::fs::read(&path)?)
+        } else {
+            None
+        };
+        // the JSON output of the dependencies is in the same directory, remove it so it doesn't
+        // end up next to the HTML documentation
+        if doc_dir.is_dir() {
+            std::fs::remove_dir_all(&doc_dir)?;
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Header {
+            format_version: i32,
+        }
+
+        content
+            .map(|content| {
+                let header: Header = serde_json::from_slice(&content)?;
+                Ok(RustdocJson {
+                    format_version: header.format_version,
+                    content,
+                })
+            })
+            .transpose()
     }
 
     fn execute_build(
@@ -592,16 +848,7 @@ impl RustwideBuilder {
         let cargo_metadata =
             CargoMetadata::load(&self.workspace, &self.toolchain, &build.host_source_dir())?;
 
-        let mut rustdoc_flags = vec![if create_essential_files {
-            "--emit=unversioned-shared-resources,toolchain-shared-resources"
-        } else {
-            "--emit=invocation-specific"
-        }
-        .to_string()];
-        rustdoc_flags.extend(vec![
-            "--resource-suffix".to_string(),
-            format!("-{}", parse_rustc_version(&self.rustc_version)?),
-        ]);
+        let rustdoc_flags = rustdoc_flags(&self.rustc_version, create_essential_files)?;
 
         let mut storage = LogStorage::new(LevelFilter::Info);
         storage.set_max_size(limits.max_log_size());
@@ -609,19 +856,38 @@ impl RustwideBuilder {
         // we have to run coverage before the doc-build because currently it
         // deletes the doc-target folder.
         // https://github.com/rust-lang/cargo/issues/9447
-        let doc_coverage = match self.get_coverage(target, build, metadata, limits) {
-            Ok(cov) => cov,
+        let file_coverage = match self.get_coverage(target, build, metadata, limits) {
+            Ok(files) => files,
             Err(err) => {
                 log::info!("error when trying to get coverage: {}", err);
                 log::info!("continuing anyways.");
-                None
+                Vec::new()
+            }
+        };
+
+        // the JSON output is only stored for the default target
+        let rustdoc_json = if is_default_target && !create_essential_files {
+            match self.get_rustdoc_json(target, build, metadata, limits, &cargo_metadata) {
+                Ok(json) => json,
+                Err(err) => {
+                    log::info!("error when trying to get the rustdoc JSON output: {}", err);
+                    log::info!("continuing anyways.");
+                    None
+                }
             }
+        } else {
+            None
         };
 
-        let successful = logging::capture(&storage, || {
-            self.prepare_command(build, target, metadata, limits, rustdoc_flags)
+        let invocation = cargo_invocation(&self.config, target, metadata, rustdoc_flags);
+        let result = logging::capture(&storage, || {
+            self.prepare_command(build, target, limits, &invocation)
                 .and_then(|command| command.run().map_err(Error::from))
-                .is_ok()
+        });
+        let successful = result.is_ok();
+        let build_log = storage.to_string();
+        let failure_reason = result.err().map(|err| {
+            BuildFailure::classify(err.downcast_ref::<CommandError>(), limits, &build_log)
         });
 
         // For proc-macros, cargo will put the output in `target/doc`.
@@ -646,10 +912,14 @@ impl RustwideBuilder {
                 rustc_version: self.rustc_version.clone(),
                 docsrs_version: format!("docsrs {}", crate::BUILD_VERSION),
                 successful,
+                failure_reason,
+                doctests: None,
             },
-            doc_coverage,
+            file_coverage,
+            rustdoc_json,
             cargo_metadata,
-            build_log: storage.to_string(),
+            invocation,
+            build_log,
             target: target.to_string(),
         })
     }
@@ -658,9 +928,8 @@ impl RustwideBuilder {
         &self,
         build: &'ws Build,
         target: &str,
-        metadata: &Metadata,
         limits: &Limits,
-        mut rustdoc_flags_extras: Vec<String>,
+        invocation: &CargoInvocation,
     ) -> Result<Command<'ws, 'pl>> {
         // If the explicit target is not a tier one target, we need to install it.
         if !docsrs_metadata::DEFAULT_TARGETS.contains(&target) {
@@ -670,61 +939,16 @@ impl RustwideBuilder {
                 .map_err(FailureError::compat)?;
         }
 
-        // Add docs.rs specific arguments
-        let mut cargo_args = vec![
-            // We know that `metadata` unconditionally passes `-Z rustdoc-map`.
-            // Don't copy paste this, since that fact is not stable and may change in the future.
-            "-Zunstable-options".into(),
-            // Add `target` so that if a dependency has target-specific docs, this links to them properly.
-            //
-            // Note that this includes the target even if this is the default, since the dependency
-            // may have a different default (and the web backend will take care of redirecting if
-            // necessary).
-            //
-            // FIXME: host-only crates like proc-macros should probably not have this passed? but #1417 should make it OK
-            format!(
-                r#"--config=doc.extern-map.registries.crates-io="https://docs.rs/{{pkg_name}}/{{version}}/{}""#,
-                target
-            ),
-        ];
-        if let Some(cpu_limit) = self.config.build_cpu_limit {
-            cargo_args.push(format!("-j{}", cpu_limit));
-        }
-        // Cargo has a series of frightening bugs around cross-compiling proc-macros:
-        // - Passing `--target` causes RUSTDOCFLAGS to fail to be passed 🤦
-        // - Passing `--target` will *create* `target/{target-name}/doc` but will put the docs in `target/doc` anyway
-        // As a result, it's not possible for us to support cross-compiling proc-macros.
-        // However, all these caveats unfortunately still apply when `{target-name}` is the host.
-        // So, only pass `--target` for crates that aren't proc-macros.
-        //
-        // Originally, this had a simpler check `target != HOST_TARGET`, but *that* was buggy when `HOST_TARGET` wasn't the same as the default target.
-        // Rather than trying to keep track of it all, only special case proc-macros, which are what we actually care about.
-        if !metadata.proc_macro {
-            cargo_args.push("--target".into());
-            cargo_args.push(target.into());
-        };
-
-        #[rustfmt::skip]
-        const UNCONDITIONAL_ARGS: &[&str] = &[
-            "--static-root-path", "/",
-            "--cap-lints", "warn",
-            "--disable-per-crate-search",
-            "--extern-html-root-takes-precedence",
-        ];
-
-        rustdoc_flags_extras.extend(UNCONDITIONAL_ARGS.iter().map(|&s| s.to_owned()));
-        let cargo_args = metadata.cargo_args(&cargo_args, &rustdoc_flags_extras);
-
         let mut command = build
             .cargo()
             .timeout(Some(limits.timeout()))
             .no_output_timeout(None);
 
-        for (key, val) in metadata.environment_variables() {
+        for (key, val) in &invocation.environment {
             command = command.env(key, val);
         }
 
-        Ok(command.args(&cargo_args))
+        Ok(command.args(&invocation.args))
     }
 
     fn copy_docs(
@@ -751,20 +975,7 @@ impl RustwideBuilder {
     }
 
     fn should_build(&self, conn: &mut Client, name: &str, version: &str) -> Result<bool> {
-        if self.skip_build_if_exists {
-            // Check whether no successful builds are present in the database.
-            Ok(conn
-                .query(
-                    "SELECT 1 FROM crates, releases, builds
-                     WHERE crates.id = releases.crate_id AND releases.id = builds.rid
-                       AND crates.name = $1 AND releases.version = $2
-                       AND builds.build_status = TRUE;",
-                    &[&name, &version],
-                )?
-                .is_empty())
-        } else {
-            Ok(true)
-        }
+        Ok(!self.skip_build_if_exists || !has_successful_build(conn, name, version)?)
     }
 
     fn get_repo(&self, metadata: &MetadataPackage) -> Result<Option<i32>> {
@@ -774,32 +985,407 @@ impl RustwideBuilder {
     }
 }
 
+/// The docker image builds run in when no other image is assigned to the crate.
+fn default_docker_image(config: &Config) -> String {
+    config
+        .docker_image
+        .clone()
+        .unwrap_or_else(|| DEFAULT_DOCKER_IMAGE.into())
+}
+
+fn has_successful_build(conn: &mut Client, name: &str, version: &str) -> Result<bool> {
+    Ok(!conn
+        .query(
+            "SELECT 1 FROM crates, releases, builds
+             WHERE crates.id = releases.crate_id AND releases.id = builds.rid
+               AND crates.name = $1 AND releases.version = $2
+               AND builds.build_status = TRUE;",
+            &[&name, &version],
+        )?
+        .is_empty())
+}
+
+/// The flags rustdoc is run with to build the documentation, besides the unconditional ones.
+fn rustdoc_flags(rustc_version: &str, create_essential_files: bool) -> Result<Vec<String>> {
+    Ok(vec![
+        if create_essential_files {
+            "--emit=unversioned-shared-resources,toolchain-shared-resources"
+        } else {
+            "--emit=invocation-specific"
+        }
+        .to_string(),
+        "--resource-suffix".to_string(),
+        format!("-{}", parse_rustc_version(rustc_version)?),
+    ])
+}
+
+/// The arguments and environment cargo is run with to document `target`.
+fn cargo_invocation(
+    config: &Config,
+    target: &str,
+    metadata: &Metadata,
+    mut rustdoc_flags_extras: Vec<String>,
+) -> CargoInvocation {
+    // Add docs.rs specific arguments
+    let mut cargo_args = vec![
+        // We know that `metadata` unconditionally passes `-Z rustdoc-map`.
+        // Don't copy paste this, since that fact is not stable and may change in the future.
+        "-Zunstable-options".into(),
+        // Add `target` so that if a dependency has target-specific docs, this links to them properly.
+        //
+        // Note that this includes the target even if this is the default, since the dependency
+        // may have a different default (and the web backend will take care of redirecting if
+        // necessary).
+        //
+        // FIXME: host-only crates like proc-macros should probably not have this passed? but #1417 should make it OK
+        format!(
+            r#"--config=doc.extern-map.registries.crates-io="https://docs.rs/{{pkg_name}}/{{version}}/{}""#,
+            target
+        ),
+    ];
+    if let Some(cpu_limit) = config.build_cpu_limit {
+        cargo_args.push(format!("-j{}", cpu_limit));
+    }
+    // Cargo has a series of frightening bugs around cross-compiling proc-macros:
+    // - Passing `--target` causes RUSTDOCFLAGS to fail to be passed 🤦
+    // - Passing `--target` will *create* `target/{target-name}/doc` but will put the docs in `target/doc` anyway
+    // As a result, it's not possible for us to support cross-compiling proc-macros.
+    // However, all these caveats unfortunately still apply when `{target-name}` is the host.
+    // So, only pass `--target` for crates that aren't proc-macros.
+    //
+    // Originally, this had a simpler check `target != HOST_TARGET`, but *that* was buggy when `HOST_TARGET` wasn't the same as the default target.
+    // Rather than trying to keep track of it all, only special case proc-macros, which are what we actually care about.
+    if !metadata.proc_macro {
+        cargo_args.push("--target".into());
+        cargo_args.push(target.into());
+    };
+
+    #[rustfmt::skip]
+    const UNCONDITIONAL_ARGS: &[&str] = &[
+        "--static-root-path", "/",
+        "--cap-lints", "warn",
+        "--disable-per-crate-search",
+        "--extern-html-root-takes-precedence",
+    ];
+
+    rustdoc_flags_extras.extend(UNCONDITIONAL_ARGS.iter().map(|&s| s.to_owned()));
+    CargoInvocation {
+        args: metadata.cargo_args(&cargo_args, &rustdoc_flags_extras),
+        environment: metadata
+            .environment_variables()
+            .into_iter()
+            .map(|(key, val)| (key.to_string(), val))
+            .collect(),
+        rustdoc_flags: rustdoc_flags_extras,
+    }
+}
+
+/// Uses the docker image if it's available locally, and pulls it otherwise.
+fn load_sandbox_image(name: &str) -> Result<SandboxImage> {
+    match SandboxImage::local(name) {
+        Ok(image) => Ok(image),
+        Err(CommandError::SandboxImageMissing(_)) => Ok(SandboxImage::remote(name)?),
+        Err(err) => Err(err.into()),
+    }
+}
+
 struct FullBuildResult {
     result: BuildResult,
     target: String,
     cargo_metadata: CargoMetadata,
-    doc_coverage: Option<DocCoverage>,
+    /// the documentation coverage of each source file
+    file_coverage: Vec<FileCoverage>,
+    rustdoc_json: Option<RustdocJson>,
+    invocation: CargoInvocation,
     build_log: String,
 }
 
-#[derive(Clone, Copy)]
-pub(crate) struct DocCoverage {
-    /// The total items that could be documented in the current crate, used to calculate
-    /// documentation coverage.
-    pub(crate) total_items: i32,
-    /// The items of the crate that are documented, used to calculate documentation coverage.
-    pub(crate) documented_items: i32,
-    /// The total items that could have code examples in the current crate, used to calculate
-    /// documentation coverage.
-    pub(crate) total_items_needing_examples: i32,
-    /// The items of the crate that have a code example, used to calculate documentation coverage.
-    pub(crate) items_with_examples: i32,
+/// How cargo is run to document a target.
+struct CargoInvocation {
+    args: Vec<String>,
+    environment: BTreeMap<String, String>,
+    /// the flags passed to rustdoc through the `build.rustdocflags` config in `args`, besides the
+    /// ones from the metadata of the crate
+    rustdoc_flags: Vec<String>,
+}
+
+/// What building a release would do, see [`DryRun::new`].
+pub struct DryRun {
+    name: String,
+    version: String,
+    /// why the release wouldn't be built
+    skipped: Option<&'static str>,
+    docker_image: String,
+    rustc_version: String,
+    limits: Limits,
+    /// the targets which would be built, starting with the default one
+    targets: Vec<(String, CargoInvocation)>,
+}
+
+impl DryRun {
+    /// Returns what building a release would do, without building anything.
+    ///
+    /// Unlike [`RustwideBuilder::init`], this doesn't initialize the rustwide workspace: it only
+    /// needs the database, the configuration, the toolchain installed in the workspace and, for
+    /// crates from a registry, the index checked out already. Only the `.crate` file of those is
+    /// downloaded, up to its manifest, to read their docs.rs metadata.
+    pub fn new(
+        context: &dyn Context,
+        name: &str,
+        version: &str,
+        kind: PackageKind<'_>,
+        skip_build_if_exists: bool,
+    ) -> Result<Self> {
+        let config = context.config()?;
+        let mut conn = context.pool()?.get()?;
+
+        let skipped = if is_blacklisted(&mut conn, name)? {
+            Some("the crate is blacklisted")
+        } else if skip_build_if_exists && has_successful_build(&mut conn, name, version)? {
+            Some("a successful build already exists")
+        } else {
+            None
+        };
+        let limits = Limits::for_crate(&mut conn, name)?;
+        let docker_image = docker_images::image_for_crate(&mut conn, name)?
+            .unwrap_or_else(|| default_docker_image(&config));
+
+        let metadata = match kind {
+            PackageKind::Local(path) => Metadata::from_crate_root(path)?,
+            PackageKind::CratesIo | PackageKind::Registry(_) => {
+                let registry = match kind {
+                    PackageKind::Registry(registry) => Some(registry),
+                    _ => None,
+                };
+                // unlike `context.index()`, this never clones the index
+                let index = Index::open_existing(
+                    config.registry_index_path.clone(),
+                    config.registry_url.clone(),
+                )?;
+                if index.repository_url() != registry {
+                    bail!(
+                        "the index is the one of {}, not of {}",
+                        index.repository_url().unwrap_or("crates.io"),
+                        registry.unwrap_or("crates.io"),
+                    );
+                }
+                index
+                    .api()
+                    .get_manifest(index.crate_download_url(name, version)?, name, version)?
+                    .parse::<Metadata>()?
+            }
+        };
+
+        let rustc_version = installed_rustc_version(&config).map_err(|err| {
+            err.context("failed to detect the rustc version, is the toolchain installed?")
+        })?;
+        let rustdoc_flags = rustdoc_flags(&rustc_version, false)?;
+
+        let docsrs_metadata::BuildTargets {
+            default_target,
+            other_targets,
+        } = metadata.targets(config.include_default_targets);
+        let targets = std::iter::once(default_target)
+            .chain(other_targets.into_iter().take(limits.targets()))
+            .map(|target| {
+                (
+                    target.to_string(),
+                    cargo_invocation(&config, target, &metadata, rustdoc_flags.clone()),
+                )
+            })
+            .collect();
+
+        Ok(DryRun {
+            name: name.into(),
+            version: version.into(),
+            skipped,
+            docker_image,
+            rustc_version,
+            limits,
+            targets,
+        })
+    }
+
+    /// Returns what building a local crate would do, see [`DryRun::new`].
+    pub fn for_local(
+        context: &dyn Context,
+        path: &Path,
+        skip_build_if_exists: bool,
+    ) -> Result<Self> {
+        #[derive(serde::Deserialize)]
+        struct Manifest {
+            package: Package,
+        }
+        #[derive(serde::Deserialize)]
+        struct Package {
+            name: String,
+            version: String,
+        }
+
+        let manifest: Manifest =
+            toml::from_str(&std::fs::read_to_string(path.join("Cargo.toml"))?)?;
+        Self::new(
+            context,
+            &manifest.package.name,
+            &manifest.package.version,
+            PackageKind::Local(path),
+            skip_build_if_exists,
+        )
+    }
+}
+
+/// The output of `rustc --version` of the configured toolchain, as it's installed in the rustwide
+/// workspace. rustwide installs rustup in the workspace, whose proxies pick the toolchain.
+fn installed_rustc_version(config: &Config) -> Result<String> {
+    let output = std::process::Command::new(
+        config
+            .rustwide_workspace
+            .join("cargo-home")
+            .join("bin")
+            .join("rustc"),
+    )
+    .arg(format!("+{}", config.toolchain))
+    .arg("--version")
+    .env("CARGO_HOME", config.rustwide_workspace.join("cargo-home"))
+    .env("RUSTUP_HOME", config.rustwide_workspace.join("rustup-home"))
+    .output()?;
+    if !output.status.success() {
+        bail!(
+            "`rustc --version` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+impl fmt::Display for DryRun {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} {}", self.name, self.version)?;
+        if let Some(skipped) = self.skipped {
+            writeln!(f, "not built: {}", skipped)?;
+        }
+        writeln!(f, "rustc version: {}", self.rustc_version)?;
+        writeln!(f, "docker image: {}", self.docker_image)?;
+        writeln!(
+            f,
+            "limits: {} bytes of memory, {} seconds timeout, {} targets, networking {}, \
+             {} bytes of build log",
+            self.limits.memory(),
+            self.limits.timeout().as_secs(),
+            self.limits.targets(),
+            if self.limits.networking() {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            self.limits.max_log_size(),
+        )?;
+
+        for (i, (target, invocation)) in self.targets.iter().enumerate() {
+            writeln!(f)?;
+            if i == 0 {
+                writeln!(f, "# {} (default target)", target)?;
+            } else {
+                writeln!(
+                    f,
+                    "# {} (only built if the default target is documented)",
+                    target
+                )?;
+            }
+            for (key, value) in &invocation.environment {
+                writeln!(f, "environment: {}={}", key, shell_quote(value))?;
+            }
+            let quote_all =
+                |args: &[String]| args.iter().map(|arg| shell_quote(arg)).collect::<Vec<_>>();
+            writeln!(
+                f,
+                "rustdoc flags: {}",
+                quote_all(&invocation.rustdoc_flags).join(" ")
+            )?;
+            writeln!(f, "cargo {}", quote_all(&invocation.args).join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The rustdoc JSON output of a crate.
+struct RustdocJson {
+    /// the `format_version` field of the output
+    format_version: i32,
+    content: Vec<u8>,
 }
 
 pub(crate) struct BuildResult {
     pub(crate) rustc_version: String,
     pub(crate) docsrs_version: String,
     pub(crate) successful: bool,
+    pub(crate) failure_reason: Option<BuildFailure>,
+    /// the results of the doctests, if they were run
+    pub(crate) doctests: Option<DoctestResult>,
+}
+
+/// The results of running the doctests of a crate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) struct DoctestResult {
+    /// whether the doctests compiled and passed
+    pub(crate) successful: bool,
+    pub(crate) passed: i32,
+    pub(crate) failed: i32,
+    pub(crate) ignored: i32,
+}
+
+impl DoctestResult {
+    /// Adds the counts of a summary line of libtest, like
+    /// `test result: ok. 3 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out`.
+    fn add_summary(&mut self, line: &str) {
+        let counts = match line.trim().strip_prefix("test result: ") {
+            Some(summary) => summary.split_once(". ").map_or("", |(_, counts)| counts),
+            None => return,
+        };
+        for count in counts.split("; ") {
+            let (number, kind) = match count.split_once(' ') {
+                Some((number, kind)) => match number.parse::<i32>() {
+                    Ok(number) => (number, kind),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            match kind {
+                "passed" => self.passed += number,
+                "failed" => self.failed += number,
+                "ignored" => self.ignored += number,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// The path of the log of the doctests of a build in the storage, next to the build logs of its
+/// targets.
+pub(crate) fn doctest_log_path(build_id: i32) -> String {
+    format!("build-logs/{}/doctests.txt", build_id)
+}
+
+/// The result of building the documentation for one of the targets of a build.
+pub(crate) struct TargetBuildResult {
+    pub(crate) target: String,
+    pub(crate) successful: bool,
+    pub(crate) failure_reason: Option<BuildFailure>,
+    /// the arguments cargo was run with
+    pub(crate) cargo_args: Vec<String>,
+}
+
+/// What's needed to run a build again, next to the cargo arguments of its targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BuildReproducibility {
+    pub(crate) docker_image: String,
+    /// the environment variables cargo was run with
+    pub(crate) environment: BTreeMap<String, String>,
+    pub(crate) rustdoc_flags: Vec<String>,
+    /// the lockfile the build used, if there was one
+    pub(crate) cargo_lock: Option<String>,
+    /// whether the lockfile of the crate was replaced because the build failed with it
+    pub(crate) lockfile_regenerated: bool,
 }
 
 #[cfg(test)]
@@ -807,6 +1393,28 @@ mod tests {
     use super::*;
     use crate::test::{assert_redirect, assert_success, wrapper};
 
+    #[test]
+    fn doctest_summary() {
+        let mut doctests = DoctestResult::default();
+        for line in &[
+            "running 4 tests",
+            "test src/lib.rs - foo (line 3) ... ok",
+            "test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.52s",
+            "test result: ok. 3 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out",
+        ] {
+            doctests.add_summary(line);
+        }
+        assert_eq!(
+            doctests,
+            DoctestResult {
+                successful: false,
+                passed: 5,
+                failed: 1,
+                ignored: 1,
+            }
+        );
+    }
+
     #[test]
     #[ignore]
     fn test_build_crate() {
@@ -1082,6 +1690,55 @@ mod tests {
         });
     }
 
+    #[test]
+    #[ignore]
+    fn test_dry_run() {
+        wrapper(|env| {
+            let dir = tempfile::tempdir()?;
+            std::fs::create_dir(dir.path().join("src"))?;
+            std::fs::write(dir.path().join("src/lib.rs"), "")?;
+            std::fs::write(
+                dir.path().join("Cargo.toml"),
+                r#"
+                    [package]
+                    name = "dry-run"
+                    version = "0.1.0"
+
+                    [features]
+                    foo = []
+
+                    [package.metadata.docs.rs]
+                    features = ["foo"]
+                    targets = ["x86_64-unknown-linux-gnu", "i686-pc-windows-msvc"]
+                "#,
+            )?;
+
+            let mut builder = RustwideBuilder::init(env).unwrap();
+            builder.update_toolchain()?;
+            let output = DryRun::for_local(env, dir.path(), false)?.to_string();
+            assert!(output.starts_with("dry-run 0.1.0\n"));
+            assert!(!output.contains("not built"));
+            assert!(output.contains("# x86_64-unknown-linux-gnu (default target)\n"));
+            assert!(output.contains("# i686-pc-windows-msvc (only built"));
+            assert!(output.contains("environment: DOCS_RS=1\n"));
+            assert!(output.contains("cargo rustdoc --lib -Zrustdoc-map --features foo"));
+
+            // nothing was built
+            let builds: i64 = env
+                .db()
+                .conn()
+                .query_one("SELECT COUNT(*) FROM builds", &[])?
+                .get(0);
+            assert_eq!(builds, 0);
+
+            crate::db::blacklist::add_crate(&mut env.db().conn(), "dry-run")?;
+            let output = DryRun::for_local(env, dir.path(), false)?.to_string();
+            assert!(output.contains("not built: the crate is blacklisted\n"));
+
+            Ok(())
+        });
+    }
+
     #[test]
     #[ignore]
     fn test_rustflags_are_passed_to_build_script() {
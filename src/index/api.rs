@@ -3,6 +3,8 @@ use chrono::{DateTime, Utc};
 use reqwest::header::{HeaderValue, ACCEPT, USER_AGENT};
 use semver::Version;
 use serde::Deserialize;
+use std::io::Read;
+use std::path::Path;
 use url::Url;
 
 use crate::error::Result;
@@ -182,4 +184,69 @@ impl Api {
 
         Ok(result)
     }
+
+    /// Downloads the `.crate` file of a release from `download_url`, see
+    /// [`Index::crate_download_url`](super::Index::crate_download_url), and returns its
+    /// `Cargo.toml`, without unpacking anything else.
+    pub(crate) fn get_manifest(
+        &self,
+        download_url: Url,
+        name: &str,
+        version: &str,
+    ) -> Result<String> {
+        // crates.io answers with the URL of the file instead of redirecting to it when JSON is
+        // accepted
+        let response = self
+            .client
+            .get(download_url)
+            .header(ACCEPT, HeaderValue::from_static("*/*"))
+            .send()?
+            .error_for_status()?;
+        manifest_from_crate_file(response, name, version)
+            .with_context(|| format!("Failed to read the manifest of {}-{}", name, version))
+    }
+}
+
+/// Reads `Cargo.toml` from a gzipped `.crate` file.
+fn manifest_from_crate_file(crate_file: impl Read, name: &str, version: &str) -> Result<String> {
+    let path = format!("{}-{}/Cargo.toml", name, version);
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(crate_file));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(&path) {
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            return Ok(manifest);
+        }
+    }
+    Err(anyhow!("{} is missing", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+
+    #[test]
+    fn read_manifest_from_crate_file() {
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, content) in &[
+            ("foo-0.1.0/src/lib.rs", "pub fn foo() {}"),
+            ("foo-0.1.0/Cargo.toml", "[package]\nname = \"foo\""),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        let crate_file = tar.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(
+            manifest_from_crate_file(&*crate_file, "foo", "0.1.0").unwrap(),
+            "[package]\nname = \"foo\""
+        );
+        assert!(manifest_from_crate_file(&*crate_file, "foo", "0.2.0").is_err());
+    }
 }
@@ -13,11 +13,14 @@ pub struct Index {
     path: PathBuf,
     api: Api,
     repository_url: Option<String>,
+    /// the `dl` field of the config, see [`Index::crate_download_url`]
+    download_url: String,
 }
 
 #[derive(Debug, serde::Deserialize, Clone)]
 #[serde(rename_all = "kebab-case")]
 struct IndexConfig {
+    dl: String,
     #[serde(default)]
     api: Option<Url>,
 }
@@ -55,6 +58,7 @@ impl Index {
             path,
             api,
             repository_url: Some(url),
+            download_url: config.dl,
         })
     }
 
@@ -69,6 +73,22 @@ impl Index {
             path,
             api,
             repository_url: None,
+            download_url: config.dl,
+        })
+    }
+
+    /// Opens the index checked out at `path` as it is, without cloning or fetching it.
+    pub(crate) fn open_existing(path: PathBuf, repository_url: Option<String>) -> Result<Self> {
+        let repo = git2::Repository::open(&path).with_context(|| {
+            format!("the registry index isn't checked out in {}", path.display())
+        })?;
+        let config = load_config(&repo).context("loading registry config")?;
+        let api = Api::new(config.api).context("initialising registry api client")?;
+        Ok(Self {
+            path,
+            api,
+            repository_url,
+            download_url: config.dl,
         })
     }
 
@@ -121,4 +141,70 @@ impl Index {
     pub fn repository_url(&self) -> Option<&str> {
         self.repository_url.as_deref()
     }
+
+    /// The URL of the `.crate` file of a release in the registry of this index.
+    pub(crate) fn crate_download_url(&self, name: &str, version: &str) -> Result<Url> {
+        crate_download_url(&self.download_url, name, version)
+    }
+}
+
+/// Builds the URL of a `.crate` file from the `dl` field of the config of an index, as cargo does:
+/// the markers in the field are replaced, and fields without markers are the prefix of
+/// `/{crate}/{version}/download`.
+fn crate_download_url(dl: &str, name: &str, version: &str) -> Result<Url> {
+    const MARKERS: &[&str] = &["{crate}", "{version}", "{prefix}", "{lowerprefix}"];
+
+    let url = if MARKERS.iter().any(|marker| dl.contains(marker)) {
+        let prefix = match name.len() {
+            1 => "1".to_string(),
+            2 => "2".to_string(),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        };
+        dl.replace("{crate}", name)
+            .replace("{version}", version)
+            .replace("{prefix}", &prefix)
+            .replace("{lowerprefix}", &prefix.to_lowercase())
+    } else {
+        format!("{}/{}/{}/download", dl.trim_end_matches('/'), name, version)
+    };
+    Url::parse(&url).with_context(|| format!("invalid download url {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_urls() {
+        let url = |dl, name| crate_download_url(dl, name, "1.0.0").unwrap().to_string();
+        assert_eq!(
+            url("https://static.crates.io/crates", "serde"),
+            "https://static.crates.io/crates/serde/1.0.0/download"
+        );
+        assert_eq!(
+            url("https://example.com/dl/", "a"),
+            "https://example.com/dl/a/1.0.0/download"
+        );
+        assert_eq!(
+            url(
+                "https://example.com/{prefix}/{crate}-{version}.crate",
+                "Serde"
+            ),
+            "https://example.com/Se/rd/Serde-1.0.0.crate"
+        );
+        assert_eq!(
+            url("https://example.com/{lowerprefix}/{crate}", "Foo"),
+            "https://example.com/3/f/Foo"
+        );
+        assert!(crate_download_url("not a url", "a", "1.0.0").is_err());
+    }
+
+    #[test]
+    fn open_existing_doesnt_clone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        assert!(Index::open_existing(path.clone(), None).is_err());
+        assert!(!path.exists());
+    }
 }
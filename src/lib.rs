@@ -2,9 +2,12 @@
 //! documentation of crates for the Rust Programming Language.
 #![allow(clippy::cognitive_complexity)]
 
-pub use self::build_queue::BuildQueue;
+pub use self::build_queue::{
+    BuildQueue, QuarantinedCrate, QueueFilter, QueuePosition, QueuedCrate,
+};
 pub use self::config::Config;
 pub use self::context::Context;
+pub use self::docbuilder::DryRun;
 pub use self::docbuilder::PackageKind;
 pub use self::docbuilder::RustwideBuilder;
 pub use self::index::Index;
@@ -24,8 +24,14 @@ metrics! {
         prioritized_crates_count: IntGauge,
         /// Number of crates that failed to build
         failed_crates_count: IntGauge,
+        /// Number of crates that aren't built because their build panicked
+        quarantined_crates_count: IntGauge,
         /// Whether the build queue is locked
         queue_is_locked: IntGauge,
+        /// Number of builders paused because there isn't enough free disk space
+        pub(crate) paused_builders: IntGauge,
+        /// The free disk space in the rustwide workspace and the temporary directory, in bytes
+        pub(crate) free_disk_space: IntGaugeVec["location"],
 
         /// The number of idle database connections
         idle_db_connections: IntGauge,
@@ -67,10 +73,26 @@ metrics! {
         pub(crate) failed_builds: IntCounter,
         /// Number of builds that did not complete due to not being a library
         pub(crate) non_library_builds: IntCounter,
+        /// Number of builds that panicked, quarantining their crate
+        pub(crate) quarantined_builds: IntCounter,
 
         /// Number of files uploaded to the storage backend
         pub(crate) uploaded_files_total: IntCounter,
 
+        /// The total size of the archive indexes in the local cache, in bytes
+        pub(crate) archive_index_cache_size: IntGauge,
+        /// Number of archive index lookups served from the local cache
+        pub(crate) archive_index_cache_hits: IntCounter,
+        /// Number of archive indexes downloaded into the local cache
+        pub(crate) archive_index_cache_misses: IntCounter,
+        /// Number of archive indexes removed from the local cache to stay below its maximum size
+        pub(crate) archive_index_cache_evictions: IntCounter,
+
+        /// Number of orphaned objects deleted from the storage by the garbage collector
+        pub(crate) gc_storage_deleted_objects: IntCounter,
+        /// The size of the orphaned objects deleted from the storage, in bytes
+        pub(crate) gc_storage_reclaimed_bytes: IntCounter,
+
         /// The number of attempted files that failed due to a memory limit
         pub(crate) html_rewrite_ooms: IntCounter,
 
@@ -164,6 +186,8 @@ impl Metrics {
         self.prioritized_crates_count
             .set(queue.prioritized_count()? as i64);
         self.failed_crates_count.set(queue.failed_count()? as i64);
+        self.quarantined_crates_count
+            .set(queue.quarantined_count()? as i64);
 
         self.recently_accessed_releases.gather(self);
         self.gather_system_performance();
@@ -0,0 +1,418 @@
+use super::archive_index;
+use crate::error::Result;
+use crate::utils::report_error;
+use crate::Metrics;
+use anyhow::Context as _;
+use path_slash::PathExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+use walkdir::WalkDir;
+
+/// Local cache for the indexes of the remote archives.
+///
+/// The total size of the cached indexes is kept below a configurable maximum by evicting the
+/// least recently used ones. Indexes are handed out as open files, so an index that is evicted
+/// while another thread is searching it stays readable until that thread is done.
+///
+/// The lock on the cache state is only held to update it, never while reading or writing files.
+pub(crate) struct ArchiveIndexCache {
+    root: PathBuf,
+    max_size: u64,
+    metrics: Arc<Metrics>,
+    state: Arc<Mutex<CacheState>>,
+}
+
+struct CacheEntry {
+    size: u64,
+    last_access: u64,
+}
+
+struct CacheState {
+    /// the cached indexes, by their remote path
+    entries: HashMap<String, CacheEntry>,
+    /// the remote paths of the cached indexes, ordered by their last access
+    by_last_access: BTreeMap<u64, String>,
+    total_size: u64,
+    next_access: u64,
+    /// the access of indexes left over from a previous run, counting down from where
+    /// `next_access` started, so they're evicted before any index used since
+    next_leftover_access: u64,
+}
+
+impl Default for CacheState {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_last_access: BTreeMap::new(),
+            total_size: 0,
+            next_access: u64::MAX / 2,
+            next_leftover_access: u64::MAX / 2 - 1,
+        }
+    }
+}
+
+impl CacheState {
+    /// Marks the index as used, adding it if it wasn't accounted for yet, or if its size
+    /// changed.
+    fn touch(&mut self, key: &str, size: u64) {
+        let access = self.next_access;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.size == size => {
+                self.by_last_access.remove(&entry.last_access);
+                self.by_last_access.insert(access, key.to_owned());
+                entry.last_access = access;
+                self.next_access += 1;
+            }
+            _ => self.insert(key.to_owned(), size),
+        }
+    }
+
+    fn insert(&mut self, key: String, size: u64) {
+        let access = self.next_access;
+        self.next_access += 1;
+        self.insert_at(key, size, access);
+    }
+
+    /// Adds an index left over from a previous run as less recently used than all the known
+    /// ones, unless it's already known.
+    fn insert_leftover(&mut self, key: String, size: u64) {
+        if self.entries.contains_key(&key) {
+            return;
+        }
+        let access = self.next_leftover_access;
+        self.next_leftover_access -= 1;
+        self.insert_at(key, size, access);
+    }
+
+    fn insert_at(&mut self, key: String, size: u64, access: u64) {
+        self.remove(&key);
+
+        self.by_last_access.insert(access, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                size,
+                last_access: access,
+            },
+        );
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_last_access.remove(&entry.last_access);
+            self.total_size -= entry.size;
+        }
+    }
+
+    fn pop_least_recently_used(&mut self) -> Option<String> {
+        let key = self.by_last_access.values().next()?.clone();
+        self.remove(&key);
+        Some(key)
+    }
+
+    /// Removes the least recently used indexes until the total size is below `max_size`, and
+    /// returns them so their files can be removed once the lock is released.
+    ///
+    /// The most recently used index is never evicted, even if it's bigger than the whole cache.
+    fn evict(&mut self, max_size: u64, metrics: &Metrics) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_size > max_size && self.entries.len() > 1 {
+            match self.pop_least_recently_used() {
+                Some(key) => evicted.push(key),
+                None => break,
+            }
+        }
+
+        metrics
+            .archive_index_cache_evictions
+            .inc_by(evicted.len() as u64);
+        metrics.archive_index_cache_size.set(self.total_size as i64);
+        evicted
+    }
+}
+
+impl ArchiveIndexCache {
+    /// Creates the cache. Indexes left over from a previous run are only accounted for once
+    /// they're used, or once [`ArchiveIndexCache::scan_in_background`] found them.
+    pub(crate) fn new(root: PathBuf, max_size: u64, metrics: Arc<Metrics>) -> Self {
+        Self {
+            root,
+            max_size,
+            metrics,
+            state: Arc::new(Mutex::new(CacheState::default())),
+        }
+    }
+
+    /// Accounts for the indexes left over from a previous run in a background thread, so they
+    /// are evicted too.
+    pub(crate) fn scan_in_background(&self) {
+        let root = self.root.clone();
+        let max_size = self.max_size;
+        let metrics = self.metrics.clone();
+        let state = self.state.clone();
+
+        let spawned = thread::Builder::new()
+            .name("archive index cache scanner".into())
+            .spawn(move || {
+                if let Err(err) = scan_leftovers(&root, max_size, &metrics, &state) {
+                    report_error(&err.context("failed to scan the archive index cache"));
+                }
+            });
+        if let Err(err) = spawned {
+            report_error(&anyhow::Error::from(err).context("failed to start the cache scanner"));
+        }
+    }
+
+    /// Opens the local index for the remote index at `remote_index_path`, calling `fetch` to get
+    /// the remote index if it's not cached yet.
+    pub(crate) fn open(
+        &self,
+        remote_index_path: &str,
+        fetch: impl FnOnce() -> Result<Vec<u8>>,
+    ) -> Result<fs::File> {
+        let local_index_path = self.root.join(remote_index_path);
+
+        if let Some(file) = archive_index::open_local(&local_index_path)? {
+            let size = file.metadata()?.len();
+            let evicted = {
+                let mut state = self.state.lock().unwrap();
+                state.touch(remote_index_path, size);
+                state.evict(self.max_size, &self.metrics)
+            };
+            remove_files(&self.root, &evicted)?;
+
+            self.metrics.archive_index_cache_hits.inc();
+            return Ok(file);
+        }
+
+        // somebody else removed the file, or it was never downloaded.
+        self.state.lock().unwrap().remove(remote_index_path);
+
+        // Concurrent downloads of the same index are fine, as the file is replaced atomically.
+        self.metrics.archive_index_cache_misses.inc();
+        let remote_index = fetch()?;
+        self.insert(remote_index_path, &remote_index)
+    }
+
+    /// Stores the local version of `remote_index` in the cache, and returns the opened file.
+    pub(crate) fn insert(&self, remote_index_path: &str, remote_index: &[u8]) -> Result<fs::File> {
+        let local_index_path = self.root.join(remote_index_path);
+        archive_index::store_local(remote_index, &local_index_path)?;
+        let file = fs::File::open(&local_index_path).context("could not open file")?;
+        let size = file.metadata()?.len();
+
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            state.insert(remote_index_path.to_owned(), size);
+            state.evict(self.max_size, &self.metrics)
+        };
+        remove_files(&self.root, &evicted)?;
+
+        Ok(file)
+    }
+
+    #[cfg(test)]
+    fn scan_leftovers(&self) -> Result<()> {
+        scan_leftovers(&self.root, self.max_size, &self.metrics, &self.state)
+    }
+}
+
+/// Adds the index files in `root` which aren't accounted for yet to the cache, and evicts
+/// indexes if the cache is too big now.
+fn scan_leftovers(
+    root: &Path,
+    max_size: u64,
+    metrics: &Metrics,
+    state: &Mutex<CacheState>,
+) -> Result<()> {
+    let mut leftovers = Vec::new();
+    if root.exists() {
+        for entry in WalkDir::new(root) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // evicted while scanning
+                Err(err)
+                    if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) =>
+                {
+                    continue
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let key = entry
+                .path()
+                .strip_prefix(root)?
+                .to_slash()
+                .context("non UTF-8 path in the archive index cache")?
+                .into_owned();
+            leftovers.push((metadata.modified()?, key, metadata.len()));
+        }
+    }
+
+    // We don't know when the leftovers were last accessed, so they're ordered by their
+    // modification time, the most recently modified one being added first.
+    leftovers.sort();
+    let evicted = {
+        let mut state = state.lock().unwrap();
+        for (_, key, size) in leftovers.into_iter().rev() {
+            state.insert_leftover(key, size);
+        }
+        state.evict(max_size, metrics)
+    };
+    remove_files(root, &evicted)
+}
+
+fn remove_files(root: &Path, keys: &[String]) -> Result<()> {
+    for key in keys {
+        match fs::remove_file(root.join(key)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::archive_index::find_in_file;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    fn remote_index(files: &[&str]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for file in files {
+            zip.start_file(
+                *file,
+                FileOptions::default().compression_method(zip::CompressionMethod::Zstd),
+            )
+            .unwrap();
+            zip.write_all(b"content").unwrap();
+        }
+        let mut zip = zip.finish().unwrap();
+
+        let mut index = Vec::new();
+        archive_index::create(&mut zip, &mut index).unwrap();
+        index
+    }
+
+    #[test]
+    fn cache_hits_and_misses() {
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let cache = ArchiveIndexCache::new(dir.path().to_owned(), std::u64::MAX, metrics.clone());
+
+        let index = remote_index(&["a.html"]);
+        let file = cache.open("a.zip.index", || Ok(index.clone())).unwrap();
+        assert!(find_in_file(&file, "a.html").unwrap().is_some());
+        assert_eq!(metrics.archive_index_cache_misses.get(), 1);
+
+        let file = cache
+            .open("a.zip.index", || panic!("should not fetch again"))
+            .unwrap();
+        assert!(find_in_file(&file, "a.html").unwrap().is_some());
+        assert_eq!(metrics.archive_index_cache_hits.get(), 1);
+        assert_eq!(
+            metrics.archive_index_cache_size.get() as u64,
+            fs::metadata(dir.path().join("a.zip.index")).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+
+        let index = remote_index(&["file.html"]);
+        let mut local = Vec::new();
+        archive_index::convert_to_local(&index, &mut local).unwrap();
+        let size = local.len() as u64;
+
+        // room for two indexes
+        let cache = ArchiveIndexCache::new(dir.path().to_owned(), size * 2, metrics.clone());
+        cache.insert("a.zip.index", &index).unwrap();
+        cache.insert("b.zip.index", &index).unwrap();
+        cache
+            .open("a.zip.index", || panic!("a should be cached"))
+            .unwrap();
+
+        // `b` is the least recently used one
+        let file = cache.insert("c.zip.index", &index).unwrap();
+        assert!(dir.path().join("a.zip.index").exists());
+        assert!(!dir.path().join("b.zip.index").exists());
+        assert!(dir.path().join("c.zip.index").exists());
+        assert_eq!(metrics.archive_index_cache_evictions.get(), 1);
+        assert_eq!(metrics.archive_index_cache_size.get() as u64, size * 2);
+
+        // an opened index is still readable after being evicted
+        cache.insert("d.zip.index", &index).unwrap();
+        cache.insert("e.zip.index", &index).unwrap();
+        assert!(!dir.path().join("c.zip.index").exists());
+        assert!(find_in_file(&file, "file.html").unwrap().is_some());
+
+        // evicted indexes are downloaded again
+        let mut fetched = false;
+        cache
+            .open("b.zip.index", || {
+                fetched = true;
+                Ok(index.clone())
+            })
+            .unwrap();
+        assert!(fetched);
+    }
+
+    #[test]
+    fn accounts_for_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = remote_index(&["file.html"]);
+        let mut local = Vec::new();
+        archive_index::convert_to_local(&index, &mut local).unwrap();
+        let size = local.len() as u64;
+
+        fs::create_dir_all(dir.path().join("rustdoc/krate")).unwrap();
+        for name in ["0.1.0", "0.2.0", "0.3.0"] {
+            fs::write(
+                dir.path().join(format!("rustdoc/krate/{}.zip.index", name)),
+                &local,
+            )
+            .unwrap();
+        }
+
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let cache = ArchiveIndexCache::new(dir.path().to_owned(), size * 2, metrics.clone());
+        // leftovers are accounted for when they're used, without downloading them again
+        cache
+            .open("rustdoc/krate/0.1.0.zip.index", || {
+                panic!("should be cached")
+            })
+            .unwrap();
+        assert_eq!(metrics.archive_index_cache_size.get() as u64, size);
+
+        // the least recently used leftover is evicted once all of them are found
+        cache.scan_leftovers().unwrap();
+        assert_eq!(metrics.archive_index_cache_size.get() as u64, size * 2);
+        assert_eq!(metrics.archive_index_cache_evictions.get(), 1);
+        assert!(dir.path().join("rustdoc/krate/0.1.0.zip.index").exists());
+
+        // the remaining ones are used without downloading them again
+        let remaining = ["0.1.0", "0.2.0", "0.3.0"]
+            .iter()
+            .map(|name| format!("rustdoc/krate/{}.zip.index", name))
+            .filter(|path| dir.path().join(path).exists())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining.len(), 2);
+        for path in remaining {
+            cache.open(&path, || panic!("should be cached")).unwrap();
+        }
+    }
+}
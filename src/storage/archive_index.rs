@@ -1,12 +1,11 @@
 use crate::error::Result;
-use crate::storage::{compression::CompressionAlgorithm, FileRange};
-use anyhow::{bail, Context as _};
+use crate::storage::{compression::CompressionAlgorithm, dedup::ContentHash, FileRange};
+use anyhow::{anyhow, bail, Context as _};
 use memmap2::MmapOptions;
-use serde::de::DeserializeSeed;
-use serde::de::{IgnoredAny, MapAccess, Visitor};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::fmt;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 use std::{fs, io};
 
@@ -14,18 +13,36 @@ use std::{fs, io};
 pub(crate) struct FileInfo {
     range: FileRange,
     compression: CompressionAlgorithm,
+    /// Set when the file is stored deduplicated in its own blob, instead of inside the archive.
+    /// The range is relative to that blob then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<ContentHash>,
 }
 
 impl FileInfo {
+    pub(crate) fn new(
+        range: FileRange,
+        compression: CompressionAlgorithm,
+        blob: Option<ContentHash>,
+    ) -> Self {
+        Self {
+            range,
+            compression,
+            blob,
+        }
+    }
     pub(crate) fn range(&self) -> FileRange {
         self.range.clone()
     }
     pub(crate) fn compression(&self) -> CompressionAlgorithm {
         self.compression
     }
+    pub(crate) fn blob(&self) -> Option<&ContentHash> {
+        self.blob.as_ref()
+    }
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 struct Index {
     files: HashMap<String, FileInfo>,
 }
@@ -47,122 +64,221 @@ pub(crate) fn create<R: io::Read + io::Seek, W: io::Write>(
                 range: FileRange::new(zf.data_start(), zf.data_start() + zf.compressed_size() - 1),
                 compression: match zf.compression() {
                     zip::CompressionMethod::Bzip2 => CompressionAlgorithm::Bzip2,
+                    zip::CompressionMethod::Zstd => CompressionAlgorithm::Zstd,
                     c => bail!("unsupported compression algorithm {} in zip-file", c),
                 },
+                blob: None,
             },
         );
     }
 
+    write_remote(files, writer)
+}
+
+/// Writes the index stored next to the remote archive, in CBOR.
+pub(crate) fn write_remote<W: io::Write>(
+    files: HashMap<String, FileInfo>,
+    writer: &mut W,
+) -> Result<()> {
     serde_cbor::to_writer(writer, &Index { files }).context("serialization error")
 }
 
-pub(crate) fn find_in_slice(bytes: &[u8], search_for: &str) -> Result<Option<FileInfo>> {
-    let mut deserializer = serde_cbor::Deserializer::from_slice(bytes);
+/// The local copy of the index uses its own format, so lookups don't need to walk the whole
+/// CBOR map. The file starts with a header (magic bytes and the number of entries), followed by
+/// fixed-size entries sorted by path, followed by the paths themselves.
+/// All numbers are little endian.
+///
+/// Every entry consists of:
+/// * `u32`: offset of the path, relative to the start of the paths
+/// * `u32`: length of the path
+/// * `u64`: start of the range of the file in the archive
+/// * `u64`: end of the range of the file in the archive (inclusive)
+/// * `u32`: compression algorithm
+/// * `u32`: flags, `1` if the file is stored deduplicated in its own blob
+/// * `[u8; 32]`: hash of the contents of the deduplicated file, zeroes otherwise
+const LOCAL_INDEX_MAGIC: &[u8; 8] = b"DRSIDX02";
+const LOCAL_HEADER_SIZE: usize = 12;
+const LOCAL_ENTRY_SIZE: usize = 64;
+/// The first version of the local format didn't support deduplicated files, its entries end after
+/// the compression algorithm. It's still read, so existing caches don't have to be thrown away.
+const LOCAL_INDEX_MAGIC_V1: &[u8; 8] = b"DRSIDX01";
+const LOCAL_ENTRY_SIZE_V1: usize = 28;
 
-    /// This visitor will just find the `files` element in the top-level map.
-    /// Then it will call the `FindFileVisitor` that should find the actual
-    /// FileInfo for the path we are searching for.
-    struct FindFileListVisitor {
-        search_for: String,
-    }
+const LOCAL_FLAG_BLOB: u32 = 1;
 
-    impl<'de> Visitor<'de> for FindFileListVisitor {
-        type Value = Option<FileInfo>;
+/// Parses the CBOR index stored next to the remote archive.
+pub(crate) fn parse_remote(remote_index: &[u8]) -> Result<HashMap<String, FileInfo>> {
+    let index: Index = serde_cbor::from_slice(remote_index).context("deserialization error")?;
+    Ok(index.files)
+}
 
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            write!(formatter, "a map with a 'files' key")
-        }
+/// Converts the CBOR index stored next to the remote archive into the local index format.
+pub(crate) fn convert_to_local<W: io::Write>(remote_index: &[u8], writer: &mut W) -> Result<()> {
+    let mut files: Vec<_> = parse_remote(remote_index)?.into_iter().collect();
+    files.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
 
-        fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
-        where
-            V: MapAccess<'de>,
-        {
-            /// This visitor will walk the full `files` map and search for
-            /// the path we want to have.
-            /// Return value is just the `FileInfo` we want to have, or
-            /// `None`.
-            struct FindFileVisitor {
-                search_for: String,
-            }
+    writer.write_all(LOCAL_INDEX_MAGIC)?;
+    writer.write_all(&u32::try_from(files.len())?.to_le_bytes())?;
 
-            impl<'de> DeserializeSeed<'de> for FindFileVisitor {
-                type Value = Option<FileInfo>;
-                fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
-                where
-                    D: Deserializer<'de>,
-                {
-                    deserializer.deserialize_map(self)
-                }
+    let mut offset: u32 = 0;
+    for (path, info) in &files {
+        let len = u32::try_from(path.len())?;
+        writer.write_all(&offset.to_le_bytes())?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&info.range.start().to_le_bytes())?;
+        writer.write_all(&info.range.end().to_le_bytes())?;
+        writer.write_all(&(info.compression as u32).to_le_bytes())?;
+        match &info.blob {
+            Some(hash) => {
+                writer.write_all(&LOCAL_FLAG_BLOB.to_le_bytes())?;
+                writer.write_all(hash)?;
             }
-
-            impl<'de> Visitor<'de> for FindFileVisitor {
-                type Value = Option<FileInfo>;
-                fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                    write!(
-                        formatter,
-                        "a map with path => FileInfo, searching for path {:?}",
-                        self.search_for
-                    )
-                }
-                fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
-                where
-                    V: MapAccess<'de>,
-                {
-                    while let Some(key) = map.next_key::<&str>()? {
-                        if key == self.search_for {
-                            let value = map.next_value::<FileInfo>()?;
-                            // skip over the rest of the data without really parsing it.
-                            // If we don't do this the serde_cbor deserializer fails because not
-                            // the whole map is consumed.
-                            while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
-                            return Ok(Some(value));
-                        } else {
-                            // skip parsing the FileInfo structure when the key doesn't match.
-                            map.next_value::<IgnoredAny>()?;
-                        }
-                    }
-
-                    Ok(None)
-                }
+            None => {
+                writer.write_all(&0u32.to_le_bytes())?;
+                writer.write_all(&ContentHash::default())?;
             }
+        }
+        offset = offset.checked_add(len).context("archive index too big")?;
+    }
 
-            while let Some(key) = map.next_key::<&str>()? {
-                if key == "files" {
-                    return map.next_value_seed(FindFileVisitor {
-                        search_for: self.search_for,
-                    });
-                }
-            }
+    for (path, _) in &files {
+        writer.write_all(path.as_bytes())?;
+    }
 
-            Ok(None)
-        }
+    Ok(())
+}
+
+/// Converts the remote index and stores it at `local_path`, replacing any existing file.
+///
+/// The file is replaced atomically, so concurrent lookups never see a partially written index.
+pub(crate) fn store_local(remote_index: &[u8], local_path: &Path) -> Result<()> {
+    let parent = local_path.parent().context("index path without parent")?;
+    fs::create_dir_all(parent)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    convert_to_local(remote_index, &mut io::BufWriter::new(&mut file))?;
+    file.persist(local_path)?;
+    Ok(())
+}
+
+/// Checks whether the file at `local_path` uses the current local index format.
+///
+/// Index files downloaded by older versions of docs.rs are a plain copy of the remote CBOR index.
+#[cfg(test)]
+pub(crate) fn is_local_format(local_path: &Path) -> Result<bool> {
+    let mut file = fs::File::open(local_path).context("could not open file")?;
+    has_local_magic(&mut file)
+}
+
+fn has_local_magic(file: &mut fs::File) -> Result<bool> {
+    let mut magic = [0; LOCAL_INDEX_MAGIC.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == LOCAL_INDEX_MAGIC || &magic == LOCAL_INDEX_MAGIC_V1),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Opens the local index file at `local_path`, or returns `None` if it doesn't exist.
+///
+/// Files in the legacy format are rewritten to the current local format first. The legacy index
+/// is read through the same handle its format was checked on, so concurrently migrating the same
+/// file is fine.
+pub(crate) fn open_local(local_path: &Path) -> Result<Option<fs::File>> {
+    let mut file = match fs::File::open(local_path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context("could not open file"),
+    };
+    if has_local_magic(&mut file)? {
+        return Ok(Some(file));
     }
 
-    impl<'de> DeserializeSeed<'de> for FindFileListVisitor {
-        type Value = Option<FileInfo>;
+    let mut remote_index = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut remote_index)
+        .context("could not read file")?;
+    store_local(&remote_index, local_path)?;
+    Ok(Some(
+        fs::File::open(local_path).context("could not open file")?,
+    ))
+}
 
-        fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
-        where
-            D: Deserializer<'de>,
-        {
-            deserializer.deserialize_map(self)
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+pub(crate) fn find_in_slice(bytes: &[u8], search_for: &str) -> Result<Option<FileInfo>> {
+    let entry_size = match bytes.get(..LOCAL_INDEX_MAGIC.len()) {
+        Some(magic) if magic == LOCAL_INDEX_MAGIC => LOCAL_ENTRY_SIZE,
+        Some(magic) if magic == LOCAL_INDEX_MAGIC_V1 => LOCAL_ENTRY_SIZE_V1,
+        _ => bail!("unknown archive index format"),
+    };
+    let count = read_u32(bytes, LOCAL_INDEX_MAGIC.len()).context("truncated archive index")?;
+    let paths_start = LOCAL_HEADER_SIZE + count as usize * entry_size;
+
+    let path_at = |idx: usize| -> Result<&[u8]> {
+        let entry = LOCAL_HEADER_SIZE + idx * entry_size;
+        let offset = read_u32(bytes, entry).context("truncated archive index")? as usize;
+        let len = read_u32(bytes, entry + 4).context("truncated archive index")? as usize;
+        bytes
+            .get(paths_start + offset..paths_start + offset + len)
+            .context("truncated archive index")
+    };
+
+    // binary search over the sorted entries
+    let (mut low, mut high) = (0, count as usize);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match path_at(mid)?.cmp(search_for.as_bytes()) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => {
+                let entry = LOCAL_HEADER_SIZE + mid * entry_size;
+                let start = read_u64(bytes, entry + 8).context("truncated archive index")?;
+                let end = read_u64(bytes, entry + 16).context("truncated archive index")?;
+                let compression = read_u32(bytes, entry + 24).context("truncated archive index")?;
+                let blob = if entry_size == LOCAL_ENTRY_SIZE
+                    && read_u32(bytes, entry + 28).context("truncated archive index")?
+                        & LOCAL_FLAG_BLOB
+                        != 0
+                {
+                    Some(
+                        bytes
+                            .get(entry + 32..entry + 64)
+                            .context("truncated archive index")?
+                            .try_into()?,
+                    )
+                } else {
+                    None
+                };
+
+                return Ok(Some(FileInfo {
+                    range: FileRange::new(start, end),
+                    compression: (compression as i32).try_into().map_err(|alg| {
+                        anyhow!("invalid compression algorithm {} in archive index", alg)
+                    })?,
+                    blob,
+                }));
+            }
         }
     }
 
-    Ok(FindFileListVisitor {
-        search_for: search_for.to_string(),
-    }
-    .deserialize(&mut deserializer)?)
+    Ok(None)
 }
 
-pub(crate) fn find_in_file<P: AsRef<Path>>(
-    archive_index_path: P,
-    search_for: &str,
-) -> Result<Option<FileInfo>> {
-    let file = fs::File::open(archive_index_path).context("could not open file")?;
+pub(crate) fn find_in_file(file: &fs::File, search_for: &str) -> Result<Option<FileInfo>> {
     let mmap = unsafe {
         MmapOptions::new()
-            .map(&file)
+            .map(file)
             .context("could not create memory map")?
     };
 
@@ -191,8 +307,10 @@ mod tests {
         archive.write_all(&objectcontent).unwrap();
         tf = archive.finish().unwrap();
 
+        let mut remote = Vec::new();
+        create(&mut tf, &mut remote).unwrap();
         let mut buf = Vec::new();
-        create(&mut tf, &mut buf).unwrap();
+        convert_to_local(&remote, &mut buf).unwrap();
 
         let fi = find_in_slice(&buf, "testfile1").unwrap().unwrap();
         assert_eq!(fi.range, FileRange::new(39, 459));
@@ -200,4 +318,168 @@ mod tests {
 
         assert!(find_in_slice(&buf, "some_other_file").unwrap().is_none());
     }
+
+    #[test]
+    fn index_records_compression_per_file() {
+        let mut tf = tempfile::tempfile().unwrap();
+
+        let objectcontent: Vec<u8> = (0..255).collect();
+
+        let mut archive = zip::ZipWriter::new(tf);
+        for (name, method) in [
+            ("old_file", zip::CompressionMethod::Bzip2),
+            ("new_file", zip::CompressionMethod::Zstd),
+        ] {
+            archive
+                .start_file(name, FileOptions::default().compression_method(method))
+                .unwrap();
+            archive.write_all(&objectcontent).unwrap();
+        }
+        tf = archive.finish().unwrap();
+
+        let mut remote = Vec::new();
+        create(&mut tf, &mut remote).unwrap();
+        let mut buf = Vec::new();
+        convert_to_local(&remote, &mut buf).unwrap();
+
+        let old = find_in_slice(&buf, "old_file").unwrap().unwrap();
+        assert_eq!(old.compression, CompressionAlgorithm::Bzip2);
+        let new = find_in_slice(&buf, "new_file").unwrap().unwrap();
+        assert_eq!(new.compression, CompressionAlgorithm::Zstd);
+    }
+
+    #[test]
+    fn index_create_unsupported_compression() {
+        let mut tf = tempfile::tempfile().unwrap();
+
+        let mut archive = zip::ZipWriter::new(tf);
+        archive
+            .start_file(
+                "testfile1",
+                FileOptions::default().compression_method(zip::CompressionMethod::Stored),
+            )
+            .unwrap();
+        archive.write_all(b"content").unwrap();
+        tf = archive.finish().unwrap();
+
+        assert!(create(&mut tf, &mut Vec::new()).is_err());
+    }
+
+    fn remote_index_with(paths: &[String]) -> Vec<u8> {
+        let files = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                (
+                    path.clone(),
+                    FileInfo {
+                        range: FileRange::new(i as u64 * 10, i as u64 * 10 + 9),
+                        compression: CompressionAlgorithm::Zstd,
+                        blob: None,
+                    },
+                )
+            })
+            .collect();
+
+        serde_cbor::to_vec(&Index { files }).unwrap()
+    }
+
+    #[test]
+    fn local_index_lookup() {
+        let paths: Vec<String> = (0..1000)
+            .map(|i| format!("dir{}/file{}.html", i % 7, i))
+            .collect();
+
+        let mut buf = Vec::new();
+        convert_to_local(&remote_index_with(&paths), &mut buf).unwrap();
+
+        for (i, path) in paths.iter().enumerate() {
+            let fi = find_in_slice(&buf, path).unwrap().unwrap();
+            assert_eq!(fi.range, FileRange::new(i as u64 * 10, i as u64 * 10 + 9));
+            assert_eq!(fi.compression, CompressionAlgorithm::Zstd);
+        }
+
+        for missing in ["", "dir0", "dir0/file0", "dir9/file1.html", "zzz"] {
+            assert!(find_in_slice(&buf, missing).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn local_index_empty() {
+        let mut buf = Vec::new();
+        convert_to_local(&remote_index_with(&[]), &mut buf).unwrap();
+
+        assert!(find_in_slice(&buf, "some_file").unwrap().is_none());
+    }
+
+    #[test]
+    fn local_index_rejects_remote_format() {
+        let remote = remote_index_with(&["some_file".into()]);
+        assert!(find_in_slice(&remote, "some_file").is_err());
+    }
+
+    #[test]
+    fn local_index_with_deduplicated_files() {
+        let mut files = HashMap::new();
+        files.insert(
+            "in_archive".to_string(),
+            FileInfo::new(FileRange::new(0, 9), CompressionAlgorithm::Bzip2, None),
+        );
+        files.insert(
+            "deduplicated".to_string(),
+            FileInfo::new(
+                FileRange::new(0, 99),
+                CompressionAlgorithm::Zstd,
+                Some([42; 32]),
+            ),
+        );
+        let mut remote = Vec::new();
+        write_remote(files, &mut remote).unwrap();
+
+        let mut buf = Vec::new();
+        convert_to_local(&remote, &mut buf).unwrap();
+
+        let fi = find_in_slice(&buf, "in_archive").unwrap().unwrap();
+        assert_eq!(fi.blob(), None);
+        let fi = find_in_slice(&buf, "deduplicated").unwrap().unwrap();
+        assert_eq!(fi.blob(), Some(&[42; 32]));
+        assert_eq!(fi.range(), FileRange::new(0, 99));
+        assert_eq!(fi.compression(), CompressionAlgorithm::Zstd);
+    }
+
+    #[test]
+    fn read_first_local_index_version() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(LOCAL_INDEX_MAGIC_V1);
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&9u32.to_le_bytes());
+        buf.extend_from_slice(&10u64.to_le_bytes());
+        buf.extend_from_slice(&19u64.to_le_bytes());
+        buf.extend_from_slice(&(CompressionAlgorithm::Zstd as u32).to_le_bytes());
+        buf.extend_from_slice(b"some_file");
+
+        let fi = find_in_slice(&buf, "some_file").unwrap().unwrap();
+        assert_eq!(fi.range(), FileRange::new(10, 19));
+        assert_eq!(fi.compression(), CompressionAlgorithm::Zstd);
+        assert_eq!(fi.blob(), None);
+    }
+
+    #[test]
+    fn migrate_legacy_local_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("folder/test.zip.index");
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, remote_index_with(&["some_file".into()])).unwrap();
+
+        assert!(!is_local_format(&path).unwrap());
+        let file = open_local(&path).unwrap().unwrap();
+        assert!(is_local_format(&path).unwrap());
+        assert!(open_local(&dir.path().join("missing.zip.index"))
+            .unwrap()
+            .is_none());
+
+        assert!(find_in_file(&file, "some_file").unwrap().is_some());
+        assert!(find_in_file(&file, "other_file").unwrap().is_none());
+    }
 }
@@ -1,6 +1,6 @@
 use anyhow::Error;
 use bzip2::read::{BzDecoder, BzEncoder};
-use bzip2::Compression;
+use flate2::read::{GzDecoder, GzEncoder};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
@@ -31,11 +31,11 @@ macro_rules! enum_id {
         }
 
         impl std::str::FromStr for CompressionAlgorithm {
-            type Err = ();
+            type Err = UnknownCompressionAlgorithm;
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 match s {
                     $(stringify!($variant) => Ok(Self::$variant),)*
-                    _ => Err(()),
+                    _ => Err(UnknownCompressionAlgorithm(s.into())),
                 }
             }
         }
@@ -52,10 +52,16 @@ macro_rules! enum_id {
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+#[error("unknown compression algorithm {0:?}")]
+pub struct UnknownCompressionAlgorithm(String);
+
 enum_id! {
     pub enum CompressionAlgorithm {
         Zstd = 0,
         Bzip2 = 1,
+        Gzip = 2,
+        Brotli = 3,
     }
 }
 
@@ -65,12 +71,39 @@ impl Default for CompressionAlgorithm {
     }
 }
 
+impl CompressionAlgorithm {
+    /// The name of the algorithm in the `Content-Encoding` header, for the algorithms that
+    /// browsers are able to decompress on their own.
+    pub(crate) fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            CompressionAlgorithm::Zstd => Some("zstd"),
+            CompressionAlgorithm::Gzip => Some("gzip"),
+            CompressionAlgorithm::Brotli => Some("br"),
+            CompressionAlgorithm::Bzip2 => None,
+        }
+    }
+}
+
 // public for benchmarking
 pub fn compress(content: impl Read, algorithm: CompressionAlgorithm) -> Result<Vec<u8>, Error> {
     match algorithm {
         CompressionAlgorithm::Zstd => Ok(zstd::encode_all(content, 9)?),
         CompressionAlgorithm::Bzip2 => {
-            let mut compressor = BzEncoder::new(content, Compression::best());
+            let mut compressor = BzEncoder::new(content, bzip2::Compression::best());
+
+            let mut data = vec![];
+            compressor.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        CompressionAlgorithm::Gzip => {
+            let mut compressor = GzEncoder::new(content, flate2::Compression::best());
+
+            let mut data = vec![];
+            compressor.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        CompressionAlgorithm::Brotli => {
+            let mut compressor = brotli::CompressorReader::new(content, 4096, 9, 22);
 
             let mut data = vec![];
             compressor.read_to_end(&mut data)?;
@@ -92,6 +125,12 @@ pub fn decompress(
         CompressionAlgorithm::Bzip2 => {
             io::copy(&mut BzDecoder::new(content), &mut buffer)?;
         }
+        CompressionAlgorithm::Gzip => {
+            io::copy(&mut GzDecoder::new(content), &mut buffer)?;
+        }
+        CompressionAlgorithm::Brotli => {
+            io::copy(&mut brotli::Decompressor::new(content, 4096), &mut buffer)?;
+        }
     }
 
     Ok(buffer.into_inner())
@@ -115,6 +154,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_algorithm() {
+        for alg in CompressionAlgorithm::AVAILABLE {
+            assert_eq!(
+                alg.to_string().parse::<CompressionAlgorithm>().unwrap(),
+                *alg
+            );
+        }
+        assert!("zstd".parse::<CompressionAlgorithm>().is_err());
+    }
+
     #[test]
     fn test_decompression_too_big() {
         const MAX_SIZE: usize = 1024;
@@ -1,9 +1,9 @@
-use super::{Blob, FileRange, StorageTransaction};
+use super::{Blob, FileRange, StorageTransaction, StoredObject};
 use crate::db::Pool;
 use crate::error::Result;
 use crate::Metrics;
 use postgres::Transaction;
-use std::{convert::TryFrom, sync::Arc};
+use std::{convert::TryFrom, fs, path::Path, sync::Arc};
 
 pub(crate) struct DatabaseBackend {
     pool: Pool,
@@ -21,6 +21,39 @@ impl DatabaseBackend {
         Ok(conn.query(query, &[&path])?[0].get(0))
     }
 
+    pub(super) fn size(&self, path: &str) -> Result<u64> {
+        let query = "SELECT LENGTH(content) FROM files WHERE path = $1";
+        let mut conn = self.pool.get()?;
+        match conn.query_opt(query, &[&path])? {
+            Some(row) => Ok(row.get::<_, i32>(0) as u64),
+            None => Err(super::PathNotFoundError.into()),
+        }
+    }
+
+    pub(super) fn list_objects(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredObject>> {
+        Ok(self
+            .pool
+            .get()?
+            .query(
+                "SELECT path, LENGTH(content), date_updated FROM files
+                 WHERE $1::TEXT IS NULL OR path > $1
+                 ORDER BY path
+                 LIMIT $2",
+                &[&after, &(limit as i64)],
+            )?
+            .into_iter()
+            .map(|row| StoredObject {
+                path: row.get(0),
+                size: row.get::<_, i32>(1) as u64,
+                date_updated: row.get(2),
+            })
+            .collect())
+    }
+
     pub(super) fn get(
         &self,
         path: &str,
@@ -96,6 +129,52 @@ impl DatabaseBackend {
         }
     }
 
+    /// Calls `f` with the contents of the blob at `path`, or with the part of them in `range`,
+    /// exactly as they're stored.
+    ///
+    /// `BYTEA` values can't be read in chunks, so `f` is called once with the whole content,
+    /// which is at most 1GB.
+    pub(super) fn read_chunks(
+        &self,
+        path: &str,
+        range: Option<FileRange>,
+        f: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let (start, len) = match range {
+            // SUBSTRING counts from 1
+            Some(range) => (
+                i32::try_from(*range.start())? + 1,
+                i32::try_from(range.end() - range.start() + 1)?,
+            ),
+            None => (1, i32::MAX),
+        };
+        let row = self
+            .pool
+            .get()?
+            .query_opt(
+                "SELECT SUBSTRING(content FROM $2 FOR $3) FROM files WHERE path = $1",
+                &[&path, &start, &len],
+            )?
+            .ok_or(super::PathNotFoundError)?;
+        f(row.get::<_, &[u8]>(0))
+    }
+
+    /// Stores the local file at `local_path` uncompressed at `path`.
+    ///
+    /// `BYTEA` values can't be written in chunks, so the whole file is read into memory.
+    pub(super) fn store_file(&self, path: &str, mime: &str, local_path: &Path) -> Result<()> {
+        let content = fs::read(local_path)?;
+        self.pool.get()?.execute(
+            "INSERT INTO files (path, mime, content, compression)
+             VALUES ($1, $2, $3, NULL)
+             ON CONFLICT (path) DO UPDATE
+                SET mime = EXCLUDED.mime, content = EXCLUDED.content, compression = NULL",
+            &[&path, &mime, &content],
+        )?;
+        self.metrics.uploaded_files_total.inc();
+        Ok(())
+    }
+
     pub(super) fn start_connection(&self) -> Result<DatabaseClient> {
         Ok(DatabaseClient {
             conn: self.pool.get()?,
@@ -147,6 +226,12 @@ impl<'a> StorageTransaction for DatabaseStorageTransaction<'a> {
         Ok(())
     }
 
+    fn delete_batch(&mut self, paths: &[String]) -> Result<()> {
+        self.transaction
+            .execute("DELETE FROM files WHERE path = ANY($1)", &[&paths])?;
+        Ok(())
+    }
+
     fn complete(self: Box<Self>) -> Result<()> {
         self.transaction.commit()?;
         Ok(())
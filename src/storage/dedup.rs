@@ -0,0 +1,399 @@
+//! Deduplicated storage of the files in the archives.
+//!
+//! Many files are byte-identical across the releases of a crate. Instead of storing them inside
+//! the zip archive, the files can be stored compressed in their own blobs, named after the hash of
+//! their contents, and the index of the archive points to those blobs.
+//!
+//! The `archive_blobs` table records which archive references which blob, and `content_blobs`
+//! keeps the reference count of every blob. A blob is deleted once the last archive referencing
+//! it is gone.
+
+use super::{
+    archive_index::{self, FileInfo},
+    compress, detect_mime, get_file_list, Blob, CompressionAlgorithm, FileRange, Storage,
+};
+use crate::error::Result;
+use chrono::Utc;
+use postgres::Transaction;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// SHA-256 of the uncompressed contents of a file.
+pub(crate) type ContentHash = [u8; 32];
+
+pub(crate) fn content_hash(content: &[u8]) -> ContentHash {
+    Sha256::digest(content).into()
+}
+
+pub(crate) fn content_blob_path(hash: &ContentHash) -> String {
+    let hash = hex::encode(hash);
+    format!("blobs/{}/{}", &hash[..2], hash)
+}
+
+impl Storage {
+    /// Stores all files in `root_dir` as deduplicated blobs, together with an index for
+    /// `archive_path` pointing to them.
+    pub(super) fn store_all_deduplicated(
+        &self,
+        archive_path: &str,
+        root_dir: &Path,
+    ) -> Result<HashMap<PathBuf, String>> {
+        let alg = CompressionAlgorithm::Zstd;
+        let mut file_paths = HashMap::new();
+        let mut files = HashMap::new();
+        let mut blobs = HashMap::new();
+
+        for file_path in get_file_list(root_dir)? {
+            let content = fs::read(root_dir.join(&file_path))?;
+            let hash = content_hash(&content);
+            let compressed_len = match blobs.entry(hash) {
+                Entry::Occupied(entry) => {
+                    let compressed: &Vec<u8> = entry.get();
+                    compressed.len()
+                }
+                Entry::Vacant(entry) => entry.insert(compress(&*content, alg)?).len(),
+            };
+
+            files.insert(
+                file_path.to_str().unwrap().to_owned(),
+                FileInfo::new(
+                    FileRange::new(0, compressed_len as u64 - 1),
+                    alg,
+                    Some(hash),
+                ),
+            );
+            let mime = detect_mime(&file_path);
+            file_paths.insert(file_path, mime.to_string());
+        }
+
+        let mut index_content = Vec::new();
+        archive_index::write_remote(files, &mut index_content)?;
+        let index_alg = CompressionAlgorithm::default();
+        let compressed_index_content = compress(&index_content[..], index_alg)?;
+        let remote_index_path = format!("{}.index", &archive_path);
+
+        let mut conn = self.pool.get()?;
+        let mut transaction = conn.transaction()?;
+
+        // A rebuild replaces the references of the previous build. They are only released after
+        // adding the new ones, so blobs used by both builds are kept.
+        let previous = take_references(&mut transaction, Archives::Exact(archive_path))?;
+
+        let mut new_blobs = Vec::new();
+        for (hash, content) in blobs {
+            let refcount: i32 = transaction
+                .query_one(
+                    "INSERT INTO content_blobs (hash, size, refcount)
+                     VALUES ($1, $2, 1)
+                     ON CONFLICT (hash) DO UPDATE
+                        SET refcount = content_blobs.refcount + 1
+                     RETURNING refcount",
+                    &[&&hash[..], &(content.len() as i64)],
+                )?
+                .get(0);
+            transaction.execute(
+                "INSERT INTO archive_blobs (archive, hash) VALUES ($1, $2)",
+                &[&archive_path, &&hash[..]],
+            )?;
+
+            // The row of a new blob stays locked until the transaction is committed, so nobody
+            // else can reference it before it's uploaded.
+            if refcount == 1 {
+                new_blobs.push(Blob {
+                    path: content_blob_path(&hash),
+                    mime: "application/octet-stream".to_owned(),
+                    content,
+                    compression: Some(alg),
+                    date_updated: Utc::now(),
+                });
+            }
+        }
+
+        // additionally store the index in the local cache, so it's directly available
+        self.archive_index_cache
+            .insert(&remote_index_path, &index_content)?;
+
+        new_blobs.push(Blob {
+            path: remote_index_path,
+            mime: "application/octet-stream".to_owned(),
+            content: compressed_index_content,
+            compression: Some(index_alg),
+            date_updated: Utc::now(),
+        });
+        self.store_inner(new_blobs.into_iter().map(Ok))?;
+
+        let unreferenced = release_blobs(&mut transaction, previous)?;
+        transaction.commit()?;
+        self.delete_unreferenced_blobs(unreferenced)?;
+
+        Ok(file_paths)
+    }
+
+    /// Removes the references of the archive at `archive_path` to its deduplicated files, and
+    /// deletes the files that aren't referenced anymore.
+    pub(crate) fn release_deduplicated_files(&self, archive_path: &str) -> Result<()> {
+        self.release_references(Archives::Exact(archive_path))
+    }
+
+    /// Like [`Storage::release_deduplicated_files`], but for all archives whose path starts with
+    /// `prefix`.
+    pub(crate) fn release_deduplicated_files_with_prefix(&self, prefix: &str) -> Result<()> {
+        self.release_references(Archives::Prefix(prefix))
+    }
+
+    fn release_references(&self, archives: Archives<'_>) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let mut transaction = conn.transaction()?;
+        let hashes = take_references(&mut transaction, archives)?;
+        let unreferenced = release_blobs(&mut transaction, hashes)?;
+        transaction.commit()?;
+
+        self.delete_unreferenced_blobs(unreferenced)
+    }
+
+    /// Deletes the blobs which are still unreferenced.
+    ///
+    /// This is only done after the references were released in a committed transaction, so a
+    /// failed commit can't leave archives pointing to deleted blobs. The rows of the blobs are
+    /// locked while they're deleted, so a concurrent upload either references a blob again
+    /// before it's checked here, or waits and uploads the blob again afterwards.
+    fn delete_unreferenced_blobs(&self, hashes: Vec<Vec<u8>>) -> Result<()> {
+        if hashes.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get()?;
+        let mut transaction = conn.transaction()?;
+        let unreferenced: Vec<Vec<u8>> = transaction
+            .query(
+                "SELECT hash FROM content_blobs
+                 WHERE hash = ANY($1) AND refcount <= 0
+                 FOR UPDATE",
+                &[&hashes],
+            )?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        for hash in &unreferenced {
+            let hash: ContentHash = hash[..].try_into()?;
+            self.delete_prefix(&content_blob_path(&hash))?;
+        }
+        transaction.execute(
+            "DELETE FROM content_blobs WHERE hash = ANY($1)",
+            &[&unreferenced],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// Decrements the reference counts of the blobs, returning the hashes of the ones which aren't
+/// referenced anymore.
+fn release_blobs(transaction: &mut Transaction, hashes: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    if hashes.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(transaction
+        .query(
+            "UPDATE content_blobs
+             SET refcount = content_blobs.refcount - released.count
+             FROM (
+                SELECT hash, COUNT(*) AS count
+                FROM UNNEST($1::BYTEA[]) AS hash
+                GROUP BY hash
+             ) AS released
+             WHERE content_blobs.hash = released.hash
+             RETURNING content_blobs.hash, content_blobs.refcount",
+            &[&hashes],
+        )?
+        .into_iter()
+        .filter(|row| row.get::<_, i32>(1) <= 0)
+        .map(|row| row.get(0))
+        .collect())
+}
+
+/// The archives whose references are released.
+#[derive(Debug, Clone, Copy)]
+enum Archives<'a> {
+    /// the archive at this path
+    Exact(&'a str),
+    /// all archives whose path starts with this prefix
+    Prefix(&'a str),
+}
+
+/// Removes the references of the archives, returning the hashes of the referenced blobs.
+fn take_references(transaction: &mut Transaction, archives: Archives<'_>) -> Result<Vec<Vec<u8>>> {
+    let rows = match archives {
+        Archives::Exact(path) => transaction.query(
+            "DELETE FROM archive_blobs WHERE archive = $1 RETURNING hash",
+            &[&path],
+        )?,
+        // not using `LIKE`, as `_` is a wildcard there and common in crate names
+        Archives::Prefix(prefix) => transaction.query(
+            "DELETE FROM archive_blobs
+             WHERE LEFT(archive, LENGTH($1)) = $1
+             RETURNING hash",
+            &[&prefix],
+        )?,
+    };
+    Ok(rows.into_iter().map(|row| row.get(0)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{wrapper, TestEnvironment};
+    use std::sync::Arc;
+
+    fn store_archive(env: &TestEnvironment, archive_path: &str, files: &[(&str, &str)]) {
+        let dir = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        env.storage()
+            .store_all_in_archive(archive_path, dir.path())
+            .unwrap();
+    }
+
+    fn refcounts(env: &TestEnvironment) -> Vec<(String, i32)> {
+        let mut refcounts: Vec<_> = env
+            .db()
+            .conn()
+            .query("SELECT hash, refcount FROM content_blobs", &[])
+            .unwrap()
+            .into_iter()
+            .map(|row| (hex::encode(row.get::<_, Vec<u8>>(0)), row.get(1)))
+            .collect();
+        refcounts.sort();
+        refcounts
+    }
+
+    fn blob_exists(env: &TestEnvironment, content: &str) -> bool {
+        env.storage()
+            .exists(&content_blob_path(&content_hash(content.as_bytes())))
+            .unwrap()
+    }
+
+    #[test]
+    fn identical_files_are_stored_once() {
+        wrapper(|env| {
+            env.override_config(|config| config.archive_deduplication = true);
+            store_archive(
+                env,
+                "rustdoc/krate/0.1.0.zip",
+                &[("a.html", "same"), ("b.html", "old")],
+            );
+            store_archive(
+                env,
+                "rustdoc/krate/0.2.0.zip",
+                &[("a.html", "same"), ("b.html", "new")],
+            );
+
+            let storage = env.storage();
+            for (archive, expected) in [
+                ("rustdoc/krate/0.1.0.zip", "old"),
+                ("rustdoc/krate/0.2.0.zip", "new"),
+            ] {
+                let file = storage.get_from_archive(archive, "b.html", std::usize::MAX, None)?;
+                assert_eq!(file.content, expected.as_bytes());
+                assert_eq!(file.mime, "text/html");
+                let file = storage.get_from_archive(archive, "a.html", std::usize::MAX, None)?;
+                assert_eq!(file.content, b"same");
+            }
+            // the files are not stored in an archive
+            assert!(!storage.exists("rustdoc/krate/0.1.0.zip")?);
+
+            let hash = |content: &str| hex::encode(content_hash(content.as_bytes()));
+            let mut expected = vec![(hash("same"), 2), (hash("old"), 1), (hash("new"), 1)];
+            expected.sort();
+            assert_eq!(refcounts(env), expected);
+
+            storage.release_deduplicated_files("rustdoc/krate/0.1.0.zip")?;
+            assert!(blob_exists(env, "same"));
+            assert!(!blob_exists(env, "old"));
+            assert!(blob_exists(env, "new"));
+
+            storage.release_deduplicated_files_with_prefix("rustdoc/krate/")?;
+            assert!(refcounts(env).is_empty());
+            assert!(!blob_exists(env, "same"));
+            assert!(!blob_exists(env, "new"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn prefixes_are_matched_literally() {
+        wrapper(|env| {
+            env.override_config(|config| config.archive_deduplication = true);
+            store_archive(env, "rustdoc/a-b/0.1.0.zip", &[("a.html", "shared")]);
+            store_archive(env, "rustdoc/a_b/0.1.0.zip", &[("a.html", "shared")]);
+            store_archive(env, "rustdoc/a%b/0.1.0.zip", &[("b.html", "other")]);
+
+            // `_` and `%` are no wildcards
+            env.storage()
+                .release_deduplicated_files_with_prefix("rustdoc/a_b/")?;
+            env.storage()
+                .release_deduplicated_files("rustdoc/a%b/0.1.0.z_p")?;
+            let hash = |content: &str| hex::encode(content_hash(content.as_bytes()));
+            let mut expected = vec![(hash("shared"), 1), (hash("other"), 1)];
+            expected.sort();
+            assert_eq!(refcounts(env), expected);
+            assert!(blob_exists(env, "shared"));
+
+            let file = env.storage().get_from_archive(
+                "rustdoc/a-b/0.1.0.zip",
+                "a.html",
+                std::usize::MAX,
+                None,
+            )?;
+            assert_eq!(file.content, b"shared");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn rebuilds_replace_references() {
+        wrapper(|env| {
+            env.override_config(|config| config.archive_deduplication = true);
+            let archive = "rustdoc/krate/0.1.0.zip";
+            store_archive(env, archive, &[("a.html", "kept"), ("b.html", "replaced")]);
+            store_archive(env, archive, &[("a.html", "kept"), ("b.html", "new")]);
+
+            let hash = |content: &str| hex::encode(content_hash(content.as_bytes()));
+            let mut expected = vec![(hash("kept"), 1), (hash("new"), 1)];
+            expected.sort();
+            assert_eq!(refcounts(env), expected);
+            assert!(blob_exists(env, "kept"));
+            assert!(!blob_exists(env, "replaced"));
+
+            // switching back to plain archives releases the deduplicated files
+            let mut config = env.base_config();
+            config.local_archive_cache_path = env.config().local_archive_cache_path.clone();
+            let plain = Storage::new(env.db().pool(), env.metrics(), Arc::new(config))?;
+            let dir = tempfile::tempdir()?;
+            fs::write(dir.path().join("a.html"), "kept")?;
+            plain.store_all_in_archive(archive, dir.path())?;
+
+            assert!(refcounts(env).is_empty());
+            assert!(!blob_exists(env, "kept"));
+            assert_eq!(
+                plain
+                    .get_from_archive(archive, "a.html", std::usize::MAX, None)?
+                    .content,
+                b"kept"
+            );
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,367 @@
+use super::{Blob, CompressionAlgorithm, FileRange, StorageTransaction, StoredObject};
+use crate::{Config, Metrics};
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
+use path_slash::PathExt;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use walkdir::WalkDir;
+
+/// The filesystem can't store the mime type and the compression algorithm of a file, so they are
+/// kept in a small JSON file in a separate directory tree mirroring the one with the contents.
+#[derive(Serialize, Deserialize)]
+struct FileMetadata {
+    mime: String,
+    compression: Option<CompressionAlgorithm>,
+}
+
+pub(super) struct FilesystemBackend {
+    files_root: PathBuf,
+    metadata_root: PathBuf,
+    metrics: Arc<Metrics>,
+}
+
+impl FilesystemBackend {
+    pub(super) fn new(metrics: Arc<Metrics>, config: &Config) -> Result<Self, Error> {
+        let files_root = config.local_storage_path.join("files");
+        let metadata_root = config.local_storage_path.join("metadata");
+        for root in [&files_root, &metadata_root] {
+            fs::create_dir_all(root).with_context(|| {
+                format!("failed to create storage directory {}", root.display())
+            })?;
+        }
+
+        Ok(Self {
+            files_root,
+            metadata_root,
+            metrics,
+        })
+    }
+
+    /// Returns the location of the contents and of the metadata for `path`, or `None` if the path
+    /// would point outside of the storage directory.
+    fn locate(&self, path: &str) -> Option<(PathBuf, PathBuf)> {
+        let relative = Path::new(path);
+        // Some of the paths are built from the URL of the request, make sure they can't be used
+        // to read arbitrary files from the server.
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        Some((
+            self.files_root.join(relative),
+            self.metadata_root.join(relative),
+        ))
+    }
+
+    pub(super) fn exists(&self, path: &str) -> Result<bool, Error> {
+        Ok(match self.locate(path) {
+            Some((file_path, _)) => file_path.is_file(),
+            None => false,
+        })
+    }
+
+    pub(super) fn size(&self, path: &str) -> Result<u64, Error> {
+        match self.locate(path) {
+            Some((file_path, _)) if file_path.is_file() => Ok(fs::metadata(file_path)?.len()),
+            _ => Err(super::PathNotFoundError.into()),
+        }
+    }
+
+    /// Lists the paths of the stored files in lexicographic order.
+    ///
+    /// The tree is walked in that order, skipping the directories which only contain paths up to
+    /// `after`, so listing a batch doesn't read the whole tree.
+    pub(super) fn list_objects(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredObject>, Error> {
+        let files_root = &self.files_root;
+        let relative_path = |path: &Path| -> Result<String, Error> {
+            Ok(path
+                .strip_prefix(files_root)?
+                .to_slash()
+                .ok_or_else(|| anyhow!("non UTF-8 path in storage"))?
+                .into_owned())
+        };
+
+        let walker = WalkDir::new(files_root)
+            // A directory is sorted as its name followed by a slash, which is how its paths
+            // start, so the files are visited ordered by their whole path.
+            .sort_by_key(|entry| {
+                let mut key = entry.file_name().to_string_lossy().into_owned();
+                if entry.file_type().is_dir() {
+                    key.push('/');
+                }
+                key
+            })
+            .into_iter()
+            .filter_entry(|entry| match after {
+                Some(after) if entry.depth() > 0 && entry.file_type().is_dir() => {
+                    match relative_path(entry.path()) {
+                        // the directory contains paths after `after`
+                        Ok(dir) => {
+                            let prefix = format!("{}/", dir);
+                            after.starts_with(&prefix) || prefix.as_str() > after
+                        }
+                        Err(_) => true,
+                    }
+                }
+                _ => true,
+            });
+
+        let mut objects = Vec::new();
+        for entry in walker {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = relative_path(entry.path())?;
+            if matches!(after, Some(after) if path.as_str() <= after) {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            objects.push(StoredObject {
+                path,
+                size: metadata.len(),
+                date_updated: DateTime::<Utc>::from(metadata.modified()?),
+            });
+            if objects.len() >= limit {
+                break;
+            }
+        }
+
+        Ok(objects)
+    }
+
+    pub(super) fn get(
+        &self,
+        path: &str,
+        max_size: usize,
+        range: Option<FileRange>,
+    ) -> Result<Blob, Error> {
+        let (file_path, metadata_path) = self.locate(path).ok_or(super::PathNotFoundError)?;
+
+        let mut file = match fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(super::PathNotFoundError.into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let file_metadata = file.metadata()?;
+        if !file_metadata.is_file() {
+            return Err(super::PathNotFoundError.into());
+        }
+
+        let metadata: FileMetadata = serde_json::from_slice(
+            &fs::read(&metadata_path)
+                .with_context(|| format!("failed to read the metadata of {}", path))?,
+        )?;
+
+        let mut content = crate::utils::sized_buffer::SizedBuffer::new(max_size);
+        if let Some(range) = range {
+            file.seek(SeekFrom::Start(*range.start()))?;
+            let len = range.end() - range.start() + 1;
+            content.reserve(len.try_into().ok().unwrap_or(0));
+            io::copy(&mut file.take(len), &mut content)?;
+        } else {
+            content.reserve(file_metadata.len().try_into().ok().unwrap_or(0));
+            io::copy(&mut file, &mut content)?;
+        }
+
+        Ok(Blob {
+            path: path.into(),
+            mime: metadata.mime,
+            date_updated: DateTime::<Utc>::from(file_metadata.modified()?),
+            content: content.into_inner(),
+            compression: metadata.compression,
+        })
+    }
+
+    /// Calls `f` with every chunk of the contents of the file at `path`, or of the part of them
+    /// in `range`, exactly as they're stored.
+    pub(super) fn read_chunks(
+        &self,
+        path: &str,
+        range: Option<FileRange>,
+        f: &mut dyn FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let (file_path, _) = self.locate(path).ok_or(super::PathNotFoundError)?;
+        let mut file = match fs::File::open(&file_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(super::PathNotFoundError.into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut reader: Box<dyn Read> = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(*range.start()))?;
+                Box::new(file.take(range.end() - range.start() + 1))
+            }
+            None => Box::new(file),
+        };
+
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buffer)? {
+                0 => return Ok(()),
+                len => f(&buffer[..len])?,
+            }
+        }
+    }
+
+    /// Stores a copy of the local file at `local_path` uncompressed at `path`.
+    pub(super) fn store_file(
+        &self,
+        path: &str,
+        mime: &str,
+        local_path: &Path,
+    ) -> Result<(), Error> {
+        let (file_path, metadata_path) = self
+            .locate(path)
+            .ok_or_else(|| anyhow!("invalid storage path {:?}", path))?;
+
+        let metadata = FileMetadata {
+            mime: mime.into(),
+            compression: None,
+        };
+        write_atomically(&metadata_path, &serde_json::to_vec(&metadata)?)?;
+
+        let parent = file_path
+            .parent()
+            .ok_or_else(|| anyhow!("storage path without a parent directory"))?;
+        fs::create_dir_all(parent)?;
+        let mut copy = tempfile::NamedTempFile::new_in(parent)?;
+        io::copy(&mut fs::File::open(local_path)?, &mut copy)?;
+        copy.persist(&file_path)?;
+
+        self.metrics.uploaded_files_total.inc();
+        Ok(())
+    }
+
+    pub(super) fn start_storage_transaction(&self) -> FilesystemStorageTransaction<'_> {
+        FilesystemStorageTransaction { fs: self }
+    }
+}
+
+/// Atomically replaces the file at `path`, so that concurrent readers never see a partial write.
+fn write_atomically(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("storage path without parent"))?;
+    fs::create_dir_all(parent)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    io::Write::write_all(&mut file, content)?;
+    file.persist(path)?;
+    Ok(())
+}
+
+pub(super) struct FilesystemStorageTransaction<'a> {
+    fs: &'a FilesystemBackend,
+}
+
+impl<'a> StorageTransaction for FilesystemStorageTransaction<'a> {
+    fn store_batch(&mut self, batch: Vec<Blob>) -> Result<(), Error> {
+        for blob in batch {
+            let (file_path, metadata_path) = self
+                .fs
+                .locate(&blob.path)
+                .ok_or_else(|| anyhow!("invalid storage path {:?}", blob.path))?;
+
+            let metadata = FileMetadata {
+                mime: blob.mime,
+                compression: blob.compression,
+            };
+            write_atomically(&metadata_path, &serde_json::to_vec(&metadata)?)?;
+            write_atomically(&file_path, &blob.content)?;
+
+            self.fs.metrics.uploaded_files_total.inc();
+        }
+        Ok(())
+    }
+
+    fn delete_prefix(&mut self, prefix: &str) -> Result<(), Error> {
+        // The prefix doesn't have to end at a directory boundary, so the walk starts at the
+        // deepest directory containing everything the prefix could match.
+        let directory = match prefix.rfind('/') {
+            Some(idx) => &prefix[..idx],
+            None => "",
+        };
+        let (files_dir, metadata_dir) = match self.fs.locate(directory) {
+            Some(dirs) => dirs,
+            None => return Ok(()),
+        };
+
+        for (root, dir) in [
+            (&self.fs.files_root, files_dir),
+            (&self.fs.metadata_root, metadata_dir),
+        ] {
+            if !dir.exists() {
+                continue;
+            }
+
+            for entry in WalkDir::new(&dir).contents_first(true) {
+                let entry = entry?;
+                let relative = entry
+                    .path()
+                    .strip_prefix(root)?
+                    .to_slash()
+                    .ok_or_else(|| anyhow!("non UTF-8 path in storage"))?
+                    .into_owned();
+
+                if entry.file_type().is_dir() {
+                    // Directories are only removed once they don't contain anything anymore.
+                    if !relative.is_empty() && format!("{}/", relative).starts_with(prefix) {
+                        match fs::remove_dir(entry.path()) {
+                            Ok(()) => {}
+                            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                            Err(_) if fs::read_dir(entry.path())?.next().is_some() => {}
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                } else if relative.starts_with(prefix) {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_batch(&mut self, paths: &[String]) -> Result<(), Error> {
+        for path in paths {
+            let (file_path, metadata_path) = match self.fs.locate(path) {
+                Some(paths) => paths,
+                None => continue,
+            };
+            for path in [file_path, metadata_path] {
+                match fs::remove_file(path) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// The tests for this module are in src/storage/mod.rs, as part of the backend tests. Please add
+// any test checking the public interface there.
@@ -0,0 +1,403 @@
+//! Garbage collection of the objects in the storage that no release or build references.
+//!
+//! Failed builds, interrupted uploads and releases that were moved to archive storage leave
+//! objects behind in the storage. Only the prefixes owned by a release (`rustdoc/`, `sources/`
+//! and `downloads/`) or by a build (`build-logs/`) are collected, everything else is left alone.
+//! Cached offline bundles and rustdoc JSON outputs are kept as long as their release exists.
+
+use super::{Storage, StoredObject};
+use crate::{error::Result, Metrics};
+use chrono::{DateTime, Duration, Utc};
+use postgres::Client;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug)]
+pub struct GcStorageOptions {
+    /// objects updated more recently than this are never collected, as they could belong to a
+    /// build that's still running
+    pub grace_period: Duration,
+    /// delete the orphaned objects instead of only reporting them
+    pub delete: bool,
+    /// how many objects are listed at once
+    pub batch_size: usize,
+}
+
+/// An object in the storage that's not referenced anymore.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OrphanedObject {
+    pub path: String,
+    pub size: u64,
+    pub date_updated: DateTime<Utc>,
+}
+
+/// Totals of a garbage collection run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GcSummary {
+    /// number of objects checked
+    pub checked: u64,
+    /// number of orphaned objects found
+    pub orphans: u64,
+    /// size of the orphaned objects, in bytes
+    pub bytes: u64,
+}
+
+/// What an object in the storage belongs to, based on its path.
+#[derive(Debug, PartialEq, Eq)]
+enum Owner<'a> {
+    Release {
+        name: &'a str,
+        version: &'a str,
+        /// whether the object is an archive or its index, instead of a single file
+        archive: bool,
+    },
+    /// a cached offline bundle or the rustdoc JSON output of a release
+    Download {
+        name: &'a str,
+        version: &'a str,
+    },
+    Build(i32),
+}
+
+impl<'a> Owner<'a> {
+    fn of(path: &'a str) -> Option<Self> {
+        let (prefix, rest) = path.split_once('/')?;
+        match prefix {
+            "rustdoc" | "sources" => {
+                let (name, rest) = rest.split_once('/')?;
+                if let Some((version, _)) = rest.split_once('/') {
+                    Some(Owner::Release {
+                        name,
+                        version,
+                        archive: false,
+                    })
+                } else if let Some(version) =
+                    rest.strip_suffix(".json").filter(|_| prefix == "rustdoc")
+                {
+                    Some(Owner::Download { name, version })
+                } else {
+                    let version = rest
+                        .strip_suffix(".zip")
+                        .or_else(|| rest.strip_suffix(".zip.index"))?;
+                    Some(Owner::Release {
+                        name,
+                        version,
+                        archive: true,
+                    })
+                }
+            }
+            "downloads" => {
+                let (name, rest) = rest.split_once('/')?;
+                let (version, _) = rest.split_once('/')?;
+                Some(Owner::Download { name, version })
+            }
+            "build-logs" => {
+                let (id, _) = rest.split_once('/')?;
+                Some(Owner::Build(id.parse().ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Finds the objects in the `rustdoc/`, `sources/`, `downloads/` and `build-logs/` prefixes which
+/// don't belong to any release or build, calling `report` for each of them and deleting them if
+/// `options.delete` is set.
+///
+/// The files of a release are orphaned too if they're stored differently than the release says,
+/// like the separate files of a release that was rebuilt into an archive.
+pub fn gc_storage(
+    conn: &mut Client,
+    storage: &Storage,
+    metrics: &Metrics,
+    options: &GcStorageOptions,
+    mut report: impl FnMut(&OrphanedObject) -> Result<()>,
+) -> Result<GcSummary> {
+    let cutoff = Utc::now() - options.grace_period;
+    let mut summary = GcSummary::default();
+    let mut after: Option<String> = None;
+
+    loop {
+        let objects = storage.list_objects(after.as_deref(), options.batch_size)?;
+        let last = match objects.last() {
+            Some(last) => last.path.clone(),
+            None => break,
+        };
+        summary.checked += objects.len() as u64;
+
+        let orphans = find_orphans(conn, objects, cutoff)?;
+        for orphan in &orphans {
+            report(orphan)?;
+        }
+        let bytes: u64 = orphans.iter().map(|orphan| orphan.size).sum();
+        summary.orphans += orphans.len() as u64;
+        summary.bytes += bytes;
+
+        if options.delete && !orphans.is_empty() {
+            let paths: Vec<String> = orphans.into_iter().map(|orphan| orphan.path).collect();
+            for path in &paths {
+                // the files of deduplicated archives only have an index, but no zip file
+                if path.ends_with(".zip.index") {
+                    storage.release_deduplicated_files(path.trim_end_matches(".index"))?;
+                }
+            }
+            storage.transaction(|trans| trans.delete_batch(&paths))?;
+
+            metrics
+                .gc_storage_deleted_objects
+                .inc_by(paths.len() as u64);
+            metrics.gc_storage_reclaimed_bytes.inc_by(bytes);
+        }
+
+        after = Some(last);
+    }
+
+    Ok(summary)
+}
+
+fn find_orphans(
+    conn: &mut Client,
+    objects: Vec<StoredObject>,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<OrphanedObject>> {
+    let objects: Vec<_> = objects
+        .into_iter()
+        .filter(|object| object.date_updated < cutoff)
+        .collect();
+
+    let mut names = Vec::new();
+    let mut versions = Vec::new();
+    let mut build_ids = Vec::new();
+    for object in &objects {
+        match Owner::of(&object.path) {
+            Some(Owner::Release { name, version, .. })
+            | Some(Owner::Download { name, version }) => {
+                names.push(name);
+                versions.push(version);
+            }
+            Some(Owner::Build(id)) => build_ids.push(id),
+            None => {}
+        }
+    }
+
+    // whether the release of every (name, version) pair is stored in archives
+    let releases: HashMap<(String, String), bool> = if names.is_empty() {
+        HashMap::new()
+    } else {
+        conn.query(
+            "SELECT crates.name, releases.version, releases.archive_storage
+             FROM releases
+             INNER JOIN crates ON crates.id = releases.crate_id
+             WHERE (crates.name, releases.version) IN (
+                SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[])
+             )",
+            &[&names, &versions],
+        )?
+        .into_iter()
+        .map(|row| ((row.get(0), row.get(1)), row.get(2)))
+        .collect()
+    };
+    let builds: HashSet<i32> = if build_ids.is_empty() {
+        HashSet::new()
+    } else {
+        conn.query("SELECT id FROM builds WHERE id = ANY($1)", &[&build_ids])?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect()
+    };
+
+    Ok(objects
+        .into_iter()
+        .filter(|object| match Owner::of(&object.path) {
+            Some(Owner::Release {
+                name,
+                version,
+                archive,
+            }) => releases.get(&(name.to_owned(), version.to_owned())) != Some(&archive),
+            Some(Owner::Download { name, version }) => {
+                !releases.contains_key(&(name.to_owned(), version.to_owned()))
+            }
+            Some(Owner::Build(id)) => !builds.contains(&id),
+            None => false,
+        })
+        .map(|object| OrphanedObject {
+            path: object.path,
+            size: object.size,
+            date_updated: object.date_updated,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Blob;
+    use crate::test::{wrapper, TestEnvironment};
+
+    fn store(env: &TestEnvironment, paths: &[&str]) {
+        env.storage()
+            .store_blobs(
+                paths
+                    .iter()
+                    .map(|path| Blob {
+                        path: (*path).into(),
+                        mime: "text/plain".into(),
+                        date_updated: Utc::now(),
+                        compression: None,
+                        content: b"content".to_vec(),
+                    })
+                    .collect(),
+            )
+            .unwrap();
+    }
+
+    fn gc(
+        env: &TestEnvironment,
+        grace_period: Duration,
+        delete: bool,
+    ) -> Result<(GcSummary, Vec<String>)> {
+        let mut orphans = Vec::new();
+        let summary = gc_storage(
+            &mut env.db().conn(),
+            &env.storage(),
+            &env.metrics(),
+            &GcStorageOptions {
+                grace_period,
+                delete,
+                batch_size: 2,
+            },
+            |orphan| {
+                orphans.push(orphan.path.clone());
+                Ok(())
+            },
+        )?;
+        orphans.sort();
+        Ok((summary, orphans))
+    }
+
+    #[test]
+    fn owner_of_paths() {
+        let release = |name, version, archive| {
+            Some(Owner::Release {
+                name,
+                version,
+                archive,
+            })
+        };
+        assert_eq!(
+            Owner::of("rustdoc/krate/0.1.0/krate/index.html"),
+            release("krate", "0.1.0", false)
+        );
+        assert_eq!(
+            Owner::of("sources/krate/0.1.0.zip"),
+            release("krate", "0.1.0", true)
+        );
+        assert_eq!(
+            Owner::of("rustdoc/krate/0.1.0.zip.index"),
+            release("krate", "0.1.0", true)
+        );
+        assert_eq!(
+            Owner::of("build-logs/42/x86_64-unknown-linux-gnu.txt"),
+            Some(Owner::Build(42))
+        );
+        assert_eq!(
+            Owner::of("downloads/krate/0.1.0/source.tar.gz"),
+            Some(Owner::Download {
+                name: "krate",
+                version: "0.1.0"
+            })
+        );
+        assert_eq!(
+            Owner::of("rustdoc/krate/0.1.0.json"),
+            Some(Owner::Download {
+                name: "krate",
+                version: "0.1.0"
+            })
+        );
+        assert_eq!(Owner::of("sources/krate/0.1.0.json"), None);
+        assert_eq!(Owner::of("rustdoc/krate/0.1.0.tar"), None);
+        assert_eq!(Owner::of("build-logs/latest.txt"), None);
+        assert_eq!(Owner::of("blobs/ab/abcdef"), None);
+        assert_eq!(Owner::of("index.html"), None);
+    }
+
+    #[test]
+    fn collects_orphans() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("archived")
+                .version("0.1.0")
+                .archive_storage(true)
+                .create()?;
+            env.fake_release()
+                .name("plain")
+                .version("0.1.0")
+                .archive_storage(false)
+                .create()?;
+            let build_id: i32 = env
+                .db()
+                .conn()
+                .query_one("SELECT MIN(id) FROM builds", &[])?
+                .get(0);
+            let build_log = format!("build-logs/{}/x86_64-unknown-linux-gnu.txt", build_id);
+
+            store(
+                env,
+                &[
+                    // referenced
+                    "rustdoc/plain/0.1.0/plain/old.html",
+                    &build_log,
+                    "other/file.txt",
+                    // orphaned
+                    "rustdoc/archived/0.1.0/archived/index.html",
+                    "rustdoc/plain/0.1.0.zip",
+                    "rustdoc/missing/1.0.0.zip.index",
+                    "sources/missing/1.0.0/src/lib.rs",
+                    "build-logs/999999/x86_64-unknown-linux-gnu.txt",
+                ],
+            );
+
+            let expected = vec![
+                "build-logs/999999/x86_64-unknown-linux-gnu.txt",
+                "rustdoc/archived/0.1.0/archived/index.html",
+                "rustdoc/missing/1.0.0.zip.index",
+                "rustdoc/plain/0.1.0.zip",
+                "sources/missing/1.0.0/src/lib.rs",
+            ];
+
+            // recent files could belong to a build that's still running
+            let (summary, orphans) = gc(env, Duration::days(1), true)?;
+            assert!(orphans.is_empty());
+            assert_eq!(
+                summary.checked,
+                env.storage().list_paths(None, 100)?.len() as u64
+            );
+
+            // only report them
+            let (summary, orphans) = gc(env, Duration::zero(), false)?;
+            assert_eq!(orphans, expected);
+            assert_eq!(summary.orphans, 5);
+            assert_eq!(summary.bytes, 5 * b"content".len() as u64);
+            assert!(env.storage().exists("rustdoc/plain/0.1.0.zip")?);
+            assert_eq!(env.metrics().gc_storage_reclaimed_bytes.get(), 0);
+
+            let (summary, orphans) = gc(env, Duration::zero(), true)?;
+            assert_eq!(orphans, expected);
+            for path in expected {
+                assert!(!env.storage().exists(path)?);
+            }
+            assert!(env.storage().exists("rustdoc/plain/0.1.0/plain/old.html")?);
+            assert!(env.storage().exists(&build_log)?);
+            assert!(env.storage().exists("other/file.txt")?);
+            assert_eq!(env.metrics().gc_storage_deleted_objects.get(), 5);
+            assert_eq!(
+                env.metrics().gc_storage_reclaimed_bytes.get(),
+                summary.bytes
+            );
+
+            assert_eq!(gc(env, Duration::zero(), true)?.0.orphans, 0);
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,356 @@
+//! Copying the contents of one storage backend to another one.
+
+use super::{Storage, StorageKind};
+use crate::{db::Pool, error::Result, Config, Metrics};
+use anyhow::{bail, ensure};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct MigrateStorageOptions {
+    /// how many files are copied at once
+    pub batch_size: usize,
+    /// the maximum size of the files copied at once in bytes, a single bigger file is still
+    /// copied on its own
+    pub batch_max_bytes: u64,
+    /// delete the files from the source backend once they are verified in the destination
+    pub delete_source: bool,
+    /// ignore the progress of previous runs and start from the beginning
+    pub restart: bool,
+}
+
+impl Default for MigrateStorageOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            batch_max_bytes: 256 * 1024 * 1024,
+            delete_source: false,
+            restart: false,
+        }
+    }
+}
+
+/// Totals of a migration, including the files copied by previous runs that were interrupted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Copies every file from the `from` backend to the `to` backend, without changing its contents.
+///
+/// The progress is stored in the `storage_migrations` table after every batch, so an interrupted
+/// migration continues where it stopped when started again.
+pub fn migrate_storage(
+    pool: Pool,
+    metrics: Arc<Metrics>,
+    config: Arc<Config>,
+    from: StorageKind,
+    to: StorageKind,
+    options: &MigrateStorageOptions,
+) -> Result<MigrationProgress> {
+    ensure!(from != to, "can't migrate the {} storage to itself", from);
+    ensure!(options.batch_size > 0, "the batch size can't be 0");
+
+    let source = Storage::with_backend(pool.clone(), metrics.clone(), config.clone(), from)?;
+    let destination = Storage::with_backend(pool.clone(), metrics, config, to)?;
+    let (from, to) = (from.to_string(), to.to_string());
+
+    let mut conn = pool.get()?;
+    if options.restart {
+        conn.execute(
+            "DELETE FROM storage_migrations WHERE source = $1 AND destination = $2",
+            &[&from, &to],
+        )?;
+    }
+    let row = conn.query_one(
+        "INSERT INTO storage_migrations (source, destination)
+         VALUES ($1, $2)
+         ON CONFLICT (source, destination) DO UPDATE SET updated_at = NOW()
+         RETURNING last_path, files, bytes, finished_at IS NOT NULL",
+        &[&from, &to],
+    )?;
+    let mut last_path: Option<String> = row.get(0);
+    let mut progress = MigrationProgress {
+        files: row.get::<_, i64>(1) as u64,
+        bytes: row.get::<_, i64>(2) as u64,
+    };
+    if row.get(3) {
+        log::info!(
+            "the migration from {} to {} already finished, pass --restart to run it again",
+            from,
+            to
+        );
+        return Ok(progress);
+    }
+    if let Some(last_path) = &last_path {
+        log::info!(
+            "resuming the migration from {} to {} after {}",
+            from,
+            to,
+            last_path
+        );
+    }
+
+    loop {
+        let objects = source.list_objects(last_path.as_deref(), options.batch_size)?;
+        if objects.is_empty() {
+            break;
+        }
+
+        // only the files of a single batch are held in memory
+        let mut listed_bytes = 0;
+        let paths: Vec<String> = objects
+            .into_iter()
+            .enumerate()
+            .take_while(|(idx, object)| {
+                listed_bytes += object.size;
+                *idx == 0 || listed_bytes <= options.batch_max_bytes
+            })
+            .map(|(_, object)| object.path)
+            .collect();
+
+        let mut blobs = Vec::with_capacity(paths.len());
+        let mut checksums = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let blob = source.get_raw(path, std::usize::MAX, None)?;
+            checksums.push((blob.content.len() as u64, Sha256::digest(&blob.content)));
+            blobs.push(blob);
+        }
+        let batch_bytes: u64 = checksums.iter().map(|(size, _)| size).sum();
+        destination.store_inner(blobs.into_iter().map(Ok))?;
+
+        // the copies are hashed while they're read, without holding them in memory
+        for (path, (size, checksum)) in paths.iter().zip(&checksums) {
+            let mut copy_size = 0;
+            let mut hasher = Sha256::new();
+            destination.read_raw_chunks(path, None, &mut |chunk| {
+                copy_size += chunk.len() as u64;
+                hasher.update(chunk);
+                Ok(())
+            })?;
+            if copy_size != *size || hasher.finalize() != *checksum {
+                bail!(
+                    "verification of {} failed: the copy in {} doesn't match the original",
+                    path,
+                    to
+                );
+            }
+        }
+
+        if options.delete_source {
+            source.transaction(|trans| trans.delete_batch(&paths))?;
+        }
+
+        progress.files += paths.len() as u64;
+        progress.bytes += batch_bytes;
+        last_path = paths.last().cloned();
+        conn.execute(
+            "UPDATE storage_migrations
+             SET last_path = $3, files = $4, bytes = $5, updated_at = NOW()
+             WHERE source = $1 AND destination = $2",
+            &[
+                &from,
+                &to,
+                &last_path,
+                &(progress.files as i64),
+                &(progress.bytes as i64),
+            ],
+        )?;
+        log::info!(
+            "copied {} files ({} bytes), last one was {}",
+            progress.files,
+            progress.bytes,
+            last_path.as_deref().unwrap_or_default()
+        );
+    }
+
+    conn.execute(
+        "UPDATE storage_migrations SET finished_at = NOW() WHERE source = $1 AND destination = $2",
+        &[&from, &to],
+    )?;
+    Ok(progress)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Blob, CompressionAlgorithm};
+    use crate::test::{wrapper, TestEnvironment};
+    use chrono::Utc;
+
+    fn store_files(storage: &Storage, paths: &[&str]) -> Result<()> {
+        storage.store_blobs(
+            paths
+                .iter()
+                .map(|path| Blob {
+                    path: (*path).into(),
+                    mime: "text/plain".into(),
+                    date_updated: Utc::now(),
+                    compression: None,
+                    content: path.as_bytes().to_vec(),
+                })
+                .collect(),
+        )
+    }
+
+    fn migrate(
+        env: &TestEnvironment,
+        options: &MigrateStorageOptions,
+    ) -> Result<MigrationProgress> {
+        migrate_storage(
+            env.db().pool(),
+            env.metrics(),
+            env.config(),
+            StorageKind::Database,
+            StorageKind::Filesystem,
+            options,
+        )
+    }
+
+    fn filesystem(env: &TestEnvironment) -> Result<Storage> {
+        Storage::with_backend(
+            env.db().pool(),
+            env.metrics(),
+            env.config(),
+            StorageKind::Filesystem,
+        )
+    }
+
+    #[test]
+    fn copies_all_files() {
+        wrapper(|env| {
+            let storage = env.storage();
+            store_files(&storage, &["a.txt", "b/c.txt", "b/d.txt"])?;
+            storage.store_one("e.txt", b"compressed".to_vec())?;
+
+            let options = MigrateStorageOptions {
+                batch_size: 2,
+                ..Default::default()
+            };
+            let progress = migrate(env, &options)?;
+            assert_eq!(progress.files, 4);
+
+            let target = filesystem(env)?;
+            for path in ["a.txt", "b/c.txt", "b/d.txt"] {
+                let blob = target.get(path, std::usize::MAX)?;
+                assert_eq!(blob.content, path.as_bytes());
+                assert_eq!(blob.mime, "text/plain");
+            }
+            // the files are copied as they are stored, without decompressing them
+            let raw = target.get_raw("e.txt", std::usize::MAX, None)?;
+            assert_eq!(raw.compression, Some(CompressionAlgorithm::default()));
+            assert_eq!(target.get("e.txt", std::usize::MAX)?.content, b"compressed");
+
+            // the source is kept by default
+            assert!(storage.exists("a.txt")?);
+
+            // a finished migration isn't started again
+            store_files(&storage, &["f.txt"])?;
+            assert_eq!(migrate(env, &options)?, progress);
+            assert!(!target.exists("f.txt")?);
+
+            let progress = migrate(
+                env,
+                &MigrateStorageOptions {
+                    restart: true,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(progress.files, 5);
+            assert!(target.exists("f.txt")?);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn limits_the_batch_size_in_bytes() {
+        wrapper(|env| {
+            let storage = env.storage();
+            store_files(&storage, &["a.txt", "b/c.txt", "b/d.txt", "b/e.txt"])?;
+
+            // every file is copied on its own, but the migration still finishes
+            let progress = migrate(
+                env,
+                &MigrateStorageOptions {
+                    batch_max_bytes: 1,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(progress.files, 4);
+            let row = env.db().conn().query_one(
+                "SELECT last_path FROM storage_migrations WHERE finished_at IS NOT NULL",
+                &[],
+            )?;
+            assert_eq!(row.get::<_, Option<String>>(0).as_deref(), Some("b/e.txt"));
+            assert_eq!(filesystem(env)?.list_paths(None, 10)?.len(), 4);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn resumes_after_the_checkpoint() {
+        wrapper(|env| {
+            let storage = env.storage();
+            store_files(&storage, &["a.txt", "b.txt", "c.txt"])?;
+
+            // simulate a previous run that was interrupted after copying the first file
+            let first = storage.list_paths(None, 1)?.remove(0);
+            env.db().conn().execute(
+                "INSERT INTO storage_migrations (source, destination, last_path, files, bytes)
+                 VALUES ('database', 'filesystem', $1, 1, 5)",
+                &[&first],
+            )?;
+
+            let progress = migrate(env, &MigrateStorageOptions::default())?;
+            assert_eq!(progress.files, 3);
+
+            let target = filesystem(env)?;
+            assert!(!target.exists(&first)?);
+            assert_eq!(target.list_paths(None, 10)?.len(), 2);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn deletes_the_source() {
+        wrapper(|env| {
+            let storage = env.storage();
+            store_files(&storage, &["a.txt", "b.txt", "c.txt"])?;
+
+            let progress = migrate(
+                env,
+                &MigrateStorageOptions {
+                    batch_size: 2,
+                    delete_source: true,
+                    ..Default::default()
+                },
+            )?;
+            assert_eq!(progress.files, 3);
+
+            assert!(storage.list_paths(None, 10)?.is_empty());
+            assert_eq!(filesystem(env)?.list_paths(None, 10)?.len(), 3);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn rejects_the_same_backend() {
+        wrapper(|env| {
+            assert!(migrate_storage(
+                env.db().pool(),
+                env.metrics(),
+                env.config(),
+                StorageKind::Database,
+                StorageKind::Database,
+                &MigrateStorageOptions::default(),
+            )
+            .is_err());
+            Ok(())
+        });
+    }
+}
@@ -1,22 +1,33 @@
+mod archive_cache;
 mod archive_index;
 mod compression;
 mod database;
+mod dedup;
+mod filesystem;
+mod gc;
+mod migrate;
 mod s3;
+mod verify;
 
+use self::archive_cache::ArchiveIndexCache;
 pub use self::compression::{compress, decompress, CompressionAlgorithm, CompressionAlgorithms};
 use self::database::DatabaseBackend;
+use self::dedup::content_blob_path;
+use self::filesystem::FilesystemBackend;
+pub use self::gc::{gc_storage, GcStorageOptions, GcSummary, OrphanedObject};
+pub use self::migrate::{migrate_storage, MigrateStorageOptions, MigrationProgress};
 use self::s3::S3Backend;
+pub use self::verify::{verify_storage, ArchiveProblem, ReleaseReport, VerifyStorageFilter};
 use crate::error::Result;
 use crate::web::metrics::RenderingTimesRecorder;
 use crate::{db::Pool, Config, Metrics};
-use anyhow::{anyhow, ensure};
+use anyhow::ensure;
 use chrono::{DateTime, Utc};
 use path_slash::PathExt;
 use std::{
     collections::{HashMap, HashSet},
     ffi::OsStr,
-    fmt, fs,
-    io::{self, Write},
+    fmt, fs, io,
     ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::Arc,
@@ -24,7 +35,7 @@ use std::{
 
 const MAX_CONCURRENT_UPLOADS: usize = 1000;
 
-type FileRange = RangeInclusive<u64>;
+pub(crate) type FileRange = RangeInclusive<u64>;
 
 #[derive(Debug, thiserror::Error)]
 #[error("path not found")]
@@ -39,6 +50,15 @@ pub(crate) struct Blob {
     pub(crate) compression: Option<CompressionAlgorithm>,
 }
 
+/// An object in the storage, without its content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoredObject {
+    pub(crate) path: String,
+    /// the size of the stored content, compressed or not
+    pub(crate) size: u64,
+    pub(crate) date_updated: DateTime<Utc>,
+}
+
 impl Blob {
     pub(crate) fn is_empty(&self) -> bool {
         self.mime == "application/x-empty"
@@ -82,12 +102,13 @@ pub fn get_file_list<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
 
 #[derive(Debug, thiserror::Error)]
 #[error("invalid storage backend")]
-pub(crate) struct InvalidStorageBackendError;
+pub struct InvalidStorageBackendError;
 
-#[derive(Debug)]
-pub(crate) enum StorageKind {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
     Database,
     S3,
+    Filesystem,
 }
 
 impl std::str::FromStr for StorageKind {
@@ -97,30 +118,68 @@ impl std::str::FromStr for StorageKind {
         match input {
             "database" => Ok(StorageKind::Database),
             "s3" => Ok(StorageKind::S3),
+            "filesystem" => Ok(StorageKind::Filesystem),
             _ => Err(InvalidStorageBackendError),
         }
     }
 }
 
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StorageKind::Database => "database",
+            StorageKind::S3 => "s3",
+            StorageKind::Filesystem => "filesystem",
+        })
+    }
+}
+
 enum StorageBackend {
     Database(DatabaseBackend),
     S3(Box<S3Backend>),
+    Filesystem(FilesystemBackend),
 }
 
 pub struct Storage {
     backend: StorageBackend,
+    /// used for the reference counts of deduplicated files, independent of the backend
+    pool: Pool,
+    archive_index_cache: ArchiveIndexCache,
     config: Arc<Config>,
 }
 
 impl Storage {
     pub fn new(pool: Pool, metrics: Arc<Metrics>, config: Arc<Config>) -> Result<Self> {
+        let kind = config.storage_backend;
+        Self::with_backend(pool, metrics, config, kind)
+    }
+
+    /// Creates a storage using `kind` as the backend, instead of the configured one.
+    pub(crate) fn with_backend(
+        pool: Pool,
+        metrics: Arc<Metrics>,
+        config: Arc<Config>,
+        kind: StorageKind,
+    ) -> Result<Self> {
+        let archive_index_cache = ArchiveIndexCache::new(
+            config.local_archive_cache_path.clone(),
+            config.local_archive_cache_max_size,
+            metrics.clone(),
+        );
+        archive_index_cache.scan_in_background();
+
         Ok(Storage {
             config: config.clone(),
-            backend: match config.storage_backend {
+            pool: pool.clone(),
+            archive_index_cache,
+            backend: match kind {
                 StorageKind::Database => {
-                    StorageBackend::Database(DatabaseBackend::new(pool, metrics))
+                    StorageBackend::Database(DatabaseBackend::new(pool.clone(), metrics))
                 }
                 StorageKind::S3 => StorageBackend::S3(Box::new(S3Backend::new(metrics, &config)?)),
+                StorageKind::Filesystem => {
+                    StorageBackend::Filesystem(FilesystemBackend::new(metrics, &config)?)
+                }
             },
         })
     }
@@ -129,6 +188,16 @@ impl Storage {
         match &self.backend {
             StorageBackend::Database(db) => db.exists(path),
             StorageBackend::S3(s3) => s3.exists(path),
+            StorageBackend::Filesystem(fs) => fs.exists(path),
+        }
+    }
+
+    /// Returns the size of the object at `path`, as it's stored in the backend.
+    pub(crate) fn size(&self, path: &str) -> Result<u64> {
+        match &self.backend {
+            StorageBackend::Database(db) => db.size(path),
+            StorageBackend::S3(s3) => s3.size(path),
+            StorageBackend::Filesystem(fs) => fs.size(path),
         }
     }
 
@@ -146,20 +215,26 @@ impl Storage {
         version: &str,
         path: &str,
         archive_storage: bool,
+        accepted_encodings: &[CompressionAlgorithm],
         fetch_time: &mut RenderingTimesRecorder,
     ) -> Result<Blob> {
         Ok(if archive_storage {
-            self.get_from_archive(
+            self.get_from_archive_encoded(
                 &rustdoc_archive_path(name, version),
                 path,
                 self.max_file_size_for(path),
                 Some(fetch_time),
+                accepted_encodings,
             )?
         } else {
             fetch_time.step("fetch from storage");
             // Add rustdoc prefix, name and version to the path for accessing the file stored in the database
             let remote_path = format!("rustdoc/{}/{}/{}", name, version, path);
-            self.get(&remote_path, self.max_file_size_for(path))?
+            self.get_encoded(
+                &remote_path,
+                self.max_file_size_for(path),
+                accepted_encodings,
+            )?
         })
     }
 
@@ -169,17 +244,23 @@ impl Storage {
         version: &str,
         path: &str,
         archive_storage: bool,
+        accepted_encodings: &[CompressionAlgorithm],
     ) -> Result<Blob> {
         Ok(if archive_storage {
-            self.get_from_archive(
+            self.get_from_archive_encoded(
                 &source_archive_path(name, version),
                 path,
                 self.max_file_size_for(path),
                 None,
+                accepted_encodings,
             )?
         } else {
             let remote_path = format!("sources/{}/{}/{}", name, version, path);
-            self.get(&remote_path, self.max_file_size_for(path))?
+            self.get_encoded(
+                &remote_path,
+                self.max_file_size_for(path),
+                accepted_encodings,
+            )?
         })
     }
 
@@ -200,8 +281,8 @@ impl Storage {
     }
 
     pub(crate) fn exists_in_archive(&self, archive_path: &str, path: &str) -> Result<bool> {
-        match self.get_index_filename(archive_path) {
-            Ok(index_filename) => Ok(archive_index::find_in_file(index_filename, path)?.is_some()),
+        match self.open_index(archive_path) {
+            Ok(index) => Ok(archive_index::find_in_file(&index, path)?.is_some()),
             Err(err) => {
                 if err.downcast_ref::<PathNotFoundError>().is_some() {
                     Ok(false)
@@ -212,29 +293,95 @@ impl Storage {
         }
     }
 
-    pub(crate) fn get(&self, path: &str, max_size: usize) -> Result<Blob> {
-        let mut blob = match &self.backend {
-            StorageBackend::Database(db) => db.get(path, max_size, None),
-            StorageBackend::S3(s3) => s3.get(path, max_size, None),
-        }?;
-        if let Some(alg) = blob.compression {
-            blob.content = decompress(blob.content.as_slice(), alg, max_size)?;
-            blob.compression = None;
+    /// Lists the paths of all files in the archive at `archive_path`, in order.
+    pub(crate) fn list_archive_files(&self, archive_path: &str) -> Result<Vec<String>> {
+        let index = self.get(&format!("{}.index", archive_path), std::usize::MAX)?;
+        let mut files: Vec<_> = archive_index::parse_remote(&index.content)?
+            .into_keys()
+            .collect();
+        files.sort_unstable();
+        Ok(files)
+    }
+
+    /// Returns the blob at `path` exactly as it's stored in the backend, without decompressing it.
+    fn get_raw(&self, path: &str, max_size: usize, range: Option<FileRange>) -> Result<Blob> {
+        match &self.backend {
+            StorageBackend::Database(db) => db.get(path, max_size, range),
+            StorageBackend::S3(s3) => s3.get(path, max_size, range),
+            StorageBackend::Filesystem(fs) => fs.get(path, max_size, range),
+        }
+    }
+
+    /// Calls `f` with the chunks of the blob at `path`, or of the part of it in `range`, exactly
+    /// as it's stored in the backend, without holding the whole blob in memory where the backend
+    /// allows it.
+    pub(crate) fn read_raw_chunks(
+        &self,
+        path: &str,
+        range: Option<FileRange>,
+        f: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<()> {
+        match &self.backend {
+            StorageBackend::Database(db) => db.read_chunks(path, range, f),
+            StorageBackend::S3(s3) => s3.read_chunks(path, range, f),
+            StorageBackend::Filesystem(fs) => fs.read_chunks(path, range, f),
         }
+    }
+
+    /// Lists up to `limit` paths in the storage, ordered by path, starting after `after`.
+    ///
+    /// The order is only stable for the same backend, as it depends on how the backend compares
+    /// the paths.
+    #[cfg(test)]
+    pub(crate) fn list_paths(&self, after: Option<&str>, limit: usize) -> Result<Vec<String>> {
+        Ok(self
+            .list_objects(after, limit)?
+            .into_iter()
+            .map(|object| object.path)
+            .collect())
+    }
+
+    /// Like [`Storage::list_paths`], including the size and modification date of every object.
+    pub(crate) fn list_objects(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredObject>> {
+        match &self.backend {
+            StorageBackend::Database(db) => db.list_objects(after, limit),
+            StorageBackend::S3(s3) => s3.list_objects(after, limit),
+            StorageBackend::Filesystem(fs) => fs.list_objects(after, limit),
+        }
+    }
+
+    pub(crate) fn get(&self, path: &str, max_size: usize) -> Result<Blob> {
+        self.get_encoded(path, max_size, &[])
+    }
+
+    /// Fetches the blob at `path`, decompressing it unless it's stored compressed with one of the
+    /// `accepted_encodings`. In that case the compressed content is returned as it's stored, and
+    /// `Blob::compression` tells how it's compressed.
+    ///
+    /// `max_size` applies to the returned content, compressed or not.
+    pub(crate) fn get_encoded(
+        &self,
+        path: &str,
+        max_size: usize,
+        accepted_encodings: &[CompressionAlgorithm],
+    ) -> Result<Blob> {
+        let mut blob = self.get_raw(path, max_size, None)?;
+        decode(&mut blob, max_size, accepted_encodings)?;
         Ok(blob)
     }
 
-    pub(super) fn get_range(
+    pub(crate) fn get_range(
         &self,
         path: &str,
         max_size: usize,
         range: FileRange,
         compression: Option<CompressionAlgorithm>,
     ) -> Result<Blob> {
-        let mut blob = match &self.backend {
-            StorageBackend::Database(db) => db.get(path, max_size, Some(range)),
-            StorageBackend::S3(s3) => s3.get(path, max_size, Some(range)),
-        }?;
+        let mut blob = self.get_raw(path, max_size, Some(range))?;
         // `compression` represents the compression of the file-stream inside the archive.
         // We don't compress the whole archive, so the encoding of the archive's blob is irrelevant
         // here.
@@ -245,59 +392,61 @@ impl Storage {
         Ok(blob)
     }
 
-    fn get_index_filename(&self, archive_path: &str) -> Result<PathBuf> {
+    fn open_index(&self, archive_path: &str) -> Result<fs::File> {
         // remote/folder/and/x.zip.index
         let remote_index_path = format!("{}.index", archive_path);
-        let local_index_path = self
-            .config
-            .local_archive_cache_path
-            .join(&remote_index_path);
-
-        if !local_index_path.exists() {
-            let index_content = self.get(&remote_index_path, std::usize::MAX)?.content;
-
-            fs::create_dir_all(
-                local_index_path
-                    .parent()
-                    .ok_or_else(|| anyhow!("index path without parent"))?,
-            )?;
-            let mut file = fs::File::create(&local_index_path)?;
-            file.write_all(&index_content)?;
-        }
-
-        Ok(local_index_path)
+        self.archive_index_cache.open(&remote_index_path, || {
+            Ok(self.get(&remote_index_path, std::usize::MAX)?.content)
+        })
     }
 
+    #[cfg(test)]
     pub(crate) fn get_from_archive(
+        &self,
+        archive_path: &str,
+        path: &str,
+        max_size: usize,
+        fetch_time: Option<&mut RenderingTimesRecorder>,
+    ) -> Result<Blob> {
+        self.get_from_archive_encoded(archive_path, path, max_size, fetch_time, &[])
+    }
+
+    /// Like [`Storage::get_encoded`], for a file in the archive at `archive_path`.
+    pub(crate) fn get_from_archive_encoded(
         &self,
         archive_path: &str,
         path: &str,
         max_size: usize,
         mut fetch_time: Option<&mut RenderingTimesRecorder>,
+        accepted_encodings: &[CompressionAlgorithm],
     ) -> Result<Blob> {
         if let Some(ref mut t) = fetch_time {
             t.step("find path in index");
         }
-        let info = archive_index::find_in_file(self.get_index_filename(archive_path)?, path)?
+        let info = archive_index::find_in_file(&self.open_index(archive_path)?, path)?
             .ok_or(PathNotFoundError)?;
 
         if let Some(t) = fetch_time {
             t.step("range request");
         }
-        let blob = self.get_range(
-            archive_path,
+        // deduplicated files are stored in their own blob instead of the archive
+        let source = info.blob().map(content_blob_path);
+        let mut blob = self.get_raw(
+            source.as_deref().unwrap_or(archive_path),
             max_size,
-            info.range(),
-            Some(info.compression()),
+            Some(info.range()),
         )?;
-        assert_eq!(blob.compression, None);
+        // We don't compress the whole archive, so the encoding of the archive's blob is
+        // irrelevant. Only the file-stream inside the archive is compressed.
+        blob.compression = Some(info.compression());
+        decode(&mut blob, max_size, accepted_encodings)?;
 
         Ok(Blob {
             path: format!("{}/{}", archive_path, path),
             mime: detect_mime(&path).into(),
             date_updated: blob.date_updated,
             content: blob.content,
-            compression: None,
+            compression: blob.compression,
         })
     }
 
@@ -306,6 +455,11 @@ impl Storage {
         archive_path: &str,
         root_dir: &Path,
     ) -> Result<(HashMap<PathBuf, String>, CompressionAlgorithm)> {
+        if self.config.archive_deduplication {
+            let file_paths = self.store_all_deduplicated(archive_path, root_dir)?;
+            return Ok((file_paths, CompressionAlgorithm::Zstd));
+        }
+
         let mut file_paths = HashMap::new();
 
         // We are only using the `zip` library to create the archives and the matching
@@ -317,10 +471,13 @@ impl Storage {
         // For decompression we are sharing the compression algorithms defined in
         // `storage::compression`. So every new algorithm to be used inside ZIP archives
         // also has to be added as supported algorithm for storage compression, together
-        // with a mapping in `storage::archive_index::Index::new_from_zip`.
+        // with a mapping in `storage::archive_index::create`.
+        //
+        // Older archives were written using bzip2, which is why the index stores the algorithm
+        // for every file instead of assuming one for the whole archive.
 
         let options =
-            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Bzip2);
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Zstd);
 
         let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
         for file_path in get_file_list(root_dir)? {
@@ -342,16 +499,8 @@ impl Storage {
         let remote_index_path = format!("{}.index", &archive_path);
 
         // additionally store the index in the local cache, so it's directly available
-        let local_index_path = self
-            .config
-            .local_archive_cache_path
-            .join(&remote_index_path);
-        if local_index_path.exists() {
-            fs::remove_file(&local_index_path)?;
-        }
-        fs::create_dir_all(local_index_path.parent().unwrap())?;
-        let mut local_index_file = fs::File::create(&local_index_path)?;
-        local_index_file.write_all(&index_content)?;
+        self.archive_index_cache
+            .insert(&remote_index_path, &index_content)?;
 
         self.store_inner(
             vec![
@@ -374,7 +523,10 @@ impl Storage {
             .map(Ok),
         )?;
 
-        let file_alg = CompressionAlgorithm::Bzip2;
+        // the archive could have been stored deduplicated by a previous build
+        self.release_deduplicated_files(archive_path)?;
+
+        let file_alg = CompressionAlgorithm::Zstd;
         Ok((file_paths, file_alg))
     }
 
@@ -389,6 +541,7 @@ impl Storage {
                 Box::new(conn.start_storage_transaction()?)
             }
             StorageBackend::S3(s3) => Box::new(s3.start_storage_transaction()),
+            StorageBackend::Filesystem(fs) => Box::new(fs.start_storage_transaction()),
         };
 
         let res = f(trans.as_mut())?;
@@ -418,7 +571,7 @@ impl Storage {
                     .map(|file| (file_path, file))
             })
             .map(|(file_path, file)| -> Result<_> {
-                let alg = CompressionAlgorithm::default();
+                let alg = self.config.storage_compression;
                 let content = compress(file, alg)?;
                 let bucket_path = prefix.join(&file_path).to_slash().unwrap().to_string();
 
@@ -454,7 +607,7 @@ impl Storage {
     ) -> Result<CompressionAlgorithm> {
         let path = path.into();
         let content = content.into();
-        let alg = CompressionAlgorithm::default();
+        let alg = self.config.storage_compression;
         let content = compress(&*content, alg)?;
         let mime = detect_mime(&path).to_owned();
 
@@ -470,6 +623,17 @@ impl Storage {
         Ok(alg)
     }
 
+    // Store the local file at `local_path` into the backend at the given path without compressing
+    // it, for files which are compressed already and may be too big to keep in memory
+    pub(crate) fn store_file_uncompressed(&self, path: &str, local_path: &Path) -> Result<()> {
+        let mime = detect_mime(path);
+        match &self.backend {
+            StorageBackend::Database(db) => db.store_file(path, mime, local_path),
+            StorageBackend::S3(s3) => s3.store_file(path, mime, local_path),
+            StorageBackend::Filesystem(fs) => fs.store_file(path, mime, local_path),
+        }
+    }
+
     fn store_inner(&self, blobs: impl IntoIterator<Item = Result<Blob>>) -> Result<()> {
         let mut blobs = blobs.into_iter();
         self.transaction(|trans| {
@@ -508,6 +672,7 @@ impl std::fmt::Debug for Storage {
         match &self.backend {
             StorageBackend::Database(_) => write!(f, "database-backed storage"),
             StorageBackend::S3(_) => write!(f, "S3-backed storage"),
+            StorageBackend::Filesystem(_) => write!(f, "filesystem-backed storage"),
         }
     }
 }
@@ -515,9 +680,25 @@ impl std::fmt::Debug for Storage {
 trait StorageTransaction {
     fn store_batch(&mut self, batch: Vec<Blob>) -> Result<()>;
     fn delete_prefix(&mut self, prefix: &str) -> Result<()>;
+    fn delete_batch(&mut self, paths: &[String]) -> Result<()>;
     fn complete(self: Box<Self>) -> Result<()>;
 }
 
+/// Decompresses the content of `blob`, unless it's compressed with one of the `accepted_encodings`.
+fn decode(
+    blob: &mut Blob,
+    max_size: usize,
+    accepted_encodings: &[CompressionAlgorithm],
+) -> Result<()> {
+    if let Some(alg) = blob.compression {
+        if !accepted_encodings.contains(&alg) {
+            blob.content = decompress(blob.content.as_slice(), alg, max_size)?;
+            blob.compression = None;
+        }
+    }
+    Ok(())
+}
+
 fn detect_mime(file_path: impl AsRef<Path>) -> &'static str {
     let mime = mime_guess::from_path(file_path.as_ref())
         .first_raw()
@@ -544,10 +725,21 @@ pub(crate) fn rustdoc_archive_path(name: &str, version: &str) -> String {
     format!("rustdoc/{0}/{1}.zip", name, version)
 }
 
+/// The compressed rustdoc JSON output of the default target of a release.
+pub(crate) fn rustdoc_json_path(name: &str, version: &str) -> String {
+    format!("rustdoc/{0}/{1}.json", name, version)
+}
+
 pub(crate) fn source_archive_path(name: &str, version: &str) -> String {
     format!("sources/{0}/{1}.zip", name, version)
 }
 
+/// The folder of the cached offline bundles of a release, which are built from its archives when
+/// they're first downloaded.
+pub(crate) fn download_bundles_prefix(name: &str, version: &str) -> String {
+    format!("downloads/{0}/{1}/", name, version)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -596,6 +788,7 @@ mod test {
 mod backend_tests {
     use super::*;
     use std::fs;
+    use std::io::Write;
 
     fn test_exists(storage: &Storage) -> Result<()> {
         assert!(!storage.exists("path/to/file.txt").unwrap());
@@ -638,6 +831,86 @@ mod backend_tests {
         Ok(())
     }
 
+    fn test_size(storage: &Storage) -> Result<()> {
+        storage.store_blobs(vec![Blob {
+            path: "foo/bar.txt".into(),
+            mime: "text/plain".into(),
+            date_updated: Utc::now(),
+            compression: None,
+            content: b"test content\n".to_vec(),
+        }])?;
+
+        assert_eq!(storage.size("foo/bar.txt")?, 13);
+        assert!(storage
+            .size("foo/baz.txt")
+            .unwrap_err()
+            .downcast_ref::<PathNotFoundError>()
+            .is_some());
+
+        Ok(())
+    }
+
+    fn test_list_paths(storage: &Storage) -> Result<()> {
+        let paths = [
+            "a.txt",
+            "b-c.txt",
+            "b.txt",
+            "b/a.txt",
+            "b/c.txt",
+            "c/d/e.txt",
+        ];
+        storage.store_blobs(
+            paths
+                .iter()
+                .map(|path| Blob {
+                    path: (*path).into(),
+                    mime: "text/plain".into(),
+                    date_updated: Utc::now(),
+                    compression: None,
+                    content: b"content".to_vec(),
+                })
+                .collect(),
+        )?;
+
+        // the exact order depends on the backend, but every path is listed exactly once
+        let mut listed = Vec::new();
+        let mut after = None;
+        loop {
+            let batch = storage.list_paths(after.as_deref(), 2)?;
+            assert!(batch.len() <= 2);
+            match batch.last() {
+                Some(last) => after = Some(last.clone()),
+                None => break,
+            }
+            listed.extend(batch);
+        }
+        listed.sort();
+        assert_eq!(listed, paths);
+
+        for object in storage.list_objects(None, 10)? {
+            assert_eq!(object.size, b"content".len() as u64);
+            assert!(object.date_updated <= Utc::now());
+        }
+
+        Ok(())
+    }
+
+    fn test_delete_batch(storage: &Storage) -> Result<()> {
+        for path in ["foo/bar.zip", "foo/bar.zip.index", "foo/baz.txt"] {
+            storage.store_one(path, b"content".to_vec())?;
+        }
+
+        storage.transaction(|trans| {
+            trans.delete_batch(&["foo/bar.zip".into(), "foo/missing".into()])
+        })?;
+
+        assert!(!storage.exists("foo/bar.zip")?);
+        assert!(storage.exists("foo/bar.zip.index")?);
+        assert!(storage.exists("foo/baz.txt")?);
+
+        Ok(())
+    }
+
     fn test_get_range(storage: &Storage) -> Result<()> {
         let blob = Blob {
             path: "foo/bar.txt".into(),
@@ -673,6 +946,27 @@ mod backend_tests {
         Ok(())
     }
 
+    fn test_get_path_traversal(storage: &Storage) -> Result<()> {
+        storage.store_blobs(vec![Blob {
+            path: "foo/bar.txt".into(),
+            mime: "text/plain".into(),
+            date_updated: Utc::now(),
+            compression: None,
+            content: b"test content\n".to_vec(),
+        }])?;
+
+        for path in &["foo/../foo/bar.txt", "../foo/bar.txt", "/foo/bar.txt"] {
+            assert!(!storage.exists(path)?);
+            assert!(storage
+                .get(path, std::usize::MAX)
+                .unwrap_err()
+                .downcast_ref::<PathNotFoundError>()
+                .is_some());
+        }
+
+        Ok(())
+    }
+
     fn test_get_too_big(storage: &Storage) -> Result<()> {
         const MAX_SIZE: usize = 1024;
 
@@ -773,7 +1067,7 @@ mod backend_tests {
         assert!(local_index_location.exists());
         assert!(storage.exists("folder/test.zip.index")?);
 
-        assert_eq!(compression_alg, CompressionAlgorithm::Bzip2);
+        assert_eq!(compression_alg, CompressionAlgorithm::Zstd);
         assert_eq!(stored_files.len(), files.len());
         for name in &files {
             let name = Path::new(name);
@@ -799,6 +1093,12 @@ mod backend_tests {
         assert!(local_index_location.exists());
         assert!(storage.exists_in_archive("folder/test.zip", "src/main.rs")?);
 
+        // index files downloaded by older versions are migrated on the first access
+        let remote_index = storage.get("folder/test.zip.index", std::usize::MAX)?;
+        fs::write(&local_index_location, remote_index.content)?;
+        assert!(storage.exists_in_archive("folder/test.zip", "Cargo.toml")?);
+        assert!(archive_index::is_local_format(&local_index_location)?);
+
         let file =
             storage.get_from_archive("folder/test.zip", "Cargo.toml", std::usize::MAX, None)?;
         assert_eq!(file.content, b"data");
@@ -816,6 +1116,75 @@ mod backend_tests {
         Ok(())
     }
 
+    fn test_get_from_bzip2_archive(storage: &Storage) -> Result<()> {
+        // Archives created before the switch to zstd have to stay readable.
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        zip.start_file(
+            "Cargo.toml",
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Bzip2),
+        )?;
+        zip.write_all(b"data")?;
+        let mut zip_content = zip.finish()?.into_inner();
+
+        let mut index_content = vec![];
+        archive_index::create(&mut io::Cursor::new(&mut zip_content), &mut index_content)?;
+
+        storage.store_blobs(vec![
+            Blob {
+                path: "folder/old.zip".into(),
+                mime: "application/zip".into(),
+                date_updated: Utc::now(),
+                content: zip_content,
+                compression: None,
+            },
+            Blob {
+                path: "folder/old.zip.index".into(),
+                mime: "application/octet-stream".into(),
+                date_updated: Utc::now(),
+                content: index_content,
+                compression: None,
+            },
+        ])?;
+
+        let file =
+            storage.get_from_archive("folder/old.zip", "Cargo.toml", std::usize::MAX, None)?;
+        assert_eq!(file.content, b"data");
+        assert_eq!(file.mime, "text/toml");
+
+        Ok(())
+    }
+
+    fn test_get_encoded(storage: &Storage) -> Result<()> {
+        let content = b"var searchIndex = {};";
+        let blobs = [CompressionAlgorithm::Gzip, CompressionAlgorithm::Brotli]
+            .iter()
+            .map(|&alg| -> Result<_> {
+                Ok(Blob {
+                    path: format!("encoded/{}.js", alg),
+                    mime: "application/javascript".into(),
+                    date_updated: Utc::now(),
+                    content: compress(&content[..], alg)?,
+                    compression: Some(alg),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        storage.store_blobs(blobs.clone())?;
+
+        for blob in blobs {
+            let alg = blob.compression.unwrap();
+            let encoded = storage.get_encoded(&blob.path, std::usize::MAX, &[alg])?;
+            assert_eq!(encoded.compression, Some(alg));
+            assert_eq!(encoded.content, blob.content);
+
+            let decoded =
+                storage.get_encoded(&blob.path, std::usize::MAX, &[CompressionAlgorithm::Zstd])?;
+            assert_eq!(decoded.compression, None);
+            assert_eq!(decoded.content, content);
+        }
+
+        Ok(())
+    }
+
     fn test_store_all(storage: &Storage, metrics: &Metrics) -> Result<()> {
         let dir = tempfile::Builder::new()
             .prefix("docs.rs-upload-test")
@@ -1008,6 +1377,7 @@ mod backend_tests {
         backends {
             s3 => StorageKind::S3,
             database => StorageKind::Database,
+            filesystem => StorageKind::Filesystem,
         }
 
         tests {
@@ -1015,11 +1385,17 @@ mod backend_tests {
             test_exists,
             test_get_object,
             test_get_range,
+            test_size,
+            test_list_paths,
+            test_delete_batch,
+            test_get_path_traversal,
             test_get_too_big,
             test_delete_prefix,
             test_delete_prefix_without_matches,
             test_delete_percent,
             test_exists_without_remote_archive,
+            test_get_from_bzip2_archive,
+            test_get_encoded,
         }
 
         tests_with_metrics {
@@ -1,10 +1,10 @@
-use super::{Blob, FileRange, StorageTransaction};
+use super::{Blob, FileRange, StorageTransaction, StoredObject};
 use crate::{Config, Metrics};
 use anyhow::{Context, Error};
 use aws_sdk_s3::{
     error,
     model::{Delete, ObjectIdentifier},
-    types::SdkError,
+    types::{ByteStream, SdkError},
     Client, Endpoint, Region, RetryConfig,
 };
 use aws_smithy_types_convert::date_time::DateTimeExt;
@@ -13,7 +13,7 @@ use futures_util::{
     future::TryFutureExt,
     stream::{FuturesUnordered, StreamExt},
 };
-use std::{io::Write, sync::Arc};
+use std::{io::Write, path::Path, sync::Arc};
 use tokio::runtime::Runtime;
 
 pub(super) struct S3Backend {
@@ -88,6 +88,62 @@ impl S3Backend {
         })
     }
 
+    pub(super) fn size(&self, path: &str) -> Result<u64, Error> {
+        self.runtime.block_on(async {
+            match self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(path)
+                .send()
+                .await
+            {
+                Ok(res) => Ok(res.content_length.try_into()?),
+                Err(SdkError::ServiceError { err, raw })
+                    if (matches!(err.kind, error::HeadObjectErrorKind::NotFound(_))
+                        || raw.http().status() == http::StatusCode::NOT_FOUND) =>
+                {
+                    Err(super::PathNotFoundError.into())
+                }
+                Err(other) => Err(other.into()),
+            }
+        })
+    }
+
+    pub(super) fn list_objects(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredObject>, Error> {
+        self.runtime.block_on(async {
+            let list = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .set_start_after(after.map(|after| after.to_owned()))
+                .max_keys(limit.try_into()?)
+                .send()
+                .await?;
+
+            Ok(list
+                .contents
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|obj| {
+                    Some(StoredObject {
+                        path: obj.key?,
+                        size: obj.size.try_into().unwrap_or(0),
+                        // see the comment about the missing modification date in `get`
+                        date_updated: obj
+                            .last_modified
+                            .map(|dt| dt.to_chrono_utc())
+                            .unwrap_or_else(Utc::now),
+                    })
+                })
+                .collect())
+        })
+    }
+
     pub(super) fn get(
         &self,
         path: &str,
@@ -102,15 +158,7 @@ impl S3Backend {
                 .key(path)
                 .set_range(range.map(|r| format!("bytes={}-{}", r.start(), r.end())))
                 .send()
-                .map_err(|err| match err {
-                    SdkError::ServiceError { err, raw }
-                        if (matches!(err.kind, error::GetObjectErrorKind::NoSuchKey(_))
-                            || raw.http().status() == http::StatusCode::NOT_FOUND) =>
-                    {
-                        super::PathNotFoundError.into()
-                    }
-                    err => Error::from(err),
-                })
+                .map_err(get_object_error)
                 .await?;
 
             let mut content = crate::utils::sized_buffer::SizedBuffer::new(max_size);
@@ -141,6 +189,54 @@ impl S3Backend {
         })
     }
 
+    /// Calls `f` with every chunk of the contents of the object at `path`, or of the part of
+    /// them in `range`, exactly as they're stored.
+    pub(super) fn read_chunks(
+        &self,
+        path: &str,
+        range: Option<FileRange>,
+        f: &mut dyn FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self.runtime.block_on(async {
+            let res = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(path)
+                .set_range(range.map(|r| format!("bytes={}-{}", r.start(), r.end())))
+                .send()
+                .map_err(get_object_error)
+                .await?;
+
+            let mut body = res.body;
+            while let Some(data) = body.next().await.transpose()? {
+                f(data.as_ref())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Uploads the local file at `local_path` uncompressed to `path`, streaming it from disk.
+    pub(super) fn store_file(
+        &self,
+        path: &str,
+        mime: &str,
+        local_path: &Path,
+    ) -> Result<(), Error> {
+        self.runtime.block_on(async {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(path)
+                .body(ByteStream::from_path(local_path).await?)
+                .content_type(mime)
+                .send()
+                .await?;
+            self.metrics.uploaded_files_total.inc();
+            Ok(())
+        })
+    }
+
     pub(super) fn start_storage_transaction(&self) -> S3StorageTransaction {
         S3StorageTransaction { s3: self }
     }
@@ -166,6 +262,18 @@ impl S3Backend {
     }
 }
 
+fn get_object_error(err: SdkError<error::GetObjectError>) -> Error {
+    match err {
+        SdkError::ServiceError { err, raw }
+            if (matches!(err.kind, error::GetObjectErrorKind::NoSuchKey(_))
+                || raw.http().status() == http::StatusCode::NOT_FOUND) =>
+        {
+            super::PathNotFoundError.into()
+        }
+        err => Error::from(err),
+    }
+}
+
 pub(super) struct S3StorageTransaction<'a> {
     s3: &'a S3Backend,
 }
@@ -269,6 +377,40 @@ impl<'a> StorageTransaction for S3StorageTransaction<'a> {
         })
     }
 
+    fn delete_batch(&mut self, paths: &[String]) -> Result<(), Error> {
+        self.s3.runtime.block_on(async {
+            // S3 only allows deleting 1000 objects in a single request
+            for chunk in paths.chunks(1000) {
+                let to_delete = Delete::builder()
+                    .set_objects(Some(
+                        chunk
+                            .iter()
+                            .map(|path| ObjectIdentifier::builder().key(path).build())
+                            .collect(),
+                    ))
+                    .build();
+
+                let resp = self
+                    .s3
+                    .client
+                    .delete_objects()
+                    .bucket(&self.s3.bucket)
+                    .delete(to_delete)
+                    .send()
+                    .await?;
+
+                if let Some(errs) = resp.errors {
+                    for err in &errs {
+                        log::error!("error deleting file from s3: {:?}", err);
+                    }
+
+                    anyhow::bail!("deleting from s3 failed");
+                }
+            }
+            Ok(())
+        })
+    }
+
     fn complete(self: Box<Self>) -> Result<(), Error> {
         Ok(())
     }
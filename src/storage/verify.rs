@@ -0,0 +1,467 @@
+//! Integrity checks for the archives of the releases in the storage.
+
+use super::{
+    archive_index, content_blob_path, rustdoc_archive_path, source_archive_path, PathNotFoundError,
+    Storage,
+};
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+use postgres::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// A problem found with one of the archives of a release.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "problem", rename_all = "kebab-case")]
+pub enum ArchiveProblem {
+    MissingArchive {
+        archive: String,
+    },
+    MissingIndex {
+        archive: String,
+    },
+    InvalidIndex {
+        archive: String,
+        error: String,
+    },
+    /// A deduplicated file of the archive is missing.
+    MissingBlob {
+        archive: String,
+        path: String,
+        blob: String,
+    },
+    /// The index points to bytes after the end of the archive (or the blob of a deduplicated
+    /// file), which means that either it's truncated or it doesn't belong to the index.
+    RangeOutsideArchive {
+        archive: String,
+        path: String,
+        range_end: u64,
+        archive_size: u64,
+    },
+    CorruptFile {
+        archive: String,
+        path: String,
+        error: String,
+    },
+}
+
+/// All the problems found with the archives of one release.
+#[derive(Debug, Serialize)]
+pub struct ReleaseReport {
+    pub name: String,
+    pub version: String,
+    pub problems: Vec<ArchiveProblem>,
+}
+
+/// Limits which releases are checked by [`verify_storage`].
+#[derive(Debug, Default)]
+pub struct VerifyStorageFilter {
+    pub crate_name: Option<String>,
+    /// only check releases published at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// only check releases published before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Storage {
+    /// Checks that the archive at `archive_path` and its index exist, that the index can be parsed
+    /// and only points inside the archive, and that `samples` of the files in it can be
+    /// decompressed.
+    pub(crate) fn verify_archive(
+        &self,
+        archive_path: &str,
+        samples: usize,
+    ) -> Result<Vec<ArchiveProblem>> {
+        let archive = archive_path.to_owned();
+        let mut problems = Vec::new();
+
+        let archive_size = match self.size(archive_path) {
+            Ok(size) => Some(size),
+            Err(err) if err.is::<PathNotFoundError>() => None,
+            Err(err) => return Err(err),
+        };
+        let missing_archive = || ArchiveProblem::MissingArchive {
+            archive: archive.clone(),
+        };
+
+        // The remote index is checked instead of the local copy, as the local copy could hide
+        // problems with the remote one.
+        let remote_index = match self.get(&format!("{}.index", archive_path), std::usize::MAX) {
+            Ok(blob) => blob.content,
+            Err(err) if err.is::<PathNotFoundError>() => {
+                if archive_size.is_none() {
+                    problems.push(missing_archive());
+                }
+                problems.push(ArchiveProblem::MissingIndex { archive });
+                return Ok(problems);
+            }
+            // the index exists, but it couldn't be decompressed
+            Err(err) if err.is::<std::io::Error>() => {
+                problems.push(ArchiveProblem::InvalidIndex {
+                    archive,
+                    error: format!("{:#}", err),
+                });
+                return Ok(problems);
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut files = match archive_index::parse_remote(&remote_index) {
+            Ok(files) => files.into_iter().collect::<Vec<_>>(),
+            Err(err) => {
+                problems.push(ArchiveProblem::InvalidIndex {
+                    archive,
+                    error: format!("{:#}", err),
+                });
+                return Ok(problems);
+            }
+        };
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // Deduplicated files are stored in their own blobs, an archive containing only those
+        // doesn't exist at all.
+        let mut sizes = HashMap::new();
+        sizes.insert(archive_path.to_owned(), archive_size);
+        if archive_size.is_none() && files.iter().any(|(_, info)| info.blob().is_none()) {
+            problems.push(missing_archive());
+        }
+
+        let mut sources = Vec::with_capacity(files.len());
+        let mut broken_ranges = false;
+        for (path, info) in &files {
+            let source = info
+                .blob()
+                .map(content_blob_path)
+                .unwrap_or_else(|| archive_path.to_owned());
+            let size = match sizes.get(&source) {
+                Some(size) => *size,
+                None => {
+                    let size = match self.size(&source) {
+                        Ok(size) => Some(size),
+                        Err(err) if err.is::<PathNotFoundError>() => {
+                            problems.push(ArchiveProblem::MissingBlob {
+                                archive: archive.clone(),
+                                path: path.clone(),
+                                blob: source.clone(),
+                            });
+                            None
+                        }
+                        Err(err) => return Err(err),
+                    };
+                    sizes.insert(source.clone(), size);
+                    size
+                }
+            };
+
+            let range = info.range();
+            match size {
+                Some(size) if *range.end() >= size => {
+                    problems.push(ArchiveProblem::RangeOutsideArchive {
+                        archive: archive.clone(),
+                        path: path.clone(),
+                        range_end: *range.end(),
+                        archive_size: size,
+                    });
+                    broken_ranges = true;
+                }
+                Some(_) => {}
+                None => broken_ranges = true,
+            }
+            sources.push(source);
+        }
+        if broken_ranges {
+            return Ok(problems);
+        }
+
+        // Decompress evenly spaced files, so the samples are spread over the whole archive.
+        let samples = samples.min(files.len());
+        for i in 0..samples {
+            let idx = i * files.len() / samples;
+            let (path, info) = &files[idx];
+            if let Err(err) = self.get_range(
+                &sources[idx],
+                self.max_file_size_for(path),
+                info.range(),
+                Some(info.compression()),
+            ) {
+                problems.push(ArchiveProblem::CorruptFile {
+                    archive: archive.clone(),
+                    path: path.clone(),
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+/// Verifies the archives of all the releases matching `filter`, calling `report` for every
+/// release with problems. Returns the number of releases that were checked.
+pub fn verify_storage(
+    conn: &mut Client,
+    storage: &Storage,
+    filter: &VerifyStorageFilter,
+    samples: usize,
+    mut report: impl FnMut(ReleaseReport) -> Result<()>,
+) -> Result<usize> {
+    let releases = conn.query(
+        "SELECT crates.name, releases.version, releases.rustdoc_status
+         FROM releases
+         INNER JOIN crates ON crates.id = releases.crate_id
+         WHERE
+            releases.archive_storage AND
+            ($1::TEXT IS NULL OR crates.name = $1) AND
+            ($2::TIMESTAMPTZ IS NULL OR releases.release_time >= $2) AND
+            ($3::TIMESTAMPTZ IS NULL OR releases.release_time < $3)
+         ORDER BY crates.name, releases.id",
+        &[&filter.crate_name, &filter.since, &filter.until],
+    )?;
+
+    for row in &releases {
+        let name: String = row.get(0);
+        let version: String = row.get(1);
+        let rustdoc_status: bool = row.get(2);
+
+        let mut problems =
+            storage.verify_archive(&source_archive_path(&name, &version), samples)?;
+        if rustdoc_status {
+            problems
+                .extend(storage.verify_archive(&rustdoc_archive_path(&name, &version), samples)?);
+        }
+
+        if !problems.is_empty() {
+            report(ReleaseReport {
+                name,
+                version,
+                problems,
+            })?;
+        }
+    }
+
+    Ok(releases.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Blob;
+    use crate::test::wrapper;
+    use chrono::Duration;
+
+    fn verify_all(env: &crate::test::TestEnvironment) -> Result<Vec<ReleaseReport>> {
+        let mut reports = Vec::new();
+        verify_storage(
+            &mut env.db().conn(),
+            &env.storage(),
+            &VerifyStorageFilter::default(),
+            3,
+            |report| {
+                reports.push(report);
+                Ok(())
+            },
+        )?;
+        Ok(reports)
+    }
+
+    fn replace(storage: &Storage, path: &str, content: Vec<u8>) -> Result<()> {
+        storage.store_blobs(vec![Blob {
+            path: path.into(),
+            mime: "application/octet-stream".into(),
+            date_updated: Utc::now(),
+            compression: None,
+            content,
+        }])
+    }
+
+    #[test]
+    fn healthy_releases() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("krate")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", b"pub fn foo() {}")
+                .rustdoc_file("krate/index.html")
+                .create()?;
+            // releases without archives are skipped
+            env.fake_release()
+                .name("other")
+                .version("0.1.0")
+                .archive_storage(false)
+                .create()?;
+
+            assert!(verify_all(env)?.is_empty());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn missing_archive() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("krate")
+                .version("0.1.0")
+                .archive_storage(true)
+                .create()?;
+
+            let storage = env.storage();
+            let archive = rustdoc_archive_path("krate", "0.1.0");
+            storage.delete_prefix(&archive)?;
+
+            let reports = verify_all(env)?;
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].name, "krate");
+            assert_eq!(
+                reports[0].problems,
+                vec![
+                    ArchiveProblem::MissingArchive {
+                        archive: archive.clone()
+                    },
+                    ArchiveProblem::MissingIndex { archive },
+                ]
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn truncated_archive() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("krate")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", b"pub fn foo() {}")
+                .create()?;
+
+            let storage = env.storage();
+            let archive = source_archive_path("krate", "0.1.0");
+            let mut content = storage.get(&archive, std::usize::MAX)?.content;
+            content.truncate(20);
+            replace(&storage, &archive, content)?;
+
+            let reports = verify_all(env)?;
+            assert_eq!(reports.len(), 1);
+            assert!(reports[0].problems.iter().all(|problem| matches!(
+                problem,
+                ArchiveProblem::RangeOutsideArchive {
+                    archive_size: 20,
+                    ..
+                }
+            )));
+            assert!(!reports[0].problems.is_empty());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn corrupt_archive_and_index() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("krate")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", b"pub fn foo() {}")
+                .create()?;
+
+            let storage = env.storage();
+            let sources = source_archive_path("krate", "0.1.0");
+            let size = storage.size(&sources)? as usize;
+            replace(&storage, &sources, vec![0; size])?;
+
+            let rustdoc = rustdoc_archive_path("krate", "0.1.0");
+            replace(&storage, &format!("{}.index", rustdoc), b"garbage".to_vec())?;
+
+            let reports = verify_all(env)?;
+            assert_eq!(reports.len(), 1);
+            let problems = &reports[0].problems;
+            assert!(problems
+                .iter()
+                .any(|problem| matches!(problem, ArchiveProblem::CorruptFile { .. })));
+            assert!(problems
+                .iter()
+                .any(|problem| matches!(problem, ArchiveProblem::InvalidIndex { .. })));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn deduplicated_files() {
+        wrapper(|env| {
+            env.override_config(|config| config.archive_deduplication = true);
+            env.fake_release()
+                .name("krate")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", b"pub fn foo() {}")
+                .create()?;
+            assert!(verify_all(env)?.is_empty());
+
+            let storage = env.storage();
+            let blob = content_blob_path(&crate::storage::dedup::content_hash(b"pub fn foo() {}"));
+            storage.delete_prefix(&blob)?;
+
+            let reports = verify_all(env)?;
+            assert_eq!(reports.len(), 1);
+            assert_eq!(
+                reports[0].problems,
+                vec![ArchiveProblem::MissingBlob {
+                    archive: source_archive_path("krate", "0.1.0"),
+                    path: "src/lib.rs".into(),
+                    blob,
+                }]
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn filters() {
+        wrapper(|env| {
+            let now = Utc::now();
+            for (name, age) in [("old", 10), ("new", 1)] {
+                env.fake_release()
+                    .name(name)
+                    .version("0.1.0")
+                    .archive_storage(true)
+                    .release_time(now - Duration::days(age))
+                    .create()?;
+                env.storage()
+                    .delete_prefix(&source_archive_path(name, "0.1.0"))?;
+            }
+
+            let checked = |filter: VerifyStorageFilter| -> Result<Vec<String>> {
+                let mut names = Vec::new();
+                verify_storage(&mut env.db().conn(), &env.storage(), &filter, 1, |report| {
+                    names.push(report.name);
+                    Ok(())
+                })?;
+                Ok(names)
+            };
+
+            assert_eq!(
+                checked(VerifyStorageFilter {
+                    crate_name: Some("old".into()),
+                    ..Default::default()
+                })?,
+                vec!["old"]
+            );
+            assert_eq!(
+                checked(VerifyStorageFilter {
+                    since: Some(now - Duration::days(5)),
+                    ..Default::default()
+                })?,
+                vec!["new"]
+            );
+            assert_eq!(
+                checked(VerifyStorageFilter {
+                    until: Some(now - Duration::days(5)),
+                    ..Default::default()
+                })?,
+                vec!["old"]
+            );
+            Ok(())
+        });
+    }
+}
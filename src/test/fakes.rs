@@ -1,6 +1,9 @@
 use super::TestDatabase;
 
-use crate::docbuilder::{BuildResult, DocCoverage};
+use crate::docbuilder::{
+    doctest_log_path, BuildFailure, BuildReproducibility, BuildResult, DocCoverage, DoctestResult,
+    FileCoverage, TargetBuildResult, UndocumentedItem,
+};
 use crate::error::Result;
 use crate::index::api::{CrateData, CrateOwner, ReleaseData};
 use crate::storage::{rustdoc_archive_path, source_archive_path, Storage};
@@ -32,12 +35,22 @@ pub(crate) struct FakeRelease<'a> {
     readme: Option<&'a str>,
     github_stats: Option<FakeGithubStats>,
     doc_coverage: Option<DocCoverage>,
+    file_coverage: Vec<FileCoverage>,
+    undocumented_items: Vec<UndocumentedItem>,
+    /// the format version and content of the rustdoc JSON output
+    rustdoc_json: Option<(i32, &'a [u8])>,
 }
 
 pub(crate) struct FakeBuild {
     s3_build_log: Option<String>,
     db_build_log: Option<String>,
     result: BuildResult,
+    /// the results and logs of the targets other than the default one
+    other_targets: Vec<(TargetBuildResult, String)>,
+    /// the cargo arguments of the default target
+    cargo_args: Vec<String>,
+    reproducibility: Option<BuildReproducibility>,
+    doctest_log: Option<String>,
 }
 
 const DEFAULT_CONTENT: &[u8] =
@@ -93,6 +106,9 @@ impl<'a> FakeRelease<'a> {
             readme: None,
             github_stats: None,
             doc_coverage: None,
+            file_coverage: Vec::new(),
+            undocumented_items: Vec::new(),
+            rustdoc_json: None,
             archive_storage: false,
         }
     }
@@ -224,6 +240,21 @@ impl<'a> FakeRelease<'a> {
         }
     }
 
+    pub(crate) fn coverage_details(
+        mut self,
+        files: Vec<FileCoverage>,
+        undocumented_items: Vec<UndocumentedItem>,
+    ) -> Self {
+        self.file_coverage = files;
+        self.undocumented_items = undocumented_items;
+        self
+    }
+
+    pub(crate) fn rustdoc_json(mut self, format_version: i32, content: &'a [u8]) -> Self {
+        self.rustdoc_json = Some((format_version, content));
+        self
+    }
+
     pub(crate) fn features(mut self, features: HashMap<String, Vec<String>>) -> Self {
         self.package.features = features;
         self
@@ -418,6 +449,19 @@ impl<'a> FakeRelease<'a> {
         if let Some(coverage) = self.doc_coverage {
             crate::db::add_doc_coverage(&mut db.conn(), release_id, coverage)?;
         }
+        crate::db::add_doc_coverage_details(
+            &mut db.conn(),
+            release_id,
+            &self.file_coverage,
+            &self.undocumented_items,
+        )?;
+        if let Some((format_version, content)) = self.rustdoc_json {
+            storage.store_one(
+                crate::storage::rustdoc_json_path(&package.name, &package.version),
+                content,
+            )?;
+            crate::db::set_rustdoc_json(&mut db.conn(), release_id, Some(format_version))?;
+        }
 
         Ok(release_id)
     }
@@ -500,6 +544,58 @@ impl FakeBuild {
         }
     }
 
+    pub(crate) fn failure_reason(self, failure_reason: BuildFailure) -> Self {
+        Self {
+            result: BuildResult {
+                successful: false,
+                failure_reason: Some(failure_reason),
+                ..self.result
+            },
+            ..self
+        }
+    }
+
+    pub(crate) fn target(
+        mut self,
+        target: impl Into<String>,
+        failure_reason: Option<BuildFailure>,
+        build_log: impl Into<String>,
+    ) -> Self {
+        self.other_targets.push((
+            TargetBuildResult {
+                target: target.into(),
+                successful: failure_reason.is_none(),
+                failure_reason,
+                cargo_args: Vec::new(),
+            },
+            build_log.into(),
+        ));
+        self
+    }
+
+    pub(crate) fn doctests(self, doctests: DoctestResult, log: impl Into<String>) -> Self {
+        Self {
+            result: BuildResult {
+                doctests: Some(doctests),
+                ..self.result
+            },
+            doctest_log: Some(log.into()),
+            ..self
+        }
+    }
+
+    pub(crate) fn reproducibility(
+        self,
+        cargo_args: Vec<String>,
+        reproducibility: BuildReproducibility,
+    ) -> Self {
+        Self {
+            cargo_args,
+            reproducibility: Some(reproducibility),
+            ..self
+        }
+    }
+
     fn create(
         &self,
         conn: &mut Client,
@@ -521,6 +617,30 @@ impl FakeBuild {
             storage.store_one(path, s3_build_log)?;
         }
 
+        let mut targets = vec![TargetBuildResult {
+            target: default_target.into(),
+            successful: self.result.successful,
+            failure_reason: self.result.failure_reason,
+            cargo_args: self.cargo_args.clone(),
+        }];
+        for (target, build_log) in &self.other_targets {
+            let path = format!("build-logs/{}/{}.txt", build_id, target.target);
+            storage.store_one(path, build_log.clone())?;
+            targets.push(TargetBuildResult {
+                target: target.target.clone(),
+                successful: target.successful,
+                failure_reason: target.failure_reason,
+                cargo_args: target.cargo_args.clone(),
+            });
+        }
+        crate::db::add_build_targets_into_database(conn, build_id, &targets)?;
+        if let Some(doctest_log) = &self.doctest_log {
+            storage.store_one(doctest_log_path(build_id), doctest_log.clone())?;
+        }
+        if let Some(reproducibility) = &self.reproducibility {
+            crate::db::add_build_reproducibility_into_database(conn, build_id, reproducibility)?;
+        }
+
         Ok(())
     }
 }
@@ -534,7 +654,13 @@ impl Default for FakeBuild {
                 rustc_version: "rustc 2.0.0-nightly (000000000 1970-01-01)".into(),
                 docsrs_version: "docs.rs 1.0.0 (000000000 1970-01-01)".into(),
                 successful: true,
+                failure_reason: None,
+                doctests: None,
             },
+            other_targets: Vec::new(),
+            cargo_args: Vec::new(),
+            reproducibility: None,
+            doctest_log: None,
         }
     }
 }
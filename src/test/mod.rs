@@ -147,6 +147,9 @@ impl TestEnvironment {
             if config.local_archive_cache_path.exists() {
                 fs::remove_dir_all(&config.local_archive_cache_path).unwrap();
             }
+            if config.local_storage_path.exists() {
+                fs::remove_dir_all(&config.local_storage_path).unwrap();
+            }
         }
     }
 
@@ -170,6 +173,10 @@ impl TestEnvironment {
         config.local_archive_cache_path =
             std::env::temp_dir().join(format!("docsrs-test-index-{}", rand::random::<u64>()));
 
+        // Use a temporary directory for the filesystem storage.
+        config.local_storage_path =
+            std::env::temp_dir().join(format!("docsrs-test-storage-{}", rand::random::<u64>()));
+
         config
     }
 
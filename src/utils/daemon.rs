@@ -3,10 +3,11 @@
 //! This daemon will start web server, track new packages and build them
 
 use crate::{
-    utils::{queue_builder, report_error},
+    storage::{gc_storage, GcStorageOptions},
+    utils::{enqueue_campaigns, queue_builder, report_error},
     Context, RustwideBuilder,
 };
-use anyhow::{anyhow, Context as _, Error};
+use anyhow::{anyhow, bail, Context as _, Error};
 use log::{debug, info};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -48,7 +49,64 @@ fn start_registry_watcher(context: &dyn Context) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn start_daemon(context: &dyn Context, enable_registry_watcher: bool) -> Result<(), Error> {
+/// Starts `workers` threads building the crates from the queue.
+///
+/// Every worker gets its own rustwide workspace, in the `worker-{instance}-{n}` directory of the
+/// configured workspace, and its own prefix for temporary directories, so that neither is shared
+/// with the other workers of this process or with other processes on the same host.
+fn start_build_workers(
+    context: &dyn Context,
+    workers: usize,
+) -> Result<Vec<thread::JoinHandle<()>>, Error> {
+    if workers == 0 {
+        bail!("at least one build worker is needed");
+    }
+
+    let config = context.config()?;
+    let mut handles = Vec::with_capacity(workers);
+    for worker in 0..workers {
+        let build_queue = context.build_queue()?;
+        let config = config.clone();
+        let metrics = context.metrics()?;
+        let name = if workers == 1 {
+            "build queue reader".to_string()
+        } else {
+            format!("build queue reader {}", worker)
+        };
+        let workspace =
+            config
+                .rustwide_workspace
+                .join(format!("worker-{}-{}", build_queue.instance(), worker));
+        let mut rustwide_builder = RustwideBuilder::init_with_workspace(context, &workspace)?;
+        rustwide_builder.set_tempdir_prefix(queue_builder::worker_tempdir_prefix(
+            build_queue.instance(),
+            worker,
+        ));
+        handles.push(thread::Builder::new().name(name).spawn(move || {
+            queue_builder(rustwide_builder, build_queue, config, metrics).unwrap();
+        })?);
+    }
+    Ok(handles)
+}
+
+/// Starts a daemon that only builds the crates from the queue, using `workers` builders.
+///
+/// Multiple of these daemons can process the same queue, next to a daemon watching the registry.
+pub fn start_build_server(context: &dyn Context, workers: usize) -> Result<(), Error> {
+    info!("Starting {} build workers", workers);
+    for handle in start_build_workers(context, workers)? {
+        handle
+            .join()
+            .map_err(|_| anyhow!("build worker panicked"))?;
+    }
+    Ok(())
+}
+
+pub fn start_daemon(
+    context: &dyn Context,
+    enable_registry_watcher: bool,
+    build_workers: usize,
+) -> Result<(), Error> {
     // Start the web server before doing anything more expensive
     // Please check with an administrator before changing this (see #1172 for context).
     info!("Starting web server");
@@ -61,14 +119,7 @@ pub fn start_daemon(context: &dyn Context, enable_registry_watcher: bool) -> Res
     }
 
     // build new crates every minute
-    let build_queue = context.build_queue()?;
-    let rustwide_builder = RustwideBuilder::init(context)?;
-    thread::Builder::new()
-        .name("build queue reader".to_string())
-        .spawn(move || {
-            queue_builder(rustwide_builder, build_queue).unwrap();
-        })
-        .unwrap();
+    start_build_workers(context, build_workers)?;
 
     // This call will still skip github repositories updates and continue if no token is provided
     // (gitlab doesn't require to have a token). The only time this can return an error is when
@@ -84,6 +135,42 @@ pub fn start_daemon(context: &dyn Context, enable_registry_watcher: bool) -> Res
         },
     )?;
 
+    let pool = context.pool()?;
+    let build_queue = context.build_queue()?;
+    cron("rebuild campaigns", Duration::from_secs(60), move || {
+        let added = enqueue_campaigns(&mut *pool.get()?, &build_queue)?;
+        debug!("{} releases of rebuild campaigns added to queue", added);
+        Ok(())
+    })?;
+
+    let config = context.config()?;
+    if let Some(interval) = config.gc_storage_interval {
+        let pool = context.pool()?;
+        let storage = context.storage()?;
+        let metrics = context.metrics()?;
+        let options = GcStorageOptions {
+            grace_period: chrono::Duration::seconds(config.gc_storage_grace_period as i64),
+            delete: true,
+            batch_size: 1000,
+        };
+        cron(
+            "storage garbage collector",
+            Duration::from_secs(interval),
+            move || {
+                let summary =
+                    gc_storage(&mut *pool.get()?, &storage, &metrics, &options, |orphan| {
+                        debug!("deleting orphaned object {}", orphan.path);
+                        Ok(())
+                    })?;
+                info!(
+                    "deleted {} orphaned objects ({} bytes) from the storage",
+                    summary.orphans, summary.bytes
+                );
+                Ok(())
+            },
+        )?;
+    }
+
     // Never returns; `server` blocks indefinitely when dropped
     // NOTE: if a anyhow occurred earlier in `start_daemon`, the server will _not_ be joined -
     // instead it will get killed when the process exits.
@@ -1,6 +1,8 @@
 use crate::web::page::TemplateData;
 use lol_html::element;
 use lol_html::errors::RewritingError;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 use tera::Context;
 
 /// Rewrite a rustdoc page to have the docs.rs topbar
@@ -95,3 +97,117 @@ pub(crate) fn rewrite_lol(
 
     Ok(buffer)
 }
+
+/// The shared resources that rustdoc's scripts load at runtime, named after the resource suffix of
+/// the page.
+const RUNTIME_RESOURCES: &[&str] = &[
+    "search.js",
+    "settings.js",
+    "settings.css",
+    "light.css",
+    "dark.css",
+    "ayu.css",
+];
+
+/// Rewrite a rustdoc page so that it works when it's opened from disk.
+///
+/// Rustdoc is invoked with `--static-root-path /`, so the pages load the toolchain-shared
+/// resources from the root of the domain. These links are changed to point to `root` instead,
+/// the relative path from the page to the root of the documentation.
+///
+/// Returns the rewritten page and the names of the shared resources it might load.
+pub(crate) fn rewrite_for_offline(
+    html: &[u8],
+    max_allowed_memory_usage: usize,
+    root: &str,
+) -> Result<(Vec<u8>, BTreeSet<String>), RewritingError> {
+    use lol_html::html_content::Element;
+    use lol_html::{HtmlRewriter, MemorySettings, Settings};
+
+    let resources = RefCell::new(BTreeSet::new());
+    let relink = |attribute: &'static str| {
+        let resources = &resources;
+        move |element: &mut Element| {
+            if let Some(value) = element.get_attribute(attribute) {
+                let name = match value.strip_prefix('/') {
+                    Some(name) if !name.is_empty() && !name.contains('/') => name,
+                    _ => return Ok(()),
+                };
+                element.set_attribute(attribute, &format!("{}{}", root, name))?;
+                resources.borrow_mut().insert(name.to_owned());
+            }
+            Ok(())
+        }
+    };
+
+    let settings = Settings {
+        element_content_handlers: vec![
+            element!("[href]", relink("href")),
+            element!("[src]", relink("src")),
+            element!("[data-static-root-path]", |vars: &mut Element| {
+                vars.set_attribute("data-static-root-path", root)?;
+                if let Some(suffix) = vars.get_attribute("data-resource-suffix") {
+                    resources
+                        .borrow_mut()
+                        .extend(RUNTIME_RESOURCES.iter().filter_map(|resource| {
+                            let (name, extension) = resource.split_once('.')?;
+                            Some(format!("{}{}.{}", name, suffix, extension))
+                        }));
+                }
+                Ok(())
+            }),
+        ],
+        memory_settings: MemorySettings {
+            max_allowed_memory_usage,
+            ..MemorySettings::default()
+        },
+        ..Settings::default()
+    };
+
+    let mut buffer = Vec::new();
+    let mut writer = HtmlRewriter::new(settings, |bytes: &[u8]| {
+        buffer.extend_from_slice(bytes);
+    });
+
+    writer.write(html)?;
+    writer.end()?;
+
+    Ok((buffer, resources.into_inner()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offline_links() {
+        let html = br#"<html><head>
+            <link rel="stylesheet" type="text/css" href="/rustdoc-1.60.0.css">
+            <link rel="icon" href="https://example.com/favicon.ico">
+            <script src="/main-1.60.0.js"></script>
+            <script src="../search-index-1.60.0.js"></script>
+            <script src="/-/static/menu.js"></script>
+            </head><body>
+            <div id="rustdoc-vars" data-root-path="../" data-static-root-path="/"
+                data-resource-suffix="-1.60.0"></div>
+            <a href="/krate/0.1.0/krate/">krate</a>
+            </body></html>"#;
+
+        let (rewritten, resources) = rewrite_for_offline(html, 5 * 1024 * 1024, "../").unwrap();
+        let rewritten = String::from_utf8(rewritten).unwrap();
+        assert!(rewritten.contains(r#"href="../rustdoc-1.60.0.css""#));
+        assert!(rewritten.contains(r#"src="../main-1.60.0.js""#));
+        assert!(rewritten.contains(r#"data-static-root-path="../""#));
+        // other links are kept as they are
+        assert!(rewritten.contains(r#"href="https://example.com/favicon.ico""#));
+        assert!(rewritten.contains(r#"src="../search-index-1.60.0.js""#));
+        assert!(rewritten.contains(r#"src="/-/static/menu.js""#));
+        assert!(rewritten.contains(r#"href="/krate/0.1.0/krate/""#));
+
+        assert!(resources.contains("rustdoc-1.60.0.css"));
+        assert!(resources.contains("main-1.60.0.js"));
+        assert!(resources.contains("search-1.60.0.js"));
+        assert!(resources.contains("ayu-1.60.0.css"));
+        assert!(!resources.contains("menu.js"));
+    }
+}
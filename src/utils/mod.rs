@@ -2,11 +2,19 @@
 
 pub(crate) use self::cargo_metadata::{CargoMetadata, Package as MetadataPackage};
 pub(crate) use self::copy::copy_dir_all;
-pub use self::daemon::start_daemon;
-pub(crate) use self::html::rewrite_lol;
+pub use self::daemon::{start_build_server, start_daemon};
+pub(crate) use self::html::{rewrite_for_offline, rewrite_lol};
+pub(crate) use self::panic::catch_panic;
 pub use self::queue::{get_crate_priority, remove_crate_priority, set_crate_priority};
 pub use self::queue_builder::queue_builder;
-pub(crate) use self::rustc_version::{get_correct_docsrs_style_file, parse_rustc_version};
+pub(crate) use self::rebuild_campaigns::create_toolchain_campaign;
+pub use self::rebuild_campaigns::{
+    cancel_campaign, create_campaign, enqueue_campaigns, list_campaigns, Campaign,
+    CampaignSelection, DEFAULT_CAMPAIGN_MAX_QUEUED, DEFAULT_CAMPAIGN_PRIORITY,
+};
+pub(crate) use self::rustc_version::{
+    get_correct_docsrs_style_file, parse_rustc_toolchain, parse_rustc_version,
+};
 
 #[cfg(test)]
 pub(crate) use self::cargo_metadata::{Dependency, Target};
@@ -17,8 +25,10 @@ pub mod consistency;
 mod copy;
 pub(crate) mod daemon;
 mod html;
+mod panic;
 mod queue;
 pub(crate) mod queue_builder;
+mod rebuild_campaigns;
 mod rustc_version;
 pub(crate) mod sized_buffer;
 
@@ -36,3 +46,13 @@ pub(crate) fn report_error(err: &anyhow::Error) {
         log::error!("{:?}", err);
     }
 }
+
+/// Quotes `arg` for a POSIX shell, if needed.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
@@ -0,0 +1,69 @@
+//! Catching panics together with their backtrace.
+
+use std::cell::{Cell, RefCell};
+use std::panic::{self as std_panic, AssertUnwindSafe};
+use std::sync::Once;
+
+thread_local! {
+    /// whether the current thread is inside `catch_panic`
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    /// the backtrace of the last panic caught on the current thread
+    static BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A panic caught by [`catch_panic`].
+#[derive(Debug)]
+pub(crate) struct Panic {
+    pub(crate) message: String,
+    pub(crate) backtrace: Option<String>,
+}
+
+/// Runs `f`, returning the message and backtrace of the panic if it panics.
+///
+/// The backtrace is recorded by a panic hook which is installed on the first call, and which
+/// calls the previously installed hook afterwards, so panics are still logged and reported.
+pub(crate) fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, Panic> {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let previous = std_panic::take_hook();
+        std_panic::set_hook(Box::new(move |info| {
+            if CATCHING.with(Cell::get) {
+                let backtrace = format!("{:?}", backtrace::Backtrace::new());
+                BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            }
+            previous(info);
+        }));
+    });
+
+    let was_catching = CATCHING.with(|catching| catching.replace(true));
+    let result = std_panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|catching| catching.set(was_catching));
+
+    result.map_err(|payload| Panic {
+        message: if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "panicked with a non-string payload".into()
+        },
+        backtrace: BACKTRACE.with(|last| last.borrow_mut().take()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catch_panics() {
+        assert_eq!(catch_panic(|| 42).unwrap(), 42);
+
+        let panic = catch_panic(|| panic!("failed to build {}", "foo")).unwrap_err();
+        assert_eq!(panic.message, "failed to build foo");
+        assert!(panic.backtrace.unwrap().contains("catch_panic"));
+
+        let panic = catch_panic(|| std::panic::panic_any(42)).unwrap_err();
+        assert_eq!(panic.message, "panicked with a non-string payload");
+    }
+}
@@ -1,26 +1,39 @@
-use crate::{docbuilder::RustwideBuilder, utils::report_error, BuildQueue};
-use anyhow::Error;
+use crate::{docbuilder::RustwideBuilder, utils::report_error, BuildQueue, Config, Metrics};
+use anyhow::{Context, Error};
 use log::{debug, error, info, warn};
 use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 use std::{fs, io, thread};
 
-pub(crate) const TEMPDIR_PREFIX: &str = "docsrs-docs";
+pub(crate) const TEMPDIR_PREFIX: &str = "docsrs-docs-";
+
+/// The prefix of the temporary directories of `worker` of the build queue process `instance`.
+///
+/// It ends with a separator so that it isn't the prefix of another worker's one.
+pub(crate) fn worker_tempdir_prefix(instance: &str, worker: usize) -> String {
+    format!("{}{}-{}-", TEMPDIR_PREFIX, instance, worker)
+}
 
 // TODO: change to `fn() -> Result<!, Error>` when never _finally_ stabilizes
 pub fn queue_builder(
     mut builder: RustwideBuilder,
     build_queue: Arc<BuildQueue>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
 ) -> Result<(), Error> {
     /// Represents the current state of the builder thread.
     enum BuilderState {
-        /// The builder thread has just started, and hasn't built any crates yet.
+        /// The builder thread has just started or resumed after a pause, and hasn't built any
+        /// crates since.
         Fresh,
         /// The builder has just seen an empty build queue.
         EmptyQueue,
         /// The builder has just seen the lock file.
         Locked,
+        /// The builder is paused because there isn't enough free disk space.
+        LowDiskSpace,
         /// The builder has started (or just finished) building a crate.
         QueueInProgress,
     }
@@ -28,7 +41,9 @@ pub fn queue_builder(
     let mut status = BuilderState::Fresh;
 
     loop {
-        if let Err(e) = remove_tempdirs() {
+        // Every builder has its own prefix for temporary directories, and doesn't build anything
+        // right now, so all of its temporary directories are left over from a crash.
+        if let Err(e) = remove_tempdirs(builder.tempdir_prefix()) {
             report_error(&anyhow::anyhow!(e).context("failed to remove temporary directories"));
         }
 
@@ -36,6 +51,27 @@ pub fn queue_builder(
             thread::sleep(Duration::from_secs(60));
         }
 
+        // Builds fail in confusing ways once the disk is full, so pause them while there isn't
+        // enough free disk space. The rustwide caches are purged first to free some of it.
+        let paused = matches!(status, BuilderState::LowDiskSpace);
+        let enough_disk_space = has_enough_disk_space(&builder, &config, &metrics, !paused)
+            .unwrap_or_else(|e| {
+                report_error(&e.context("Failed to check the free disk space"));
+                true
+            });
+        if !enough_disk_space {
+            if !paused {
+                warn!("Not enough free disk space, pausing builds");
+                metrics.paused_builders.inc();
+            }
+            status = BuilderState::LowDiskSpace;
+            continue;
+        } else if paused {
+            info!("Enough free disk space again, resuming builds");
+            metrics.paused_builders.dec();
+            status = BuilderState::Fresh;
+        }
+
         // check lock file
         if build_queue.is_locked() {
             warn!("Lock file exists, skipping building new crates");
@@ -62,7 +98,9 @@ pub fn queue_builder(
 
         status = BuilderState::QueueInProgress;
 
-        // If a panic occurs while building a crate, lock the queue until an admin has a chance to look at it.
+        // A panic while building a crate only quarantines the crate, see
+        // `BuildQueue::process_next_crate`. If a panic occurs outside of the build, lock the
+        // queue until an admin has a chance to look at it.
         let res = catch_unwind(AssertUnwindSafe(|| {
             if let Err(e) = build_queue.build_next_queue_package(&mut builder) {
                 report_error(&e.context("Failed to build crate from queue"));
@@ -77,10 +115,78 @@ pub fn queue_builder(
     }
 }
 
+/// Returns whether there's at least `build_min_free_disk_space` of free disk space both in the
+/// rustwide workspace and the temporary directory. If there isn't and `purge` is true, the
+/// rustwide caches are purged before checking again.
+fn has_enough_disk_space(
+    builder: &RustwideBuilder,
+    config: &Config,
+    metrics: &Metrics,
+    purge: bool,
+) -> Result<bool, Error> {
+    let temp_dir = std::env::temp_dir();
+    let locations = [
+        ("rustwide_workspace", builder.workspace_path()),
+        ("tempdir", temp_dir.as_path()),
+    ];
+
+    let check = || -> Result<bool, Error> {
+        let mut enough = true;
+        for (location, path) in locations {
+            let free = free_disk_space(path)?;
+            metrics
+                .free_disk_space
+                .with_label_values(&[location])
+                .set(free as i64);
+
+            if free < config.build_min_free_disk_space {
+                warn!(
+                    "Only {} bytes of free disk space left in {}",
+                    free,
+                    path.display()
+                );
+                enough = false;
+            }
+        }
+        Ok(enough)
+    };
+
+    if check()? {
+        return Ok(true);
+    }
+    if !purge {
+        return Ok(false);
+    }
+
+    info!("Purging the rustwide caches to free disk space");
+    builder.purge_caches()?;
+    check()
+}
+
+/// Returns the disk space available to unprivileged users on the filesystem containing `path`,
+/// in bytes.
+fn free_disk_space(path: &Path) -> Result<u64, Error> {
+    use systemstat::{Platform, System};
+
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("failed to canonicalize {}", path.display()))?;
+    let mounts = System::new().mounts()?;
+
+    // the filesystem mounted at the longest prefix of the path is the one containing it
+    let filesystem = mounts
+        .iter()
+        .filter(|mount| path.starts_with(&mount.fs_mounted_on))
+        .max_by_key(|mount| mount.fs_mounted_on.len())
+        .with_context(|| format!("no filesystem contains {}", path.display()))?;
+
+    Ok(filesystem.avail.as_u64())
+}
+
 /// Sometimes, when the server hits a hard crash or a build thread panics,
 /// rustwide_builder won't actually remove the temporary directories it creates.
-/// Remove them now to avoid running out of disk space.
-fn remove_tempdirs() -> Result<(), io::Error> {
+/// Remove the ones starting with `prefix` now to avoid running out of disk space.
+fn remove_tempdirs(prefix: &str) -> Result<(), io::Error> {
     // NOTE: hardcodes that `tempfile::tempdir()` uses `std::env::temp_dir`.
     for entry in std::fs::read_dir(std::env::temp_dir())? {
         let entry = entry?;
@@ -89,7 +195,7 @@ fn remove_tempdirs() -> Result<(), io::Error> {
         }
 
         if let Some(dir_name) = entry.path().file_name() {
-            if dir_name.to_string_lossy().starts_with(TEMPDIR_PREFIX) {
+            if dir_name.to_string_lossy().starts_with(prefix) {
                 fs::remove_dir_all(entry.path())?;
             }
         }
@@ -103,17 +209,24 @@ mod tests {
     use super::*;
 
     #[test]
-    fn remove_existing_tempdirs() {
-        let file_with_prefix = tempfile::Builder::new()
-            .prefix(TEMPDIR_PREFIX)
-            .tempfile()
-            .unwrap();
-
-        let dir_with_prefix = tempfile::Builder::new()
+    fn free_disk_space_of_tempdir() {
+        let dir = tempfile::Builder::new()
             .prefix(TEMPDIR_PREFIX)
             .tempdir()
             .unwrap();
 
+        assert!(free_disk_space(dir.path()).unwrap() > 0);
+        assert!(free_disk_space(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn remove_existing_tempdirs() {
+        let prefix = format!("{}remove-test-", TEMPDIR_PREFIX);
+
+        let file_with_prefix = tempfile::Builder::new().prefix(&prefix).tempfile().unwrap();
+
+        let dir_with_prefix = tempfile::Builder::new().prefix(&prefix).tempdir().unwrap();
+
         let file_inside = dir_with_prefix.path().join("some_file_name");
         fs::File::create(&file_inside).unwrap();
 
@@ -121,9 +234,15 @@ mod tests {
 
         let other_dir = tempfile::Builder::new().tempdir().unwrap();
 
+        // the directory of another builder
+        let other_builder_dir = tempfile::Builder::new()
+            .prefix(&format!("{}other-remove-test-", TEMPDIR_PREFIX))
+            .tempdir()
+            .unwrap();
+
         assert!(dir_with_prefix.path().exists());
 
-        remove_tempdirs().unwrap();
+        remove_tempdirs(&prefix).unwrap();
 
         assert!(!dir_with_prefix.path().exists());
         assert!(!file_inside.exists());
@@ -132,5 +251,35 @@ mod tests {
         assert!(file_with_prefix.path().exists());
         assert!(other_file.path().exists());
         assert!(other_dir.path().exists());
+        assert!(other_builder_dir.path().exists());
+    }
+
+    #[test]
+    fn remove_tempdirs_of_one_worker() {
+        // a process with a single worker next to one with several
+        let single = worker_tempdir_prefix("1-single", 0);
+        let multi: Vec<_> = [0, 1, 10]
+            .iter()
+            .map(|&worker| worker_tempdir_prefix("1-multi", worker))
+            .collect();
+
+        let single_dir = tempfile::Builder::new().prefix(&single).tempdir().unwrap();
+        let multi_dirs: Vec<_> = multi
+            .iter()
+            .map(|prefix| tempfile::Builder::new().prefix(prefix).tempdir().unwrap())
+            .collect();
+
+        remove_tempdirs(&multi[1]).unwrap();
+
+        assert!(single_dir.path().exists());
+        assert!(multi_dirs[0].path().exists());
+        assert!(!multi_dirs[1].path().exists());
+        assert!(multi_dirs[2].path().exists());
+
+        remove_tempdirs(&single).unwrap();
+
+        assert!(!single_dir.path().exists());
+        assert!(multi_dirs[0].path().exists());
+        assert!(multi_dirs[2].path().exists());
     }
 }
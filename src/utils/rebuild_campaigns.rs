@@ -0,0 +1,476 @@
+//! Rebuild campaigns, which build old releases again at a low priority, for example after the
+//! toolchain was updated.
+//!
+//! A campaign selects its releases once, when it's created. They're added to the build queue a
+//! few at a time by [`enqueue_campaigns`], so the queue never holds more than `max_queued`
+//! releases of a campaign and newly published crates are still built first.
+
+use crate::error::Result;
+use crate::utils::rustc_version::{parse_rustc_date, parse_rustc_version};
+use crate::BuildQueue;
+use chrono::{DateTime, NaiveDate, Utc};
+use postgres::Client;
+use serde::Serialize;
+
+/// The queue priority of the releases of a campaign, new crates get priority 0.
+pub const DEFAULT_CAMPAIGN_PRIORITY: i32 = 20;
+/// How many releases of a campaign can be in the build queue at once.
+pub const DEFAULT_CAMPAIGN_MAX_QUEUED: i32 = 100;
+
+/// Which releases a campaign rebuilds. Only libraries which aren't yanked are selected, and
+/// every set condition has to match.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CampaignSelection {
+    /// releases documented with a rustc older than this date
+    pub built_before: Option<NaiveDate>,
+    /// releases whose repository has at least this many stars
+    pub min_stars: Option<i32>,
+    /// releases with at least this many downloads
+    pub min_downloads: Option<i32>,
+    /// releases of the crates matching this `LIKE` pattern
+    pub name_pattern: Option<String>,
+    /// select every version instead of only the latest version of each crate
+    pub all_versions: bool,
+}
+
+/// A rebuild campaign and its progress.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Campaign {
+    pub id: i32,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub priority: i32,
+    pub max_queued: i32,
+    pub cancelled: bool,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// number of selected releases
+    pub total: i64,
+    /// releases which weren't added to the build queue yet
+    pub pending: i64,
+    /// releases waiting in the build queue
+    pub queued: i64,
+    /// releases which were built, or failed to build too often
+    pub done: i64,
+}
+
+impl Campaign {
+    /// Whether the releases of the campaign are still added to the build queue.
+    pub fn is_active(&self) -> bool {
+        !self.cancelled && self.finished_at.is_none()
+    }
+}
+
+/// Creates a campaign named `name` rebuilding the releases matching `selection`, returning its
+/// id and the number of selected releases, or `None` if there's already a campaign with that
+/// name.
+pub fn create_campaign(
+    conn: &mut Client,
+    name: &str,
+    selection: &CampaignSelection,
+    priority: i32,
+    max_queued: i32,
+) -> Result<Option<(i32, u64)>> {
+    let mut transaction = conn.transaction()?;
+
+    let id: i32 = match transaction.query_opt(
+        "INSERT INTO rebuild_campaigns (
+            name, built_before, min_stars, min_downloads, name_pattern, all_versions, priority,
+            max_queued
+         )
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+         ON CONFLICT (name) DO NOTHING
+         RETURNING id",
+        &[
+            &name,
+            &selection.built_before,
+            &selection.min_stars,
+            &selection.min_downloads,
+            &selection.name_pattern,
+            &selection.all_versions,
+            &priority,
+            &max_queued,
+        ],
+    )? {
+        Some(row) => row.get(0),
+        None => return Ok(None),
+    };
+
+    let selected = transaction.execute(
+        r"INSERT INTO rebuild_campaign_releases (campaign_id, release_id)
+          SELECT $1, releases.id
+          FROM releases
+          INNER JOIN crates ON crates.id = releases.crate_id
+          LEFT JOIN repositories ON repositories.id = releases.repository_id
+          WHERE
+            releases.is_library AND
+            NOT releases.yanked AND
+            ($2::DATE IS NULL OR
+                SUBSTRING(releases.doc_rustc_version FROM '(\d{4}-\d{2}-\d{2})\)$')::DATE < $2) AND
+            ($3::INT IS NULL OR COALESCE(repositories.stars, 0) >= $3) AND
+            ($4::INT IS NULL OR COALESCE(releases.downloads, 0) >= $4) AND
+            ($5::TEXT IS NULL OR crates.name LIKE $5) AND
+            ($6 OR releases.id = crates.latest_version_id)",
+        &[
+            &id,
+            &selection.built_before,
+            &selection.min_stars,
+            &selection.min_downloads,
+            &selection.name_pattern,
+            &selection.all_versions,
+        ],
+    )?;
+
+    transaction.commit()?;
+    Ok(Some((id, selected)))
+}
+
+/// Creates the campaign rebuilding the latest releases documented with a rustc older than
+/// `rustc_version`, unless another builder already created it.
+pub(crate) fn create_toolchain_campaign(conn: &mut Client, rustc_version: &str) -> Result<()> {
+    let name = format!("toolchain {}", parse_rustc_version(rustc_version)?);
+    let selection = CampaignSelection {
+        built_before: Some(parse_rustc_date(rustc_version)?.naive_utc()),
+        ..Default::default()
+    };
+
+    if let Some((_, selected)) = create_campaign(
+        conn,
+        &name,
+        &selection,
+        DEFAULT_CAMPAIGN_PRIORITY,
+        DEFAULT_CAMPAIGN_MAX_QUEUED,
+    )? {
+        log::info!(
+            "created rebuild campaign `{}` for {} releases",
+            name,
+            selected
+        );
+    }
+    Ok(())
+}
+
+/// Returns every campaign with its progress, the newest first.
+///
+/// Queued releases which failed too often are done, they won't be built again.
+pub fn list_campaigns(conn: &mut Client, build_queue: &BuildQueue) -> Result<Vec<Campaign>> {
+    Ok(conn
+        .query(
+            "SELECT
+                rebuild_campaigns.id,
+                rebuild_campaigns.name,
+                rebuild_campaigns.created_at,
+                rebuild_campaigns.priority,
+                rebuild_campaigns.max_queued,
+                rebuild_campaigns.cancelled,
+                rebuild_campaigns.finished_at,
+                COUNT(rebuild_campaign_releases.release_id) AS total,
+                COUNT(*) FILTER (
+                    WHERE rebuild_campaign_releases.release_id IS NOT NULL
+                    AND rebuild_campaign_releases.queued_at IS NULL
+                ) AS pending,
+                COUNT(*) FILTER (
+                    WHERE rebuild_campaign_releases.queued_at IS NOT NULL AND queue.id IS NOT NULL
+                ) AS queued,
+                COUNT(*) FILTER (
+                    WHERE rebuild_campaign_releases.queued_at IS NOT NULL AND queue.id IS NULL
+                ) AS done
+             FROM rebuild_campaigns
+             LEFT JOIN rebuild_campaign_releases
+                ON rebuild_campaign_releases.campaign_id = rebuild_campaigns.id
+             LEFT JOIN releases ON releases.id = rebuild_campaign_releases.release_id
+             LEFT JOIN crates ON crates.id = releases.crate_id
+             LEFT JOIN queue ON
+                queue.name = crates.name AND
+                queue.version = releases.version AND
+                queue.attempt < $1
+             GROUP BY rebuild_campaigns.id
+             ORDER BY rebuild_campaigns.id DESC",
+            &[&build_queue.max_attempts()],
+        )?
+        .into_iter()
+        .map(|row| Campaign {
+            id: row.get("id"),
+            name: row.get("name"),
+            created_at: row.get("created_at"),
+            priority: row.get("priority"),
+            max_queued: row.get("max_queued"),
+            cancelled: row.get("cancelled"),
+            finished_at: row.get("finished_at"),
+            total: row.get("total"),
+            pending: row.get("pending"),
+            queued: row.get("queued"),
+            done: row.get("done"),
+        })
+        .collect())
+}
+
+/// Cancels a campaign, returning whether it was still active. Its releases which are already
+/// in the build queue stay there.
+pub fn cancel_campaign(conn: &mut Client, id: i32) -> Result<bool> {
+    Ok(conn.execute(
+        "UPDATE rebuild_campaigns
+         SET cancelled = TRUE
+         WHERE id = $1 AND NOT cancelled AND finished_at IS NULL",
+        &[&id],
+    )? > 0)
+}
+
+/// Adds the next releases of the active campaigns to the build queue, until every campaign has
+/// `max_queued` releases in the queue, and marks the campaigns without pending or queued
+/// releases as finished. Returns the number of releases which were added to the queue.
+///
+/// Releases which are already waiting in the queue keep their priority.
+pub fn enqueue_campaigns(conn: &mut Client, build_queue: &BuildQueue) -> Result<usize> {
+    let mut added = 0;
+    for campaign in list_campaigns(conn, build_queue)? {
+        if !campaign.is_active() {
+            continue;
+        }
+
+        if campaign.pending == 0 && campaign.queued == 0 {
+            conn.execute(
+                "UPDATE rebuild_campaigns SET finished_at = NOW() WHERE id = $1",
+                &[&campaign.id],
+            )?;
+            log::info!("rebuild campaign `{}` finished", campaign.name);
+            continue;
+        }
+
+        let free = i64::from(campaign.max_queued) - campaign.queued;
+        if free <= 0 {
+            continue;
+        }
+
+        let releases = conn.query(
+            "SELECT
+                rebuild_campaign_releases.release_id,
+                crates.name,
+                releases.version,
+                queue.id IS NOT NULL AS in_queue
+             FROM rebuild_campaign_releases
+             INNER JOIN releases ON releases.id = rebuild_campaign_releases.release_id
+             INNER JOIN crates ON crates.id = releases.crate_id
+             LEFT JOIN queue ON
+                queue.name = crates.name AND
+                queue.version = releases.version AND
+                queue.attempt < $2
+             WHERE
+                rebuild_campaign_releases.campaign_id = $1 AND
+                rebuild_campaign_releases.queued_at IS NULL
+             ORDER BY rebuild_campaign_releases.release_id
+             LIMIT $3",
+            &[&campaign.id, &build_queue.max_attempts(), &free],
+        )?;
+
+        for row in releases {
+            let release_id: i32 = row.get("release_id");
+            if !row.get::<_, bool>("in_queue") {
+                build_queue.add_crate(
+                    row.get("name"),
+                    row.get("version"),
+                    campaign.priority,
+                    None,
+                )?;
+                added += 1;
+            }
+            conn.execute(
+                "UPDATE rebuild_campaign_releases
+                 SET queued_at = NOW()
+                 WHERE campaign_id = $1 AND release_id = $2",
+                &[&campaign.id, &release_id],
+            )?;
+        }
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{wrapper, FakeBuild, TestEnvironment};
+
+    fn release(env: &TestEnvironment, name: &str, version: &str, rustc_date: &str) {
+        env.fake_release()
+            .name(name)
+            .version(version)
+            .builds(vec![FakeBuild::default().rustc_version(format!(
+                "rustc 1.60.0-nightly (000000000 {})",
+                rustc_date
+            ))])
+            .create()
+            .unwrap();
+    }
+
+    fn queued(env: &TestEnvironment) -> Vec<(String, String, i32)> {
+        let mut queued: Vec<_> = env
+            .build_queue()
+            .queued_crates()
+            .unwrap()
+            .into_iter()
+            .map(|krate| (krate.name, krate.version, krate.priority))
+            .collect();
+        queued.sort();
+        queued
+    }
+
+    #[test]
+    fn select_releases() {
+        wrapper(|env| {
+            release(env, "old", "0.1.0", "2022-01-01");
+            release(env, "old", "0.2.0", "2022-01-01");
+            release(env, "new", "0.1.0", "2022-03-01");
+            release(env, "old-popular", "0.1.0", "2022-01-01");
+            env.fake_release()
+                .name("old-popular")
+                .version("0.2.0")
+                .github_stats("some/repo", 100, 10, 10)
+                .builds(vec![FakeBuild::default()
+                    .rustc_version("rustc 1.60.0-nightly (000000000 2022-01-01)")])
+                .create()?;
+
+            let mut conn = env.db().conn();
+            let mut create = |name: &str, selection: CampaignSelection| {
+                create_campaign(&mut conn, name, &selection, 20, 10)
+                    .unwrap()
+                    .unwrap()
+                    .1
+            };
+
+            let before = Some(NaiveDate::from_ymd(2022, 2, 1));
+            let old = CampaignSelection {
+                built_before: before,
+                ..Default::default()
+            };
+            assert_eq!(create("latest", old.clone()), 2);
+            assert_eq!(
+                create(
+                    "all versions",
+                    CampaignSelection {
+                        all_versions: true,
+                        ..old.clone()
+                    }
+                ),
+                4
+            );
+            assert_eq!(
+                create(
+                    "popular",
+                    CampaignSelection {
+                        min_stars: Some(50),
+                        ..Default::default()
+                    }
+                ),
+                1
+            );
+            assert_eq!(
+                create(
+                    "pattern",
+                    CampaignSelection {
+                        name_pattern: Some("old-%".into()),
+                        ..Default::default()
+                    }
+                ),
+                1
+            );
+
+            assert_eq!(
+                create_campaign(&mut env.db().conn(), "latest", &old, 20, 10)?,
+                None
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn enqueue_throttled() {
+        wrapper(|env| {
+            for name in &["a", "b", "c", "d"] {
+                release(env, name, "0.1.0", "2022-01-01");
+            }
+            let queue = env.build_queue();
+            queue.add_crate("c", "0.1.0", 0, None)?;
+            queue.add_crate("new", "1.0.0", 0, None)?;
+
+            let mut conn = env.db().conn();
+            let (id, _) =
+                create_campaign(&mut conn, "rebuild", &CampaignSelection::default(), 20, 3)?
+                    .unwrap();
+
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 2);
+            assert_eq!(
+                queued(env),
+                vec![
+                    ("a".into(), "0.1.0".into(), 20),
+                    ("b".into(), "0.1.0".into(), 20),
+                    ("c".into(), "0.1.0".into(), 0),
+                    ("new".into(), "1.0.0".into(), 0),
+                ]
+            );
+
+            // new crates are still built before the campaign
+            let next = queue.queued_crates()?.remove(0);
+            assert_eq!(next.priority, 0);
+
+            // the queue is full
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 0);
+            let campaign = &list_campaigns(&mut conn, &queue)?[0];
+            assert_eq!(campaign.id, id);
+            assert_eq!(
+                (campaign.total, campaign.pending, campaign.queued),
+                (4, 1, 3)
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn progress_and_finish() {
+        wrapper(|env| {
+            release(env, "a", "0.1.0", "2022-01-01");
+            release(env, "b", "0.1.0", "2022-01-01");
+            let queue = env.build_queue();
+            let mut conn = env.db().conn();
+            create_campaign(&mut conn, "rebuild", &CampaignSelection::default(), 20, 1)?;
+
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 1);
+            queue.process_next_crate(|_| Ok(()))?;
+            let campaign = &list_campaigns(&mut conn, &queue)?[0];
+            assert_eq!(
+                (campaign.pending, campaign.queued, campaign.done),
+                (1, 0, 1)
+            );
+
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 1);
+            queue.process_next_crate(|_| Ok(()))?;
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 0);
+
+            let campaign = &list_campaigns(&mut conn, &queue)?[0];
+            assert_eq!((campaign.total, campaign.done), (2, 2));
+            assert!(campaign.finished_at.is_some());
+            assert!(!campaign.is_active());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn cancel() {
+        wrapper(|env| {
+            release(env, "a", "0.1.0", "2022-01-01");
+            let queue = env.build_queue();
+            let mut conn = env.db().conn();
+            let (id, _) =
+                create_campaign(&mut conn, "rebuild", &CampaignSelection::default(), 20, 1)?
+                    .unwrap();
+
+            assert!(cancel_campaign(&mut conn, id)?);
+            assert!(!cancel_campaign(&mut conn, id)?);
+            assert_eq!(enqueue_campaigns(&mut conn, &queue)?, 0);
+            assert!(queue.queued_crates()?.is_empty());
+
+            Ok(())
+        });
+    }
+}
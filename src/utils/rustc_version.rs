@@ -21,7 +21,7 @@ pub fn parse_rustc_version<S: AsRef<str>>(version: S) -> Result<String> {
     ))
 }
 
-fn parse_rustc_date<S: AsRef<str>>(version: S) -> Result<Date<Utc>> {
+pub(crate) fn parse_rustc_date<S: AsRef<str>>(version: S) -> Result<Date<Utc>> {
     static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r" (\d+)-(\d+)-(\d+)\)$").unwrap());
 
     let cap = RE
@@ -39,6 +39,31 @@ fn parse_rustc_date<S: AsRef<str>>(version: S) -> Result<Date<Utc>> {
     ))
 }
 
+/// Returns the name of the rustup toolchain with the rustc of `version`.
+///
+/// The nightly and beta toolchains are named after the day they were published, which is the day
+/// after the date of their commit in the version string.
+pub(crate) fn parse_rustc_toolchain<S: AsRef<str>>(version: S) -> Result<String> {
+    static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^rustc (\S+) \(").unwrap());
+
+    let release = RE
+        .captures(version.as_ref())
+        .with_context(|| anyhow!("Failed to parse rustc release"))?
+        .get(1)
+        .unwrap()
+        .as_str();
+    let channel = if release.ends_with("-nightly") {
+        "nightly"
+    } else if release.contains("-beta") {
+        "beta"
+    } else {
+        return Ok(release.to_owned());
+    };
+
+    let published = parse_rustc_date(version)? + chrono::Duration::days(1);
+    Ok(format!("{}-{}", channel, published.format("%Y-%m-%d")))
+}
+
 /// Picks the correct "rustdoc.css" static file depending on which rustdoc version was used to
 /// generate this version of this crate.
 pub fn get_correct_docsrs_style_file(version: &str) -> Result<String> {
@@ -65,6 +90,27 @@ fn test_parse_rustc_version() {
     );
 }
 
+#[test]
+fn test_parse_rustc_toolchain() {
+    assert_eq!(
+        parse_rustc_toolchain("rustc 1.63.0-nightly (4c5f6e627 2022-05-31)").unwrap(),
+        "nightly-2022-06-01"
+    );
+    assert_eq!(
+        parse_rustc_toolchain("rustc 1.10.0-nightly (57ef01513 2016-12-31)").unwrap(),
+        "nightly-2017-01-01"
+    );
+    assert_eq!(
+        parse_rustc_toolchain("rustc 1.62.0-beta.3 (a5cf77ca6 2022-06-02)").unwrap(),
+        "beta-2022-06-03"
+    );
+    assert_eq!(
+        parse_rustc_toolchain("rustc 1.61.0 (fe5b13d68 2022-05-18)").unwrap(),
+        "1.61.0"
+    );
+    assert!(parse_rustc_toolchain("docsrs 0.2.0 (ba9ae23 2016-05-26)").is_err());
+}
+
 #[test]
 fn test_get_correct_docsrs_style_file() {
     assert_eq!(
@@ -1,6 +1,9 @@
 use crate::{
     db::Pool,
+    docbuilder::{doctest_log_path, BuildFailure, DoctestResult},
     impl_webpage,
+    storage::PathNotFoundError,
+    utils::{parse_rustc_toolchain, shell_quote},
     web::{file::File, page::WebPage, MetaData, Nope},
     Config, Storage,
 };
@@ -8,6 +11,7 @@ use chrono::{DateTime, Utc};
 use iron::{IronError, IronResult, Request, Response};
 use router::Router;
 use serde::Serialize;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub(crate) struct BuildDetails {
@@ -16,7 +20,101 @@ pub(crate) struct BuildDetails {
     docsrs_version: String,
     build_status: bool,
     build_time: DateTime<Utc>,
+    failure_reason: Option<BuildFailure>,
     output: String,
+    /// the target whose log is shown
+    target: String,
+    targets: Vec<BuildTarget>,
+    /// a shell script running the build again, for the builds whose environment was recorded
+    reproduce_script: Option<String>,
+    doctests: Option<DoctestResult>,
+    doctest_log: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct BuildTarget {
+    target: String,
+    successful: bool,
+    failure_reason: Option<BuildFailure>,
+    #[serde(skip)]
+    cargo_args: Option<Vec<String>>,
+}
+
+/// What's needed to run a build again, see `docbuilder::BuildReproducibility`.
+struct Reproducibility {
+    docker_image: String,
+    environment: BTreeMap<String, String>,
+    rustdoc_flags: Vec<String>,
+    cargo_lock: Option<String>,
+    lockfile_regenerated: bool,
+}
+
+/// Creates a shell script downloading the crate and running cargo like the build did, for every
+/// target whose arguments were recorded.
+fn reproduce_script(
+    name: &str,
+    version: &str,
+    details: &BuildDetails,
+    reproducibility: &Reproducibility,
+) -> String {
+    let mut lines = vec![
+        "#!/bin/sh".to_string(),
+        format!(
+            "# build #{} of {} {}, run with {}",
+            details.id, name, version, details.rustc_version
+        ),
+        format!("# in the {} docker image", reproducibility.docker_image),
+        "set -e".into(),
+        String::new(),
+        format!(
+            "curl -sSfL https://static.crates.io/crates/{0}/{0}-{1}.crate | tar -xz",
+            name, version
+        ),
+        format!("cd {}-{}", name, version),
+        String::new(),
+    ];
+
+    if let Some(cargo_lock) = &reproducibility.cargo_lock {
+        lines.push(if reproducibility.lockfile_regenerated {
+            "# the build failed with the lockfile of the crate, this one was generated instead"
+                .into()
+        } else {
+            "# the lockfile the build used".into()
+        });
+        lines.push("cat > Cargo.lock <<'CARGO_LOCK_EOF'".into());
+        lines.extend(cargo_lock.lines().map(String::from));
+        lines.push("CARGO_LOCK_EOF".into());
+        lines.push(String::new());
+    }
+
+    for (key, value) in &reproducibility.environment {
+        lines.push(format!("export {}={}", key, shell_quote(value)));
+    }
+    let rustdoc_flags: Vec<_> = reproducibility
+        .rustdoc_flags
+        .iter()
+        .map(|flag| shell_quote(flag))
+        .collect();
+    lines.push(format!(
+        "# docs.rs passes these flags to rustdoc: {}",
+        rustdoc_flags.join(" ")
+    ));
+
+    // builds with a rustc version that can't be parsed fall back to the latest nightly
+    let toolchain =
+        parse_rustc_toolchain(&details.rustc_version).unwrap_or_else(|_| "nightly".into());
+    for target in &details.targets {
+        if let Some(cargo_args) = &target.cargo_args {
+            let args: Vec<_> = cargo_args.iter().map(|arg| shell_quote(arg)).collect();
+            lines.push(String::new());
+            lines.push(format!("# {}", target.target));
+            lines.push(format!("cargo +{} {}", toolchain, args.join(" ")));
+        }
+    }
+
+    let mut script = lines.join("\n");
+    script.push('\n');
+    script
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
@@ -49,7 +147,12 @@ pub fn build_details_handler(req: &mut Request) -> IronResult<Response> {
                 builds.docsrs_version,
                 builds.build_status,
                 builds.build_time,
+                builds.failure_reason,
                 builds.output,
+                builds.doctests_successful,
+                builds.doctests_passed,
+                builds.doctests_failed,
+                builds.doctests_ignored,
                 releases.default_target
              FROM builds
              INNER JOIN releases ON releases.id = builds.rid
@@ -60,28 +163,128 @@ pub fn build_details_handler(req: &mut Request) -> IronResult<Response> {
     );
 
     let build_details = if let Some(row) = row {
-        let output = if let Some(output) = row.get("output") {
-            output
+        let default_target: String = row.get("default_target");
+        let targets: Vec<BuildTarget> = ctry!(
+            req,
+            conn.query(
+                "SELECT target, successful, failure_reason, cargo_args
+                 FROM build_targets
+                 WHERE build_id = $1
+                 ORDER BY target = $2 DESC, target",
+                &[&id, &default_target]
+            )
+        )
+        .into_iter()
+        .map(|row| BuildTarget {
+            target: row.get("target"),
+            successful: row.get("successful"),
+            failure_reason: row
+                .get::<_, Option<String>>("failure_reason")
+                .and_then(|reason| reason.parse().ok()),
+            cargo_args: row
+                .get::<_, Option<Vec<String>>>("cargo_args")
+                .filter(|args| !args.is_empty()),
+        })
+        .collect();
+
+        let reproducibility = ctry!(
+            req,
+            conn.query_opt(
+                "SELECT docker_image, environment, rustdoc_flags, cargo_lock, lockfile_regenerated
+                 FROM build_reproducibility
+                 WHERE build_id = $1",
+                &[&id]
+            )
+        )
+        .map(|row| -> Result<_, serde_json::Error> {
+            Ok(Reproducibility {
+                docker_image: row.get("docker_image"),
+                environment: serde_json::from_value(row.get("environment"))?,
+                rustdoc_flags: row.get("rustdoc_flags"),
+                cargo_lock: row.get("cargo_lock"),
+                lockfile_regenerated: row.get("lockfile_regenerated"),
+            })
+        })
+        .transpose();
+        let reproducibility = ctry!(req, reproducibility);
+
+        let target = req
+            .url
+            .as_ref()
+            .query_pairs()
+            .find(|(key, _)| key == "target")
+            .map(|(_, target)| target.into_owned())
+            .filter(|target| targets.iter().any(|t| &t.target == target))
+            .unwrap_or_else(|| default_target.clone());
+
+        // the builds from before the logs were stored in the storage have the log of their
+        // default target in the database
+        let output = match row.get("output") {
+            Some(output) if target == default_target => output,
+            _ => {
+                let path = format!("build-logs/{}/{}.txt", id, target);
+                let file = ctry!(req, File::from_path(storage, &path, config, &[]));
+                ctry!(req, String::from_utf8(file.0.content))
+            }
+        };
+        let doctests = row
+            .get::<_, Option<bool>>("doctests_successful")
+            .map(|successful| DoctestResult {
+                successful,
+                passed: row.get::<_, Option<i32>>("doctests_passed").unwrap_or(0),
+                failed: row.get::<_, Option<i32>>("doctests_failed").unwrap_or(0),
+                ignored: row.get::<_, Option<i32>>("doctests_ignored").unwrap_or(0),
+            });
+        // the page is still shown if the log of the doctests is missing
+        let doctest_log = if doctests.is_some() {
+            match File::from_path(storage, &doctest_log_path(id), config, &[]) {
+                Ok(file) => Some(ctry!(req, String::from_utf8(file.0.content))),
+                Err(err) if err.is::<PathNotFoundError>() => None,
+                Err(err) => ctry!(req, Err(err)),
+            }
         } else {
-            let target: String = row.get("default_target");
-            let path = format!("build-logs/{}/{}.txt", id, target);
-            let file = ctry!(req, File::from_path(storage, &path, config));
-            ctry!(req, String::from_utf8(file.0.content))
+            None
         };
-        BuildDetails {
+
+        let mut build_details = BuildDetails {
             id,
             rustc_version: row.get("rustc_version"),
             docsrs_version: row.get("docsrs_version"),
             build_status: row.get("build_status"),
             build_time: row.get("build_time"),
+            // the reason of the shown target, the builds from before the targets were stored
+            // only have the one of their default target
+            failure_reason: match targets.iter().find(|t| t.target == target) {
+                Some(build_target) => build_target.failure_reason,
+                None => row
+                    .get::<_, Option<String>>("failure_reason")
+                    .and_then(|reason| reason.parse().ok()),
+            },
             output,
+            target,
+            targets,
+            reproduce_script: None,
+            doctests,
+            doctest_log,
+        };
+        if let Some(reproducibility) = reproducibility {
+            build_details.reproduce_script = Some(reproduce_script(
+                name,
+                version,
+                &build_details,
+                &reproducibility,
+            ));
         }
+        build_details
     } else {
         return Err(Nope::BuildNotFound.into());
     };
 
     BuildDetailsPage {
-        metadata: cexpect!(req, MetaData::from_crate(&mut conn, name, version, version)),
+        metadata: cexpect!(
+            req,
+            ctry!(req, MetaData::from_crate(&mut conn, name, version, version))
+        ),
         build_details,
     }
     .into_response(req)
@@ -89,6 +292,7 @@ pub fn build_details_handler(req: &mut Request) -> IronResult<Response> {
 
 #[cfg(test)]
 mod tests {
+    use crate::docbuilder::{doctest_log_path, BuildFailure, BuildReproducibility, DoctestResult};
     use crate::test::{wrapper, FakeBuild};
     use kuchiki::traits::TendrilSink;
     use test_case::test_case;
@@ -125,6 +329,270 @@ mod tests {
         });
     }
 
+    #[test]
+    fn failure_reason() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .builds(vec![FakeBuild::default()
+                    .s3_build_log("A build log")
+                    .failure_reason(BuildFailure::MissingSystemLibrary)])
+                .create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+
+            let node = page.select("ul > li a.release").unwrap().next().unwrap();
+            assert!(node.text_contents().contains("(Missing system library)"));
+            let attrs = node.attributes.borrow();
+            let url = attrs.get("href").unwrap();
+
+            let page = kuchiki::parse_html().one(env.frontend().get(url).send()?.text()?);
+
+            let log = page.select("pre").unwrap().next().unwrap().text_contents();
+
+            assert!(log.contains("# failure reason\nMissing system library\n"));
+            assert!(log.contains("A build log"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn build_logs_of_other_targets() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .builds(vec![FakeBuild::default()
+                    .s3_build_log("A build log")
+                    .target(
+                        "x86_64-pc-windows-msvc",
+                        Some(BuildFailure::CompileError),
+                        "A windows build log",
+                    )
+                    .target("i686-unknown-linux-gnu", None, "A 32 bit build log")])
+                .create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+            let node = page.select("ul > li a.release").unwrap().next().unwrap();
+            let attrs = node.attributes.borrow();
+            let url = attrs.get("href").unwrap();
+
+            let get_page = |query: &str| -> Result<_, anyhow::Error> {
+                Ok(kuchiki::parse_html().one(
+                    env.frontend()
+                        .get(&format!("{}{}", url, query))
+                        .send()?
+                        .text()?,
+                ))
+            };
+
+            let page = get_page("")?;
+            let targets: Vec<_> = page
+                .select("#target option")
+                .unwrap()
+                .map(|option| option.text_contents())
+                .collect();
+            assert_eq!(
+                targets,
+                vec![
+                    "x86_64-unknown-linux-gnu (success)",
+                    "i686-unknown-linux-gnu (success)",
+                    "x86_64-pc-windows-msvc (failed)",
+                ]
+            );
+            let selected = page.select_first("#target option[selected]").unwrap();
+            assert_eq!(
+                selected.attributes.borrow().get("value"),
+                Some("x86_64-unknown-linux-gnu")
+            );
+            let log = page.select_first("pre").unwrap().text_contents();
+            assert!(log.contains("A build log"));
+            assert!(!log.contains("# failure reason"));
+
+            let page = get_page("?target=x86_64-pc-windows-msvc")?;
+            let log = page.select_first("pre").unwrap().text_contents();
+            assert!(log.contains("# failure reason\nCompile error\n"));
+            assert!(log.contains("A windows build log"));
+
+            // unknown targets show the default one
+            let page = get_page("?target=unknown")?;
+            let log = page.select_first("pre").unwrap().text_contents();
+            assert!(log.contains("A build log"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn doctests() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .builds(vec![FakeBuild::default().doctests(
+                    DoctestResult {
+                        successful: false,
+                        passed: 3,
+                        failed: 1,
+                        ignored: 2,
+                    },
+                    "A doctest log",
+                )])
+                .create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+            let node = page.select_first("ul > li a.release").unwrap();
+            let attrs = node.attributes.borrow();
+            let url = attrs.get("href").unwrap();
+
+            let page = kuchiki::parse_html().one(env.frontend().get(url).send()?.text()?);
+            let log = page.select_first("pre").unwrap().text_contents();
+            assert!(log.contains("It works!"));
+            assert!(!log.contains("A doctest log"));
+
+            let log = page
+                .select_first("pre.doctest-log")
+                .unwrap()
+                .text_contents();
+            assert!(log.contains("# doctests failed: 3 passed, 1 failed, 2 ignored\n"));
+            assert!(log.contains("A doctest log"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn missing_doctest_log() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .builds(vec![FakeBuild::default().doctests(
+                    DoctestResult {
+                        successful: true,
+                        passed: 1,
+                        failed: 0,
+                        ignored: 0,
+                    },
+                    "A doctest log",
+                )])
+                .create()?;
+            let build_id: i32 = env
+                .db()
+                .conn()
+                .query_one("SELECT id FROM builds", &[])?
+                .get(0);
+            env.storage().delete_prefix(&doctest_log_path(build_id))?;
+
+            let response = env
+                .frontend()
+                .get(&format!("/crate/foo/0.1.0/builds/{}", build_id))
+                .send()?;
+            assert!(response.status().is_success());
+            let page = kuchiki::parse_html().one(response.text()?);
+            let log = page
+                .select_first("pre.doctest-log")
+                .unwrap()
+                .text_contents();
+            assert!(log.contains("# doctests passed: 1 passed, 0 failed, 0 ignored"));
+            assert!(!log.contains("A doctest log"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn reproduce_script() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .builds(vec![FakeBuild::default().reproducibility(
+                    vec![
+                        "rustdoc".into(),
+                        "--lib".into(),
+                        "--config".into(),
+                        r#"build.rustdocflags=["--cap-lints", "warn"]"#.into(),
+                        "--target".into(),
+                        "x86_64-unknown-linux-gnu".into(),
+                    ],
+                    BuildReproducibility {
+                        docker_image: "ghcr.io/rust-lang/crates-build-env/linux".into(),
+                        environment: vec![("DOCS_RS".to_string(), "1".to_string())]
+                            .into_iter()
+                            .collect(),
+                        rustdoc_flags: vec!["--cap-lints".into(), "warn".into()],
+                        cargo_lock: Some("version = 3\n".into()),
+                        lockfile_regenerated: true,
+                    },
+                )])
+                .create()?;
+            env.fake_release().name("bar").version("0.1.0").create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+            let node = page.select_first("ul > li a.release").unwrap();
+            let attrs = node.attributes.borrow();
+            let url = attrs.get("href").unwrap();
+
+            let page = kuchiki::parse_html().one(env.frontend().get(url).send()?.text()?);
+            let script = page
+                .select_first("pre.reproduce-script")
+                .unwrap()
+                .text_contents();
+            assert!(
+                script.contains("# in the ghcr.io/rust-lang/crates-build-env/linux docker image")
+            );
+            assert!(script.contains(
+                "curl -sSfL https://static.crates.io/crates/foo/foo-0.1.0.crate | tar -xz\ncd foo-0.1.0\n"
+            ));
+            assert!(script.contains("this one was generated instead"));
+            assert!(script
+                .contains("cat > Cargo.lock <<'CARGO_LOCK_EOF'\nversion = 3\nCARGO_LOCK_EOF\n"));
+            assert!(script.contains("export DOCS_RS=1\n"));
+            assert!(script.contains(
+                "# x86_64-unknown-linux-gnu\ncargo +nightly-1970-01-02 rustdoc --lib --config \
+                 'build.rustdocflags=[\"--cap-lints\", \"warn\"]' --target x86_64-unknown-linux-gnu\n"
+            ));
+
+            // builds from before the environment was recorded have no script
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/bar/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+            let node = page.select_first("ul > li a.release").unwrap();
+            let attrs = node.attributes.borrow();
+            let url = attrs.get("href").unwrap();
+            let page = kuchiki::parse_html().one(env.frontend().get(url).send()?.text()?);
+            assert!(page.select_first("pre.reproduce-script").is_err());
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn s3_build_logs() {
         wrapper(|env| {
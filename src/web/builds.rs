@@ -1,7 +1,7 @@
 use super::{match_version, redirect_base, MatchSemver};
 use crate::{
     db::Pool,
-    docbuilder::Limits,
+    docbuilder::{BuildFailure, Limits},
     impl_webpage,
     web::{page::WebPage, MetaData},
 };
@@ -22,6 +22,7 @@ pub(crate) struct Build {
     docsrs_version: String,
     build_status: bool,
     build_time: DateTime<Utc>,
+    failure_reason: Option<BuildFailure>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
@@ -83,7 +84,8 @@ pub fn build_list_handler(req: &mut Request) -> IronResult<Response> {
                 builds.rustc_version,
                 builds.docsrs_version,
                 builds.build_status,
-                builds.build_time
+                builds.build_time,
+                builds.failure_reason
              FROM builds
              INNER JOIN releases ON releases.id = builds.rid
              INNER JOIN crates ON releases.crate_id = crates.id
@@ -101,6 +103,9 @@ pub fn build_list_handler(req: &mut Request) -> IronResult<Response> {
             docsrs_version: row.get("docsrs_version"),
             build_status: row.get("build_status"),
             build_time: row.get("build_time"),
+            failure_reason: row
+                .get::<_, Option<String>>("failure_reason")
+                .and_then(|reason| reason.parse().ok()),
         })
         .collect();
 
@@ -120,7 +125,10 @@ pub fn build_list_handler(req: &mut Request) -> IronResult<Response> {
         BuildsPage {
             metadata: cexpect!(
                 req,
-                MetaData::from_crate(&mut conn, name, &version, &version_or_latest)
+                ctry!(
+                    req,
+                    MetaData::from_crate(&mut conn, name, &version, &version_or_latest)
+                )
             ),
             builds,
             limits,
@@ -131,6 +139,7 @@ pub fn build_list_handler(req: &mut Request) -> IronResult<Response> {
 
 #[cfg(test)]
 mod tests {
+    use crate::docbuilder::BuildFailure;
     use crate::test::{wrapper, FakeBuild};
     use chrono::{DateTime, Duration, Utc};
     use kuchiki::traits::TendrilSink;
@@ -192,6 +201,7 @@ mod tests {
                         .docsrs_version("docs.rs 1.0.0"),
                     FakeBuild::default()
                         .successful(false)
+                        .failure_reason(BuildFailure::Timeout)
                         .rustc_version("rustc (blabla 2020-01-01)")
                         .docsrs_version("docs.rs 2.0.0"),
                     FakeBuild::default()
@@ -208,6 +218,10 @@ mod tests {
             )?;
 
             assert_eq!(value.pointer("/0/build_status"), Some(&true.into()));
+            assert_eq!(
+                value.pointer("/0/failure_reason"),
+                Some(&serde_json::Value::Null)
+            );
             assert_eq!(
                 value.pointer("/0/docsrs_version"),
                 Some(&"docs.rs 3.0.0".into())
@@ -223,6 +237,7 @@ mod tests {
             .is_ok());
 
             assert_eq!(value.pointer("/1/build_status"), Some(&false.into()));
+            assert_eq!(value.pointer("/1/failure_reason"), Some(&"timeout".into()));
             assert_eq!(
                 value.pointer("/1/docsrs_version"),
                 Some(&"docs.rs 2.0.0".into())
@@ -310,6 +325,38 @@ mod tests {
         });
     }
 
+    #[test]
+    fn overridden_networking_and_log_size() {
+        wrapper(|env| {
+            env.fake_release().name("foo").version("0.1.0").create()?;
+            crate::db::sandbox_overrides::set(
+                &mut env.db().conn(),
+                "foo",
+                &crate::db::sandbox_overrides::Overrides {
+                    networking: Some(true),
+                    max_log_size: Some(1024 * 1024),
+                    ..Default::default()
+                },
+            )?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/builds")
+                    .send()?
+                    .text()?,
+            );
+            let values: Vec<_> = page
+                .select(".about table tr td:last-child")
+                .unwrap()
+                .map(|row| row.text_contents())
+                .collect();
+            assert!(values.contains(&"allowed".to_string()));
+            assert!(values.contains(&"1 MB".to_string()));
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn latest_200() {
         wrapper(|env| {
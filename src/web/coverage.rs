@@ -0,0 +1,421 @@
+use super::{match_version, redirect_base, MatchSemver};
+use crate::{
+    db::Pool,
+    docbuilder::{DocCoverage, FileCoverage, UndocumentedItem, MAX_UNDOCUMENTED_ITEMS},
+    impl_webpage,
+    web::{page::WebPage, MetaData},
+};
+use chrono::{DateTime, Utc};
+use iron::{
+    headers::{
+        AccessControlAllowOrigin, CacheControl, CacheDirective, ContentType, Expires, HttpDate,
+    },
+    status, IronResult, Request, Response, Url,
+};
+use postgres::Row;
+use router::Router;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct VersionCoverage {
+    version: String,
+    release_time: DateTime<Utc>,
+    #[serde(flatten)]
+    coverage: DocCoverage,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Coverage {
+    total: Option<DocCoverage>,
+    files: Vec<FileCoverage>,
+    undocumented_items: Vec<UndocumentedItem>,
+    /// whether only the first `MAX_UNDOCUMENTED_ITEMS` undocumented items were stored
+    undocumented_items_truncated: bool,
+    /// the coverage of every release of the crate, oldest first
+    versions: Vec<VersionCoverage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct CoveragePage {
+    metadata: MetaData,
+    coverage: Coverage,
+}
+
+impl_webpage! {
+    CoveragePage = "crate/coverage.html",
+}
+
+/// Reads the coverage columns of a row, which are nullable in the `doc_coverage` table.
+fn doc_coverage(row: &Row) -> DocCoverage {
+    let get = |column| row.get::<_, Option<i32>>(column).unwrap_or(0);
+    DocCoverage {
+        total_items: get("total_items"),
+        documented_items: get("documented_items"),
+        total_items_needing_examples: get("total_items_needing_examples"),
+        items_with_examples: get("items_with_examples"),
+    }
+}
+
+pub fn coverage_handler(req: &mut Request) -> IronResult<Response> {
+    let router = extension!(req, Router);
+    let name = cexpect!(req, router.find("name"));
+    let req_version = router.find("version");
+
+    let mut conn = extension!(req, Pool).get()?;
+
+    let is_json = matches!(req.url.path().last(), Some(segment) if segment.ends_with(".json"));
+
+    let (version, version_or_latest) =
+        match match_version(&mut conn, name, req_version).and_then(|m| m.assume_exact())? {
+            MatchSemver::Exact((version, _)) => (version.clone(), version),
+            MatchSemver::Latest((version, _)) => (version, "latest".to_string()),
+
+            MatchSemver::Semver((version, _)) => {
+                let ext = if is_json { ".json" } else { "" };
+                let url = ctry!(
+                    req,
+                    Url::parse(&format!(
+                        "{}/crate/{}/{}/coverage{}",
+                        redirect_base(req),
+                        name,
+                        version,
+                        ext,
+                    )),
+                );
+
+                return Ok(super::redirect(url));
+            }
+        };
+
+    let row = ctry!(
+        req,
+        conn.query_one(
+            "SELECT releases.id,
+                releases.crate_id,
+                doc_coverage.total_items,
+                doc_coverage.documented_items,
+                doc_coverage.total_items_needing_examples,
+                doc_coverage.items_with_examples,
+                doc_coverage.undocumented_items
+             FROM releases
+             INNER JOIN crates ON releases.crate_id = crates.id
+             LEFT JOIN doc_coverage ON doc_coverage.release_id = releases.id
+             WHERE crates.name = $1 AND releases.version = $2",
+            &[&name, &version]
+        )
+    );
+    let release_id: i32 = row.get("id");
+    let crate_id: i32 = row.get("crate_id");
+    let undocumented_items_count: Option<i32> = row.get("undocumented_items");
+    let total = row
+        .get::<_, Option<i32>>("total_items")
+        .map(|_| doc_coverage(&row));
+
+    let files = ctry!(
+        req,
+        conn.query(
+            "SELECT file, total_items, documented_items,
+                total_items_needing_examples, items_with_examples
+             FROM doc_coverage_files
+             WHERE release_id = $1
+             ORDER BY file",
+            &[&release_id]
+        )
+    )
+    .into_iter()
+    .map(|row| FileCoverage {
+        file: row.get("file"),
+        coverage: doc_coverage(&row),
+    })
+    .collect();
+
+    let undocumented_items: Vec<_> = ctry!(
+        req,
+        conn.query(
+            "SELECT path, kind, file, line
+             FROM undocumented_items
+             WHERE release_id = $1
+             ORDER BY file, line, path
+             LIMIT $2",
+            &[&release_id, &(MAX_UNDOCUMENTED_ITEMS as i64)]
+        )
+    )
+    .into_iter()
+    .map(|row| UndocumentedItem {
+        path: row.get("path"),
+        kind: row.get("kind"),
+        file: row.get("file"),
+        line: row.get("line"),
+    })
+    .collect();
+    let undocumented_items_truncated = matches!(
+        undocumented_items_count,
+        Some(count) if count as usize > undocumented_items.len()
+    );
+
+    let versions = ctry!(
+        req,
+        conn.query(
+            "SELECT releases.version,
+                releases.release_time,
+                doc_coverage.total_items,
+                doc_coverage.documented_items,
+                doc_coverage.total_items_needing_examples,
+                doc_coverage.items_with_examples
+             FROM doc_coverage
+             INNER JOIN releases ON releases.id = doc_coverage.release_id
+             WHERE releases.crate_id = $1 AND doc_coverage.total_items IS NOT NULL
+             ORDER BY releases.release_time, releases.id",
+            &[&crate_id]
+        )
+    )
+    .into_iter()
+    .map(|row| VersionCoverage {
+        version: row.get("version"),
+        release_time: row.get("release_time"),
+        coverage: doc_coverage(&row),
+    })
+    .collect();
+
+    let coverage = Coverage {
+        total,
+        files,
+        undocumented_items,
+        undocumented_items_truncated,
+        versions,
+    };
+
+    if is_json {
+        let mut resp = Response::with((status::Ok, serde_json::to_string(&coverage).unwrap()));
+        resp.headers.set(ContentType::json());
+        resp.headers.set(Expires(HttpDate(time::now())));
+        resp.headers.set(CacheControl(vec![
+            CacheDirective::NoCache,
+            CacheDirective::NoStore,
+            CacheDirective::MustRevalidate,
+        ]));
+        resp.headers.set(AccessControlAllowOrigin::Any);
+
+        Ok(resp)
+    } else {
+        CoveragePage {
+            metadata: cexpect!(
+                req,
+                ctry!(
+                    req,
+                    MetaData::from_crate(&mut conn, name, &version, &version_or_latest)
+                )
+            ),
+            coverage,
+        }
+        .into_response(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::docbuilder::{DocCoverage, FileCoverage, UndocumentedItem, MAX_UNDOCUMENTED_ITEMS};
+    use crate::test::wrapper;
+    use kuchiki::traits::TendrilSink;
+    use serde_json::json;
+
+    fn coverage(total_items: i32, documented_items: i32) -> DocCoverage {
+        DocCoverage {
+            total_items,
+            documented_items,
+            total_items_needing_examples: 2,
+            items_with_examples: 1,
+        }
+    }
+
+    #[test]
+    fn coverage_report() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .doc_coverage(coverage(10, 4))
+                .create()?;
+            env.fake_release()
+                .name("foo")
+                .version("0.2.0")
+                .doc_coverage(coverage(10, 8))
+                .coverage_details(
+                    vec![
+                        FileCoverage {
+                            file: "src/lib.rs".into(),
+                            coverage: coverage(6, 6),
+                        },
+                        FileCoverage {
+                            file: "src/bar.rs".into(),
+                            coverage: coverage(4, 2),
+                        },
+                    ],
+                    vec![UndocumentedItem {
+                        path: "foo::bar::Bar".into(),
+                        kind: "struct".into(),
+                        file: Some("src/bar.rs".into()),
+                        line: Some(3),
+                    }],
+                )
+                .create()?;
+
+            let web = env.frontend();
+
+            let page =
+                kuchiki::parse_html().one(web.get("/crate/foo/0.2.0/coverage").send()?.text()?);
+            let files: Vec<_> = page
+                .select(".coverage-files .file")
+                .unwrap()
+                .map(|node| node.text_contents().trim().to_string())
+                .collect();
+            assert_eq!(files, vec!["src/bar.rs", "src/lib.rs"]);
+            let items: Vec<_> = page
+                .select(".undocumented-items .item-path")
+                .unwrap()
+                .map(|node| node.text_contents().trim().to_string())
+                .collect();
+            assert_eq!(items, vec!["foo::bar::Bar"]);
+            let versions: Vec<_> = page
+                .select(".coverage-versions .version")
+                .unwrap()
+                .map(|node| node.text_contents().trim().to_string())
+                .collect();
+            assert_eq!(versions, vec!["0.1.0", "0.2.0"]);
+
+            let json: serde_json::Value =
+                web.get("/crate/foo/0.2.0/coverage.json").send()?.json()?;
+            assert_eq!(json["total"]["documented_items"], 8);
+            assert_eq!(
+                json["files"][0],
+                json!({
+                    "file": "src/bar.rs",
+                    "total_items": 4,
+                    "documented_items": 2,
+                    "total_items_needing_examples": 2,
+                    "items_with_examples": 1,
+                })
+            );
+            assert_eq!(
+                json["undocumented_items"],
+                json!([{
+                    "path": "foo::bar::Bar",
+                    "kind": "struct",
+                    "file": "src/bar.rs",
+                    "line": 3,
+                }])
+            );
+            let versions: Vec<_> = json["versions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|version| {
+                    (
+                        version["version"].clone(),
+                        version["documented_items"].clone(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                versions,
+                vec![(json!("0.1.0"), json!(4)), (json!("0.2.0"), json!(8))]
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn undocumented_items_are_capped() {
+        wrapper(|env| {
+            let items = (0..MAX_UNDOCUMENTED_ITEMS + 5)
+                .map(|i| UndocumentedItem {
+                    path: format!("foo::item_{}", i),
+                    kind: "function".into(),
+                    file: Some("src/lib.rs".into()),
+                    line: Some(i as i32),
+                })
+                .collect();
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .doc_coverage(coverage(2000, 0))
+                .coverage_details(Vec::new(), items)
+                .create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/coverage")
+                    .send()?
+                    .text()?,
+            );
+            assert_eq!(
+                page.select(".undocumented-items .item-path")
+                    .unwrap()
+                    .count(),
+                MAX_UNDOCUMENTED_ITEMS
+            );
+            assert!(page.text_contents().contains(&format!(
+                "only the first {} are listed",
+                MAX_UNDOCUMENTED_ITEMS
+            )));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn exactly_max_undocumented_items_arent_truncated() {
+        wrapper(|env| {
+            let items = (0..MAX_UNDOCUMENTED_ITEMS)
+                .map(|i| UndocumentedItem {
+                    path: format!("foo::item_{}", i),
+                    kind: "function".into(),
+                    file: Some("src/lib.rs".into()),
+                    line: Some(i as i32),
+                })
+                .collect();
+            env.fake_release()
+                .name("foo")
+                .version("0.1.0")
+                .doc_coverage(coverage(2000, 1000))
+                .coverage_details(Vec::new(), items)
+                .create()?;
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/crate/foo/0.1.0/coverage")
+                    .send()?
+                    .text()?,
+            );
+            assert_eq!(
+                page.select(".undocumented-items .item-path")
+                    .unwrap()
+                    .count(),
+                MAX_UNDOCUMENTED_ITEMS
+            );
+            assert!(!page.text_contents().contains("only the first"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn no_coverage() {
+        wrapper(|env| {
+            env.fake_release().name("foo").version("0.1.0").create()?;
+
+            let web = env.frontend();
+            let page =
+                kuchiki::parse_html().one(web.get("/crate/foo/0.1.0/coverage").send()?.text()?);
+            assert!(page.select_first(".coverage-files").is_err());
+
+            let json: serde_json::Value =
+                web.get("/crate/foo/0.1.0/coverage.json").send()?.json()?;
+            assert_eq!(json["total"], serde_json::Value::Null);
+            assert_eq!(json["versions"], json!([]));
+
+            Ok(())
+        });
+    }
+}
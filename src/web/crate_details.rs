@@ -1,4 +1,5 @@
 use super::{match_version, redirect_base, render_markdown, MatchSemver, MetaData};
+use crate::docbuilder::DoctestResult;
 use crate::utils::{get_correct_docsrs_style_file, report_error};
 use crate::{db::Pool, impl_webpage, repositories::RepositoryStatsUpdater, web::page::WebPage};
 use anyhow::anyhow;
@@ -28,6 +29,8 @@ pub struct CrateDetails {
     last_successful_build: Option<String>,
     rustdoc_status: bool,
     pub archive_storage: bool,
+    /// whether the rustdoc JSON output is available
+    rustdoc_json: bool,
     repository_url: Option<String>,
     homepage_url: Option<String>,
     keywords: Option<Value>,
@@ -43,6 +46,8 @@ pub struct CrateDetails {
     documented_items: Option<f32>,
     total_items_needing_examples: Option<f32>,
     items_with_examples: Option<f32>,
+    /// the results of the doctests of the latest build, if they were run
+    doctests: Option<DoctestResult>,
     /// Database id for this crate
     pub(crate) crate_id: i32,
     /// Database id for this release
@@ -101,6 +106,7 @@ impl CrateDetails {
                 releases.build_status,
                 releases.rustdoc_status,
                 releases.archive_storage,
+                releases.rustdoc_json,
                 releases.repository_url,
                 releases.homepage_url,
                 releases.keywords,
@@ -121,10 +127,21 @@ impl CrateDetails {
                 doc_coverage.total_items,
                 doc_coverage.documented_items,
                 doc_coverage.total_items_needing_examples,
-                doc_coverage.items_with_examples
+                doc_coverage.items_with_examples,
+                latest_build.doctests_successful,
+                latest_build.doctests_passed,
+                latest_build.doctests_failed,
+                latest_build.doctests_ignored
             FROM releases
             INNER JOIN crates ON releases.crate_id = crates.id
             LEFT JOIN doc_coverage ON doc_coverage.release_id = releases.id
+            LEFT JOIN LATERAL (
+                SELECT *
+                FROM builds
+                WHERE builds.rid = releases.id
+                ORDER BY builds.id DESC
+                LIMIT 1
+            ) AS latest_build ON TRUE
             LEFT JOIN repositories ON releases.repository_id = repositories.id
             WHERE crates.name = $1 AND releases.version = $2;";
 
@@ -162,6 +179,7 @@ impl CrateDetails {
             target_name: krate.get("target_name"),
             default_target: krate.get("default_target"),
             doc_targets: MetaData::parse_doc_targets(krate.get("doc_targets")),
+            failed_targets: MetaData::load_failed_targets(conn, name, version)?,
             yanked: krate.get("yanked"),
             rustdoc_css_file: get_correct_docsrs_style_file(krate.get("doc_rustc_version"))?,
         };
@@ -184,6 +202,7 @@ impl CrateDetails {
             last_successful_build: None,
             rustdoc_status: krate.get("rustdoc_status"),
             archive_storage: krate.get("archive_storage"),
+            rustdoc_json: krate.get("rustdoc_json"),
             repository_url: krate.get("repository_url"),
             homepage_url: krate.get("homepage_url"),
             keywords: krate.get("keywords"),
@@ -199,6 +218,14 @@ impl CrateDetails {
             total_items: total_items.map(|v| v as f32),
             total_items_needing_examples: total_items_needing_examples.map(|v| v as f32),
             items_with_examples: items_with_examples.map(|v| v as f32),
+            doctests: krate
+                .get::<_, Option<bool>>("doctests_successful")
+                .map(|successful| DoctestResult {
+                    successful,
+                    passed: krate.get::<_, Option<i32>>("doctests_passed").unwrap_or(0),
+                    failed: krate.get::<_, Option<i32>>("doctests_failed").unwrap_or(0),
+                    ignored: krate.get::<_, Option<i32>>("doctests_ignored").unwrap_or(0),
+                }),
             crate_id,
             release_id,
         };
@@ -351,8 +378,9 @@ pub fn crate_details_handler(req: &mut Request) -> IronResult<Response> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::docbuilder::BuildFailure;
     use crate::index::api::CrateOwner;
-    use crate::test::{assert_redirect, wrapper, TestDatabase};
+    use crate::test::{assert_redirect, wrapper, FakeBuild, TestDatabase};
     use anyhow::{Context, Error};
     use kuchiki::traits::TendrilSink;
     use std::collections::HashMap;
@@ -974,6 +1002,47 @@ mod tests {
         });
     }
 
+    #[test]
+    fn failed_targets_link_to_their_build_log() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("dummy")
+                .version("0.4.0")
+                .rustdoc_file("dummy/index.html")
+                .default_target("x86_64-unknown-linux-gnu")
+                .add_target("x86_64-unknown-linux-gnu")
+                .builds(vec![FakeBuild::default().target(
+                    "x86_64-pc-windows-msvc",
+                    Some(BuildFailure::CompileError),
+                    "A windows build log",
+                )])
+                .create()?;
+            let build_id: i32 = env
+                .db()
+                .conn()
+                .query_one("SELECT MAX(id) FROM builds", &[])?
+                .get(0);
+
+            let response = env.frontend().get("/crate/dummy/0.4.0").send()?;
+            assert!(response.status().is_success());
+
+            let page = kuchiki::parse_html().one(response.text()?);
+            let failed = page
+                .select_first(r#"a[aria-label="Platform"] + ul li a.failed-target"#)
+                .expect("missing failed target");
+            assert_eq!(failed.text_contents().trim(), "x86_64-pc-windows-msvc");
+            assert_eq!(
+                failed.attributes.borrow().get("href").unwrap(),
+                format!(
+                    "/crate/dummy/0.4.0/builds/{}?target=x86_64-pc-windows-msvc",
+                    build_id
+                )
+            );
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn latest_url() {
         wrapper(|env| {
@@ -1011,4 +1080,67 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn links_to_rustdoc_json() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .rustdoc_json(15, b"{}")
+                .create()?;
+            env.fake_release().name("other").version("0.1.0").create()?;
+
+            let link = |url: &str| -> Result<Option<String>, anyhow::Error> {
+                let page = kuchiki::parse_html().one(env.frontend().get(url).send()?.text()?);
+                Ok(page
+                    .select_first("a.rustdoc-json")
+                    .ok()
+                    .and_then(|a| a.attributes.borrow().get("href").map(str::to_owned)))
+            };
+            assert_eq!(
+                link("/crate/dummy/0.1.0")?.as_deref(),
+                Some("/crate/dummy/0.1.0/json")
+            );
+            assert_eq!(
+                link("/crate/dummy/latest")?.as_deref(),
+                Some("/crate/dummy/latest/json")
+            );
+            assert_eq!(link("/crate/other/0.1.0")?, None);
+
+            Ok(())
+        });
+    }
+    #[test]
+    fn doctest_results_of_latest_build() {
+        wrapper(|env| {
+            let doctests = |successful, failed| DoctestResult {
+                successful,
+                passed: 4,
+                failed,
+                ignored: 1,
+            };
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .builds(vec![
+                    FakeBuild::default().doctests(doctests(false, 2), "failed"),
+                    FakeBuild::default().doctests(doctests(true, 0), "passed"),
+                ])
+                .create()?;
+            env.fake_release().name("other").version("0.1.0").create()?;
+
+            let page =
+                kuchiki::parse_html().one(env.frontend().get("/crate/dummy/0.1.0").send()?.text()?);
+            let text = page.select_first("li.doctests").unwrap().text_contents();
+            assert!(text.contains("Passed"));
+            assert!(text.contains("4 passed, 0 failed, 1 ignored"));
+
+            let page =
+                kuchiki::parse_html().one(env.frontend().get("/crate/other/0.1.0").send()?.text()?);
+            assert!(page.select_first("li.doctests").is_err());
+
+            Ok(())
+        });
+    }
 }
@@ -0,0 +1,867 @@
+//! Offline bundles of the documentation and the source code of a release.
+//!
+//! The bundles are built from the archives of the release when they're first downloaded, and
+//! cached in the storage until the release is rebuilt. They are written to a temporary file while
+//! they're built and streamed from the storage when they're served, so they're never held in
+//! memory as a whole.
+
+use super::{error::Nope, match_version, redirect_base, MatchSemver, MetaData};
+use crate::{
+    db::Pool,
+    error::{Result, SizeLimitReached},
+    storage::{
+        download_bundles_prefix, rustdoc_archive_path, source_archive_path, FileRange,
+        PathNotFoundError, Storage,
+    },
+    utils::rewrite_for_offline,
+    Config,
+};
+use flate2::{write::GzEncoder, Compression};
+use iron::{
+    headers::{
+        AcceptRanges, ByteRangeSpec, CacheControl, CacheDirective, ContentLength, ContentRange,
+        ContentRangeSpec, ContentType, Range, RangeUnit,
+    },
+    response::WriteBody,
+    status, IronResult, Request, Response, Url,
+};
+use regex::Regex;
+use router::Router;
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex},
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// `/crate/:name/:version/download`, a zip of the documentation for one target, which works when
+/// it's opened from disk.
+pub fn rustdoc_download_handler(req: &mut Request) -> IronResult<Response> {
+    let router = extension!(req, Router);
+    let name = cexpect!(req, router.find("name"));
+    let req_version = router.find("version");
+
+    let mut conn = extension!(req, Pool).get()?;
+    let version =
+        match match_version(&mut conn, name, req_version).and_then(|m| m.assume_exact())? {
+            MatchSemver::Exact((version, _)) => version,
+            MatchSemver::Latest((version, _)) | MatchSemver::Semver((version, _)) => {
+                let mut url = ctry!(
+                    req,
+                    Url::parse(&format!(
+                        "{}/crate/{}/{}/download",
+                        redirect_base(req),
+                        name,
+                        version
+                    )),
+                );
+                url.as_mut().set_query(req.url.query());
+                return Ok(super::redirect(url));
+            }
+        };
+    let row = ctry!(
+        req,
+        conn.query_opt(
+            "SELECT releases.archive_storage, releases.rustdoc_status, releases.default_target,
+                    releases.doc_targets, releases.target_name
+             FROM releases
+             INNER JOIN crates ON crates.id = releases.crate_id
+             WHERE crates.name = $1 AND releases.version = $2",
+            &[&name, &version],
+        )
+    )
+    .ok_or(Nope::VersionNotFound)?;
+
+    // the bundles are only built from archives
+    let (archive_storage, rustdoc_status): (bool, bool) = (row.get(0), row.get(1));
+    if !archive_storage || !rustdoc_status {
+        return Err(Nope::ResourceNotFound.into());
+    }
+    let default_target: String = row.get(2);
+    let doc_targets = MetaData::parse_doc_targets(row.get(3));
+    let target_name: String = row.get(4);
+
+    let target = req
+        .url
+        .as_ref()
+        .query_pairs()
+        .find(|(key, _)| key == "target")
+        .map(|(_, target)| target.into_owned())
+        .unwrap_or_else(|| default_target.clone());
+    if target != default_target && !doc_targets.contains(&target) {
+        return Err(Nope::ResourceNotFound.into());
+    }
+
+    let storage = extension!(req, Storage);
+    let config = extension!(req, Config);
+    let builds = extension!(req, BundleBuilds);
+    let bundle = DocsBundle {
+        name,
+        version: &version,
+        target: &target,
+        target_name: &target_name,
+        other_targets: doc_targets
+            .iter()
+            .filter(|other| **other != target && **other != default_target)
+            .map(String::as_str)
+            .collect(),
+        is_default_target: target == default_target,
+    };
+    let path = format!(
+        "{}docs-{}.zip",
+        download_bundles_prefix(name, &version),
+        target
+    );
+    let status = ctry!(
+        req,
+        ensure_bundle(storage, builds, config, &path, |out| bundle
+            .build(storage, config, out))
+    );
+
+    serve_bundle(
+        status,
+        req,
+        storage,
+        &path,
+        &format!("{}-{}-{}-docs.zip", name, version, target),
+        "application/zip",
+    )
+}
+
+/// `/crate/:name/:version/source.tar.gz`, the source code of the release.
+pub fn source_download_handler(req: &mut Request) -> IronResult<Response> {
+    let router = extension!(req, Router);
+    let name = cexpect!(req, router.find("name"));
+    let req_version = router.find("version");
+
+    let mut conn = extension!(req, Pool).get()?;
+    let version =
+        match match_version(&mut conn, name, req_version).and_then(|m| m.assume_exact())? {
+            MatchSemver::Exact((version, _)) => version,
+            MatchSemver::Latest((version, _)) | MatchSemver::Semver((version, _)) => {
+                let mut url = ctry!(
+                    req,
+                    Url::parse(&format!(
+                        "{}/crate/{}/{}/source.tar.gz",
+                        redirect_base(req),
+                        name,
+                        version
+                    )),
+                );
+                url.as_mut().set_query(req.url.query());
+                return Ok(super::redirect(url));
+            }
+        };
+    let archive_storage: bool = ctry!(
+        req,
+        conn.query_opt(
+            "SELECT releases.archive_storage
+             FROM releases
+             INNER JOIN crates ON crates.id = releases.crate_id
+             WHERE crates.name = $1 AND releases.version = $2",
+            &[&name, &version],
+        )
+    )
+    .ok_or(Nope::VersionNotFound)?
+    .get(0);
+    if !archive_storage {
+        return Err(Nope::ResourceNotFound.into());
+    }
+
+    let storage = extension!(req, Storage);
+    let config = extension!(req, Config);
+    let builds = extension!(req, BundleBuilds);
+    let path = format!("{}source.tar.gz", download_bundles_prefix(name, &version));
+    let status = ctry!(
+        req,
+        ensure_bundle(storage, builds, config, &path, |out| build_source_bundle(
+            storage, config, name, &version, out
+        ))
+    );
+
+    serve_bundle(
+        status,
+        req,
+        storage,
+        &path,
+        &format!("{}-{}.tar.gz", name, version),
+        "application/gzip",
+    )
+}
+
+/// The paths of the bundles this server is building, so that every bundle is only built once at a
+/// time, and at most `max_concurrent_bundle_builds` bundles are built at the same time.
+#[derive(Debug, Default)]
+pub(super) struct BundleBuilds(Mutex<HashSet<String>>);
+
+impl BundleBuilds {
+    /// Marks the bundle at `path` as being built until the returned guard is dropped, unless it's
+    /// being built already or `max_builds` other bundles are.
+    fn start<'a>(
+        &'a self,
+        path: &'a str,
+        max_builds: usize,
+    ) -> std::result::Result<BundleBuildGuard<'a>, BundleStatus> {
+        let mut building = self.0.lock().unwrap();
+        if building.contains(path) {
+            Err(BundleStatus::Building)
+        } else if building.len() >= max_builds {
+            Err(BundleStatus::Busy)
+        } else {
+            building.insert(path.to_owned());
+            Ok(BundleBuildGuard { builds: self, path })
+        }
+    }
+}
+
+struct BundleBuildGuard<'a> {
+    builds: &'a BundleBuilds,
+    path: &'a str,
+}
+
+impl Drop for BundleBuildGuard<'_> {
+    fn drop(&mut self) {
+        self.builds.0.lock().unwrap().remove(self.path);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum BundleStatus {
+    Ready,
+    /// another request is building the bundle
+    Building,
+    /// the server is building as many other bundles as it can at the same time
+    Busy,
+    /// the bundle is bigger than `max_download_bundle_size`
+    TooBig,
+}
+
+/// The path of the marker stored instead of a bundle that's too big, so that it isn't built again
+/// for every request. Like the bundle, it's removed when the release is rebuilt.
+fn too_big_marker_path(path: &str) -> String {
+    format!("{}.too-big", path)
+}
+
+/// Builds and stores the bundle at `path`, unless it's already cached, known to be too big, or
+/// this server is building it or too many other bundles.
+fn ensure_bundle(
+    storage: &Storage,
+    builds: &BundleBuilds,
+    config: &Config,
+    path: &str,
+    build: impl FnOnce(&mut BundleWriter<'_>) -> Result<()>,
+) -> Result<BundleStatus> {
+    let too_big_marker = too_big_marker_path(path);
+    let cached_status = || -> Result<Option<BundleStatus>> {
+        Ok(if storage.exists(path)? {
+            Some(BundleStatus::Ready)
+        } else if storage.exists(&too_big_marker)? {
+            Some(BundleStatus::TooBig)
+        } else {
+            None
+        })
+    };
+
+    if let Some(status) = cached_status()? {
+        return Ok(status);
+    }
+    let _guard = match builds.start(path, config.max_concurrent_bundle_builds) {
+        Ok(guard) => guard,
+        Err(status) => return Ok(status),
+    };
+    // the build that was running before might have finished after the check above
+    if let Some(status) = cached_status()? {
+        return Ok(status);
+    }
+
+    let mut file = tempfile::NamedTempFile::new()?;
+    let mut out = BundleWriter::new(file.as_file_mut(), config.max_download_bundle_size);
+    match build(&mut out).and_then(|()| Ok(out.flush()?)) {
+        Ok(()) => {}
+        Err(err) if is_size_limit_reached(&err) => {
+            storage.store_one(too_big_marker, Vec::new())?;
+            return Ok(BundleStatus::TooBig);
+        }
+        Err(err) => return Err(err),
+    }
+    drop(out);
+
+    storage.store_file_uncompressed(path, file.path())?;
+    Ok(BundleStatus::Ready)
+}
+
+fn is_size_limit_reached(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.is::<SizeLimitReached>()
+            || matches!(
+                err.downcast_ref::<io::Error>().and_then(|err| err.get_ref()),
+                Some(err) if err.is::<SizeLimitReached>()
+            )
+    })
+}
+
+/// Buffers the writes of a bundle to its temporary file, failing with [`SizeLimitReached`] once
+/// more than `limit` bytes were written.
+///
+/// Bytes written again after seeking back are counted twice, which only happens for the few bytes
+/// of the headers the zip writer updates after every file.
+struct BundleWriter<'a> {
+    inner: BufWriter<&'a mut fs::File>,
+    written: usize,
+    limit: usize,
+}
+
+impl<'a> BundleWriter<'a> {
+    fn new(file: &'a mut fs::File, limit: usize) -> Self {
+        Self {
+            inner: BufWriter::new(file),
+            written: 0,
+            limit,
+        }
+    }
+}
+
+impl Write for BundleWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() > self.limit {
+            return Err(io::Error::other(SizeLimitReached));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for BundleWriter<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// A response body streaming a blob, or a part of it, from the storage.
+struct StorageBody {
+    storage: Arc<Storage>,
+    path: String,
+    range: Option<FileRange>,
+}
+
+impl WriteBody for StorageBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        self.storage
+            .read_raw_chunks(&self.path, self.range.clone(), &mut |chunk| {
+                Ok(res.write_all(chunk)?)
+            })
+            .map_err(io::Error::other)
+    }
+}
+
+/// Serves the bundle at `path`, or the part of it requested in the `Range` header.
+///
+/// A bundle that's still being built is answered with `202 Accepted`, one that can't be built
+/// right now because the server is busy with `503 Service Unavailable`, and one that's too big
+/// with a 404.
+fn serve_bundle(
+    status: BundleStatus,
+    req: &Request,
+    storage: &Arc<Storage>,
+    path: &str,
+    filename: &str,
+    mime: &str,
+) -> IronResult<Response> {
+    match status {
+        BundleStatus::Ready => {}
+        BundleStatus::Building | BundleStatus::Busy => {
+            let mut response = if status == BundleStatus::Building {
+                Response::with((
+                    status::Accepted,
+                    "The download is being prepared, please try again in a few seconds.",
+                ))
+            } else {
+                Response::with((
+                    status::ServiceUnavailable,
+                    "Too many downloads are being prepared, please try again in a few seconds.",
+                ))
+            };
+            response
+                .headers
+                .set_raw("Retry-After", vec![b"10".to_vec()]);
+            response
+                .headers
+                .set(CacheControl(vec![CacheDirective::NoStore]));
+            return Ok(response);
+        }
+        BundleStatus::TooBig => return Err(Nope::ResourceNotFound.into()),
+    }
+
+    let size = ctry!(req, storage.size(path));
+    let range = match req.headers.get::<Range>() {
+        // multiple ranges are not supported, the whole file is served for them instead
+        Some(Range::Bytes(specs)) if specs.len() == 1 => match satisfiable_range(&specs[0], size) {
+            Some(range) => Some(range),
+            None => {
+                let mut response = Response::with(status::RangeNotSatisfiable);
+                response.headers.set(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(size),
+                }));
+                return Ok(response);
+            }
+        },
+        _ => None,
+    };
+
+    let body: Box<dyn WriteBody> = Box::new(StorageBody {
+        storage: storage.clone(),
+        path: path.to_owned(),
+        range: range.clone(),
+    });
+    let mut response = match range {
+        Some(range) => {
+            let mut response = Response::with((status::PartialContent, body));
+            response
+                .headers
+                .set(ContentLength(range.end() - range.start() + 1));
+            response.headers.set(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((*range.start(), *range.end())),
+                instance_length: Some(size),
+            }));
+            response
+        }
+        None => {
+            let mut response = Response::with((status::Ok, body));
+            response.headers.set(ContentLength(size));
+            response
+        }
+    };
+
+    response.headers.set(ContentType(mime.parse().unwrap()));
+    response.headers.set(AcceptRanges(vec![RangeUnit::Bytes]));
+    response.headers.set_raw(
+        "Content-Disposition",
+        vec![format!("attachment; filename=\"{}\"", filename).into_bytes()],
+    );
+    // the bundles change when the release is rebuilt, so they're not cached forever
+    response.headers.set(CacheControl(vec![
+        CacheDirective::Public,
+        CacheDirective::MaxAge(24 * 60 * 60),
+    ]));
+    Ok(response)
+}
+
+/// The bytes of a file of `size` bytes requested by `spec`, if any of them are inside the file.
+fn satisfiable_range(spec: &ByteRangeSpec, size: u64) -> Option<FileRange> {
+    let (start, end) = match *spec {
+        ByteRangeSpec::FromTo(start, end) => (start, end.min(size.checked_sub(1)?)),
+        ByteRangeSpec::AllFrom(start) => (start, size.checked_sub(1)?),
+        ByteRangeSpec::Last(len) => (size.checked_sub(len.min(size))?, size.checked_sub(1)?),
+    };
+    if start > end {
+        return None;
+    }
+    Some(start..=end)
+}
+
+struct DocsBundle<'a> {
+    name: &'a str,
+    version: &'a str,
+    target: &'a str,
+    target_name: &'a str,
+    /// the other targets of the release, which are stored in their own folder
+    other_targets: Vec<&'a str>,
+    is_default_target: bool,
+}
+
+impl DocsBundle<'_> {
+    /// The path of `file` inside the bundle, if the bundle contains it.
+    fn path_in_bundle<'f>(&self, file: &'f str) -> Option<&'f str> {
+        if self.is_default_target {
+            let folder = file.split('/').next().unwrap_or_default();
+            if self.other_targets.contains(&folder) {
+                None
+            } else {
+                Some(file)
+            }
+        } else {
+            file.strip_prefix(self.target)?.strip_prefix('/')
+        }
+    }
+
+    fn build(&self, storage: &Storage, config: &Config, out: &mut BundleWriter<'_>) -> Result<()> {
+        let archive = rustdoc_archive_path(self.name, self.version);
+        let root = format!("{}-{}/", self.name, self.version);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let mut zip = ZipWriter::new(out);
+        let mut resources = BTreeSet::new();
+        let mut has_index = false;
+
+        for file in storage.list_archive_files(&archive)? {
+            let path = match self.path_in_bundle(&file) {
+                Some(path) => path,
+                None => continue,
+            };
+            let mut content = storage
+                .get_from_archive_encoded(
+                    &archive,
+                    &file,
+                    config.max_download_bundle_size,
+                    None,
+                    &[],
+                )?
+                .content;
+            if path.ends_with(".html") {
+                let relative_root = "../".repeat(path.matches('/').count());
+                let (rewritten, used) = rewrite_for_offline(
+                    &content,
+                    config.max_parse_memory,
+                    if relative_root.is_empty() {
+                        "./"
+                    } else {
+                        &relative_root
+                    },
+                )?;
+                content = rewritten;
+                resources.extend(used);
+            }
+            has_index |= path == "index.html";
+
+            zip.start_file(format!("{}{}", root, path), options)?;
+            zip.write_all(&content)?;
+        }
+
+        // the toolchain-shared resources, and the fonts and images the stylesheets load
+        let font_url = Regex::new(r#"url\(["']?([^"')/:]+)["']?\)"#).unwrap();
+        let mut pending: Vec<String> = resources.iter().cloned().collect();
+        while let Some(resource) = pending.pop() {
+            let content = match storage.get(&resource, config.max_file_size) {
+                Ok(blob) => blob.content,
+                Err(err) if err.is::<PathNotFoundError>() => continue,
+                Err(err) => return Err(err),
+            };
+            if resource.ends_with(".css") {
+                for url in font_url.captures_iter(&String::from_utf8_lossy(&content)) {
+                    if resources.insert(url[1].to_owned()) {
+                        pending.push(url[1].to_owned());
+                    }
+                }
+            }
+            zip.start_file(format!("{}{}", root, resource), options)?;
+            zip.write_all(&content)?;
+        }
+
+        if !has_index {
+            zip.start_file(format!("{}index.html", root), options)?;
+            write!(
+                zip,
+                "<!DOCTYPE html><meta http-equiv=\"refresh\" content=\"0; url={0}/index.html\">\
+                 <a href=\"{0}/index.html\">{0}</a>",
+                self.target_name
+            )?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+fn build_source_bundle(
+    storage: &Storage,
+    config: &Config,
+    name: &str,
+    version: &str,
+    out: &mut BundleWriter<'_>,
+) -> Result<()> {
+    let archive = source_archive_path(name, version);
+    let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+
+    for file in storage.list_archive_files(&archive)? {
+        let blob = storage.get_from_archive_encoded(
+            &archive,
+            &file,
+            config.max_download_bundle_size,
+            None,
+            &[],
+        )?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(blob.content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(blob.date_updated.timestamp().max(0) as u64);
+        header.set_cksum();
+        tar.append_data(
+            &mut header,
+            format!("{}-{}/{}", name, version, file),
+            &*blob.content,
+        )?;
+    }
+
+    tar.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::wrapper;
+    use flate2::read::GzDecoder;
+    use std::io::{Cursor, Read};
+
+    fn unzip(content: &[u8]) -> Vec<(String, String)> {
+        let mut zip = zip::ZipArchive::new(Cursor::new(content)).unwrap();
+        let mut files = Vec::new();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).unwrap();
+            let mut content = String::new();
+            file.read_to_string(&mut content).unwrap();
+            files.push((file.name().to_owned(), content));
+        }
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn docs_bundle() {
+        wrapper(|env| {
+            env.storage().store_one(
+                "rustdoc-1.60.0.css",
+                b"@font-face { src: url(\"FiraSans-1.60.0.woff2\"); }".to_vec(),
+            )?;
+            env.storage()
+                .store_one("FiraSans-1.60.0.woff2", b"font".to_vec())?;
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .archive_storage(true)
+                .rustdoc_file_with(
+                    "dummy/index.html",
+                    br#"<html><head><link rel="stylesheet" href="/rustdoc-1.60.0.css"></head><body></body></html>"#,
+                )
+                .add_platform("x86_64-pc-windows-msvc")
+                .create()?;
+            let web = env.frontend();
+
+            let resp = web.get("/crate/dummy/0.1.0/download").send()?;
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers()["Content-Type"], "application/zip");
+            assert_eq!(resp.headers()["Accept-Ranges"], "bytes");
+            let files = unzip(&resp.bytes()?);
+            let paths: Vec<_> = files.iter().map(|(path, _)| path.as_str()).collect();
+            assert_eq!(
+                paths,
+                [
+                    "dummy-0.1.0/FiraSans-1.60.0.woff2",
+                    "dummy-0.1.0/dummy/index.html",
+                    "dummy-0.1.0/index.html",
+                    "dummy-0.1.0/rustdoc-1.60.0.css",
+                ]
+            );
+            assert!(files[1].1.contains(r#"href="../rustdoc-1.60.0.css""#));
+            assert!(files[2].1.contains("dummy/index.html"));
+
+            // the bundle is cached
+            assert!(env
+                .storage()
+                .exists("downloads/dummy/0.1.0/docs-x86_64-unknown-linux-gnu.zip")?);
+
+            let resp = web
+                .get("/crate/dummy/0.1.0/download?target=x86_64-pc-windows-msvc")
+                .send()?;
+            assert!(resp.status().is_success());
+            assert!(unzip(&resp.bytes()?)
+                .iter()
+                .any(|(path, _)| path == "dummy-0.1.0/dummy/index.html"));
+
+            assert_eq!(
+                web.get("/crate/dummy/0.1.0/download?target=aarch64-apple-darwin")
+                    .send()?
+                    .status(),
+                404
+            );
+
+            let resp = web.get("/crate/dummy/latest/download").send()?;
+            assert!(resp.url().as_str().ends_with("/crate/dummy/0.1.0/download"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn source_bundle_with_ranges() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", b"pub fn dummy() {}")
+                .create()?;
+            let web = env.frontend();
+
+            let resp = web.get("/crate/dummy/0.1.0/source.tar.gz").send()?;
+            assert!(resp.status().is_success());
+            let bundle = resp.bytes()?.to_vec();
+
+            let mut archive = tar::Archive::new(GzDecoder::new(&*bundle));
+            let mut files = Vec::new();
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                files.push((entry.path()?.to_str().unwrap().to_owned(), content));
+            }
+            assert_eq!(
+                files,
+                [(
+                    "dummy-0.1.0/src/lib.rs".to_owned(),
+                    "pub fn dummy() {}".to_owned()
+                )]
+            );
+
+            let resp = web
+                .get("/crate/dummy/0.1.0/source.tar.gz")
+                .header("Range", "bytes=0-9")
+                .send()?;
+            assert_eq!(resp.status(), 206);
+            assert_eq!(
+                resp.headers()["Content-Range"],
+                format!("bytes 0-9/{}", bundle.len()).as_str()
+            );
+            assert_eq!(resp.bytes()?, &bundle[..10]);
+
+            let resp = web
+                .get("/crate/dummy/0.1.0/source.tar.gz")
+                .header("Range", "bytes=-5")
+                .send()?;
+            assert_eq!(resp.status(), 206);
+            assert_eq!(resp.bytes()?, &bundle[bundle.len() - 5..]);
+
+            let resp = web
+                .get("/crate/dummy/0.1.0/source.tar.gz")
+                .header("Range", format!("bytes={}-", bundle.len()))
+                .send()?;
+            assert_eq!(resp.status(), 416);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn too_big() {
+        wrapper(|env| {
+            env.override_config(|config| config.max_download_bundle_size = 100);
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .archive_storage(true)
+                .source_file("src/lib.rs", &[b'a'; 1000])
+                .create()?;
+            let web = env.frontend();
+
+            assert_eq!(
+                web.get("/crate/dummy/0.1.0/source.tar.gz").send()?.status(),
+                404
+            );
+            assert!(!env
+                .storage()
+                .exists("downloads/dummy/0.1.0/source.tar.gz")?);
+            assert!(env
+                .storage()
+                .exists("downloads/dummy/0.1.0/source.tar.gz.too-big")?);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn too_big_bundles_are_not_built_again() {
+        wrapper(|env| {
+            env.override_config(|config| config.max_download_bundle_size = 100);
+            let storage = env.storage();
+            let builds = BundleBuilds::default();
+            let path = "downloads/dummy/0.1.0/source.tar.gz";
+
+            let status = ensure_bundle(&storage, &builds, &env.config(), path, |out| {
+                Ok(out.write_all(&[0; 1000])?)
+            })?;
+            assert_eq!(status, BundleStatus::TooBig);
+
+            let status = ensure_bundle(&storage, &builds, &env.config(), path, |_| {
+                panic!("the bundle was built again")
+            })?;
+            assert_eq!(status, BundleStatus::TooBig);
+
+            // rebuilding the release removes the marker
+            storage.delete_prefix("downloads/dummy/0.1.0/")?;
+            let status = ensure_bundle(&storage, &builds, &env.config(), path, |out| {
+                Ok(out.write_all(b"bundle")?)
+            })?;
+            assert_eq!(status, BundleStatus::Ready);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn limited_concurrent_builds() {
+        wrapper(|env| {
+            env.override_config(|config| config.max_concurrent_bundle_builds = 1);
+            let storage = env.storage();
+            let builds = BundleBuilds::default();
+
+            let _guard = builds
+                .start("downloads/other/0.1.0/source.tar.gz", 1)
+                .unwrap();
+            let status = ensure_bundle(
+                &storage,
+                &builds,
+                &env.config(),
+                "downloads/dummy/0.1.0/source.tar.gz",
+                |_| panic!("too many bundles are being built"),
+            )?;
+            assert_eq!(status, BundleStatus::Busy);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn built_once_at_a_time() {
+        wrapper(|env| {
+            let storage = env.storage();
+            let builds = BundleBuilds::default();
+            let path = "downloads/dummy/0.1.0/source.tar.gz";
+
+            let guard = builds.start(path, 1).unwrap();
+            let status = ensure_bundle(&storage, &builds, &env.config(), path, |_| {
+                panic!("the bundle is already being built")
+            })?;
+            assert_eq!(status, BundleStatus::Building);
+            assert!(!storage.exists(path)?);
+            drop(guard);
+
+            let status = ensure_bundle(&storage, &builds, &env.config(), path, |out| {
+                Ok(out.write_all(b"bundle")?)
+            })?;
+            assert_eq!(status, BundleStatus::Ready);
+            assert_eq!(storage.get(path, usize::MAX)?.content, b"bundle");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn not_in_archive_storage() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .archive_storage(false)
+                .create()?;
+            let web = env.frontend();
+            assert_eq!(web.get("/crate/dummy/0.1.0/download").send()?.status(), 404);
+            assert_eq!(
+                web.get("/crate/dummy/0.1.0/source.tar.gz").send()?.status(),
+                404
+            );
+            Ok(())
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use crate::web::page::TemplateData;
+use crate::web::{download::BundleBuilds, page::TemplateData};
 use crate::{
     db::Pool, repositories::RepositoryStatsUpdater, BuildQueue, Config, Context, Metrics, Storage,
 };
@@ -15,6 +15,7 @@ pub(super) struct InjectExtensions {
     metrics: Arc<Metrics>,
     template_data: Arc<TemplateData>,
     repository_stats_updater: Arc<RepositoryStatsUpdater>,
+    bundle_builds: Arc<BundleBuilds>,
 }
 
 impl InjectExtensions {
@@ -30,6 +31,7 @@ impl InjectExtensions {
             metrics: context.metrics()?,
             repository_stats_updater: context.repository_stats_updater()?,
             template_data,
+            bundle_builds: Arc::new(BundleBuilds::default()),
         })
     }
 }
@@ -46,6 +48,8 @@ impl BeforeMiddleware for InjectExtensions {
             .insert::<TemplateData>(self.template_data.clone());
         req.extensions
             .insert::<RepositoryStatsUpdater>(self.repository_stats_updater.clone());
+        req.extensions
+            .insert::<BundleBuilds>(self.bundle_builds.clone());
 
         Ok(())
     }
@@ -66,3 +70,4 @@ key!(Storage => Arc<Storage>);
 key!(Metrics => Arc<Metrics>);
 key!(TemplateData => Arc<TemplateData>);
 key!(RepositoryStatsUpdater => Arc<RepositoryStatsUpdater>);
+key!(BundleBuilds => Arc<BundleBuilds>);
@@ -72,7 +72,10 @@ pub fn build_features_handler(req: &mut Request) -> IronResult<Response> {
     FeaturesPage {
         metadata: cexpect!(
             req,
-            MetaData::from_crate(&mut conn, name, &version, &version_or_latest)
+            ctry!(
+                req,
+                MetaData::from_crate(&mut conn, name, &version, &version_or_latest)
+            )
         ),
         features,
         default_len,
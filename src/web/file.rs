@@ -1,27 +1,41 @@
 //! Database based file handler
 
-use crate::storage::{Blob, Storage};
+use crate::storage::{Blob, CompressionAlgorithm, Storage};
 use crate::{error::Result, Config};
-use iron::{status, Response};
+use iron::headers::{AcceptEncoding, Encoding};
+use iron::{status, Request, Response};
 
 #[derive(Debug)]
 pub(crate) struct File(pub(crate) Blob);
 
 impl File {
     /// Gets file from database
-    pub(super) fn from_path(storage: &Storage, path: &str, config: &Config) -> Result<File> {
+    ///
+    /// The file is returned compressed if it's stored with one of the `accepted_encodings`.
+    pub(super) fn from_path(
+        storage: &Storage,
+        path: &str,
+        config: &Config,
+        accepted_encodings: &[CompressionAlgorithm],
+    ) -> Result<File> {
         let max_size = if path.ends_with(".html") {
             config.max_file_size_html
         } else {
             config.max_file_size
         };
 
-        Ok(File(storage.get(path, max_size)?))
+        Ok(File(storage.get_encoded(
+            path,
+            max_size,
+            accepted_encodings,
+        )?))
     }
 
     /// Consumes File and creates a iron response
     pub(super) fn serve(self) -> Response {
-        use iron::headers::{CacheControl, CacheDirective, ContentType, HttpDate, LastModified};
+        use iron::headers::{
+            CacheControl, CacheDirective, ContentEncoding, ContentType, HttpDate, LastModified,
+        };
 
         let mut response = Response::with((status::Ok, self.0.content));
         let cache = vec![
@@ -32,6 +46,15 @@ impl File {
             .headers
             .set(ContentType(self.0.mime.parse().unwrap()));
         response.headers.set(CacheControl(cache));
+        if let Some(encoding) = self.0.compression.and_then(|alg| alg.content_encoding()) {
+            response
+                .headers
+                .set(ContentEncoding(vec![encoding.parse().unwrap()]));
+        }
+        // the same URL is served with a different encoding depending on the request
+        response
+            .headers
+            .set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
         // FIXME: This is so horrible
         response.headers.set(LastModified(HttpDate(
             time::strptime(
@@ -44,6 +67,27 @@ impl File {
     }
 }
 
+/// The compression algorithms the client accepts in the `Accept-Encoding` header, which can be
+/// served without decompressing the files.
+pub(super) fn accepted_encodings(req: &Request) -> Vec<CompressionAlgorithm> {
+    let accepted = match req.headers.get::<AcceptEncoding>() {
+        Some(accepted) => accepted,
+        None => return Vec::new(),
+    };
+
+    accepted
+        .iter()
+        // a quality of 0 means the encoding is not acceptable
+        .filter(|quality| quality.quality > iron::headers::q(0.0))
+        .filter_map(|quality| match &quality.item {
+            Encoding::Gzip => Some(CompressionAlgorithm::Gzip),
+            Encoding::EncodingExt(ext) if ext == "br" => Some(CompressionAlgorithm::Brotli),
+            Encoding::EncodingExt(ext) if ext == "zstd" => Some(CompressionAlgorithm::Zstd),
+            _ => None,
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,6 +105,7 @@ mod tests {
                 &env.storage(),
                 "rustdoc/fake-package/1.0.0/fake-package/index.html",
                 &env.config(),
+                &[],
             )
             .unwrap();
             file.0.date_updated = now;
@@ -102,6 +147,7 @@ mod tests {
                     &env.storage(),
                     &format!("rustdoc/dummy/0.1.0/{}", path),
                     &env.config(),
+                    &[],
                 )
             };
             let assert_len = |len, path| {
@@ -74,8 +74,10 @@ macro_rules! extension {
 
 mod build_details;
 mod builds;
+mod coverage;
 pub(crate) mod crate_details;
 mod csp;
+mod download;
 mod error;
 mod extensions;
 mod features;
@@ -84,6 +86,7 @@ pub(crate) mod metrics;
 mod releases;
 mod routes;
 mod rustdoc;
+mod rustdoc_json;
 mod sitemap;
 mod source;
 mod statics;
@@ -103,7 +106,7 @@ use iron::{
     Chain, Handler, Iron, IronError, IronResult, Listening, Request, Response, Url,
 };
 use page::TemplateData;
-use postgres::Client;
+use postgres::{Client, GenericClient};
 use router::{NoRoute, TrailingSlash};
 use semver::{Version, VersionReq};
 use serde::Serialize;
@@ -517,6 +520,8 @@ pub(crate) struct MetaData {
     pub(crate) rustdoc_status: bool,
     pub(crate) default_target: String,
     pub(crate) doc_targets: Vec<String>,
+    /// the targets that failed to build in the latest build, shown with a link to their log
+    pub(crate) failed_targets: Vec<FailedTarget>,
     pub(crate) yanked: bool,
     /// CSS file to use depending on the rustdoc version used to generate this version of this
     /// crate.
@@ -529,10 +534,9 @@ impl MetaData {
         name: &str,
         version: &str,
         version_or_latest: &str,
-    ) -> Option<MetaData> {
-        let rows = conn
-            .query(
-                "SELECT crates.name,
+    ) -> Result<Option<MetaData>, anyhow::Error> {
+        let rows = conn.query(
+            "SELECT crates.name,
                        releases.version,
                        releases.description,
                        releases.target_name,
@@ -544,13 +548,15 @@ impl MetaData {
                 FROM releases
                 INNER JOIN crates ON crates.id = releases.crate_id
                 WHERE crates.name = $1 AND releases.version = $2",
-                &[&name, &version],
-            )
-            .unwrap();
+            &[&name, &version],
+        )?;
 
-        let row = rows.get(0)?;
+        let row = match rows.get(0) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
 
-        Some(MetaData {
+        Ok(Some(MetaData {
             name: row.get(0),
             version: row.get(1),
             version_or_latest: version_or_latest.to_string(),
@@ -559,9 +565,40 @@ impl MetaData {
             rustdoc_status: row.get(4),
             default_target: row.get(5),
             doc_targets: MetaData::parse_doc_targets(row.get(6)),
+            failed_targets: MetaData::load_failed_targets(conn, name, version)?,
             yanked: row.get(7),
-            rustdoc_css_file: get_correct_docsrs_style_file(row.get(8)).unwrap(),
-        })
+            rustdoc_css_file: get_correct_docsrs_style_file(row.get(8))?,
+        }))
+    }
+
+    /// Loads the targets that failed to build in the latest build of a release.
+    fn load_failed_targets(
+        conn: &mut impl GenericClient,
+        name: &str,
+        version: &str,
+    ) -> Result<Vec<FailedTarget>, anyhow::Error> {
+        Ok(conn
+            .query(
+                "SELECT build_targets.target, build_targets.build_id
+                 FROM build_targets
+                 WHERE
+                    build_targets.build_id = (
+                        SELECT MAX(builds.id)
+                        FROM builds
+                        INNER JOIN releases ON releases.id = builds.rid
+                        INNER JOIN crates ON crates.id = releases.crate_id
+                        WHERE crates.name = $1 AND releases.version = $2
+                    )
+                    AND NOT build_targets.successful
+                 ORDER BY build_targets.target",
+                &[&name, &version],
+            )?
+            .into_iter()
+            .map(|row| FailedTarget {
+                target: row.get(0),
+                build_id: row.get(1),
+            })
+            .collect())
     }
 
     fn parse_doc_targets(targets: Value) -> Vec<String> {
@@ -577,6 +614,13 @@ impl MetaData {
     }
 }
 
+/// A target whose documentation failed to build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct FailedTarget {
+    pub(crate) target: String,
+    pub(crate) build_id: i32,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub(crate) struct ErrorPage {
     /// The title of the page
@@ -665,6 +709,15 @@ mod test {
                     .unwrap()
                     .any(|e| e.text_contents().contains(value)));
             }
+            assert_eq!(
+                foo_crate
+                    .select_first("a.coverage-report")
+                    .unwrap()
+                    .attributes
+                    .borrow()
+                    .get("href"),
+                Some("/crate/foo/0.0.1/coverage")
+            );
 
             let foo_doc = kuchiki::parse_html().one(web.get("/foo/0.0.1/foo").send()?.text()?);
             assert!(foo_doc
@@ -939,6 +992,7 @@ mod test {
                 "x86_64-unknown-linux-gnu".to_string(),
                 "arm64-unknown-linux-gnu".to_string(),
             ],
+            failed_targets: vec![],
             yanked: false,
             rustdoc_css_file: "rustdoc.css".to_string(),
         };
@@ -955,6 +1009,7 @@ mod test {
                 "x86_64-unknown-linux-gnu",
                 "arm64-unknown-linux-gnu",
             ],
+            "failed_targets": [],
             "yanked": false,
             "rustdoc_css_file": "rustdoc.css",
         });
@@ -974,6 +1029,7 @@ mod test {
                 "x86_64-unknown-linux-gnu",
                 "arm64-unknown-linux-gnu",
             ],
+            "failed_targets": [],
             "yanked": false,
             "rustdoc_css_file": "rustdoc.css",
         });
@@ -993,6 +1049,7 @@ mod test {
                 "x86_64-unknown-linux-gnu",
                 "arm64-unknown-linux-gnu",
             ],
+            "failed_targets": [],
             "yanked": false,
             "rustdoc_css_file": "rustdoc.css",
         });
@@ -1005,7 +1062,7 @@ mod test {
         wrapper(|env| {
             release("0.1.0", env);
             let mut conn = env.db().conn();
-            let metadata = MetaData::from_crate(&mut conn, "foo", "0.1.0", "latest");
+            let metadata = MetaData::from_crate(&mut conn, "foo", "0.1.0", "latest")?;
             assert_eq!(
                 metadata.unwrap(),
                 MetaData {
@@ -1017,6 +1074,7 @@ mod test {
                     rustdoc_status: true,
                     default_target: "x86_64-unknown-linux-gnu".to_string(),
                     doc_targets: vec![],
+                    failed_targets: vec![],
                     yanked: false,
                     rustdoc_css_file: "rustdoc.css".to_string(),
                 },
@@ -1,4 +1,4 @@
-use crate::error::Result;
+use crate::{docbuilder::BuildFailure, error::Result};
 use anyhow::Context;
 use chrono::{DateTime, Utc};
 use path_slash::PathExt;
@@ -104,6 +104,7 @@ pub(super) fn load_templates(conn: &mut Client) -> Result<Tera> {
     tera.register_filter("timeformat", timeformat);
     tera.register_filter("dbg", dbg);
     tera.register_filter("dedent", dedent);
+    tera.register_filter("failure_reason", failure_reason);
     tera.register_filter("fas", IconType::Strong);
     tera.register_filter("far", IconType::Regular);
     tera.register_filter("fab", IconType::Brand);
@@ -237,6 +238,16 @@ fn dedent(value: &Value, args: &HashMap<String, Value>) -> TeraResult<Value> {
     Ok(Value::String(unindented))
 }
 
+/// Describe the reason of a failed build
+fn failure_reason(value: &Value, _args: &HashMap<String, Value>) -> TeraResult<Value> {
+    let reason: BuildFailure = value
+        .as_str()
+        .and_then(|reason| reason.parse().ok())
+        .ok_or_else(|| tera::Error::msg(format!("invalid build failure reason {}", value)))?;
+
+    Ok(Value::String(reason.description().into()))
+}
+
 enum IconType {
     Strong,
     Regular,
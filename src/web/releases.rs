@@ -1,8 +1,9 @@
 //! Releases web handlers
 
 use crate::{
-    build_queue::QueuedCrate,
+    build_queue::{QuarantinedCrate, QueuedCrate},
     db::{Pool, PoolClient},
+    docbuilder::BuildFailure,
     impl_webpage,
     utils::report_error,
     web::{error::Nope, match_version, page::WebPage, redirect_base},
@@ -22,6 +23,7 @@ use router::Router;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str;
+use strum::IntoEnumIterator;
 use url::form_urlencoded;
 
 /// Number of release in home page
@@ -40,6 +42,7 @@ pub struct Release {
     rustdoc_status: bool,
     pub(crate) build_time: DateTime<Utc>,
     stars: i32,
+    failure_reason: Option<BuildFailure>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -56,7 +59,15 @@ impl Default for Order {
     }
 }
 
-pub(crate) fn get_releases(conn: &mut Client, page: i64, limit: i64, order: Order) -> Vec<Release> {
+/// Lists the latest releases of the crates, only the failed ones with `failure_reason` if the
+/// order is one of the failure orders.
+pub(crate) fn get_releases(
+    conn: &mut Client,
+    page: i64,
+    limit: i64,
+    order: Order,
+    failure_reason: Option<BuildFailure>,
+) -> Vec<Release> {
     let offset = (page - 1) * limit;
 
     // WARNING: it is _crucial_ that this always be hard-coded and NEVER be user input
@@ -73,13 +84,15 @@ pub(crate) fn get_releases(conn: &mut Client, page: i64, limit: i64, order: Orde
             releases.target_name,
             releases.rustdoc_status,
             builds.build_time,
-            repositories.stars
+            repositories.stars,
+            builds.failure_reason
         FROM crates
         INNER JOIN releases ON crates.latest_version_id = releases.id
         INNER JOIN builds ON releases.id = builds.rid
         LEFT JOIN repositories ON releases.repository_id = repositories.id
         WHERE
             ((NOT $3) OR (releases.build_status = FALSE AND releases.is_library = TRUE)) 
+            AND ($4::TEXT IS NULL OR builds.failure_reason = $4)
             AND {0} IS NOT NULL
 
         ORDER BY {0} DESC
@@ -87,19 +100,28 @@ pub(crate) fn get_releases(conn: &mut Client, page: i64, limit: i64, order: Orde
         ordering,
     );
 
-    conn.query(query.as_str(), &[&limit, &offset, &filter_failed])
-        .unwrap()
-        .into_iter()
-        .map(|row| Release {
-            name: row.get(0),
-            version: row.get(1),
-            description: row.get(2),
-            target_name: row.get(3),
-            rustdoc_status: row.get(4),
-            build_time: row.get(5),
-            stars: row.get::<_, Option<i32>>(6).unwrap_or(0),
-        })
-        .collect()
+    let failure_reason = failure_reason
+        .filter(|_| filter_failed)
+        .map(|reason| reason.to_string());
+    conn.query(
+        query.as_str(),
+        &[&limit, &offset, &filter_failed, &failure_reason],
+    )
+    .unwrap()
+    .into_iter()
+    .map(|row| Release {
+        name: row.get(0),
+        version: row.get(1),
+        description: row.get(2),
+        target_name: row.get(3),
+        rustdoc_status: row.get(4),
+        build_time: row.get(5),
+        stars: row.get::<_, Option<i32>>(6).unwrap_or(0),
+        failure_reason: row
+            .get::<_, Option<String>>(7)
+            .and_then(|reason| reason.parse().ok()),
+    })
+    .collect()
 }
 
 fn get_releases_by_owner(
@@ -150,6 +172,7 @@ fn get_releases_by_owner(
                 build_time: row.get(4),
                 rustdoc_status: row.get(5),
                 stars: row.get::<_, Option<i32>>(6).unwrap_or(0),
+                failure_reason: None,
             }
         })
         .collect();
@@ -268,6 +291,7 @@ fn get_search_results(
                     target_name: row.get("target_name"),
                     rustdoc_status: row.get("rustdoc_status"),
                     stars: stars.unwrap_or(0),
+                    failure_reason: None,
                 },
             )
         })
@@ -299,7 +323,7 @@ impl_webpage! {
 
 pub fn home_page(req: &mut Request) -> IronResult<Response> {
     let mut conn = extension!(req, Pool).get()?;
-    let recent_releases = get_releases(&mut conn, 1, RELEASES_IN_HOME, Order::ReleaseTime);
+    let recent_releases = get_releases(&mut conn, 1, RELEASES_IN_HOME, Order::ReleaseTime, None);
 
     HomePage { recent_releases }.into_response(req)
 }
@@ -316,7 +340,7 @@ impl_webpage! {
 
 pub fn releases_feed_handler(req: &mut Request) -> IronResult<Response> {
     let mut conn = extension!(req, Pool).get()?;
-    let recent_releases = get_releases(&mut conn, 1, RELEASES_IN_FEED, Order::ReleaseTime);
+    let recent_releases = get_releases(&mut conn, 1, RELEASES_IN_FEED, Order::ReleaseTime, None);
 
     ReleaseFeed { recent_releases }.into_response(req)
 }
@@ -330,6 +354,11 @@ struct ViewReleases {
     show_previous_page: bool,
     page_number: i64,
     owner: Option<String>,
+    /// the reasons the failures can be filtered by, empty for the other pages
+    failure_reasons: Vec<BuildFailure>,
+    failure_reason: Option<BuildFailure>,
+    /// appended to the links of the pagination
+    query: String,
 }
 
 impl_webpage! {
@@ -367,9 +396,29 @@ fn releases_handler(req: &mut Request, release_type: ReleaseType) -> IronResult<
         ),
     };
 
+    let is_failures = matches!(
+        release_type,
+        ReleaseType::RecentFailures | ReleaseType::Failures
+    );
+    let failure_reason: Option<BuildFailure> = if is_failures {
+        req.url
+            .as_ref()
+            .query_pairs()
+            .find(|(key, _)| key == "reason")
+            .and_then(|(_, reason)| reason.parse().ok())
+    } else {
+        None
+    };
+
     let releases = {
         let mut conn = extension!(req, Pool).get()?;
-        get_releases(&mut conn, page_number, RELEASES_IN_RELEASES, release_order)
+        get_releases(
+            &mut conn,
+            page_number,
+            RELEASES_IN_RELEASES,
+            release_order,
+            failure_reason,
+        )
     };
 
     // Show next and previous page buttons
@@ -386,6 +435,15 @@ fn releases_handler(req: &mut Request, release_type: ReleaseType) -> IronResult<
         show_previous_page,
         page_number,
         owner: None,
+        failure_reasons: if is_failures {
+            BuildFailure::iter().collect()
+        } else {
+            Vec::new()
+        },
+        failure_reason,
+        query: failure_reason
+            .map(|reason| format!("?reason={}", reason))
+            .unwrap_or_default(),
     }
     .into_response(req)
 }
@@ -445,6 +503,9 @@ pub fn owner_handler(req: &mut Request) -> IronResult<Response> {
         show_previous_page,
         page_number,
         owner: Some(owner_route_value.into()),
+        failure_reasons: Vec::new(),
+        failure_reason: None,
+        query: String::new(),
     }
     .into_response(req)
 }
@@ -729,6 +790,7 @@ pub fn activity_handler(req: &mut Request) -> IronResult<Response> {
 struct BuildQueuePage {
     description: &'static str,
     queue: Vec<QueuedCrate>,
+    quarantined: Vec<QuarantinedCrate>,
 }
 
 impl_webpage! {
@@ -736,17 +798,23 @@ impl_webpage! {
 }
 
 pub fn build_queue_handler(req: &mut Request) -> IronResult<Response> {
-    let mut queue = ctry!(req, extension!(req, BuildQueue).queued_crates());
+    let build_queue = extension!(req, BuildQueue);
+    let mut queue = ctry!(req, build_queue.queued_crates());
+    let quarantined = ctry!(req, build_queue.quarantined_crates());
+    let now = Utc::now();
     for krate in queue.iter_mut() {
         // The priority here is inverted: in the database if a crate has a higher priority it
         // will be built after everything else, which is counter-intuitive for people not
         // familiar with docs.rs's inner workings.
         krate.priority = -krate.priority;
+        // only show when crates are deferred
+        krate.next_attempt_at = krate.next_attempt_at.filter(|at| *at > now);
     }
 
     BuildQueuePage {
         description: "List of crates scheduled to build",
         queue,
+        quarantined,
     }
     .into_response(req)
 }
@@ -755,7 +823,7 @@ pub fn build_queue_handler(req: &mut Request) -> IronResult<Response> {
 mod tests {
     use super::*;
     use crate::index::api::CrateOwner;
-    use crate::test::{assert_redirect, assert_success, wrapper, TestFrontend};
+    use crate::test::{assert_redirect, assert_success, wrapper, FakeBuild, TestFrontend};
     use anyhow::Error;
     use chrono::{Duration, TimeZone};
     use kuchiki::traits::TendrilSink;
@@ -783,7 +851,7 @@ mod tests {
             // release without stars will not be shown
             env.fake_release().name("baz").version("1.0.0").create()?;
 
-            let releases = get_releases(&mut db.conn(), 1, 10, Order::GithubStars);
+            let releases = get_releases(&mut db.conn(), 1, 10, Order::GithubStars, None);
             assert_eq!(
                 vec![
                     "bar", // 20 stars
@@ -1235,6 +1303,60 @@ mod tests {
         })
     }
 
+    #[test]
+    fn failures_filtered_by_reason() {
+        wrapper(|env| {
+            env.fake_release()
+                .name("oom")
+                .version("0.1.0")
+                .github_stats("some/repo", 33, 22, 11)
+                .builds(vec![
+                    FakeBuild::default().failure_reason(BuildFailure::OutOfMemory)
+                ])
+                .create()?;
+            env.fake_release()
+                .name("compile_error")
+                .version("0.1.0")
+                .github_stats("some/repo", 33, 22, 11)
+                .builds(vec![
+                    FakeBuild::default().failure_reason(BuildFailure::CompileError)
+                ])
+                .create()?;
+
+            for page in &["/releases/recent-failures", "/releases/failures"] {
+                assert_eq!(get_release_links(page, env.frontend())?.len(), 2);
+
+                let links =
+                    get_release_links(&format!("{}?reason=out_of_memory", page), env.frontend())?;
+                assert_eq!(links, vec!["/oom/0.1.0/oom/".to_string()]);
+            }
+
+            let page = kuchiki::parse_html().one(
+                env.frontend()
+                    .get("/releases/recent-failures?reason=compile_error")
+                    .send()?
+                    .text()?,
+            );
+            let selected = page
+                .select_first("#reason option[selected]")
+                .expect("missing selected reason");
+            assert_eq!(selected.text_contents(), "Compile error");
+            assert!(page
+                .select_first(".release .description")
+                .unwrap()
+                .text_contents()
+                .contains("Compile error:"));
+
+            // the other pages ignore the reason
+            assert_eq!(
+                get_release_links("/releases/recent/1?reason=compile_error", env.frontend())?.len(),
+                2
+            );
+
+            Ok(())
+        })
+    }
+
     #[test]
     fn releases_homepage_and_recent() {
         wrapper(|env| {
@@ -1378,6 +1500,83 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_releases_queue_deferred() {
+        wrapper(|env| {
+            let queue = env.build_queue();
+            let web = env.frontend();
+
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate_not_before(
+                "bar",
+                "0.1.0",
+                0,
+                None,
+                Some(Utc.ymd(2099, 1, 1).and_hms(12, 0, 0)),
+            )?;
+            env.db()
+                .conn()
+                .execute("UPDATE queue SET attempt = 1 WHERE name = 'bar'", &[])?;
+
+            let page = kuchiki::parse_html().one(web.get("/releases/queue").send()?.text()?);
+            let items: Vec<_> = page
+                .select(".queue-list > li")
+                .expect("missing list items")
+                .map(|li| {
+                    li.text_contents()
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            assert_eq!(
+                items,
+                vec![
+                    "foo 1.0.0",
+                    "bar 0.1.0 (failed 1 time) (not before 2099-01-01 12:00:00 UTC)",
+                ]
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_releases_queue_quarantined() {
+        wrapper(|env| {
+            let queue = env.build_queue();
+            let web = env.frontend();
+
+            queue.add_crate("foo", "1.0.0", 0, None)?;
+            queue.add_crate("bar", "0.1.0", 0, None)?;
+            queue.process_next_crate(|_| panic!("the builder fell over"))?;
+
+            let page = kuchiki::parse_html().one(web.get("/releases/queue").send()?.text()?);
+            let queued: Vec<_> = page
+                .select(".queue-list > li a")
+                .expect("missing list items")
+                .map(|a| {
+                    a.text_contents()
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            assert_eq!(queued, vec!["bar 0.1.0"]);
+
+            let quarantined: Vec<_> = page
+                .select(".quarantine-list > li")
+                .expect("missing quarantined items")
+                .map(|li| li.text_contents())
+                .collect();
+            assert_eq!(quarantined.len(), 1);
+            assert!(quarantined[0].contains("foo 1.0.0"));
+            assert!(quarantined[0].contains("the builder fell over"));
+
+            Ok(())
+        });
+    }
+
     #[test]
     fn nonexistent_owner_page() {
         wrapper(|env| {
@@ -115,10 +115,30 @@ pub(super) fn build_routes() -> Routes {
         "/crate/:name/:version/builds/:id",
         super::build_details::build_details_handler,
     );
+    routes.internal_page(
+        "/crate/:name/:version/coverage",
+        super::coverage::coverage_handler,
+    );
+    routes.static_resource(
+        "/crate/:name/:version/coverage.json",
+        super::coverage::coverage_handler,
+    );
     routes.internal_page(
         "/crate/:name/:version/features",
         super::features::build_features_handler,
     );
+    routes.internal_page(
+        "/crate/:name/:version/download",
+        super::download::rustdoc_download_handler,
+    );
+    routes.internal_page(
+        "/crate/:name/:version/json",
+        super::rustdoc_json::rustdoc_json_handler,
+    );
+    routes.internal_page(
+        "/crate/:name/:version/source.tar.gz",
+        super::download::source_download_handler,
+    );
     routes.internal_page(
         "/crate/:name/:version/source",
         SimpleRedirect::new(|url| url.set_path(&format!("{}/", url.path()))),
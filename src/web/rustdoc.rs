@@ -5,8 +5,13 @@ use crate::{
     repositories::RepositoryStatsUpdater,
     utils,
     web::{
-        crate_details::CrateDetails, csp::Csp, error::Nope, file::File, match_version,
-        metrics::RenderingTimesRecorder, redirect_base, MatchSemver, MetaData,
+        crate_details::CrateDetails,
+        csp::Csp,
+        error::Nope,
+        file::{accepted_encodings, File},
+        match_version,
+        metrics::RenderingTimesRecorder,
+        redirect_base, MatchSemver, MetaData,
     },
     Config, Metrics, Storage,
 };
@@ -121,7 +126,7 @@ pub fn rustdoc_redirector_handler(req: &mut Request) -> IronResult<Response> {
 
             let path = req.url.path();
             let path = path.join("/");
-            return match File::from_path(storage, &path, config) {
+            return match File::from_path(storage, &path, config, &accepted_encodings(req)) {
                 Ok(f) => Ok(f.serve()),
                 Err(..) => Err(Nope::ResourceNotFound.into()),
             };
@@ -377,12 +382,20 @@ pub fn rustdoc_html_server_handler(req: &mut Request) -> IronResult<Response> {
     }
     let mut path = ctry!(req, percent_decode(path.as_bytes()).decode_utf8());
 
+    // HTML pages are rewritten before serving them, everything else can be served compressed
+    let accepted_encodings = if path.ends_with(".html") {
+        Vec::new()
+    } else {
+        accepted_encodings(req)
+    };
+
     // Attempt to load the file from the database
     let blob = match storage.fetch_rustdoc_file(
         &name,
         &version,
         &path,
         krate.archive_storage,
+        &accepted_encodings,
         &mut rendering_time,
     ) {
         Ok(file) => file,
@@ -708,7 +721,9 @@ impl Handler for SharedResourceHandler {
                 let storage = extension!(req, Storage);
                 let config = extension!(req, Config);
 
-                if let Ok(file) = File::from_path(storage, filename, config) {
+                if let Ok(file) =
+                    File::from_path(storage, filename, config, &accepted_encodings(req))
+                {
                     return Ok(file.serve());
                 }
             }
@@ -791,6 +806,48 @@ mod test {
         });
     }
 
+    #[test_case(true)]
+    #[test_case(false)]
+    fn serves_precompressed_assets(archive_storage: bool) {
+        wrapper(|env| {
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .archive_storage(archive_storage)
+                .rustdoc_file("dummy/index.html")
+                .rustdoc_file_with("dummy/search.js", b"var searchIndex = {};" as &[u8])
+                .create()?;
+            let web = env.frontend();
+
+            let resp = web
+                .get("/dummy/0.1.0/dummy/search.js")
+                .header("Accept-Encoding", "gzip, zstd")
+                .send()?;
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers()["Content-Encoding"], "zstd");
+            assert_eq!(resp.headers()["Vary"], "Accept-Encoding");
+            assert_eq!(zstd::decode_all(&*resp.bytes()?)?, b"var searchIndex = {};");
+
+            // the client doesn't support zstd
+            let resp = web
+                .get("/dummy/0.1.0/dummy/search.js")
+                .header("Accept-Encoding", "gzip, zstd;q=0")
+                .send()?;
+            assert!(resp.headers().get("Content-Encoding").is_none());
+            assert_eq!(resp.bytes()?, b"var searchIndex = {};" as &[u8]);
+
+            // HTML pages are always decompressed to rewrite them
+            let resp = web
+                .get("/dummy/0.1.0/dummy/index.html")
+                .header("Accept-Encoding", "zstd")
+                .send()?;
+            assert!(resp.headers().get("Content-Encoding").is_none());
+            assert!(resp.text()?.contains("<html"));
+
+            Ok(())
+        });
+    }
+
     #[test_case(true)]
     #[test_case(false)]
     fn default_target_redirects_to_base(archive_storage: bool) {
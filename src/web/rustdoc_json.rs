@@ -0,0 +1,126 @@
+//! The rustdoc JSON output of the default target of a release.
+
+use super::{
+    error::Nope,
+    file::{accepted_encodings, File},
+    match_version, redirect_base, MatchSemver,
+};
+use crate::{db::Pool, storage::rustdoc_json_path, Config, Storage};
+use iron::{IronResult, Request, Response, Url};
+use router::Router;
+
+/// The header with the `format_version` of the served rustdoc JSON output.
+const FORMAT_VERSION_HEADER: &str = "X-Rustdoc-Json-Format-Version";
+
+/// `/crate/:name/:version/json`, the rustdoc JSON output of the default target of a release.
+pub fn rustdoc_json_handler(req: &mut Request) -> IronResult<Response> {
+    let router = extension!(req, Router);
+    let name = cexpect!(req, router.find("name"));
+    let req_version = router.find("version");
+
+    let mut conn = extension!(req, Pool).get()?;
+    let version =
+        match match_version(&mut conn, name, req_version).and_then(|m| m.assume_exact())? {
+            MatchSemver::Exact((version, _)) => version,
+            MatchSemver::Latest((version, _)) | MatchSemver::Semver((version, _)) => {
+                let url = ctry!(
+                    req,
+                    Url::parse(&format!(
+                        "{}/crate/{}/{}/json",
+                        redirect_base(req),
+                        name,
+                        version
+                    )),
+                );
+                return Ok(super::redirect(url));
+            }
+        };
+    let row = ctry!(
+        req,
+        conn.query_opt(
+            "SELECT releases.rustdoc_json, releases.rustdoc_json_format_version
+             FROM releases
+             INNER JOIN crates ON crates.id = releases.crate_id
+             WHERE crates.name = $1 AND releases.version = $2",
+            &[&name, &version],
+        )
+    )
+    .ok_or(Nope::VersionNotFound)?;
+
+    let (rustdoc_json, format_version): (bool, Option<i32>) = (row.get(0), row.get(1));
+    if !rustdoc_json {
+        return Err(Nope::ResourceNotFound.into());
+    }
+
+    let storage = extension!(req, Storage);
+    let config = extension!(req, Config);
+    let file = match File::from_path(
+        storage,
+        &rustdoc_json_path(name, &version),
+        config,
+        &accepted_encodings(req),
+    ) {
+        Ok(file) => file,
+        Err(_) => return Err(Nope::ResourceNotFound.into()),
+    };
+
+    let mut response = file.serve();
+    if let Some(format_version) = format_version {
+        response.headers.set_raw(
+            FORMAT_VERSION_HEADER,
+            vec![format_version.to_string().into_bytes()],
+        );
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test::{assert_redirect, wrapper};
+    use reqwest::StatusCode;
+
+    #[test]
+    fn serve_rustdoc_json() {
+        wrapper(|env| {
+            let json = br#"{"format_version":15,"root":"0:0"}"#;
+            env.fake_release()
+                .name("dummy")
+                .version("0.1.0")
+                .rustdoc_json(15, json)
+                .create()?;
+
+            let web = env.frontend();
+            let response = web.get("/crate/dummy/0.1.0/json").send()?;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers()[FORMAT_VERSION_HEADER].to_str().unwrap(),
+                "15"
+            );
+            assert_eq!(
+                response.headers()["Content-Type"].to_str().unwrap(),
+                "application/json"
+            );
+            assert_eq!(response.bytes()?.as_ref(), json);
+
+            assert_redirect("/crate/dummy/latest/json", "/crate/dummy/0.1.0/json", web)?;
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn missing_rustdoc_json() {
+        wrapper(|env| {
+            env.fake_release().name("dummy").version("0.1.0").create()?;
+
+            let web = env.frontend();
+            let response = web.get("/crate/dummy/0.1.0/json").send()?;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            let response = web.get("/crate/missing/0.1.0/json").send()?;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            Ok(())
+        });
+    }
+}
@@ -1,4 +1,12 @@
-use crate::{db::Pool, docbuilder::Limits, impl_webpage, web::error::Nope, web::page::WebPage};
+use crate::{
+    db::Pool,
+    docbuilder::Limits,
+    impl_webpage,
+    utils::{list_campaigns, Campaign},
+    web::error::Nope,
+    web::page::WebPage,
+    BuildQueue,
+};
 use chrono::{DateTime, Utc};
 use iron::{
     headers::ContentType,
@@ -94,6 +102,8 @@ struct AboutBuilds {
     rustc_version: Option<String>,
     /// The default crate build limits
     limits: Limits,
+    /// The rebuild campaigns which are still running
+    campaigns: Vec<Campaign>,
     /// Just for the template, since this isn't shared with AboutPage
     active_tab: &'static str,
 }
@@ -115,9 +125,15 @@ pub fn about_builds_handler(req: &mut Request) -> IronResult<Response> {
         }
     });
 
+    let campaigns = ctry!(req, list_campaigns(&mut conn, extension!(req, BuildQueue)))
+        .into_iter()
+        .filter(Campaign::is_active)
+        .collect();
+
     AboutBuilds {
         rustc_version,
         limits: Limits::default(),
+        campaigns,
         active_tab: "builds",
     }
     .into_response(req)
@@ -249,6 +265,38 @@ mod tests {
         })
     }
 
+    #[test]
+    fn about_builds_shows_campaigns() {
+        wrapper(|env| {
+            use crate::utils::{cancel_campaign, create_campaign, CampaignSelection};
+            use kuchiki::traits::TendrilSink;
+
+            env.fake_release().name("foo").version("0.1.0").create()?;
+            let mut conn = env.db().conn();
+            let selection = CampaignSelection::default();
+            create_campaign(&mut conn, "running", &selection, 20, 10)?;
+            let (cancelled, _) =
+                create_campaign(&mut conn, "cancelled", &selection, 20, 10)?.unwrap();
+            cancel_campaign(&mut conn, cancelled)?;
+
+            let page =
+                kuchiki::parse_html().one(env.frontend().get("/about/builds").send()?.text()?);
+            let campaigns: Vec<_> = page
+                .select(".rebuild-campaigns li")
+                .unwrap()
+                .map(|node| {
+                    node.text_contents()
+                        .split_whitespace()
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                })
+                .collect();
+            assert_eq!(campaigns, vec!["running: 0 of 1 releases rebuilt"]);
+
+            Ok(())
+        })
+    }
+
     #[test]
     fn robots_txt() {
         wrapper(|env| {
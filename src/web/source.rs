@@ -10,6 +10,7 @@ use crate::{
     },
     Storage,
 };
+use anyhow::Result;
 use iron::{IronResult, Request, Response};
 use postgres::Client;
 use router::Router;
@@ -57,10 +58,9 @@ impl FileList {
         version: &str,
         version_or_latest: &str,
         req_path: &str,
-    ) -> Option<FileList> {
-        let rows = conn
-            .query(
-                "SELECT crates.name,
+    ) -> Result<Option<FileList>> {
+        let rows = conn.query(
+            "SELECT crates.name,
                         releases.version,
                         releases.description,
                         releases.target_name,
@@ -73,15 +73,17 @@ impl FileList {
                 FROM releases
                 LEFT OUTER JOIN crates ON crates.id = releases.crate_id
                 WHERE crates.name = $1 AND releases.version = $2",
-                &[&name, &version],
-            )
-            .unwrap();
+            &[&name, &version],
+        )?;
 
         if rows.is_empty() {
-            return None;
+            return Ok(None);
         }
 
-        let files: Value = rows[0].try_get(5).ok()?;
+        let files: Value = match rows[0].try_get(5) {
+            Ok(files) => files,
+            Err(_) => return Ok(None),
+        };
 
         let mut file_list = Vec::new();
         if let Some(files) = files.as_array() {
@@ -124,7 +126,7 @@ impl FileList {
             }
 
             if file_list.is_empty() {
-                return None;
+                return Ok(None);
             }
 
             file_list.sort_by(|a, b| {
@@ -138,7 +140,7 @@ impl FileList {
                 }
             });
 
-            Some(FileList {
+            Ok(Some(FileList {
                 metadata: MetaData {
                     name: rows[0].get(0),
                     version: rows[0].get(1),
@@ -148,13 +150,14 @@ impl FileList {
                     rustdoc_status: rows[0].get(4),
                     default_target: rows[0].get(6),
                     doc_targets: MetaData::parse_doc_targets(rows[0].get(7)),
+                    failed_targets: MetaData::load_failed_targets(conn, name, version)?,
                     yanked: rows[0].get(8),
-                    rustdoc_css_file: get_correct_docsrs_style_file(rows[0].get(9)).unwrap(),
+                    rustdoc_css_file: get_correct_docsrs_style_file(rows[0].get(9))?,
                 },
                 files: file_list,
-            })
+            }))
         } else {
-            None
+            Ok(None)
         }
     }
 }
@@ -259,7 +262,7 @@ pub fn source_browser_handler(req: &mut Request) -> IronResult<Response> {
     // skip if request is a directory
     let blob = if !file_path.ends_with('/') {
         storage
-            .fetch_source_file(crate_name, &version, &file_path, archive_storage)
+            .fetch_source_file(crate_name, &version, &file_path, archive_storage, &[])
             .ok()
     } else {
         None
@@ -281,12 +284,15 @@ pub fn source_browser_handler(req: &mut Request) -> IronResult<Response> {
         (None, false)
     };
 
-    let file_list = FileList::from_path(
-        &mut conn,
-        crate_name,
-        &version,
-        &version_or_latest,
-        &req_path,
+    let file_list = ctry!(
+        req,
+        FileList::from_path(
+            &mut conn,
+            crate_name,
+            &version,
+            &version_or_latest,
+            &req_path,
+        )
     )
     .ok_or(Nope::ResourceNotFound)?;
 